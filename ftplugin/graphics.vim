@@ -5,9 +5,95 @@ endif
 let g:loaded_graphical_preview = 1
 
 let s:path = resolve(expand('<sfile>:p:h') . "/../")
-let s:inst = libcallex#load(s:path . "/target/release/libvim_graphical_preview.so")
+
+" Fallback to the daemon if the shared library can't be loaded, it answers the same calls
+let s:daemon = {'id': 0, 'responses': {}, 'partial': ''}
+
+function! s:daemon.call(method, args, type) dict
+    if get(self, 'rpc', 0)
+        let res = json_decode(rpcrequest(self.chan, a:method, a:args[0]))
+        return a:type ==# 'string' ? json_encode(res) : get(res, 'ok', 0)
+    endif
+
+    let self.id += 1
+    let req = json_encode({'id': self.id, 'method': a:method, 'params': a:args[0]})
+    if has('nvim')
+        call chansend(self.chan, req . "\n")
+        call wait(5000, {-> has_key(self.responses, self.id)})
+        let res = remove(self.responses, self.id)
+    else
+        let res = json_decode(ch_evalraw(self.chan, req . "\n"))['result']
+    endif
+
+    return a:type ==# 'string' ? json_encode(res) : get(res, 'ok', 0)
+endfunction
+
+" Socket data arrives in arbitrary pieces, the first line continues the last incomplete one and
+" the last line is incomplete until data ending with a newline was received
+function! s:daemon.on_stdout(job, lines, event) dict
+    let lines = copy(a:lines)
+    let lines[0] = self.partial . lines[0]
+    let self.partial = remove(lines, -1)
+
+    for line in lines
+        if !empty(line)
+            let res = json_decode(line)
+            let self.responses[res['id']] = res['result']
+        endif
+    endfor
+endfunction
+
+function! s:StartDaemon() abort
+    let cmd = [s:path . "/target/release/vim-graphical-preview"]
+    if has('nvim')
+        let s:daemon.chan = jobstart(cmd + ['--msgpack'], {'rpc': v:true})
+        let s:daemon.rpc = 1
+    else
+        let s:daemon.chan = job_getchannel(job_start(cmd, {'in_mode': 'nl', 'out_mode': 'nl', 'err_io': 'null'}))
+    endif
+
+    return s:daemon
+endfunction
+
+" Connect to a daemon listening on a unix socket, started with `--socket`
+function! s:ConnectDaemon(path) abort
+    if has('nvim')
+        let s:daemon.chan = sockconnect('pipe', a:path, {'on_data': s:daemon.on_stdout})
+    else
+        let s:daemon.chan = ch_open('unix:' . a:path, {'mode': 'nl'})
+    endif
+
+    return s:daemon
+endfunction
+
+" Call a function of the renderer for the current buffer
+function! s:Call(method, arg, type)
+    return s:inst.call(a:method, [bufnr('%') . "\n" . a:arg], a:type)
+endfunction
+
+if exists('g:graphical_preview_socket')
+    let s:inst = s:ConnectDaemon(g:graphical_preview_socket)
+else
+    try
+        let s:inst = libcallex#load(s:path . "/target/release/libvim_graphical_preview.so")
+    catch
+        let s:inst = s:StartDaemon()
+    endtry
+endif
 let s:folds = []
 
+let s:res = json_decode(s:Call("update_config", json_encode(get(g:, 'graphical_preview_config', {})), "string"))
+if has_key(s:res, 'err')
+    echoerr "Error: " . s:res['err']
+endif
+
+let s:caps = json_decode(s:Call("capabilities", "", "string"))
+if has_key(s:caps, 'ok') && !s:caps['ok']['supported']
+    echohl WarningMsg
+    echomsg "graphical-preview: no graphics protocol detected, set g:graphical_preview_config.backend to override"
+    echohl None
+endif
+
 function! PrintError(msg) abort
     execute 'normal! \<Esc>'
     echohl ErrorMsg
@@ -15,15 +101,104 @@ function! PrintError(msg) abort
     echohl None
 endfunction
 
-function! DrawInner(id)
-    let res = s:inst.call("draw", [""], "string")
+" Draw until the write budget is used up, the rest is continued from a timer so that user input
+" is handled in between
+function! s:DrawStep(method)
+    " images would be drawn over prompts, the draw waits until they are gone
+    if s:UpdateUiState()
+        let g:timer = timer_start(200, "DrawInner")
+        return
+    endif
+
+    let res = s:Call(a:method, "", "string")
     let res = json_decode(res)
 
+    if has_key(res, 'ok')
+        let b:graphical_preview_skipped = res['ok']['skipped']
+        redrawstatus
+    endif
+
     if has_key(res, 'err')
-	call PrintError("Error: " . res['err'])
-    elseif has_key(res, 'ok') && res['ok'] == 1
-	call Draw()
+        call PrintError("Error: " . res['err'])
+        call s:UpdateStatus()
+    elseif has_key(res, 'ok') && res['ok']['remaining'] > 0
+        let g:timer = timer_start(res['ok']['delay'], "ContinueDraw")
+    elseif has_key(res, 'ok') && res['ok']['pending'] > 0
+        call Draw()
+    else
+        call s:UpdateStatus()
+        call s:StartAnimation()
+    endif
+endfunction
+
+" Pass whether the command-line window or a more or hit-enter prompt is shown, returns it
+function! s:UpdateUiState(...)
+    let busy = a:0 > 0 ? a:1 : getcmdwintype() !=# '' || mode(1) =~# '^r'
+    if busy != get(s:, 'ui_busy', 0)
+        let s:ui_busy = busy
+        call s:Call("set_ui_state", busy ? "busy" : "idle", "string")
+    endif
+    return busy
+endfunction
+
+" Play animated images after a draw, the previous loop is stopped
+function! s:StartAnimation()
+    if exists('s:animate_timer')
+        call timer_stop(s:animate_timer)
     endif
+    call s:Animate()
+endfunction
+
+" Draw the next frames of animated images, repeated as long as any of them is visible
+function! s:Animate(...)
+    let res = json_decode(s:Call("animate", "", "string"))
+    if has_key(res, 'ok') && res['ok']['delay'] > 0
+        let s:animate_timer = timer_start(res['ok']['delay'], function('s:Animate'))
+    endif
+endfunction
+
+" Short summary of the nodes not shown in the last draw, for use in 'statusline'
+function! GraphicalPreviewStatusline()
+    let skipped = len(get(b:, 'graphical_preview_skipped', []))
+    if skipped == 0
+        return ''
+    endif
+
+    return skipped . (skipped == 1 ? ' figure' : ' figures') . ' not shown'
+endfunction
+
+" Show the fallback text of nodes which can't be displayed as virtual text
+function! s:UpdateStatus()
+    let res = json_decode(s:Call("node_status", "", "string"))
+    if !has_key(res, 'ok')
+        return
+    endif
+    let b:graphical_preview_status = res['ok']
+    let fallbacks = filter(copy(res['ok']), {_, node -> type(node['fallback']) == v:t_string})
+
+    if has('nvim')
+        let ns = nvim_create_namespace('graphical_preview')
+        call nvim_buf_clear_namespace(0, ns, 0, -1)
+        for node in fallbacks
+            call nvim_buf_set_extmark(0, ns, node['line'] - 1, 0, {'virt_text': [[node['fallback'], 'Comment']]})
+        endfor
+    elseif has('textprop') && has('patch-9.0.0067')
+        if empty(prop_type_get('graphical_preview_fallback'))
+            call prop_type_add('graphical_preview_fallback', {'highlight': 'Comment'})
+        endif
+        call prop_remove({'type': 'graphical_preview_fallback', 'all': 1})
+        for node in fallbacks
+            call prop_add(node['line'], 0, {'type': 'graphical_preview_fallback', 'text': ' ' . node['fallback'], 'text_align': 'after'})
+        endfor
+    endif
+endfunction
+
+function! DrawInner(id)
+    call s:DrawStep("draw")
+endfunction
+
+function! ContinueDraw(id)
+    call s:DrawStep("continue_draw")
 endfunction
 
 function! Draw()
@@ -34,6 +209,53 @@ function! Draw()
     let g:timer = timer_start(50, "DrawInner")
 endfunction
 
+" Search missing binaries again and draw nodes which were waiting for them
+function! s:RecheckBinaries(...)
+    let res = json_decode(s:Call("recheck_binaries", "", "string"))
+    if !has_key(res, 'ok')
+        return
+    endif
+
+    if res['ok']['retried'] > 0
+        call Draw()
+    endif
+    if a:0 == 0
+        echo empty(res['ok']['missing']) ? "no binaries missing" : "missing binaries: " . join(res['ok']['missing'], ", ")
+    endif
+endfunction
+
+" binaries installed while vim is running are picked up without editing the document
+if !exists('s:recheck_timer')
+    let s:recheck_timer = timer_start(5000, function('s:RecheckBinaries'), {'repeat': -1})
+endif
+
+" Colors of the Normal highlight, only known as `#rrggbb` in the GUI or with 'termguicolors'
+function! s:Colors()
+    let colors = {}
+    for [key, attr] in [['foreground', 'fg#'], ['background', 'bg#']]
+        let color = synIDattr(synIDtrans(hlID('Normal')), attr)
+        if color =~# '^#\x\{6}$'
+            let colors[key] = color
+        endif
+    endfor
+    return colors
+endfunction
+
+" All windows showing the current buffer, positions are one based like `win_screenpos`
+function! s:Windows()
+    let windows = []
+    for winid in win_findbuf(bufnr('%'))
+        let info = getwininfo(winid)[0]
+        call add(windows, {
+           \'file_range': [info['topline'], info['botline']],
+           \'viewport': [info['height'], info['width']],
+           \'winpos': [info['winrow'], info['wincol'] + get(info, 'textoff', 0)],
+           \})
+    endfor
+
+    return windows
+endfunction
+
 function! s:UpdateMetadata()
     let winpos = win_screenpos("0")
     if exists('&number') && &number == 1
@@ -46,50 +268,590 @@ function! s:UpdateMetadata()
        \'cursor': getcurpos()[1],
        \'winpos': winpos,
        \'char_height': 0,
+       \'char_width': 0,
+       \'windows': s:Windows(),
+       \'file': expand('%:p'),
+       \'filetype': &filetype,
+       \'colors': s:Colors(),
        \}
 
-    call s:inst.call("update_metadata", [json_encode(metadata)], "")
+    call s:Call("update_metadata", json_encode(metadata), "")
     call Draw()
 endfunction
 
-function! s:UpdateFolds()
+" Send the folds which changed since the last update, after a text change all folds are open
+function! s:UpdateFolds(reset)
     call s:UpdateMetadata()
-    let l:folding_state = []
+    let known = a:reset ? {} : get(b:, 'graphical_preview_fold_state', {})
+    let state = {}
+    let any_changed = 0
     for lnum in s:folds
-        call add(l:folding_state, [lnum, foldclosedend(lnum)])
+        let end = foldclosedend(lnum)
+        let state[lnum] = end
+        if get(known, lnum, -1) == end
+            continue
+        endif
+
+        if end == -1
+            let res = s:Call("fold_opened", lnum, "string")
+        else
+            let res = s:Call("fold_closed", lnum . " " . end, "string")
+        endif
+        let res = json_decode(res)
+        if has_key(res, 'err')
+            call PrintError("Error: " . res['err'])
+        elseif res['ok']
+            let any_changed = 1
+        endif
     endfor
+    let b:graphical_preview_fold_state = state
     mode
-    let any_changed =  s:inst.call("set_folds", [json_encode(folding_state)], "")
     if any_changed
         call Draw()
     endif
 endfunction
 
-function! s:TextChanged()
+" Changes reported by the listener since the last update, see `listener_add()`
+function! s:OnLines(bufnr, start, end, added, changes)
+    call add(b:graphical_preview_lines, [a:start, a:end, a:added])
+endfunction
+
+function! s:TextChanged(full)
     call s:UpdateMetadata()
-    let current_buf = join(getline(1,'$'), "\n")
-    let res = s:inst.call("update_content", [current_buf], "string")
-    let res = json_decode(res)['ok']
+
+    if exists('*listener_add') && !exists('b:graphical_preview_lines')
+        let b:graphical_preview_lines = []
+        call listener_add(function('s:OnLines'))
+    endif
+    if exists('b:graphical_preview_lines')
+        call listener_flush()
+    endif
+
+    " send only the changed lines if there was a single change since the last update
+    let changes = get(b:, 'graphical_preview_lines', [])
+    let b:graphical_preview_lines = []
+    let res = {}
+    if !a:full && len(changes) == 1
+        let [first, last, added] = changes[0]
+        let update = {'first': first, 'last': last, 'lines': getline(first, last + added - 1)}
+        let res = json_decode(s:Call("update_lines", json_encode(update), "string"))
+    endif
+    if !has_key(res, 'ok')
+        let current_buf = join(getline(1,'$'), "\n")
+        let res = json_decode(s:Call("update_content", current_buf, "string"))
+    endif
+    call s:ApplyRedrawState(res['ok'])
+endfunction
+
+" Apply placeholders, folds and overlaps of a parsed buffer and draw it if nodes changed
+function! s:ApplyRedrawState(res)
+    let res = a:res
+    let b:graphical_preview_placeholders = get(res, 'placeholders', [])
+    let b:graphical_preview_changes = get(res, 'changes', {})
+    " only report overlaps when they change, not on every keystroke
+    let overlaps = get(res, 'overlaps', [])
+    if overlaps != get(b:, 'graphical_preview_overlaps', [])
+        for elm in overlaps
+            call PrintError(printf("Node at line %d reaches line %d into the next node at line %d", elm['line'], elm['end'], elm['next']))
+        endfor
+    endif
+    let b:graphical_preview_overlaps = overlaps
+    if exists('#User#GraphicalPreviewChanged')
+        doautocmd <nomodeline> User GraphicalPreviewChanged
+    endif
     if has_key(res, 'update_folding')
         let s:folds = res['update_folding']
-        call s:UpdateFolds()
+        call s:UpdateFolds(1)
     endif
     if res['should_redraw']
         call Draw()
     endif
+    if get(g:, 'graphical_preview_strict', 0)
+        call s:Validate()
+    endif
+endfunction
+
+" Set a parameter of the fence under the cursor, `+` and `-` move it by one step
+function! s:SetParam(...)
+    let usage = "Usage: GraphicalPreviewParam {name} {number|+|-}"
+    if a:0 != 2
+        call PrintError(usage)
+        return
+    endif
+
+    let [name, value] = a:000
+    let update = {'line': line('.'), 'name': name}
+    if value ==# '+' || value ==# '-'
+        let update['steps'] = value ==# '+' ? 1 : -1
+    elseif value =~# '^[-+]\?\(\d\+\.\?\d*\|\.\d\+\)\([eE][-+]\?\d\+\)\?$'
+        let update['value'] = str2float(value)
+    else
+        call PrintError(usage)
+        return
+    endif
+
+    let res = json_decode(s:Call("set_param", json_encode(update), "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    call s:ApplyRedrawState(res['ok']['state'])
+    echo printf("%s = %g", name, res['ok']['value'])
+endfunction
+
+function! s:Validate()
+    let res = json_decode(s:Call("validate", "", "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    let items = []
+    for elm in res['ok']
+        call add(items, {'bufnr': bufnr('%'), 'lnum': elm['line'], 'type': 'W',
+            \'text': printf('reserved %d lines, image requires %d', elm['reserved'], elm['required'])})
+    endfor
+    call setloclist(0, items, 'r')
+endfunction
+
+" Set the `height=` of the fence under the cursor to the lines its image needs
+function! s:FitHeight(line)
+    let node = get(filter(copy(get(b:, 'graphical_preview_status', [])),
+        \{_, node -> node['line'] <= a:line && type(node['suggested_height']) == v:t_number}), -1, {})
+    let text = empty(node) ? '' : getline(node['line'])
+    if text !~ '^\s*\(```\|\~\~\~\)'
+        call PrintError("Error: no rendered fence at the cursor")
+        return
+    endif
+
+    let height = 'height=' . node['suggested_height']
+    if text =~ 'height=\d\+'
+        let text = substitute(text, 'height=\d\+', height, '')
+    else
+        let text = substitute(text, '\s*$', ',' . height, '')
+    endif
+    call setline(node['line'], text)
+endfunction
+
+function! s:SlowNodes()
+    let res = json_decode(s:Call("slow_nodes", "", "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    let items = []
+    for elm in res['ok']
+        call add(items, {'bufnr': bufnr('%'), 'lnum': elm['line'], 'type': 'W',
+            \'text': printf('%s node took %sms to generate, %sms to encode', elm['kind'],
+            \get(elm, 'generate_ms', '-'), get(elm, 'encode_ms', '-'))})
+    endfor
+    call setloclist(0, items, 'r')
+endfunction
+
+function! s:RenderQueue()
+    let res = json_decode(s:Call("render_queue", "", "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    let items = []
+    for elm in res['ok']
+        if elm['line'] isnot v:null
+            call add(items, {'bufnr': bufnr('%'), 'lnum': elm['line'], 'type': 'I',
+                \'text': printf('%s node waiting for %sms, priority %d', elm['kind'],
+                \elm['waiting_ms'], elm['priority'])})
+        endif
+    endfor
+    call setloclist(0, items, 'r')
+    echo printf("%d nodes waiting, %d in other buffers", len(res['ok']), len(res['ok']) - len(items))
+endfunction
+
+" Image covering the screen position, or an empty dictionary
+function! s:ImageAt(row, col)
+    let res = json_decode(s:Call("painted_regions", "", "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return {}
+    endif
+
+    for region in res['ok']
+        if a:row >= region['row'] && a:row < region['row'] + region['rows']
+            \ && a:col >= region['column'] && a:col < region['column'] + region['columns']
+            return region
+        endif
+    endfor
+    return {}
+endfunction
+
+" Jump to the source of the image at the screen position, files of images are opened
+function! s:JumpToSource(row, col)
+    let region = s:ImageAt(a:row, a:col)
+    if empty(region)
+        echo "no image at this position"
+    elseif region['file'] isnot v:null
+        execute 'edit' fnameescape(region['file'])
+    else
+        call cursor(region['line'], 1)
+    endif
+endfunction
+
+function! s:Prioritize(line)
+    let res = json_decode(s:Call("prioritize", string(a:line), "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+    elseif !res['ok']
+        echo "node is not waiting to be rendered"
+    endif
 endfunction
 
 function! s:ClearAll()
-    call s:inst.call("clear_all", [""], "")
+    call s:Call("clear_all", "", "")
     mode
 endfunction
 
-:autocmd VimEnter,TextChanged,InsertLeave * call <SID>TextChanged()
+function! s:ToggleOverlay()
+    call s:Call("toggle_overlay", "", "string")
+    call s:ClearAll()
+    call Draw()
+endfunction
+
+" Map the mouse buttons of the current buffer to events on images
+function! s:MapMouse()
+    nnoremap <buffer> <silent> <2-LeftMouse> :call <SID>MouseEvent('double', "\<lt>2-LeftMouse>")<CR>
+    nnoremap <buffer> <silent> <LeftMouse> :call <SID>MouseEvent('click', "\<lt>LeftMouse>")<CR>
+    nnoremap <buffer> <silent> <ScrollWheelUp> :call <SID>MouseEvent('scroll_up', "\<lt>ScrollWheelUp>")<CR>
+    nnoremap <buffer> <silent> <ScrollWheelDown> :call <SID>MouseEvent('scroll_down', "\<lt>ScrollWheelDown>")<CR>
+endfunction
+
+"" React to a mouse event on an image, events outside of images are passed on to vim
+function! s:MouseEvent(kind, keys)
+    let pos = getmousepos()
+    let res = json_decode(s:Call("mouse_event", pos['screenrow'] . " " . pos['screencol'] . " " . a:kind, "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    let res = res['ok']
+    if res['action'] ==# 'none'
+        call feedkeys(a:keys, 'n')
+    elseif res['action'] ==# 'pan'
+        " show more of the image by scrolling its cropped part into the window
+        let height = winheight(pos['winid'])
+        let topline = a:kind ==# 'scroll_down' ? max([1, res['last'] - height + 1]) : res['line']
+        call win_execute(pos['winid'], 'call winrestview({"topline": ' . topline . '})')
+    else
+        if res['action'] ==# 'open'
+            let opener = has('mac') ? 'open' : 'xdg-open'
+            if has('nvim')
+                call jobstart([opener, res['path']], {'detach': v:true})
+            else
+                call job_start([opener, res['path']])
+            endif
+        endif
+
+        call s:ClearAll()
+        call Draw()
+    endif
+endfunction
+
+function! s:PinNode(line)
+    let res = json_decode(s:Call("pin_node", string(a:line), "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+    call Draw()
+endfunction
+
+function! s:UnpinNode()
+    call s:Call("unpin_node", "", "")
+    call s:ClearAll()
+    call Draw()
+endfunction
+
+" Enlarge or shrink the figure under the cursor, `zoom_in` or `zoom_out`
+function! s:ZoomNode(line, direction)
+    let res = json_decode(s:Call(a:direction, string(a:line), "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+    echo printf("zoom %d%%", float2nr(round(res['ok'] * 100)))
+    call Draw()
+endfunction
+
+" Move the visible part of the figure under the cursor by columns and lines
+function! s:PanNode(line, dx, dy)
+    let input = json_encode({'line': a:line, 'dx': str2nr(a:dx), 'dy': str2nr(a:dy)})
+    let res = json_decode(s:Call("pan_node", input, "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+    call Draw()
+endfunction
+
+function! s:ResetPan(line)
+    let res = json_decode(s:Call("reset_pan", string(a:line), "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+    call Draw()
+endfunction
+
+" List the previous figures of the named fence under the cursor, newest first
+function! s:FigureHistory(line)
+    let res = json_decode(s:Call("figure_history", string(a:line), "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    for version in res['ok']
+        echo printf("%2d  %6d s ago  %s", version['index'], version['age_secs'], version['path'])
+    endfor
+endfunction
+
+" Show a previous figure of the named fence under the cursor, by default the one before the newest
+function! s:ShowVersion(line, ...)
+    let index = a:0 > 0 ? str2nr(a:1) : 1
+    let res = json_decode(s:Call("show_version", json_encode({'line': a:line, 'index': index}), "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+    call Draw()
+endfunction
+
+function! s:HideVersion()
+    call s:Call("hide_version", "", "")
+    call s:ClearAll()
+    call Draw()
+endfunction
+
+" Compare the figure under the cursor with the one at another line, blended or side by side
+function! s:CompareNodes(line, other, ...)
+    let request = {'line_a': a:line, 'line_b': str2nr(a:other)}
+    if a:0 > 0
+        let request['mode'] = a:1
+    endif
+    let res = json_decode(s:Call("compare_nodes", json_encode(request), "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+    call Draw()
+endfunction
+
+function! s:HideComparison()
+    call s:Call("hide_comparison", "", "")
+    call s:ClearAll()
+    call Draw()
+endfunction
+
+function! s:PopupNode(line)
+    let res = json_decode(s:Call("popup_node", string(a:line), "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+    endif
+endfunction
+
+function! s:Capabilities()
+    let res = json_decode(s:Call("capabilities", "", "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    echo res['ok']
+endfunction
+
+function! s:ReloadConfig()
+    let res = json_decode(s:Call("reload_config", "", "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    call s:TextChanged(1)
+endfunction
+
+" List the cached figures, the quality level of the backend and how fast the terminal takes the
+" images
+function! s:CacheStats()
+    let res = json_decode(s:Call("cache_stats", "", "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    let stats = res['ok']['quality']
+    if type(stats) == v:t_dict
+        let throughput = type(stats['throughput']) == v:t_number ? printf("%d KiB/s, %d ms per draw", stats['throughput'] / 1024, stats['latency_ms']) : "not measured yet"
+        echo printf("%s quality %d of %d (0 is best), %s, stepped down %d and up %d times", stats['backend'],
+            \ stats['level'], stats['levels'], throughput, stats['steps_down'], stats['steps_up'])
+    endif
+
+    if type(res['ok']['store_error']) == v:t_string
+        call PrintError("Could not write the cache: " . res['ok']['store_error'])
+    endif
+
+    for group in res['ok']['artifacts']
+        let document = empty(group['document']) ? '-' : fnamemodify(group['document'], ':~:.')
+        echo printf("%-40s %-10s %5d nodes %8d KiB  %d-%d days", document, group['kind'],
+            \ group['count'], group['bytes'] / 1024, group['newest_days'], group['oldest_days'])
+    endfor
+endfunction
+
+" Arguments are of the form key=value with keys kind, document and older_than_days
+function! s:PurgeCache(...)
+    let filter = {}
+    for arg in a:000
+        let [key, value] = split(arg, '=', 1)[0:1]
+        let filter[key] = key == 'older_than_days' ? str2nr(value) : (key == 'document' ? fnamemodify(value, ':p') : value)
+    endfor
+
+    let res = json_decode(s:Call("purge_cache", json_encode(filter), "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    echo printf("removed %d files, %d KiB", res['ok']['files'], res['ok']['bytes'] / 1024)
+endfunction
+
+function! s:BuildInfo()
+    let res = json_decode(s:Call("build_info", "", "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    echo res['ok']
+endfunction
+
+" Write the manifest of all nodes to a file, without a file it is printed
+function! s:DocumentManifest(...)
+    let res = json_decode(s:Call("document_manifest", "", "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    let manifest = json_encode(res['ok'])
+    if a:0 == 0
+        echo manifest
+        return
+    endif
+
+    call writefile([manifest], fnamemodify(a:1, ':p'))
+    echo printf("Manifest of %d nodes written to %s", len(res['ok']['nodes']), a:1)
+endfunction
+
+" Render, disable, enable, export or purge all figures of the section under the cursor
+function! s:SectionAction(line, action, ...)
+    let input = json_encode({'line': a:line, 'action': a:action})
+    let res = json_decode(s:Call("section_action", input, "string"))
+    if has_key(res, 'err')
+        call PrintError("Error: " . res['err'])
+        return
+    endif
+
+    let res = res['ok']
+    if a:action ==# 'export'
+        let manifest = json_encode(res['manifest'])
+        if a:0 == 0
+            echo manifest
+            return
+        endif
+
+        call writefile([manifest], fnamemodify(a:1, ':p'))
+        echo printf("Manifest of %d nodes written to %s", res['nodes'], a:1)
+        return
+    endif
+
+    if a:action ==# 'purge'
+        echo printf("%d figures of lines %d-%d purged, %d files removed", res['nodes'], res['first'], res['last'], res['purged']['files'])
+    else
+        echo printf("%s %d figures of lines %d-%d", a:action, res['nodes'], res['first'], res['last'])
+    endif
+    call Draw()
+endfunction
+
+function! s:SectionActions(...)
+    return "render\ndisable\nenable\nexport\npurge"
+endfunction
+
+command! GraphicalPreviewToggleOverlay call <SID>ToggleOverlay()
+command! GraphicalPreviewValidate call <SID>Validate()
+command! GraphicalPreviewFitHeight call <SID>FitHeight(line('.'))
+command! GraphicalPreviewSlow call <SID>SlowNodes()
+command! GraphicalPreviewQueue call <SID>RenderQueue()
+command! GraphicalPreviewPrioritize call <SID>Prioritize(line('.'))
+command! GraphicalPreviewJump call <SID>JumpToSource(screenrow(), screencol())
+command! GraphicalPreviewPin call <SID>PinNode(line('.'))
+command! GraphicalPreviewUnpin call <SID>UnpinNode()
+command! GraphicalPreviewZoomIn call <SID>ZoomNode(line('.'), "zoom_in")
+command! GraphicalPreviewZoomOut call <SID>ZoomNode(line('.'), "zoom_out")
+command! -nargs=+ GraphicalPreviewPan call <SID>PanNode(line('.'), <f-args>)
+command! GraphicalPreviewResetPan call <SID>ResetPan(line('.'))
+command! GraphicalPreviewPopup call <SID>PopupNode(line('.'))
+command! GraphicalPreviewHistory call <SID>FigureHistory(line('.'))
+command! -nargs=? GraphicalPreviewShowVersion call <SID>ShowVersion(line('.'), <f-args>)
+command! GraphicalPreviewHideVersion call <SID>HideVersion()
+command! -nargs=+ GraphicalPreviewCompare call <SID>CompareNodes(line('.'), <f-args>)
+command! GraphicalPreviewHideComparison call <SID>HideComparison()
+command! GraphicalPreviewCapabilities call <SID>Capabilities()
+command! GraphicalPreviewBuildInfo call <SID>BuildInfo()
+command! -nargs=* GraphicalPreviewParam call <SID>SetParam(<f-args>)
+command! -nargs=? -complete=file GraphicalPreviewManifest call <SID>DocumentManifest(<f-args>)
+command! -nargs=+ -complete=custom,<SID>SectionActions GraphicalPreviewSection call <SID>SectionAction(line('.'), <f-args>)
+command! GraphicalPreviewReloadConfig call <SID>ReloadConfig()
+command! GraphicalPreviewRecheck call <SID>RecheckBinaries()
+command! GraphicalPreviewCacheStats call <SID>CacheStats()
+command! -nargs=* GraphicalPreviewPurge call <SID>PurgeCache(<f-args>)
+
+:autocmd VimEnter,BufEnter * call <SID>TextChanged(1)
+:autocmd TextChanged,InsertLeave * call <SID>TextChanged(0)
+:autocmd BufDelete * call s:inst.call("remove_buffer", [expand('<abuf>')], "")
+:autocmd FocusLost * call s:Call("set_focus", "0", "string")
+:autocmd FocusGained * call s:Call("set_focus", "1", "string") | call Draw()
+:autocmd CmdwinEnter * call <SID>UpdateUiState(1)
+:autocmd CmdwinLeave * call <SID>UpdateUiState(0) | call Draw()
+if exists('##VimSuspend')
+    :autocmd VimSuspend * call s:Call("suspend", "", "string")
+    :autocmd VimResume * call s:Call("resume", "", "string") | call Draw()
+endif
+" images of other tabs are removed from the screen and drawn again when returning
+:autocmd TabLeave * for buf in tabpagebuflist() | call s:inst.call("buffer_hidden", [string(buf)], "string") | endfor
+:autocmd TabEnter * for buf in tabpagebuflist() | call s:inst.call("buffer_shown", [string(buf)], "string") | endfor | call Draw()
+:autocmd BufWinLeave * if count(tabpagebuflist(), str2nr(expand('<abuf>'))) <= 1 | call s:inst.call("buffer_hidden", [expand('<abuf>')], "string") | endif
+:autocmd BufWinEnter * call s:inst.call("buffer_shown", [expand('<abuf>')], "string")
 :autocmd VimResized * call <SID>UpdateMetadata()
+:autocmd ColorScheme * call <SID>UpdateMetadata()
 :autocmd CursorMoved * call <SID>UpdateMetadata()
+if exists('##WinScrolled')
+    " scrolling or resizing other windows of the buffer
+    :autocmd WinScrolled * call <SID>UpdateMetadata()
+endif
+if exists('##WinResized')
+    " splits opened or closed next to the window move it on the screen
+    :autocmd WinResized * call <SID>UpdateMetadata()
+endif
 :autocmd InsertEnter * call <SID>ClearAll()
 
-map zo :foldopen<CR>:call <SID>UpdateFolds()<CR>
-map zc :foldclose<CR>:call <SID>UpdateFolds()<CR>
-map zO :foldopen!<CR>:call <SID>UpdateFolds()<CR>
-map zC :foldclose!<CR>:call <SID>UpdateFolds()<CR>
+if has('mouse')
+    " the plugin is only loaded once, so the mappings are added to each buffer entered
+    call s:MapMouse()
+    :autocmd BufEnter * call <SID>MapMouse()
+endif
+
+map zo :foldopen<CR>:call <SID>UpdateFolds(0)<CR>
+map zc :foldclose<CR>:call <SID>UpdateFolds(0)<CR>
+map zO :foldopen!<CR>:call <SID>UpdateFolds(0)<CR>
+map zC :foldclose!<CR>:call <SID>UpdateFolds(0)<CR>
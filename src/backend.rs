@@ -0,0 +1,284 @@
+//! Terminal graphics protocols
+//!
+//! A backend turns a prepared image into the escape sequences of a graphics protocol. The
+//! sequences are written at the current cursor position.
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::Path;
+use std::process::ChildStdin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::{Error, Result};
+use crate::process::Process;
+use crate::raster::Image;
+use crate::render::ART_PATH;
+use crate::sixel;
+use crate::utils;
+
+pub trait Backend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Encode an image and pass finished chunks to `out`, returns the complete blob
+    ///
+    /// Fails if the image couldn't be handed over, e.g. when writing a file for another process.
+    fn encode(&self, image: Image, out: &mut dyn FnMut(&[u8])) -> Result<Vec<u8>>;
+
+    /// Sequence removing all images from the screen
+    ///
+    /// This is only necessary for protocols where images are not overwritten by text.
+    fn clear(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Whether images stay on the screen when text is scrolled over them
+    fn persistent(&self) -> bool {
+        false
+    }
+
+    /// Select the quality images are encoded with, from zero for the best up to `qualities() - 1`
+    ///
+    /// Returns whether the quality changed, protocols transmitting lossless images ignore it.
+    fn set_quality(&self, _level: usize) -> bool {
+        false
+    }
+
+    /// Number of quality levels, a single one if the quality can't be changed
+    fn qualities(&self) -> usize {
+        1
+    }
+
+    /// Show a chunk of an encoded image at the given row and column
+    ///
+    /// Returns `false` if the chunk should be written to the terminal at this position instead,
+    /// which is the case for all protocols based on escape sequences.
+    fn place(&self, _chunk: &[u8], _pos: (usize, usize)) -> bool {
+        false
+    }
+}
+
+/// Names of all backends, as accepted by `from_name`
+pub const NAMES: &[&str] = &["sixel", "kitty", "iterm", "ueberzug"];
+
+/// Select a backend by its name, `ueberzug` places its images with the given layer
+pub fn from_name(name: &str, layer: &Layer) -> Result<Arc<dyn Backend>> {
+    match name {
+        "sixel" => Ok(Arc::new(Sixel::default())),
+        "kitty" => Ok(Arc::new(Kitty)),
+        "iterm" | "iterm2" => Ok(Arc::new(Iterm)),
+        "ueberzug" | "ueberzugpp" => Ok(Arc::new(Ueberzug::new(layer.clone())?)),
+        _ => Err(Error::UnknownBackend(name.to_string())),
+    }
+}
+
+/// SIXEL graphics, supported by xterm, foot, wezterm, mlterm and others
+///
+/// The quality selects one of the palettes of the encoder, coarser palettes give smaller blobs.
+#[derive(Default)]
+pub struct Sixel {
+    level: AtomicUsize,
+}
+
+impl Backend for Sixel {
+    fn name(&self) -> &'static str {
+        "sixel"
+    }
+
+    fn encode(&self, image: Image, out: &mut dyn FnMut(&[u8])) -> Result<Vec<u8>> {
+        let (width, height) = (image.width(), image.height());
+        match image.rgba() {
+            Some(pixels) => {
+                let mut blob = Vec::new();
+                let palette = sixel::PALETTES[self.level.load(Ordering::Relaxed)];
+                for chunk in sixel::Encoder::new(&pixels, width, height, palette) {
+                    out(&chunk);
+                    blob.extend_from_slice(&chunk);
+                }
+
+                Ok(blob)
+            },
+            None => {
+                let blob = image.sixel().unwrap_or_default();
+                out(&blob);
+
+                Ok(blob)
+            }
+        }
+    }
+
+    fn set_quality(&self, level: usize) -> bool {
+        let level = level.min(sixel::PALETTES.len() - 1);
+
+        self.level.swap(level, Ordering::Relaxed) != level
+    }
+
+    fn qualities(&self) -> usize {
+        sixel::PALETTES.len()
+    }
+}
+
+/// Kitty graphics protocol, supported by kitty, ghostty and wezterm
+///
+/// The image is transmitted as PNG in base64 encoded chunks of 4096 bytes.
+pub struct Kitty;
+
+impl Backend for Kitty {
+    fn name(&self) -> &'static str {
+        "kitty"
+    }
+
+    fn encode(&self, image: Image, out: &mut dyn FnMut(&[u8])) -> Result<Vec<u8>> {
+        let png = utils::base64(&image.png());
+        let chunks = png.as_bytes().chunks(4096).collect::<Vec<_>>();
+
+        let mut blob = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            // transmit and display in the first chunk, quiet responses and keep the cursor
+            let head = if i == 0 { "a=T,f=100,q=2,C=1," } else { "" };
+            let more = if i + 1 < chunks.len() { 1 } else { 0 };
+
+            blob.extend_from_slice(format!("\x1b_G{}m={};", head, more).as_bytes());
+            blob.extend_from_slice(chunk);
+            blob.extend_from_slice(b"\x1b\\");
+        }
+
+        out(&blob);
+        Ok(blob)
+    }
+
+    fn clear(&self) -> Option<Vec<u8>> {
+        Some(b"\x1b_Ga=d,q=2\x1b\\".to_vec())
+    }
+
+    fn persistent(&self) -> bool {
+        true
+    }
+}
+
+/// Inline images of iTerm2 with the OSC 1337 `File=` sequence, also understood by wezterm
+///
+/// The image is already fitted to the node, so the size is given in pixels to avoid scaling by
+/// the terminal.
+pub struct Iterm;
+
+impl Backend for Iterm {
+    fn name(&self) -> &'static str {
+        "iterm"
+    }
+
+    fn encode(&self, image: Image, out: &mut dyn FnMut(&[u8])) -> Result<Vec<u8>> {
+        let (width, height) = (image.width(), image.height());
+        let png = image.png();
+
+        let blob = format!(
+            "\x1b]1337;File=inline=1;size={};width={}px;height={}px;preserveAspectRatio=1;doNotMoveCursor=1:{}\x07",
+            png.len(), width, height, utils::base64(&png)
+        ).into_bytes();
+
+        out(&blob);
+        Ok(blob)
+    }
+}
+
+/// Process of ueberzugpp, shared by the buffers through the registry
+///
+/// It is started by the first buffer selecting the backend and runs until the registry is gone.
+#[derive(Clone, Default)]
+pub struct Layer(Arc<Mutex<Option<ChildStdin>>>);
+
+impl Layer {
+    /// Start ueberzugpp unless it is already running
+    fn start(&self) -> Result<()> {
+        let mut stdin = self.0.lock().unwrap();
+        if stdin.is_none() {
+            *stdin = Some(Process::new("ueberzugpp")?
+                .arg("layer")
+                .arg("--silent")
+                .spawn_piped()?);
+        }
+
+        Ok(())
+    }
+
+    fn send(&self, cmd: &str) {
+        if let Some(stdin) = &mut *self.0.lock().unwrap() {
+            let _ = writeln!(stdin, "{}", cmd);
+            let _ = stdin.flush();
+        }
+    }
+}
+
+/// Images drawn by ueberzugpp, for terminals without any graphics protocol
+///
+/// The image is stored as PNG and ueberzugpp places a window over the terminal cell. Commands are
+/// written as JSON lines to a long running `ueberzugpp layer` process, so the blob of a node is
+/// only the part of the command describing the image. Placements are identified by their cell,
+/// such that a new image replaces the old one at the same position like with the other protocols.
+/// Each buffer only removes the images it placed itself.
+pub struct Ueberzug {
+    layer: Layer,
+    placed: Mutex<BTreeSet<String>>,
+}
+
+impl Ueberzug {
+    pub fn new(layer: Layer) -> Result<Ueberzug> {
+        layer.start()?;
+
+        Ok(Ueberzug {
+            layer,
+            placed: Mutex::new(BTreeSet::new()),
+        })
+    }
+}
+
+impl Backend for Ueberzug {
+    fn name(&self) -> &'static str {
+        "ueberzug"
+    }
+
+    fn encode(&self, image: Image, out: &mut dyn FnMut(&[u8])) -> Result<Vec<u8>> {
+        let (width, height) = (image.width(), image.height());
+        let png = image.png();
+
+        let path = Path::new(ART_PATH).join(utils::hash_bytes(&png)).with_extension("png");
+        if !path.exists() {
+            std::fs::write(&path, &png).map_err(Error::Io)?;
+        }
+
+        // ueberzugpp scales into a box of cells, round up to avoid shrinking the image
+        let (char_width, char_height) = (utils::char_pixel_width(), utils::char_pixel_height());
+        let blob = format!(
+            "\"path\":\"{}\",\"max_width\":{},\"max_height\":{}",
+            path.display(), (width + char_width - 1) / char_width, (height + char_height - 1) / char_height
+        ).into_bytes();
+
+        out(&blob);
+        Ok(blob)
+    }
+
+    fn clear(&self) -> Option<Vec<u8>> {
+        let placed = std::mem::take(&mut *self.placed.lock().unwrap());
+        for identifier in placed {
+            self.layer.send(&format!("{{\"action\":\"remove\",\"identifier\":\"{}\"}}", identifier));
+        }
+
+        Some(Vec::new())
+    }
+
+    fn persistent(&self) -> bool {
+        true
+    }
+
+    fn place(&self, chunk: &[u8], (row, column): (usize, usize)) -> bool {
+        let identifier = format!("{}x{}", row, column);
+
+        // ueberzugpp counts cells from zero, the terminal from one
+        self.layer.send(&format!(
+            "{{\"action\":\"add\",\"identifier\":\"{}\",\"x\":{},\"y\":{},{}}}",
+            identifier, column.saturating_sub(1), row.saturating_sub(1), String::from_utf8_lossy(chunk)
+        ));
+        self.placed.lock().unwrap().insert(identifier);
+
+        true
+    }
+}
@@ -0,0 +1,4 @@
+//! Command line interface generating figures without vim, see `cli` for the commands
+fn main() {
+    std::process::exit(vim_graphical_preview::cli::run());
+}
@@ -0,0 +1,160 @@
+//! Statistics and purging of the artifact cache
+//!
+//! All files belonging to a node share the id of the node as file name, for example the `tex`,
+//! `dvi` and `svg` of an equation. The manifest records the content type and document of each
+//! id, files without a record like images written for ueberzug are grouped as `other`. Previous
+//! figures of named fences in the history folder count as artifacts they were copied from.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use miniserde::{json, Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::history::{self, HISTORY_DIR};
+use crate::manifest::{Manifest, Origin};
+use crate::render::ART_PATH;
+
+/// Files in the cache folder which are not artifacts
+const RESERVED: &[&str] = &["manifest.json", "origins.json", "hash_version", "graphical-preview.sock"];
+
+/// Seconds of a day
+const DAY: u64 = 24 * 60 * 60;
+
+struct Artifact {
+    path: PathBuf,
+    id: String,
+    bytes: u64,
+    age_days: u64,
+}
+
+/// Number of artifacts, their size and age for a content type in a document
+#[derive(Debug, Serialize)]
+pub struct CacheGroup {
+    document: String,
+    kind: String,
+    count: usize,
+    bytes: u64,
+    oldest_days: u64,
+    newest_days: u64,
+}
+
+/// Selection of artifacts to remove, missing fields match everything
+#[derive(Debug, Deserialize)]
+pub struct PurgeFilter {
+    kind: Option<String>,
+    document: Option<String>,
+    older_than_days: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Purged {
+    files: usize,
+    bytes: u64,
+}
+
+/// All artifacts in the cache folder and the history of named fences
+fn artifacts() -> Result<Vec<Artifact>> {
+    let now = SystemTime::now();
+
+    let mut folders = vec![PathBuf::from(ART_PATH)];
+    if let Ok(entries) = fs::read_dir(Path::new(ART_PATH).join(HISTORY_DIR)) {
+        folders.extend(entries.flatten().map(|x| x.path()).filter(|x| x.is_dir()));
+    }
+
+    let mut artifacts = Vec::new();
+    for (idx, folder) in folders.iter().enumerate() {
+        for entry in fs::read_dir(folder).map_err(Error::Io)? {
+            let entry = entry.map_err(Error::Io)?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let metadata = entry.metadata().map_err(Error::Io)?;
+            if !metadata.is_file() || RESERVED.contains(&name.as_str()) {
+                continue;
+            }
+
+            // versions in the history are named by the time they were recorded and their artifact
+            let id = match idx {
+                0 => name.split('.').next(),
+                _ => history::artifact_of(&name),
+            };
+
+            let age = metadata.modified().ok()
+                .and_then(|x| now.duration_since(x).ok())
+                .unwrap_or_default();
+
+            artifacts.push(Artifact {
+                path: entry.path(),
+                id: id.unwrap_or_default().to_string(),
+                bytes: metadata.len(),
+                age_days: age.as_secs() / DAY,
+            });
+        }
+    }
+
+    Ok(artifacts)
+}
+
+fn origin_of(origins: &BTreeMap<String, Origin>, id: &str) -> Origin {
+    origins.get(id).cloned().unwrap_or_else(|| Origin {
+        kind: "other".to_string(),
+        document: String::new(),
+    })
+}
+
+/// Cache contents grouped by document and content type
+pub fn stats() -> Result<Vec<CacheGroup>> {
+    let manifest = Manifest::load();
+
+    let mut groups: BTreeMap<(String, String), (Vec<String>, CacheGroup)> = BTreeMap::new();
+    for artifact in artifacts()? {
+        let Origin { kind, document } = origin_of(manifest.origins(), &artifact.id);
+
+        let (ids, group) = groups.entry((document.clone(), kind.clone()))
+            .or_insert_with(|| (Vec::new(), CacheGroup {
+                document, kind, count: 0, bytes: 0, oldest_days: 0, newest_days: u64::MAX,
+            }));
+
+        if !ids.contains(&artifact.id) {
+            ids.push(artifact.id);
+            group.count += 1;
+        }
+        group.bytes += artifact.bytes;
+        group.oldest_days = group.oldest_days.max(artifact.age_days);
+        group.newest_days = group.newest_days.min(artifact.age_days);
+    }
+
+    Ok(groups.into_values().map(|(_, group)| group).collect())
+}
+
+/// Remove artifacts matching the filter, e.g. `{"kind": "gnuplot", "older_than_days": 30}`
+pub fn purge(filter: &str) -> Result<String> {
+    let filter: PurgeFilter = json::from_str(filter).map_err(|_| Error::InvalidFilter)?;
+    let manifest = Manifest::load();
+
+    let purged = remove(|artifact| {
+        let origin = origin_of(manifest.origins(), &artifact.id);
+
+        filter.kind.as_ref().map_or(true, |x| *x == origin.kind)
+            && filter.document.as_ref().map_or(true, |x| Path::new(x) == Path::new(&origin.document))
+            && filter.older_than_days.map_or(true, |x| artifact.age_days >= x)
+    })?;
+
+    Ok(json::to_string(&purged))
+}
+
+/// Remove the artifacts of the given ids, previous figures in the history are kept
+pub fn purge_ids(ids: &[String]) -> Result<Purged> {
+    remove(|artifact| artifact.path.parent() == Some(Path::new(ART_PATH)) && ids.contains(&artifact.id))
+}
+
+fn remove(matches: impl Fn(&Artifact) -> bool) -> Result<Purged> {
+    let mut purged = Purged { files: 0, bytes: 0 };
+    for artifact in artifacts()?.into_iter().filter(|x| matches(x)) {
+        fs::remove_file(&artifact.path).map_err(Error::Io)?;
+        purged.files += 1;
+        purged.bytes += artifact.bytes;
+    }
+
+    Ok(purged)
+}
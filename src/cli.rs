@@ -0,0 +1,356 @@
+//! Command line interface generating the figures of documents without vim
+//!
+//! `vgp render notes.md --out-dir build/` parses each document and generates all of its figures
+//! with the same renderers and configuration files as the plugin, so that the cache is warm for
+//! the next session. With `--out-dir` the generated SVG files, or PNG files of figures drawn in
+//! memory, are copied to the folder and named by their artifact. Images of the document itself
+//! are only checked.
+//!
+//! A line `notes.md:12 gnuplot build/1f2e..svg` is printed for each figure and failures are
+//! reported on stderr, the exit status is one if any figure failed.
+//!
+//! `vgp manifest notes.md` generates the figures as well and prints a JSON manifest per document
+//! with the content hash, artifact and renderer versions of each node, for static site generators
+//! or pandoc filters reusing the artifacts.
+//!
+//! `vgp doctor` prints a report of the environment for bug reports: the build, binaries used by
+//! renderers, ImageMagick delegates, the graphics protocols of the terminal, the cache folder and
+//! the configuration files.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{self, Config};
+use crate::error::{Error, Result};
+use crate::render::{Generated, Render, ART_PATH};
+use crate::{raster, registry, terminal};
+
+const USAGE: &str = "usage: vgp render <document>.. [--out-dir <folder>] [--config <file>]
+       vgp manifest <document>.. [--config <file>]
+       vgp doctor
+
+render    generate all figures of the documents like the plugin does, which fills its cache
+manifest  generate all figures and print a JSON manifest of the artifacts per document
+doctor    print a report of binaries, terminal and cache to include in bug reports
+
+options of render:
+  --out-dir <folder>  copy the generated figures to the folder
+  --config <file>     JSON configuration taking precedence over the configuration files";
+
+/// Binaries called by renderers and what they are needed for
+const BINARIES: &[(&str, &str)] = &[
+    ("latex", "math, latex, table and beamer fences"),
+    ("dvisvgm", "math, latex, table and beamer fences"),
+    ("lualatex", "latex fences with luamplib"),
+    ("dvips", "latex fences with PSTricks"),
+    ("gnuplot", "gnuplot and csv fences"),
+    ("dot", "graphviz fences"),
+    ("mmdc", "mermaid fences"),
+    ("plantuml", "plantuml fences without a server"),
+    ("curl", "plantuml fences with a server"),
+    ("vl-convert", "vega-lite fences"),
+    ("pikchr", "pikchr fences"),
+    ("lilypond", "lilypond fences"),
+    ("ffmpeg", "posters of videos"),
+    ("ffmpegthumbnailer", "posters of videos without ffmpeg"),
+    ("tmux", "popups and drawing inside of tmux"),
+    ("ueberzugpp", "the ueberzug backend"),
+];
+
+struct Args {
+    documents: Vec<PathBuf>,
+    out_dir: Option<PathBuf>,
+    config: Option<PathBuf>,
+}
+
+/// Parse the arguments following the subcommand, `None` if they are invalid
+fn parse_args(args: &[String]) -> Option<Args> {
+    let mut parsed = Args { documents: Vec::new(), out_dir: None, config: None };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out-dir" => parsed.out_dir = Some(PathBuf::from(args.next()?)),
+            "--config" => parsed.config = Some(PathBuf::from(args.next()?)),
+            x if x.starts_with("--") => return None,
+            x => parsed.documents.push(PathBuf::from(x)),
+        }
+    }
+
+    Some(parsed).filter(|x| !x.documents.is_empty())
+}
+
+/// Configuration of the plugin, merged with the given file
+///
+/// Project files are restricted like in the plugin. Nothing is drawn, so the terminal is neither
+/// probed for a graphics protocol nor for its colors unless configured otherwise.
+fn load_config(path: Option<&Path>) -> Result<Config> {
+    let mut files = config::files();
+    files.extend(path.map(Path::to_path_buf));
+    let mut config = config::merge_files(None, &files)?.unwrap_or_default();
+
+    config.backend.get_or_insert_with(|| "sixel".to_string());
+    config.theme.get_or_insert(false);
+
+    Ok(config)
+}
+
+/// Copy the figure to the output folder, returns its new path
+fn export(generated: &Generated, out_dir: &Path) -> Result<Option<PathBuf>> {
+    match (&generated.path, &generated.png) {
+        (Some(path), _) => {
+            let target = out_dir.join(path.file_name().unwrap_or_default());
+            fs::copy(path, &target).map_err(Error::Io)?;
+
+            Ok(Some(target))
+        },
+        (None, Some(png)) => {
+            let target = out_dir.join(&generated.id).with_extension("png");
+            fs::write(&target, png).map_err(Error::Io)?;
+
+            Ok(Some(target))
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+/// Read and parse a document like the plugin does
+fn open_document(document: &Path, config: &Config) -> Result<Render> {
+    let content = fs::read_to_string(document).map_err(Error::Io)?;
+
+    let mut render = Render::new();
+    render.open_document(&document.to_string_lossy(), &content, config.clone())?;
+
+    Ok(render)
+}
+
+/// Generate the figures of a document, returns the number of failed figures
+fn render_document(document: &Path, args: &Args, config: &Config) -> Result<usize> {
+    let mut render = open_document(document, config)?;
+
+    let mut failed = 0;
+    for generated in render.generate_all()? {
+        let location = format!("{}:{}", document.display(), generated.line);
+        if let Some(error) = &generated.error {
+            eprintln!("{} {} failed: {}", location, generated.kind, error);
+            failed += 1;
+            continue;
+        }
+
+        let path = match &args.out_dir {
+            Some(out_dir) => export(&generated, out_dir)?,
+            None => generated.path.clone(),
+        };
+        match path {
+            Some(path) => println!("{} {} {}", location, generated.kind, path.display()),
+            None => println!("{} {}", location, generated.kind),
+        }
+    }
+
+    Ok(failed)
+}
+
+/// Generate the figures of a document and print its manifest, returns the number of failed figures
+fn manifest_document(document: &Path, config: &Config) -> Result<usize> {
+    let mut render = open_document(document, config)?;
+
+    let generated = render.generate_all()?;
+    for failed in generated.iter().filter(|x| x.error.is_some()) {
+        eprintln!("{}:{} {} failed: {}", document.display(), failed.line, failed.kind, failed.error.as_deref().unwrap_or_default());
+    }
+    println!("{}", render.document_manifest("")?);
+
+    Ok(generated.iter().filter(|x| x.error.is_some()).count())
+}
+
+/// Parse the arguments and load the configuration, or the exit status if that failed
+fn prepare(args: &[String]) -> std::result::Result<(Args, Config), i32> {
+    let args = parse_args(args).ok_or_else(|| {
+        eprintln!("{}", USAGE);
+        2
+    })?;
+
+    let config = load_config(args.config.as_deref()).map_err(|err| {
+        eprintln!("vgp: {}", err);
+        2
+    })?;
+
+    Ok((args, config))
+}
+
+/// Generate the figures of the given documents and print their manifests, returns the exit status
+fn manifest(args: &[String]) -> i32 {
+    let (args, config) = match prepare(args) {
+        Ok(prepared) if prepared.0.out_dir.is_none() => prepared,
+        Ok(_) => {
+            eprintln!("{}", USAGE);
+            return 2;
+        },
+        Err(status) => return status,
+    };
+
+    let mut status = 0;
+    for document in &args.documents {
+        match manifest_document(document, &config) {
+            Ok(0) => {},
+            Ok(_) => status = 1,
+            Err(err) => {
+                eprintln!("vgp: {}: {}", document.display(), err);
+                status = 1;
+            },
+        }
+    }
+
+    status
+}
+
+/// Generate the figures of the given documents, returns the exit status
+fn render(args: &[String]) -> i32 {
+    let (args, config) = match prepare(args) {
+        Ok(prepared) => prepared,
+        Err(status) => return status,
+    };
+
+    if let Some(out_dir) = &args.out_dir {
+        if let Err(err) = fs::create_dir_all(out_dir) {
+            eprintln!("vgp: could not create {}: {}", out_dir.display(), err);
+            return 2;
+        }
+    }
+
+    let mut status = 0;
+    for document in &args.documents {
+        match render_document(document, &args, &config) {
+            Ok(0) => {},
+            Ok(_) => status = 1,
+            Err(err) => {
+                eprintln!("vgp: {}: {}", document.display(), err);
+                status = 1;
+            },
+        }
+    }
+
+    status
+}
+
+/// Line of the report with the path of a binary or the reason it wasn't found
+fn locate(binary: &str, purpose: &str) -> String {
+    match which::which(binary) {
+        Ok(path) => format!("  ok       {:<18} {:<40} {}", binary, purpose, path.display()),
+        Err(err) => format!("  missing  {:<18} {:<40} {}", binary, purpose, err),
+    }
+}
+
+/// Whether the cache folder can be written, with the number of files and their size
+fn check_cache() -> std::result::Result<String, String> {
+    let dir = Path::new(ART_PATH);
+    if !dir.exists() {
+        return Ok("not created yet".to_string());
+    }
+
+    let probe = dir.join(format!(".doctor-{}", std::process::id()));
+    fs::write(&probe, b"").map_err(|err| format!("not writable: {}", err))?;
+    let _ = fs::remove_file(&probe);
+
+    let entries = fs::read_dir(dir).map_err(|err| format!("not readable: {}", err))?;
+    let (files, bytes) = entries.flatten()
+        .filter_map(|x| x.metadata().ok())
+        .filter(|x| x.is_file())
+        .fold((0, 0), |(files, bytes), x| (files + 1, bytes + x.len()));
+
+    Ok(format!("writable, {} files, {} KiB", files, bytes / 1024))
+}
+
+/// Print a report of the environment, returns the exit status
+fn doctor() -> i32 {
+    let yes_no = |x: bool| if x { "yes" } else { "no" };
+
+    println!("vim-graphical-preview {} ({})", env!("CARGO_PKG_VERSION"), env!("GIT_HASH"));
+    println!("features: {}", registry::features().join(", "));
+    println!("imagemagick: {}", raster::version());
+    println!("delegates: {}", raster::delegates().unwrap_or_else(|| "none".to_string()));
+
+    println!("\nbinaries:");
+    for (binary, purpose) in BINARIES {
+        println!("{}", locate(binary, purpose));
+    }
+
+    // commands of the configuration are run like the binaries of the renderers
+    let mut configs = Vec::new();
+    for path in config::files() {
+        let config = match path.exists() {
+            true => Some(Config::read(&path)),
+            false => None,
+        };
+        configs.push((path, config));
+    }
+
+    let commands = configs.iter()
+        .filter_map(|(_, config)| config.as_ref()?.as_ref().ok())
+        .flat_map(|config| {
+            let handlers = config.handlers.iter().flatten()
+                .map(|(name, command)| (format!("handler of {} fences", name), command.clone()));
+            let filters = config.filters.iter().flatten()
+                .flat_map(|(name, filters)| filters.iter()
+                    .filter_map(move |x| Some((format!("filter of {} fences", name), x.command.clone()?))));
+
+            handlers.chain(filters).collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    for (purpose, command) in commands {
+        if let Some(binary) = command.split_whitespace().next() {
+            println!("{}", locate(binary, &purpose));
+        }
+    }
+
+    println!("\nterminal:");
+    if nix::unistd::isatty(1).unwrap_or(false) {
+        let caps = terminal::detect();
+        println!("  responded: {}", yes_no(caps.responded));
+        println!("  sixel: {}, kitty: {}, iterm: {}, ueberzug: {}, tmux: {}",
+            yes_no(caps.sixel), yes_no(caps.kitty), yes_no(caps.iterm), yes_no(caps.ueberzug), yes_no(caps.tmux));
+        println!("  backend: {}", if caps.any() { caps.best_backend() } else { "none" });
+        println!("  colors: {}", if caps.theme.is_empty() { "not reported".to_string() } else { caps.theme.key() });
+    } else {
+        println!("  not probed, standard output is not a terminal");
+    }
+    for key in ["TERM", "TERM_PROGRAM", "TMUX"] {
+        println!("  {}={}", key, std::env::var(key).unwrap_or_default());
+    }
+
+    println!("\ncache {}:", ART_PATH);
+    let cache = check_cache();
+    match &cache {
+        Ok(state) => println!("  {}", state),
+        Err(err) => println!("  {}", err),
+    }
+
+    println!("\nconfiguration:");
+    let mut valid = true;
+    for (path, config) in &configs {
+        match config {
+            None => println!("  missing  {}", path.display()),
+            Some(Ok(_)) => println!("  ok       {}", path.display()),
+            Some(Err(err)) => {
+                println!("  invalid  {}: {}", path.display(), err);
+                valid = false;
+            },
+        }
+    }
+
+    if cache.is_ok() && valid { 0 } else { 1 }
+}
+
+/// Run the command line interface, returns the exit status
+pub fn run() -> i32 {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    match args.split_first() {
+        Some((command, rest)) if command == "render" => render(rest),
+        Some((command, rest)) if command == "manifest" => manifest(rest),
+        Some((command, rest)) if command == "doctor" && rest.is_empty() => doctor(),
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        },
+    }
+}
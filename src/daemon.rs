@@ -0,0 +1,193 @@
+//! Standalone daemon speaking line delimited JSON over stdio or a unix socket
+//!
+//! This is a fallback for setups where the shared library can't be loaded by libcallex. Each
+//! request is a single line `{"id": 1, "method": "draw", "params": ""}` and is answered by
+//! `{"id": 1, "result": {"ok": ..}}`, where the result is the same as returned by the C interface.
+//!
+//! Graphics are written to the terminal, so stdin and stdout are moved away for the protocol and
+//! replaced by the controlling terminal before any request is handled.
+//!
+//! With `--socket [path]` the same protocol is served on a unix domain socket instead, by default
+//! at `ART_PATH/graphical-preview.sock`, so that several clients can share one daemon and
+//! requests can be sent by hand with `socat - UNIX-CONNECT:<path>`. Each connection has its own
+//! buffers, graphics are written to the terminal the daemon was started in.
+//!
+//! With `--msgpack` the daemon speaks msgpack-rpc on stdio instead and can be started by Neovim
+//! with `jobstart(cmd, {'rpc': v:true})`. Each method takes a single string parameter and returns
+//! the JSON string of the C interface.
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+
+use miniserde::{json, Deserialize};
+use nix::unistd::{dup, dup2};
+
+use crate::dispatch_with;
+use crate::msgpack::{self, Value};
+use crate::registry::Registry;
+use crate::render::ART_PATH;
+
+/// All connections draw to the same terminal, their requests are handled one after another
+static DISPATCH: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    id: u64,
+    method: String,
+    params: String,
+}
+
+/// Move the protocol to new descriptors and attach stdin and stdout to the terminal
+fn detach_stdio() -> nix::Result<(File, File)> {
+    let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")
+        .map_err(|_| nix::Error::ENXIO)?;
+
+    let (input, output) = (dup(0)?, dup(1)?);
+    dup2(tty.as_raw_fd(), 0)?;
+    dup2(tty.as_raw_fd(), 1)?;
+
+    unsafe { Ok((File::from_raw_fd(input), File::from_raw_fd(output))) }
+}
+
+/// Call an exported function with the terminal locked
+///
+/// A panicking call is answered with an error, the following requests are still handled.
+fn dispatch_locked(registry: &mut Registry, method: &str, params: &str) -> Option<String> {
+    let _lock = DISPATCH.lock().unwrap_or_else(|err| err.into_inner());
+
+    panic::catch_unwind(AssertUnwindSafe(|| dispatch_with(registry, method, params)))
+        .unwrap_or_else(|_| Some("{ \"err\": \"Internal error\" }".to_string()))
+}
+
+/// Answer a single request line
+fn handle(registry: &mut Registry, line: &str) -> String {
+    match json::from_str::<Request>(line) {
+        Ok(req) => {
+            let result = dispatch_locked(registry, &req.method, &req.params)
+                .unwrap_or_else(|| format!("{{ \"err\": \"Unknown method {}\" }}", req.method));
+
+            format!("{{\"id\":{},\"result\":{}}}", req.id, result)
+        },
+        Err(_) => "{\"id\":null,\"result\":{ \"err\": \"Invalid request\" }}".to_string(),
+    }
+}
+
+/// Handle requests until the input is closed, the buffers are dropped afterwards
+fn serve<R: BufRead, W: Write>(input: R, mut output: W) {
+    let mut registry = Registry::default();
+
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        if writeln!(output, "{}", handle(&mut registry, &line)).and_then(|_| output.flush()).is_err() {
+            break;
+        }
+    }
+}
+
+/// Handle msgpack-rpc requests and notifications until the input is closed
+fn serve_msgpack<R: Read, W: Write>(mut input: R, mut output: W) {
+    let mut registry = Registry::default();
+
+    while let Ok(msg) = msgpack::read(&mut input) {
+        let msg = match msg {
+            Value::Array(msg) => msg,
+            _ => continue,
+        };
+
+        let mut call = |method: &Value, params: Option<&Value>| {
+            let params = match params {
+                Some(Value::Array(params)) => params.first().and_then(Value::as_str).unwrap_or(""),
+                _ => "",
+            };
+
+            method.as_str().and_then(|method| dispatch_locked(&mut registry, method, params))
+        };
+
+        match msg.as_slice() {
+            // request with message id, the response is [1, id, error, result]
+            [Value::UInt(0), id, method, params] => {
+                let (error, result) = match call(method, Some(params)) {
+                    Some(result) => (Value::Nil, Value::Str(result)),
+                    None => (Value::Str(format!("Unknown method {:?}", method)), Value::Nil),
+                };
+
+                let response = Value::Array(vec![Value::UInt(1), id.clone(), error, result]);
+                if msgpack::write(&mut output, &response).and_then(|_| output.flush()).is_err() {
+                    break;
+                }
+            },
+            // notifications are not answered
+            [Value::UInt(2), method, params] => {
+                call(method, Some(params));
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Accept connections on a unix socket, each connection is served in its own thread
+///
+/// Buffer numbers are only unique within one vim instance, so every connection keeps its own
+/// registry.
+fn listen(path: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(ART_PATH)?;
+
+    // remove the socket of a previous instance
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        thread::spawn(move || {
+            if let Ok(output) = stream.try_clone() {
+                serve(BufReader::new(stream), output);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Run the daemon with the arguments of the process
+pub fn run() -> i32 {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    if args.first().map_or(false, |x| x == "--socket") {
+        let path = args.get(1).map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(ART_PATH).join("graphical-preview.sock"));
+
+        return match listen(&path) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("Could not listen on {}: {}", path.display(), err);
+                1
+            }
+        };
+    }
+
+    let (input, output) = match detach_stdio() {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Could not attach to the terminal: {}", err);
+            return 1;
+        }
+    };
+
+    if args.first().map_or(false, |x| x == "--msgpack") {
+        serve_msgpack(BufReader::new(input), output);
+    } else {
+        serve(BufReader::new(input), output);
+    }
+
+    0
+}
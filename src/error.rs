@@ -8,10 +8,34 @@ pub type Result<T> = result::Result<T, Error>;
 pub enum Error {
     InvalidMath(String, String, usize), // reason, element, line
     InvalidDvisvgm(String),
+    InvalidDiagram(&'static str, String), // binary, error output
+    InvalidCustom(String, String), // fence name, error output
+    InvalidQrCode(String),
     FileNotFound(PathBuf),
-    BinaryNotFound(which::Error),
+    BinaryNotFound(String, which::Error), // binary, reason
     UnknownFence(String),
     InvalidImage(String),
+    MissingFeature(&'static str),
+    NodeNotFound(usize),
+    NodeNotReady(usize),
+    NoTmux,
+    Timeout(String),
+    InvalidConfig,
+    InvalidUpdate,
+    InvalidFolds,
+    InvalidFilter,
+    InvalidPattern(String, String), // pattern, reason
+    FilterFailed(String, String), // binary, error output
+    InvalidEvent,
+    DependencyFailed(String), // name of the fence
+    UnknownParam(String, usize), // name, line
+    NodeNotNamed(usize),
+    VersionNotFound(String, usize), // name, index
+    ShellEscapeNotAllowed,
+    UnknownBackend(String),
+    UnsupportedFile(PathBuf, String),
+    EmptyTransclusion(String),
+    InvalidMirror(String),
     Io(io::Error),
 }
  
@@ -22,14 +46,62 @@ impl fmt::Display for Error {
                 format!("could not parse math {} at {} bc. {}", element, line, reason),
             Error::InvalidDvisvgm(err) => 
                 err.to_string(),
+            Error::InvalidDiagram(binary, err) =>
+                format!("{} failed: {}", binary, err),
+            Error::InvalidCustom(name, err) =>
+                format!("handler of {} fences failed: {}", name, err),
+            Error::InvalidQrCode(err) =>
+                format!("could not encode QR code: {}", err),
             Error::FileNotFound(path) =>
                 format!("could not find file {}", path.to_str().unwrap()),
-            Error::BinaryNotFound(binary) => 
-                format!("binary not found: {}", binary),
+            Error::BinaryNotFound(binary, err) =>
+                format!("binary {} not found: {}", binary, err),
             Error::UnknownFence(kind) =>
                 format!("unknown fence with name {}", kind),
             Error::InvalidImage(path) =>
                 format!("could not read in {} as image", path),
+            Error::MissingFeature(feature) =>
+                format!("compiled without feature {}", feature),
+            Error::NodeNotFound(line) =>
+                format!("no node at line {}", line),
+            Error::NodeNotReady(line) =>
+                format!("node at line {} is not rendered yet", line),
+            Error::NoTmux =>
+                "not running inside tmux".to_string(),
+            Error::Timeout(binary) =>
+                format!("{} did not finish in time", binary),
+            Error::InvalidConfig =>
+                "could not parse configuration".to_string(),
+            Error::InvalidUpdate =>
+                "invalid line update".to_string(),
+            Error::InvalidFolds =>
+                "invalid fold state".to_string(),
+            Error::InvalidFilter =>
+                "could not parse cache filter".to_string(),
+            Error::InvalidPattern(pattern, err) =>
+                format!("invalid filter pattern {}: {}", pattern, err),
+            Error::FilterFailed(binary, err) =>
+                format!("filter {} failed: {}", binary, err),
+            Error::InvalidEvent =>
+                "could not parse mouse event".to_string(),
+            Error::DependencyFailed(name) =>
+                format!("fence {} this one depends on failed", name),
+            Error::UnknownParam(name, line) =>
+                format!("node at line {} has no parameter {}", line, name),
+            Error::NodeNotNamed(line) =>
+                format!("node at line {} has no name", line),
+            Error::VersionNotFound(name, index) =>
+                format!("{} has no version {}", name, index),
+            Error::ShellEscapeNotAllowed =>
+                "shell-escape is not allowed for this document, add it to `shell_escape` in the configuration".to_string(),
+            Error::UnknownBackend(name) =>
+                format!("unknown graphics backend {}", name),
+            Error::UnsupportedFile(path, kind) =>
+                format!("unsupported file {} of type {}", path.to_str().unwrap(), kind),
+            Error::EmptyTransclusion(target) =>
+                format!("{} has no figures besides videos", target),
+            Error::InvalidMirror(path) =>
+                format!("mirror {} is neither a terminal nor a FIFO", path),
             Error::Io(io_err) => format!("IO error: {}", io_err)
         };
 
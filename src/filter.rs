@@ -0,0 +1,137 @@
+//! Filters applied to the content of fences before rendering
+//!
+//! Filters are configured per fence name, filters of `*` apply to all fences and run first. Each
+//! filter either replaces a regular expression or pipes the content through a command. The
+//! filtered content is rendered and hashed, so a filter doesn't change the document but its
+//! images. Filters from the first command on run when the image is generated in the background,
+//! a failing command is the error of its node.
+use std::collections::BTreeMap;
+
+use regex::Regex;
+
+use crate::config;
+use crate::error::{Error, Result};
+use crate::process::Process;
+
+#[derive(Clone, Debug)]
+pub enum Filter {
+    Replace(Regex, String),
+    /// Binary and arguments of the command
+    Command(Vec<String>),
+}
+
+impl Filter {
+    pub fn new(filter: &config::Filter) -> Result<Filter> {
+        match filter {
+            config::Filter { pattern: Some(pattern), command: None, replace } => {
+                let regex = Regex::new(pattern)
+                    .map_err(|err| Error::InvalidPattern(pattern.clone(), err.to_string()))?;
+
+                Ok(Filter::Replace(regex, replace.clone().unwrap_or_default()))
+            },
+            config::Filter { pattern: None, command: Some(command), .. } if !command.trim().is_empty() =>
+                Ok(Filter::Command(command.split_whitespace().map(|x| x.to_string()).collect())),
+            _ => Err(Error::InvalidConfig),
+        }
+    }
+
+    /// Text identifying the filter, part of the id of nodes it applies to
+    fn key(&self) -> String {
+        match self {
+            Filter::Replace(regex, replace) => format!("s/{}/{}", regex.as_str(), replace),
+            Filter::Command(args) => args.join(" "),
+        }
+    }
+
+    /// Filter the content, commands are run on the calling thread
+    fn run(&self, content: String) -> Result<String> {
+        let args = match self {
+            Filter::Replace(regex, replace) => return Ok(regex.replace_all(&content, replace.as_str()).to_string()),
+            Filter::Command(args) => args,
+        };
+
+        let cmd = args[1..].iter()
+            .fold(Process::new(&args[0])?, |cmd, arg| cmd.arg(arg))
+            .stdin(content)
+            .run()?;
+
+        if !cmd.status.success() {
+            let buf = String::from_utf8_lossy(&cmd.stderr);
+            return Err(Error::FilterFailed(args[0].clone(), buf.trim().to_string()));
+        }
+
+        Ok(String::from_utf8_lossy(&cmd.stdout).to_string())
+    }
+}
+
+/// Run the filters left by `Filters::apply` in order
+pub fn run(filters: &[Filter], content: String) -> Result<String> {
+    filters.iter().try_fold(content, |content, filter| filter.run(content))
+}
+
+/// Text identifying a sequence of filters, empty if there are none
+pub fn key(filters: &[Filter]) -> String {
+    filters.iter().map(Filter::key).collect::<Vec<_>>().join("\n")
+}
+
+#[derive(Default)]
+pub struct Filters {
+    by_fence: BTreeMap<String, Vec<Filter>>,
+}
+
+impl Filters {
+    /// Compile filters of the configuration, fence names are case insensitive
+    pub fn new(filters: &BTreeMap<String, Vec<config::Filter>>) -> Result<Filters> {
+        let by_fence = filters.iter()
+            .map(|(name, filters)| Ok((name.to_lowercase(), filters.iter().map(Filter::new).collect::<Result<Vec<_>>>()?)))
+            .collect::<Result<_>>()?;
+
+        Ok(Filters { by_fence })
+    }
+
+    /// Filter the content of a fence with the given name up to the first command
+    ///
+    /// Returns the content and the filters from the first command on, which are run with `run`
+    /// when the image is generated.
+    pub fn apply(&self, name: &str, content: String) -> (String, Vec<Filter>) {
+        let mut filters = self.by_fence.get("*").into_iter().flatten()
+            .chain(self.by_fence.get(name).into_iter().flatten())
+            .peekable();
+
+        let mut content = content;
+        while let Some(Filter::Replace(regex, replace)) = filters.peek() {
+            content = regex.replace_all(&content, replace.as_str()).to_string();
+            filters.next();
+        }
+
+        (content, filters.cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(pattern: Option<&str>, command: Option<&str>) -> config::Filter {
+        config::Filter {
+            pattern: pattern.map(|x| x.to_string()),
+            replace: pattern.map(|_| "b".to_string()),
+            command: command.map(|x| x.to_string()),
+        }
+    }
+
+    #[test]
+    fn commands_are_left_to_generation() {
+        let filters = Filters::new(&BTreeMap::from([
+            ("*".to_string(), vec![filter(Some("a"), None)]),
+            ("dot".to_string(), vec![filter(None, Some("cat")), filter(Some("c"), None)]),
+        ])).unwrap();
+
+        let (content, rest) = filters.apply("math", "abc".to_string());
+        assert_eq!((content.as_str(), rest.len()), ("bbc", 0));
+
+        let (content, rest) = filters.apply("dot", "abc".to_string());
+        assert_eq!((content.as_str(), rest.len()), ("bbc", 2));
+        assert_eq!(key(&rest), "cat\ns/c/b");
+    }
+}
@@ -0,0 +1,107 @@
+//! Previous figures of named fences
+//!
+//! Each time a fence named with `name=plot` renders a new figure, its artifact is copied to
+//! `history/<key>/<millis>-<artifact>.<ext>` in the cache folder, the key is derived from the
+//! document and the name. The last versions are kept to compare a plot with the one before a
+//! change of its script. Versions belong to the same document and content type as the artifact
+//! they were copied from, so that they are listed and purged with the cache.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use miniserde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::render::ART_PATH;
+use crate::utils;
+
+/// Folder of the history in the cache folder
+pub const HISTORY_DIR: &str = "history";
+/// Number of versions kept per named fence
+pub const HISTORY_LEN: usize = 10;
+
+/// Previous figure of a named fence, the newest has index zero
+#[derive(Debug, Serialize)]
+pub struct Version {
+    pub index: usize,
+    pub artifact: String,
+    pub path: String,
+    pub age_secs: u64,
+}
+
+/// Folder of the versions of a named fence in a document
+fn folder(document: &str, name: &str) -> PathBuf {
+    Path::new(ART_PATH).join(HISTORY_DIR).join(utils::hash(&format!("{}\n{}", document, name)))
+}
+
+/// Artifact of a file in the history, the part after the time it was recorded
+pub fn artifact_of(file_name: &str) -> Option<&str> {
+    let (millis, rest) = file_name.split_once('-')?;
+    millis.parse::<u64>().ok()?;
+
+    rest.split('.').next()
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Versions of a named fence, newest first
+pub fn versions(document: &str, name: &str) -> Vec<Version> {
+    let entries = match fs::read_dir(folder(document, name)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut versions = entries.flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let artifact = artifact_of(&file_name)?.to_string();
+            let recorded = file_name.split_once('-')?.0.parse::<u64>().ok()?;
+
+            Some((recorded, artifact, entry.path()))
+        })
+        .collect::<Vec<_>>();
+    versions.sort_by_key(|x| std::cmp::Reverse(x.0));
+
+    let now = millis(SystemTime::now());
+    versions.into_iter()
+        .enumerate()
+        .map(|(index, (recorded, artifact, path))| Version {
+            index,
+            artifact,
+            path: path.to_string_lossy().to_string(),
+            age_secs: now.saturating_sub(recorded) / 1000,
+        })
+        .collect()
+}
+
+/// Copy the artifact of a named fence to its history, unless it is the newest version already
+///
+/// Versions beyond the `keep` newest are removed.
+pub fn record(document: &str, name: &str, artifact: &Path, keep: usize) -> Result<()> {
+    let stem = match artifact.file_stem().and_then(|x| x.to_str()) {
+        Some(stem) if keep > 0 => stem,
+        _ => return Ok(()),
+    };
+
+    let versions = versions(document, name);
+    if versions.first().is_some_and(|x| x.artifact == stem) {
+        return Ok(());
+    }
+
+    let folder = folder(document, name);
+    fs::create_dir_all(&folder).map_err(Error::Io)?;
+
+    let mut file_name = format!("{}-{}", millis(SystemTime::now()), stem);
+    if let Some(ext) = artifact.extension().and_then(|x| x.to_str()) {
+        file_name = format!("{}.{}", file_name, ext);
+    }
+    fs::copy(artifact, folder.join(file_name)).map_err(Error::Io)?;
+
+    for old in versions.iter().skip(keep - 1) {
+        let _ = fs::remove_file(&old.path);
+    }
+
+    Ok(())
+}
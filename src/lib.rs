@@ -8,13 +8,33 @@ use std::mem::MaybeUninit;
 mod error;
 mod utils;
 mod render;
+mod registry;
 mod content;
 mod node_view;
+mod manifest;
+mod cache;
+mod history;
+mod process;
+mod config;
+mod filter;
+mod backend;
+mod terminal;
+mod mirror;
+mod unicode;
+mod sixel;
+mod quality;
+mod raster;
+pub mod daemon;
+#[cfg(feature = "cli")]
+pub mod cli;
+mod msgpack;
+#[cfg(feature = "snap")]
+mod snap;
 
 use error::Result;
 
 struct SingletonReader {
-    inner: RefCell<render::Render>,
+    inner: RefCell<registry::Registry>,
 }
 
 fn singleton() -> &'static SingletonReader {
@@ -26,7 +46,7 @@ fn singleton() -> &'static SingletonReader {
         ONCE.call_once(|| {
             // Make it
             let singleton = SingletonReader {
-                inner: RefCell::new(render::Render::new()),
+                inner: RefCell::new(registry::Registry::default()),
             };
             // Store it to the static var, i.e. initialize it
             SINGLETON.write(singleton);
@@ -38,13 +58,87 @@ fn singleton() -> &'static SingletonReader {
     }
 }
 
-pub fn result_to_cstring<T: ToString>(res: Result<T>) -> CString {
-    let inner = match res {
+pub fn result_to_string<T: ToString>(res: Result<T>) -> String {
+    match res {
         Ok(inn) => format!("{{ \"ok\": {} }}", inn.to_string()),
         Err(err) => format!("{{ \"err\": \"{}\" }}", err.to_string()),
+    }
+}
+
+pub fn result_to_cstring<T: ToString>(res: Result<T>) -> CString {
+    CString::new(result_to_string(res)).unwrap()
+}
+
+/// Call an exported function by name, returns the same JSON as the C interface
+///
+/// Functions without a return value answer with `null` instead of panicking on an error.
+pub fn dispatch(name: &str, input: &str) -> Option<String> {
+    dispatch_with(&mut singleton().inner.borrow_mut(), name, input)
+}
+
+/// Call an exported function by name on the buffers of the given registry
+pub(crate) fn dispatch_with(registry: &mut registry::Registry, name: &str, input: &str) -> Option<String> {
+    match name {
+        "update_config" => return Some(result_to_string(registry.update_config(input))),
+        "reload_config" => return Some(result_to_string(registry.reload_config(input))),
+        "remove_buffer" => return Some(result_to_string(registry.remove_buffer(input).map(|_| "null"))),
+        "set_focus" => return Some(result_to_string(registry.set_focus(input))),
+        "set_ui_state" => return Some(result_to_string(registry.set_ui_state(input))),
+        "suspend" => return Some(result_to_string(registry.suspend(input))),
+        "resume" => return Some(result_to_string(registry.resume(input))),
+        "buffer_hidden" => return Some(result_to_string(registry.buffer_hidden(input))),
+        "buffer_shown" => return Some(result_to_string(registry.buffer_shown(input))),
+        "build_info" => return Some(result_to_string(registry.build_info(input))),
+        "cache_stats" => return Some(result_to_string(registry.cache_stats(input))),
+        "purge_cache" => return Some(result_to_string(registry.purge_cache(input))),
+        _ => {},
+    }
+
+    let (render, input) = match registry.render(input) {
+        Ok(x) => x,
+        Err(err) => return Some(result_to_string::<bool>(Err(err))),
     };
 
-    CString::new(inner).unwrap()
+    let res = match name {
+        "update_content" => result_to_string(render.update_content(input)),
+        "update_lines" => result_to_string(render.update_lines(input)),
+        "update_metadata" => result_to_string(render.update_metadata(input).map(|_| "null")),
+        "clear_all" => result_to_string(render.clear_all(input).map(|_| "null")),
+        "draw" => result_to_string(render.draw(input)),
+        "continue_draw" => result_to_string(render.continue_draw(input)),
+        "set_folds" => result_to_string(render.set_folds(input)),
+        "fold_opened" => result_to_string(render.fold_opened(input)),
+        "fold_closed" => result_to_string(render.fold_closed(input)),
+        "toggle_overlay" => result_to_string(render.toggle_overlay(input)),
+        "validate" => result_to_string(render.validate(input)),
+        "slow_nodes" => result_to_string(render.slow_nodes(input)),
+        "node_status" => result_to_string(render.node_status(input)),
+        "render_queue" => result_to_string(render.render_queue(input)),
+        "prioritize" => result_to_string(render.prioritize(input)),
+        "painted_regions" => result_to_string(render.painted_regions(input)),
+        "mouse_event" => result_to_string(render.mouse_event(input)),
+        "recheck_binaries" => result_to_string(render.recheck_binaries(input)),
+        "animate" => result_to_string(render.animate(input)),
+        "pin_node" => result_to_string(render.pin_node(input)),
+        "unpin_node" => result_to_string(render.unpin_node(input).map(|_| "null")),
+        "zoom_in" => result_to_string(render.zoom_in(input)),
+        "zoom_out" => result_to_string(render.zoom_out(input)),
+        "pan_node" => result_to_string(render.pan_node(input)),
+        "reset_pan" => result_to_string(render.reset_pan(input)),
+        "popup_node" => result_to_string(render.popup_node(input)),
+        "capabilities" => result_to_string(render.capabilities(input)),
+        "document_manifest" => result_to_string(render.document_manifest(input)),
+        "section_action" => result_to_string(render.section_action(input)),
+        "set_param" => result_to_string(render.set_param(input)),
+        "figure_history" => result_to_string(render.figure_history(input)),
+        "show_version" => result_to_string(render.show_version(input)),
+        "hide_version" => result_to_string(render.hide_version(input).map(|_| "null")),
+        "compare_nodes" => result_to_string(render.compare_nodes(input)),
+        "hide_comparison" => result_to_string(render.hide_comparison(input).map(|_| "null")),
+        _ => return None,
+    };
+
+    Some(res)
 }
 
 macro_rules! export_fn {
@@ -54,7 +148,8 @@ macro_rules! export_fn {
             let input = CStr::from_ptr(input);
             let in_str = input.to_str().unwrap();
         
-            let res = singleton().inner.borrow_mut().$fn_name(in_str);
+            let res = singleton().inner.borrow_mut().render(in_str)
+                .and_then(|(render, in_str)| render.$fn_name(in_str));
             let res_str = result_to_cstring(res);
 
             res_str.into_raw()
@@ -75,13 +170,77 @@ macro_rules! export_fn {
             let input = CStr::from_ptr(input);
             let in_str = input.to_str().unwrap();
         
+            singleton().inner.borrow_mut().render(in_str)
+                .and_then(|(render, in_str)| render.$fn_name(in_str))
+                .unwrap();
+        }
+    };
+    // functions of the registry, not bound to a single buffer
+    (registry $fn_name:ident,String) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $fn_name(input: *const c_char) -> *const c_char {
+            let input = CStr::from_ptr(input);
+            let in_str = input.to_str().unwrap();
+
+            let res = singleton().inner.borrow_mut().$fn_name(in_str);
+            result_to_cstring(res).into_raw()
+        }
+    };
+    (registry $fn_name:ident,()) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $fn_name(input: *const c_char) {
+            let input = CStr::from_ptr(input);
+            let in_str = input.to_str().unwrap();
+
             singleton().inner.borrow_mut().$fn_name(in_str).unwrap();
         }
     }
 }
 
 export_fn!(update_content, String);
+export_fn!(update_lines, String);
 export_fn!(update_metadata, ());
 export_fn!(clear_all, ());
 export_fn!(draw, String);
-export_fn!(set_folds, ());
+export_fn!(continue_draw, String);
+export_fn!(set_folds, String);
+export_fn!(fold_opened, String);
+export_fn!(fold_closed, String);
+export_fn!(toggle_overlay, String);
+export_fn!(validate, String);
+export_fn!(slow_nodes, String);
+export_fn!(node_status, String);
+export_fn!(render_queue, String);
+export_fn!(prioritize, String);
+export_fn!(painted_regions, String);
+export_fn!(mouse_event, String);
+export_fn!(recheck_binaries, String);
+export_fn!(animate, String);
+export_fn!(pin_node, String);
+export_fn!(unpin_node, ());
+export_fn!(zoom_in, String);
+export_fn!(zoom_out, String);
+export_fn!(pan_node, String);
+export_fn!(reset_pan, String);
+export_fn!(popup_node, String);
+export_fn!(registry update_config, String);
+export_fn!(registry reload_config, String);
+export_fn!(registry remove_buffer, ());
+export_fn!(registry set_focus, String);
+export_fn!(registry set_ui_state, String);
+export_fn!(registry suspend, String);
+export_fn!(registry resume, String);
+export_fn!(registry buffer_hidden, String);
+export_fn!(registry buffer_shown, String);
+export_fn!(capabilities, String);
+export_fn!(document_manifest, String);
+export_fn!(section_action, String);
+export_fn!(set_param, String);
+export_fn!(figure_history, String);
+export_fn!(show_version, String);
+export_fn!(hide_version, ());
+export_fn!(compare_nodes, String);
+export_fn!(hide_comparison, ());
+export_fn!(registry build_info, String);
+export_fn!(registry cache_stats, String);
+export_fn!(registry purge_cache, String);
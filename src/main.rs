@@ -0,0 +1,4 @@
+//! Daemon running the renderer as a separate process, see `daemon` for the protocol
+fn main() {
+    std::process::exit(vim_graphical_preview::daemon::run());
+}
@@ -0,0 +1,96 @@
+//! Natural sizes and origins of the rendered artifacts, kept next to the cache across sessions
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use miniserde::{json, Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::render::{ART_PATH, CodeId};
+
+/// Content type and document an artifact was rendered for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Origin {
+    pub kind: String,
+    pub document: String,
+}
+
+/// Natural sizes of rendered nodes, persisted next to the cache
+///
+/// This allows to know the dimension of a node before it is rendered again, for example after
+/// restarting vim. The origin of each artifact is stored as well, to group the cache by content
+/// type and document.
+pub struct Manifest {
+    sizes: BTreeMap<CodeId, (usize, usize)>,
+    origins: BTreeMap<String, Origin>,
+    dirty: bool,
+}
+
+/// Read a JSON file, a missing or broken file results in the default value
+fn read_json<T: Deserialize + Default>(path: &Path) -> T {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|x| json::from_str(&x).ok())
+        .unwrap_or_default()
+}
+
+impl Manifest {
+    fn path() -> PathBuf {
+        Path::new(ART_PATH).join("manifest.json")
+    }
+
+    fn origins_path() -> PathBuf {
+        Path::new(ART_PATH).join("origins.json")
+    }
+
+    /// Load the manifest, a missing or broken file results in an empty manifest
+    pub fn load() -> Manifest {
+        Manifest {
+            sizes: read_json(&Manifest::path()),
+            origins: read_json(&Manifest::origins_path()),
+            dirty: false,
+        }
+    }
+
+    /// Origins of all artifacts, keyed by the file stem of the artifact
+    pub fn origins(&self) -> &BTreeMap<String, Origin> {
+        &self.origins
+    }
+
+    pub fn insert_origin(&mut self, artifact: &str, origin: Origin) {
+        if self.origins.get(artifact) != Some(&origin) {
+            self.origins.insert(artifact.to_string(), origin);
+            self.dirty = true;
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<(usize, usize)> {
+        self.sizes.get(id).copied()
+    }
+
+    pub fn insert(&mut self, id: &str, size: (usize, usize)) {
+        if self.sizes.insert(id.to_string(), size) != Some(size) {
+            self.dirty = true;
+        }
+    }
+
+    /// Write the manifest to disk if anything changed since the last store
+    ///
+    /// Entries stored by other buffers in the meantime are merged first. A failed write is tried
+    /// again with the next change, not with every call.
+    pub fn store(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let Manifest { mut sizes, mut origins, .. } = Manifest::load();
+        sizes.extend(self.sizes.iter().map(|(id, size)| (id.clone(), *size)));
+        origins.extend(self.origins.iter().map(|(id, origin)| (id.clone(), origin.clone())));
+        self.sizes = sizes;
+        self.origins = origins;
+        self.dirty = false;
+
+        fs::write(Manifest::path(), json::to_string(&self.sizes)).map_err(Error::Io)?;
+        fs::write(Manifest::origins_path(), json::to_string(&self.origins)).map_err(Error::Io)
+    }
+}
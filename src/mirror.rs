@@ -0,0 +1,112 @@
+//! Mirror of the terminal output to a second screen
+//!
+//! All sequences drawing or clearing images are written to a tty or FIFO as well, for example a
+//! terminal attached to a projector running `cat` on the FIFO. A terminal of the same size shows
+//! the figures at the same positions as the editing session. Writes happen on a background
+//! thread, so that a slow reader doesn't block vim, and the mirror is closed once the reader is
+//! gone. Sequences a stalled reader hasn't taken yet are queued up to `QUEUE_LEN`, further ones
+//! are dropped.
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::libc;
+use nix::sys::signal::{pthread_sigmask, SigSet, SigmaskHow, Signal};
+use nix::unistd::isatty;
+
+use crate::error::{Error, Result};
+
+/// Sequences waiting for the reader before further ones are dropped
+const QUEUE_LEN: usize = 64;
+
+/// Path of the mirror and the sender of sequences to its writer thread
+static MIRROR: Mutex<Option<(String, SyncSender<Vec<u8>>)>> = Mutex::new(None);
+
+/// Mirror the terminal output to a tty or FIFO, `None` stops mirroring
+///
+/// Setting the same path again keeps the mirror open. A FIFO without reader results in an error
+/// instead of blocking, as does any other file than a terminal or a FIFO.
+pub fn set_mirror(path: Option<&str>) -> Result<()> {
+    let mut mirror = MIRROR.lock().unwrap();
+    if mirror.as_ref().map(|x| x.0.as_str()) == path {
+        return Ok(());
+    }
+
+    // dropping the sender ends the writer thread of the previous mirror
+    *mirror = None;
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY)
+        .open(path)
+        .map_err(Error::Io)?;
+
+    // nothing was written yet, so a regular file named by mistake is left as it is
+    let file_type = file.metadata().map_err(Error::Io)?.file_type();
+    let terminal = file_type.is_char_device() && isatty(file.as_raw_fd()).unwrap_or(false);
+    if !terminal && !file_type.is_fifo() {
+        return Err(Error::InvalidMirror(path.to_string()));
+    }
+
+    fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty()))
+        .map_err(|err| Error::Io(err.into()))?;
+
+    let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(QUEUE_LEN);
+    thread::spawn(move || {
+        // a reader closing the FIFO results in an error instead of terminating vim
+        let mut signals = SigSet::empty();
+        signals.add(Signal::SIGPIPE);
+        let _ = pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&signals), None);
+
+        for buf in receiver {
+            if file.write_all(&buf).is_err() {
+                break;
+            }
+        }
+    });
+
+    *mirror = Some((path.to_string(), sender));
+
+    Ok(())
+}
+
+/// Write a sequence to the mirror, if there is one
+pub fn write(buf: &[u8]) {
+    let mut mirror = MIRROR.lock().unwrap();
+    let closed = match mirror.as_ref() {
+        Some((_, sender)) => matches!(sender.try_send(buf.to_vec()), Err(TrySendError::Disconnected(_))),
+        None => false,
+    };
+
+    if closed {
+        *mirror = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_files_and_devices() {
+        let path = std::env::temp_dir().join(format!("mirror-test-{}", std::process::id()));
+        std::fs::write(&path, "content").unwrap();
+
+        assert!(matches!(set_mirror(path.to_str()), Err(Error::InvalidMirror(_))));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "content");
+        // a character device, but no terminal
+        assert!(matches!(set_mirror(Some("/dev/null")), Err(Error::InvalidMirror(_))));
+        assert!(MIRROR.lock().unwrap().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
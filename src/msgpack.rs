@@ -0,0 +1,260 @@
+//! Minimal MessagePack codec for the msgpack-rpc protocol of Neovim
+//!
+//! Only whole values are read and written, extension types are decoded to their raw bytes.
+use std::io::{self, Read, Write};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Ext(i8, Vec<u8>),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+fn read_bytes<R: Read>(input: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    input.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+fn read_be<R: Read>(input: &mut R, len: usize) -> io::Result<u64> {
+    Ok(read_bytes(input, len)?.iter().fold(0, |acc, x| acc << 8 | *x as u64))
+}
+
+fn read_str<R: Read>(input: &mut R, len: usize) -> io::Result<Value> {
+    let buf = read_bytes(input, len)?;
+
+    Ok(Value::Str(String::from_utf8_lossy(&buf).to_string()))
+}
+
+fn read_array<R: Read>(input: &mut R, len: usize) -> io::Result<Value> {
+    (0..len).map(|_| read(input)).collect::<io::Result<Vec<_>>>().map(Value::Array)
+}
+
+fn read_map<R: Read>(input: &mut R, len: usize) -> io::Result<Value> {
+    (0..len).map(|_| Ok((read(input)?, read(input)?))).collect::<io::Result<Vec<_>>>().map(Value::Map)
+}
+
+fn read_ext<R: Read>(input: &mut R, len: usize) -> io::Result<Value> {
+    let kind = read_be(input, 1)? as i8;
+
+    Ok(Value::Ext(kind, read_bytes(input, len)?))
+}
+
+/// Read a single value
+pub fn read<R: Read>(input: &mut R) -> io::Result<Value> {
+    let marker = read_be(input, 1)? as u8;
+
+    match marker {
+        0x00..=0x7f => Ok(Value::UInt(marker as u64)),
+        0x80..=0x8f => read_map(input, (marker & 0x0f) as usize),
+        0x90..=0x9f => read_array(input, (marker & 0x0f) as usize),
+        0xa0..=0xbf => read_str(input, (marker & 0x1f) as usize),
+        0xc0 => Ok(Value::Nil),
+        0xc2 => Ok(Value::Bool(false)),
+        0xc3 => Ok(Value::Bool(true)),
+        0xc4 | 0xc5 | 0xc6 => {
+            let len = read_be(input, 1 << (marker - 0xc4))? as usize;
+            Ok(Value::Bin(read_bytes(input, len)?))
+        },
+        0xc7 | 0xc8 | 0xc9 => {
+            let len = read_be(input, 1 << (marker - 0xc7))? as usize;
+            read_ext(input, len)
+        },
+        0xca => Ok(Value::Float(f32::from_bits(read_be(input, 4)? as u32) as f64)),
+        0xcb => Ok(Value::Float(f64::from_bits(read_be(input, 8)?))),
+        0xcc..=0xcf => Ok(Value::UInt(read_be(input, 1 << (marker - 0xcc))?)),
+        0xd0..=0xd3 => {
+            let len = 1 << (marker - 0xd0);
+            let val = read_be(input, len)?;
+            // sign extend from the width of the value
+            let shift = 64 - 8 * len as u32;
+            Ok(Value::Int(((val << shift) as i64) >> shift))
+        },
+        0xd4..=0xd8 => read_ext(input, 1 << (marker - 0xd4)),
+        0xd9 | 0xda | 0xdb => {
+            let len = read_be(input, 1 << (marker - 0xd9))? as usize;
+            read_str(input, len)
+        },
+        0xdc | 0xdd => {
+            let len = read_be(input, 2 << (marker - 0xdc))? as usize;
+            read_array(input, len)
+        },
+        0xde | 0xdf => {
+            let len = read_be(input, 2 << (marker - 0xde))? as usize;
+            read_map(input, len)
+        },
+        0xe0..=0xff => Ok(Value::Int(marker as i8 as i64)),
+        0xc1 => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid msgpack marker")),
+    }
+}
+
+/// Write a marker for a length, choosing the smallest of the three sized variants
+fn write_len<W: Write>(out: &mut W, len: usize, markers: [u8; 3]) -> io::Result<()> {
+    if len <= u8::MAX as usize && markers[0] != 0 {
+        out.write_all(&[markers[0], len as u8])
+    } else if len <= u16::MAX as usize {
+        out.write_all(&[markers[1]])?;
+        out.write_all(&(len as u16).to_be_bytes())
+    } else {
+        out.write_all(&[markers[2]])?;
+        out.write_all(&(len as u32).to_be_bytes())
+    }
+}
+
+/// Write a single value
+pub fn write<W: Write>(out: &mut W, value: &Value) -> io::Result<()> {
+    match value {
+        Value::Nil => out.write_all(&[0xc0]),
+        Value::Bool(x) => out.write_all(&[if *x { 0xc3 } else { 0xc2 }]),
+        Value::UInt(x) if *x < 0x80 => out.write_all(&[*x as u8]),
+        Value::UInt(x) => {
+            out.write_all(&[0xcf])?;
+            out.write_all(&x.to_be_bytes())
+        },
+        Value::Int(x) if *x >= 0 => write(out, &Value::UInt(*x as u64)),
+        Value::Int(x) if *x >= -32 => out.write_all(&[*x as i8 as u8]),
+        Value::Int(x) => {
+            out.write_all(&[0xd3])?;
+            out.write_all(&x.to_be_bytes())
+        },
+        Value::Float(x) => {
+            out.write_all(&[0xcb])?;
+            out.write_all(&x.to_bits().to_be_bytes())
+        },
+        Value::Str(x) => {
+            if x.len() < 32 {
+                out.write_all(&[0xa0 | x.len() as u8])?;
+            } else {
+                write_len(out, x.len(), [0xd9, 0xda, 0xdb])?;
+            }
+            out.write_all(x.as_bytes())
+        },
+        Value::Bin(x) => {
+            write_len(out, x.len(), [0xc4, 0xc5, 0xc6])?;
+            out.write_all(x)
+        },
+        Value::Array(x) => {
+            if x.len() < 16 {
+                out.write_all(&[0x90 | x.len() as u8])?;
+            } else {
+                write_len(out, x.len(), [0, 0xdc, 0xdd])?;
+            }
+            x.iter().try_for_each(|x| write(out, x))
+        },
+        Value::Map(x) => {
+            if x.len() < 16 {
+                out.write_all(&[0x80 | x.len() as u8])?;
+            } else {
+                write_len(out, x.len(), [0, 0xde, 0xdf])?;
+            }
+            x.iter().try_for_each(|(key, val)| write(out, key).and_then(|_| write(out, val)))
+        },
+        Value::Ext(kind, data) => {
+            write_len(out, data.len(), [0xc7, 0xc8, 0xc9])?;
+            out.write_all(&[*kind as u8])?;
+            out.write_all(data)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encoded bytes of a value and the value read back from them
+    fn round_trip(value: &Value) -> (Vec<u8>, Value) {
+        let mut buf = Vec::new();
+        write(&mut buf, value).unwrap();
+        let read_back = read(&mut buf.as_slice()).unwrap();
+
+        (buf, read_back)
+    }
+
+    #[test]
+    fn integer_widths() {
+        for (value, len) in [(0, 1), (0x7f, 1), (0x80, 9), (u64::MAX, 9)] {
+            let (buf, read_back) = round_trip(&Value::UInt(value));
+            assert_eq!((buf.len(), read_back), (len, Value::UInt(value)));
+        }
+        for (value, len) in [(-1, 1), (-32, 1), (-33, 9), (i64::MIN, 9)] {
+            let (buf, read_back) = round_trip(&Value::Int(value));
+            assert_eq!((buf.len(), read_back), (len, Value::Int(value)));
+        }
+        // positive integers are written unsigned
+        assert_eq!(round_trip(&Value::Int(5)).1, Value::UInt(5));
+
+        // narrower widths written by other encoders
+        let read_bytes = |buf: &[u8]| read(&mut &buf[..]).unwrap();
+        assert_eq!(read_bytes(&[0xcc, 0xff]), Value::UInt(255));
+        assert_eq!(read_bytes(&[0xcd, 0x01, 0x00]), Value::UInt(256));
+        assert_eq!(read_bytes(&[0xd0, 0x80]), Value::Int(-128));
+        assert_eq!(read_bytes(&[0xd1, 0xff, 0x7f]), Value::Int(-129));
+        assert_eq!(read_bytes(&[0xd2, 0x80, 0, 0, 0]), Value::Int(i32::MIN as i64));
+    }
+
+    #[test]
+    fn string_and_binary_lengths() {
+        for (len, marker, header) in [(0, 0xa0, 1), (31, 0xbf, 1), (32, 0xd9, 2), (255, 0xd9, 2), (256, 0xda, 3), (65535, 0xda, 3), (65536, 0xdb, 5)] {
+            let value = Value::Str("x".repeat(len));
+            let (buf, read_back) = round_trip(&value);
+            assert_eq!((buf[0], buf.len()), (marker, header + len));
+            assert_eq!(read_back, value);
+        }
+
+        for (len, marker, header) in [(0, 0xc4, 2), (255, 0xc4, 2), (256, 0xc5, 3), (65535, 0xc5, 3), (65536, 0xc6, 5)] {
+            let value = Value::Bin(vec![0xab; len]);
+            let (buf, read_back) = round_trip(&value);
+            assert_eq!((buf[0], buf.len()), (marker, header + len));
+            assert_eq!(read_back, value);
+        }
+    }
+
+    #[test]
+    fn array_and_map_lengths() {
+        for (len, marker, header) in [(0, 0x90, 1), (15, 0x9f, 1), (16, 0xdc, 3), (65535, 0xdc, 3), (65536, 0xdd, 5)] {
+            let value = Value::Array(vec![Value::Nil; len]);
+            let (buf, read_back) = round_trip(&value);
+            assert_eq!((buf[0], buf.len()), (marker, header + len));
+            assert_eq!(read_back, value);
+        }
+
+        for (len, marker, header) in [(0, 0x80, 1), (15, 0x8f, 1), (16, 0xde, 3), (65535, 0xde, 3), (65536, 0xdf, 5)] {
+            let value = Value::Map(vec![(Value::Bool(true), Value::Nil); len]);
+            let (buf, read_back) = round_trip(&value);
+            assert_eq!((buf[0], buf.len()), (marker, header + 2 * len));
+            assert_eq!(read_back, value);
+        }
+    }
+
+    #[test]
+    fn nested_values() {
+        let value = Value::Array(vec![
+            Value::UInt(0),
+            Value::Str("nvim_buf_get_lines".to_string()),
+            Value::Map(vec![(Value::Str("ok".to_string()), Value::Float(1.5))]),
+            Value::Ext(1, vec![1, 2, 3]),
+        ]);
+        assert_eq!(round_trip(&value).1, value);
+
+        // fixext of one byte, as used by Neovim for buffer handles
+        assert_eq!(read(&mut &[0xd4, 0x00, 0x05][..]).unwrap(), Value::Ext(0, vec![5]));
+        assert!(read(&mut &[0xc1][..]).is_err());
+    }
+}
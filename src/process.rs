@@ -0,0 +1,432 @@
+//! Subprocess manager for external renderers
+//!
+//! All binaries (latex, dvisvgm, gnuplot, ..) are started through this module. It looks up the
+//! binary, limits the number of concurrently running processes, feeds stdin, captures the output
+//! in background threads, enforces a timeout and always waits for the child, so that no zombie
+//! processes are left behind.
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Output, Stdio};
+use std::os::unix::process::CommandExt;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::libc;
+
+use crate::error::{Error, Result};
+
+/// Default time a renderer may take before it is killed
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time a binary may take to print its version
+const VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval in which a running child is polled
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Number of currently running jobs per key, the empty key counts all processes
+static RUNNING: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+static RUNNING_CHANGED: Condvar = Condvar::new();
+/// Configured limits per key, for example per content type
+static LIMITS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+/// Jobs waiting for a slot, locked after `RUNNING`
+static QUEUE: Mutex<Vec<Queued>> = Mutex::new(Vec::new());
+static NEXT_TICKET: AtomicU64 = AtomicU64::new(0);
+/// Binaries which were not found, they are not searched again until `recheck_binaries`
+static MISSING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+/// Versions printed by binaries, asked once in the background until `recheck_binaries`
+static VERSIONS: Mutex<BTreeMap<String, Option<String>>> = Mutex::new(BTreeMap::new());
+
+/// Niceness and IO scheduling of background threads and renderers
+static NICE: AtomicI32 = AtomicI32::new(10);
+static IDLE_IO: AtomicBool = AtomicBool::new(true);
+
+/// Set niceness and whether the idle IO scheduling class is used for background work
+pub fn set_priority(nice: i32, idle_io: bool) {
+    NICE.store(nice, Ordering::Relaxed);
+    IDLE_IO.store(idle_io, Ordering::Relaxed);
+}
+
+/// Lower CPU and IO priority of the calling thread
+///
+/// On Linux niceness and IO priority are per thread and inherited by spawned children, so this
+/// is called at the beginning of each background thread.
+#[cfg(target_os = "linux")]
+pub fn lower_priority() {
+    let nice = NICE.load(Ordering::Relaxed);
+
+    unsafe {
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        if nice != 0 {
+            libc::setpriority(libc::PRIO_PROCESS, tid, nice);
+        }
+
+        if IDLE_IO.load(Ordering::Relaxed) {
+            // IOPRIO_WHO_PROCESS with class IOPRIO_CLASS_IDLE
+            libc::syscall(libc::SYS_ioprio_set, 1, 0, 3 << 13);
+        }
+    }
+}
+
+/// Elsewhere the priority is shared by all threads of vim, so it is left alone
+#[cfg(not(target_os = "linux"))]
+pub fn lower_priority() {}
+
+/// Search the missing binaries again, returns those which are available now
+pub fn recheck_binaries() -> Vec<String> {
+    let mut missing = MISSING.lock().unwrap();
+    let found = missing.iter()
+        .filter(|name| which::which(name).is_ok())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for name in &found {
+        missing.remove(name);
+    }
+    // binaries may have been updated as well, ask the known ones again
+    let known = std::mem::take(&mut *VERSIONS.lock().unwrap());
+    for name in known.keys() {
+        binary_version(name);
+    }
+
+    found
+}
+
+/// First line a binary prints when asked for its version
+///
+/// Doesn't block, the first call for a binary asks it in a background thread and returns `None`
+/// until it answered. Missing or failing binaries stay `None`.
+pub fn binary_version(name: &str) -> Option<String> {
+    let mut versions = VERSIONS.lock().unwrap();
+    if let Some(version) = versions.get(name) {
+        return version.clone();
+    }
+    versions.insert(name.to_string(), None);
+
+    let name = name.to_string();
+    thread::spawn(move || {
+        lower_priority();
+        let version = ask_version(&name);
+        VERSIONS.lock().unwrap().insert(name, version);
+    });
+
+    None
+}
+
+/// Run a binary with its version flag and return the first line it prints
+fn ask_version(name: &str) -> Option<String> {
+    let flag = match name {
+        "dot" => "-V",
+        "plantuml" => "-version",
+        _ => "--version",
+    };
+    // graphviz prints its version to stderr
+    Process::new(name)
+        .and_then(|cmd| cmd.arg(flag).timeout(VERSION_TIMEOUT).immediate().run())
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| [output.stdout, output.stderr].iter()
+            .find_map(|x| String::from_utf8_lossy(x).lines()
+                .map(str::trim)
+                .find(|x| !x.is_empty())
+                .map(|x| x.to_string())))
+}
+
+/// Binaries which were not found since the last recheck
+pub fn missing_binaries() -> Vec<String> {
+    MISSING.lock().unwrap().iter().cloned().collect()
+}
+
+/// Set the number of jobs which may run concurrently for a key
+pub fn set_limit(key: &str, limit: usize) {
+    LIMITS.lock().unwrap().insert(key.to_string(), limit.max(1));
+    RUNNING_CHANGED.notify_all();
+}
+
+fn limit(key: &str) -> usize {
+    match LIMITS.lock().unwrap().get(key) {
+        Some(limit) => *limit,
+        None if key.is_empty() => thread::available_parallelism().map_or(1, |x| x.get()),
+        None => usize::MAX,
+    }
+}
+
+struct Queued {
+    ticket: u64,
+    key: String,
+    id: String,
+    priority: usize,
+    since: Instant,
+}
+
+/// Job waiting for a slot, as reported by `queue`
+pub struct QueueEntry {
+    pub id: String,
+    pub key: String,
+    pub priority: usize,
+    pub waiting: Duration,
+}
+
+/// All jobs waiting for a slot, in the order they will be started per key
+pub fn queue() -> Vec<QueueEntry> {
+    let _running = RUNNING.lock().unwrap();
+    let mut queue = QUEUE.lock().unwrap()
+        .iter()
+        .map(|x| (x.ticket, QueueEntry { id: x.id.clone(), key: x.key.clone(), priority: x.priority, waiting: x.since.elapsed() }))
+        .collect::<Vec<_>>();
+
+    queue.sort_by(|(t1, a), (t2, b)| b.priority.cmp(&a.priority).then(t1.cmp(t2)));
+    queue.into_iter().map(|(_, x)| x).collect()
+}
+
+/// Move all waiting jobs of an id to the front of the queue of their key
+///
+/// Returns `false` if no job with this id is waiting.
+pub fn prioritize(id: &str) -> bool {
+    let _running = RUNNING.lock().unwrap();
+    let mut queue = QUEUE.lock().unwrap();
+
+    let priority = queue.iter().map(|x| x.priority).max().unwrap_or(0) + 1;
+    let mut found = false;
+    for job in queue.iter_mut().filter(|x| x.id == id) {
+        job.priority = priority;
+        found = true;
+    }
+    RUNNING_CHANGED.notify_all();
+
+    found
+}
+
+/// Whether the job is the next of its key, higher priorities first and then in order of arrival
+fn is_next(queue: &[Queued], ticket: u64) -> bool {
+    let key = match queue.iter().find(|x| x.ticket == ticket) {
+        Some(job) => &job.key,
+        None => return true,
+    };
+
+    queue.iter()
+        .filter(|x| x.key == *key)
+        .max_by(|a, b| a.priority.cmp(&b.priority).then(b.ticket.cmp(&a.ticket)))
+        .map_or(true, |x| x.ticket == ticket)
+}
+
+/// Slot in the limit of a key, released on drop
+///
+/// Jobs exceeding the limit of their key are queued until a slot of the same key becomes free,
+/// so that one slow renderer type can't occupy all slots.
+pub struct Slot(String);
+
+impl Slot {
+    pub fn acquire(key: &str) -> Slot {
+        let mut running = RUNNING.lock().unwrap();
+        while running.get(key).copied().unwrap_or(0) >= limit(key) {
+            running = RUNNING_CHANGED.wait(running).unwrap();
+        }
+        *running.entry(key.to_string()).or_insert(0) += 1;
+
+        Slot(key.to_string())
+    }
+
+    /// Acquire a slot for the job `id`, which is listed in the queue while waiting
+    ///
+    /// Waiting jobs of the same key are started in the order of `queue`, such that a job can be
+    /// moved to the front with `prioritize`.
+    pub fn acquire_queued(key: &str, id: &str) -> Slot {
+        let ticket = NEXT_TICKET.fetch_add(1, Ordering::Relaxed);
+
+        let mut running = RUNNING.lock().unwrap();
+        QUEUE.lock().unwrap().push(Queued {
+            ticket,
+            key: key.to_string(),
+            id: id.to_string(),
+            priority: 0,
+            since: Instant::now(),
+        });
+
+        while running.get(key).copied().unwrap_or(0) >= limit(key) || !is_next(&QUEUE.lock().unwrap(), ticket) {
+            running = RUNNING_CHANGED.wait(running).unwrap();
+        }
+        QUEUE.lock().unwrap().retain(|x| x.ticket != ticket);
+        *running.entry(key.to_string()).or_insert(0) += 1;
+
+        // the next job of this key may be startable as well
+        RUNNING_CHANGED.notify_all();
+
+        Slot(key.to_string())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(running) = RUNNING.lock().unwrap().get_mut(&self.0) {
+            *running -= 1;
+        }
+        RUNNING_CHANGED.notify_all();
+    }
+}
+
+/// Builder for a single invocation of an external binary
+pub struct Process {
+    name: String,
+    cmd: Command,
+    stdin: Option<Vec<u8>>,
+    timeout: Duration,
+    /// Whether the process waits for a free slot, only renderers do
+    limited: bool,
+}
+
+impl Process {
+    /// Look up binary in `PATH` and prepare a new invocation
+    ///
+    /// Missing binaries are remembered and fail right away until they are searched again by
+    /// `recheck_binaries`.
+    pub fn new(name: &str) -> Result<Process> {
+        if MISSING.lock().unwrap().contains(name) {
+            return Err(Error::BinaryNotFound(name.to_string(), which::Error::CannotFindBinaryPath));
+        }
+
+        let path = which::which(name)
+            .map_err(|err| {
+                MISSING.lock().unwrap().insert(name.to_string());
+                Error::BinaryNotFound(name.to_string(), err)
+            })?;
+
+        Ok(Process {
+            name: name.to_string(),
+            cmd: Command::new(path),
+            stdin: None,
+            timeout: DEFAULT_TIMEOUT,
+            limited: true,
+        })
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Process {
+        self.cmd.arg(arg);
+        self
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Process {
+        self.cmd.current_dir(dir);
+        self
+    }
+
+    /// Data written to the stdin of the child, stdin is closed afterwards
+    pub fn stdin<T: Into<Vec<u8>>>(mut self, data: T) -> Process {
+        self.stdin = Some(data.into());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Process {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Run right away instead of waiting for renderers to free a slot, for short helper calls
+    /// which may come from vim's thread
+    pub fn immediate(mut self) -> Process {
+        self.limited = false;
+        self
+    }
+
+    /// Run the process to completion and capture its output
+    pub fn run(mut self) -> Result<Output> {
+        let _slot = self.limited.then(|| Slot::acquire(""));
+
+        // renderers run with reduced priority, even if started from the main thread
+        unsafe {
+            self.cmd.pre_exec(|| {
+                lower_priority();
+                Ok(())
+            });
+        }
+
+        let mut child = self.cmd
+            .stdin(if self.stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(Error::Io)?;
+
+        // feed stdin in the background, a child may not read everything before exiting
+        if let (Some(data), Some(mut stdin)) = (self.stdin.take(), child.stdin.take()) {
+            thread::spawn(move || {
+                let _ = stdin.write_all(&data);
+            });
+        }
+
+        let stdout = capture(child.stdout.take());
+        let stderr = capture(child.stderr.take());
+
+        let status = wait_timeout(&mut child, self.timeout)
+            .ok_or_else(|| Error::Timeout(self.name.clone()))?;
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().unwrap(),
+            stderr: stderr.join().unwrap(),
+        })
+    }
+
+    /// Spawn the process without waiting for it, the child is reaped in the background
+    pub fn spawn_detached(mut self) -> Result<()> {
+        let mut child = self.cmd
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(Error::Io)?;
+
+        thread::spawn(move || {
+            let _ = child.wait();
+        });
+
+        Ok(())
+    }
+
+    /// Spawn a long running process and return a pipe to its stdin
+    ///
+    /// The process exits once the pipe is closed and is then reaped in the background.
+    pub fn spawn_piped(mut self) -> Result<ChildStdin> {
+        let mut child = self.cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(Error::Io)?;
+
+        let stdin = child.stdin.take().unwrap();
+        thread::spawn(move || {
+            let _ = child.wait();
+        });
+
+        Ok(stdin)
+    }
+}
+
+/// Read a pipe to its end in a background thread
+fn capture<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+
+        buf
+    })
+}
+
+/// Wait for a child to exit, kill and reap it if the timeout is exceeded
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Option<std::process::ExitStatus> {
+    let start = Instant::now();
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(status),
+            Ok(None) if start.elapsed() < timeout => thread::sleep(POLL_INTERVAL),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    }
+}
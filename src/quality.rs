@@ -0,0 +1,175 @@
+//! Quality of images adapted to how fast the terminal takes them
+//!
+//! Writing to the terminal blocks while it is still busy with earlier output, so the time spent
+//! writing the images of a draw tells whether the terminal keeps up, for example at the end of a
+//! slow SSH session. If the throughput of the last draws drops below `SLOW_THROUGHPUT` the quality
+//! is stepped down a level, which selects a coarser SIXEL palette and smaller blobs. Once no draw
+//! was slow for `RECOVERY` it is stepped back up, one level at a time.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use miniserde::Serialize;
+
+/// Number of draws the throughput is averaged over
+const WINDOW: usize = 4;
+/// Draws writing less are too short to tell the throughput
+const MIN_BYTES: usize = 16 * 1024;
+/// Bytes per second below which the terminal doesn't keep up
+const SLOW_THROUGHPUT: u64 = 1024 * 1024;
+/// Time after a change before the next step down, so that the smaller blobs are measured first
+const SETTLE: Duration = Duration::from_secs(2);
+/// Time without slow draws after which the quality is stepped up again
+const RECOVERY: Duration = Duration::from_secs(10);
+
+/// Bytes written to the terminal and the time the writes took
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Written {
+    pub bytes: usize,
+    pub time: Duration,
+}
+
+impl Written {
+    pub fn add(&mut self, bytes: usize, time: Duration) {
+        self.bytes += bytes;
+        self.time += time;
+    }
+
+    /// Bytes per second, unknown if nothing was written
+    fn throughput(&self) -> Option<u64> {
+        let secs = self.time.as_secs_f64();
+        (secs > 0.0).then(|| (self.bytes as f64 / secs) as u64)
+    }
+}
+
+/// Quality level of the backend with the measured draws
+#[derive(Debug, Default)]
+pub struct Adaptive {
+    level: usize,
+    samples: VecDeque<Written>,
+    last_slow: Option<Instant>,
+    last_change: Option<Instant>,
+    steps_down: usize,
+    steps_up: usize,
+}
+
+/// State of the quality loop, part of the response of `cache_stats`
+#[derive(Debug, Serialize)]
+pub struct QualityStats {
+    backend: &'static str,
+    /// Current level, zero is the best quality
+    level: usize,
+    levels: usize,
+    /// Average throughput of the recent draws in bytes per second
+    throughput: Option<u64>,
+    /// Average time in milliseconds a recent draw spent writing
+    latency_ms: Option<u64>,
+    steps_down: usize,
+    steps_up: usize,
+}
+
+impl Adaptive {
+    /// Record the writes of a draw finished at `now`, returns the new level if it changed
+    pub fn record(&mut self, written: Written, levels: usize, now: Instant) -> Option<usize> {
+        if written.bytes >= MIN_BYTES {
+            self.samples.push_back(written);
+            if self.samples.len() > WINDOW {
+                self.samples.pop_front();
+            }
+        }
+
+        let since = |x: Option<Instant>| x.map_or(Duration::MAX, |x| now - x);
+        let slow = self.samples.len() >= 2
+            && self.total().throughput().is_some_and(|x| x < SLOW_THROUGHPUT);
+        if slow {
+            self.last_slow = Some(now);
+        }
+
+        if slow && self.level + 1 < levels && since(self.last_change) >= SETTLE {
+            self.level += 1;
+            self.steps_down += 1;
+        } else if self.level > 0 && since(self.last_slow) >= RECOVERY && since(self.last_change) >= RECOVERY {
+            self.level -= 1;
+            self.steps_up += 1;
+        } else {
+            return None;
+        }
+
+        // draws at the previous level don't tell about the new one
+        self.samples.clear();
+        self.last_change = Some(now);
+
+        Some(self.level)
+    }
+
+    pub fn stats(&self, backend: &'static str, levels: usize) -> QualityStats {
+        let total = self.total();
+
+        QualityStats {
+            backend,
+            level: self.level,
+            levels,
+            throughput: total.throughput(),
+            latency_ms: (!self.samples.is_empty()).then(|| total.time.as_millis() as u64 / self.samples.len() as u64),
+            steps_down: self.steps_down,
+            steps_up: self.steps_up,
+        }
+    }
+
+    fn total(&self) -> Written {
+        self.samples.iter().fold(Written::default(), |mut acc, x| {
+            acc.add(x.bytes, x.time);
+            acc
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLOW: Written = Written { bytes: 64 * 1024, time: Duration::from_secs(1) };
+    const FAST: Written = Written { bytes: 64 * 1024, time: Duration::from_millis(1) };
+
+    #[test]
+    fn steps_down_after_slow_draws() {
+        let (mut quality, start) = (Adaptive::default(), Instant::now());
+
+        // a single draw doesn't tell the throughput
+        assert_eq!(quality.record(SLOW, 3, start), None);
+        assert_eq!(quality.record(SLOW, 3, start + Duration::from_millis(100)), Some(1));
+        assert_eq!(quality.stats("sixel", 3).steps_down, 1);
+    }
+
+    #[test]
+    fn settles_before_stepping_down_again() {
+        let (mut quality, start) = (Adaptive::default(), Instant::now());
+        quality.record(SLOW, 3, start);
+        quality.record(SLOW, 3, start);
+
+        // the smaller blobs are measured first
+        assert_eq!(quality.record(SLOW, 3, start + Duration::from_millis(100)), None);
+        assert_eq!(quality.record(SLOW, 3, start + Duration::from_millis(200)), None);
+        assert_eq!(quality.record(SLOW, 3, start + SETTLE), Some(2));
+        // there is no level below the last one
+        assert_eq!(quality.record(SLOW, 3, start + SETTLE * 2), None);
+        assert_eq!(quality.record(SLOW, 3, start + SETTLE * 3), None);
+    }
+
+    #[test]
+    fn recovers_one_level_at_a_time() {
+        let (mut quality, start) = (Adaptive::default(), Instant::now());
+        quality.record(SLOW, 3, start);
+        quality.record(SLOW, 3, start);
+        quality.record(SLOW, 3, start + SETTLE);
+        quality.record(SLOW, 3, start + SETTLE);
+
+        let changed = start + SETTLE;
+        assert_eq!(quality.record(FAST, 3, changed + RECOVERY / 2), None);
+        assert_eq!(quality.record(FAST, 3, changed + RECOVERY), Some(1));
+        assert_eq!(quality.record(FAST, 3, changed + RECOVERY * 3 / 2), None);
+        assert_eq!(quality.record(FAST, 3, changed + RECOVERY * 2), Some(0));
+
+        let stats = quality.stats("sixel", 3);
+        assert_eq!((stats.level, stats.steps_down, stats.steps_up), (0, 2, 2));
+    }
+}
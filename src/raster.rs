@@ -0,0 +1,451 @@
+//! Decoded images and the transformations applied before encoding
+//!
+//! By default images are handled by ImageMagick. Built with `--no-default-features --features
+//! static` the library doesn't link against ImageMagick, SVG is rasterized by resvg and bitmaps
+//! are decoded by the image crate instead. Such a build can't break when the system ImageMagick
+//! is updated, but only reads SVG, PNG, JPEG, GIF, BMP, TIFF and WebP and has no debug overlay.
+#[cfg(not(any(feature = "magick", feature = "static")))]
+compile_error!("either feature `magick` or `static` has to be enabled");
+
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+
+pub use imp::{version, delegates, Image};
+
+/// Delay of GIF frames without one, like browsers do
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+/// Transparent columns between two images placed side by side
+const COMPARE_GAP: usize = 8;
+
+/// Transparent rows between two images placed below each other
+const STACK_GAP: usize = 16;
+
+/// How two images are combined to compare them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareMode {
+    /// Both images on top of each other, each half transparent
+    Blend,
+    /// The first image left of the second one
+    SideBySide,
+}
+
+impl CompareMode {
+    pub fn parse(name: &str) -> Option<CompareMode> {
+        match name.trim().to_lowercase().as_str() {
+            "blend" => Some(CompareMode::Blend),
+            "side" | "side-by-side" => Some(CompareMode::SideBySide),
+            _ => None,
+        }
+    }
+}
+
+/// Combine two images of the same height into one, both are aligned at the top
+///
+/// The pixels are composed after exporting them, so that ImageMagick and static builds behave the
+/// same. Pixels covered by only one image keep half of their opacity when blending.
+pub fn compose(a: &Image, b: &Image, mode: CompareMode) -> Result<Image> {
+    let invalid = || Error::InvalidImage("composite".to_string());
+    let (pixels_a, pixels_b) = (a.rgba().ok_or_else(invalid)?, b.rgba().ok_or_else(invalid)?);
+    let (width_a, width_b) = (a.width(), b.width());
+    let height = a.height().max(b.height());
+
+    // RGBA of an image at the given position, transparent outside of it
+    let pixel = |pixels: &[u8], width: usize, x: usize, y: usize| -> [u8; 4] {
+        let idx = (y * width + x) * 4;
+        match pixels.get(idx..idx + 4) {
+            Some(px) if x < width => [px[0], px[1], px[2], px[3]],
+            _ => [0; 4],
+        }
+    };
+
+    let width = match mode {
+        CompareMode::Blend => width_a.max(width_b),
+        CompareMode::SideBySide => width_a + COMPARE_GAP + width_b,
+    };
+    let mut out = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        for x in 0..width {
+            let px = match mode {
+                CompareMode::Blend => {
+                    let (pa, pb) = (pixel(&pixels_a, width_a, x, y), pixel(&pixels_b, width_b, x, y));
+                    let (alpha_a, alpha_b) = (pa[3] as usize, pb[3] as usize);
+                    let alpha = (alpha_a + alpha_b).max(1);
+
+                    let mut px = [0; 4];
+                    for c in 0..3 {
+                        px[c] = ((pa[c] as usize * alpha_a + pb[c] as usize * alpha_b) / alpha) as u8;
+                    }
+                    px[3] = ((alpha_a + alpha_b) / 2) as u8;
+                    px
+                },
+                CompareMode::SideBySide if x < width_a => pixel(&pixels_a, width_a, x, y),
+                CompareMode::SideBySide if x < width_a + COMPARE_GAP => [0; 4],
+                CompareMode::SideBySide => pixel(&pixels_b, width_b, x - width_a - COMPARE_GAP, y),
+            };
+            out.extend_from_slice(&px);
+        }
+    }
+
+    Image::from_rgba(width, height, &out)
+}
+
+/// Place images below each other, aligned at the left, e.g. the figures of a transcluded note
+///
+/// Images whose pixels can't be exported are left out.
+pub fn stack(images: &[Image]) -> Result<Image> {
+    let images = images.iter()
+        .filter(|x| x.width() > 0)
+        .filter_map(|x| x.rgba().map(|pixels| (x.width(), x.height(), pixels)))
+        .collect::<Vec<_>>();
+    let width = images.iter().map(|x| x.0).max().ok_or_else(|| Error::InvalidImage("stack".to_string()))?;
+    let height = images.iter().map(|x| x.1).sum::<usize>() + STACK_GAP * (images.len() - 1);
+
+    let mut out = Vec::with_capacity(width * height * 4);
+    for (idx, (image_width, _, pixels)) in images.iter().enumerate() {
+        if idx > 0 {
+            out.resize(out.len() + width * STACK_GAP * 4, 0);
+        }
+
+        // rows of narrower images are filled up with transparent pixels
+        for row in pixels.chunks(image_width * 4) {
+            out.extend_from_slice(row);
+            out.resize(out.len() + (width - image_width) * 4, 0);
+        }
+    }
+
+    Image::from_rgba(width, height, &out)
+}
+
+/// Delay of a frame, given in hundredths of a second
+fn frame_delay(centiseconds: usize) -> Duration {
+    match centiseconds {
+        0 => DEFAULT_FRAME_DELAY,
+        x => Duration::from_millis(x as u64 * 10),
+    }
+}
+
+#[cfg(feature = "magick")]
+mod imp {
+    use std::ffi::{CStr, CString};
+    use std::os::raw::c_void;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use magick_rust::{bindings, MagickWand, DrawingWand, PixelWand};
+
+    use crate::error::{Error, Result};
+
+    #[derive(Clone)]
+    pub struct Image(MagickWand);
+
+    /// Version of the linked ImageMagick library
+    pub fn version() -> String {
+        unsafe {
+            let version = bindings::MagickGetVersion(std::ptr::null_mut());
+            if version.is_null() {
+                "unknown".to_string()
+            } else {
+                CStr::from_ptr(version).to_string_lossy().to_string()
+            }
+        }
+    }
+
+    /// Delegate libraries ImageMagick was built with, e.g. `png rsvg xml`
+    pub fn delegates() -> Option<String> {
+        let option = CString::new("DELEGATES").unwrap();
+        unsafe {
+            let value = bindings::MagickQueryConfigureOption(option.as_ptr());
+            if value.is_null() {
+                return None;
+            }
+
+            let delegates = CStr::from_ptr(value).to_string_lossy().to_string();
+            bindings::MagickRelinquishMemory(value as *mut c_void);
+
+            Some(delegates)
+        }
+    }
+
+    impl Image {
+        /// Read a file with the ImageMagick decoder `format`, vector graphics are rasterized with
+        /// the given density
+        pub fn read(format: &str, path: &Path, density: usize) -> Result<Image> {
+            let wand = MagickWand::new();
+            wand.set_resolution(density as f64, density as f64).unwrap();
+
+            // the explicit decoder makes ImageMagick ignore the extension
+            wand.read_image(&format!("{}:{}", format, path.to_str().unwrap()))
+                .map_err(|_| Error::InvalidImage(path.to_str().unwrap().to_string()))?;
+
+            //wand.set_compression_quality(5).unwrap();
+            //wand.transform_image_colorspace(ColorspaceType_GRAYColorspace).unwrap();
+            //wand.quantize_image(8, ColorspaceType_GRAYColorspace, 0, DitherMethod_NoDitherMethod, 0).unwrap();
+
+            Ok(Image(wand))
+        }
+
+        /// Read all frames of an animated GIF with the time each frame is shown
+        ///
+        /// Frames of a GIF may only cover the changed part of the previous frame, they are
+        /// coalesced to full images.
+        pub fn read_frames(path: &Path) -> Result<Vec<(Image, Duration)>> {
+            let invalid = || Error::InvalidImage(path.to_str().unwrap().to_string());
+
+            let wand = MagickWand::new();
+            wand.read_image(&format!("GIF:{}", path.to_str().unwrap()))
+                .map_err(|_| invalid())?;
+
+            let coalesced = unsafe { bindings::MagickCoalesceImages(wand.wand) };
+            if coalesced.is_null() {
+                return Err(invalid());
+            }
+            let coalesced = MagickWand { wand: coalesced };
+
+            let count = unsafe { bindings::MagickGetNumberImages(coalesced.wand) };
+            let mut frames = Vec::with_capacity(count);
+            for idx in 0..count {
+                let frame = unsafe {
+                    bindings::MagickSetIteratorIndex(coalesced.wand, idx as isize);
+                    bindings::MagickGetImage(coalesced.wand)
+                };
+                if frame.is_null() {
+                    return Err(invalid());
+                }
+
+                let frame = MagickWand { wand: frame };
+                let delay = super::frame_delay(frame.get_image_delay());
+                frames.push((Image(frame), delay));
+            }
+
+            Ok(frames)
+        }
+
+        /// Image from RGBA pixels, row by row
+        pub fn from_rgba(width: usize, height: usize, pixels: &[u8]) -> Result<Image> {
+            // wrapped in a PAM header, which ImageMagick reads without any delegate
+            let mut blob = format!("P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n", width, height).into_bytes();
+            blob.extend_from_slice(pixels);
+
+            let wand = MagickWand::new();
+            wand.read_image_blob(&blob)
+                .map_err(|_| Error::InvalidImage("pixels".to_string()))?;
+
+            Ok(Image(wand))
+        }
+
+        pub fn width(&self) -> usize {
+            self.0.get_image_width()
+        }
+
+        pub fn height(&self) -> usize {
+            self.0.get_image_height()
+        }
+
+        /// Whether the image was rasterized from vector graphics
+        pub fn is_vector(&self) -> bool {
+            self.0.get_image_format().map_or(false, |x| x == "SVG")
+        }
+
+        /// Scale the image to the given height, keeping the aspect ratio
+        pub fn fit(&mut self, height: usize) {
+            self.0.fit(100000, height);
+        }
+
+        /// Keep the region of `width` and `height` pixels starting at `x` and `y`
+        pub fn crop(&mut self, x: usize, y: usize, width: usize, height: usize) -> Result<()> {
+            self.0.crop_image(width, height, x as isize, y as isize)
+                .map_err(|_| Error::InvalidImage("crop".to_string()))
+        }
+
+        /// Stamp debug text in the upper right corner of the image
+        pub fn stamp(&mut self, text: &str) {
+            let mut foreground = PixelWand::new();
+            let mut background = PixelWand::new();
+            foreground.set_color("#ff0000").unwrap();
+            background.set_color("#ffffffc0").unwrap();
+
+            let mut drawing = DrawingWand::new();
+            drawing.set_fill_color(&foreground);
+            drawing.set_text_under_color(&background);
+            drawing.set_font_size(12.0);
+            drawing.set_gravity(bindings::GravityType_NorthEastGravity);
+
+            // ignore failures, the overlay is only for debugging
+            let _ = self.0.annotate_image(&drawing, 2.0, 2.0, 0.0, text);
+        }
+
+        /// Pixels as RGBA bytes, row by row
+        pub fn rgba(&self) -> Option<Vec<u8>> {
+            self.0.export_image_pixels(0, 0, self.width(), self.height(), "RGBA")
+        }
+
+        pub fn png(&self) -> Vec<u8> {
+            self.0.write_image_blob("png").unwrap()
+        }
+
+        /// SIXEL encoding of ImageMagick, used if the pixels can't be exported
+        pub fn sixel(&self) -> Option<Vec<u8>> {
+            self.0.write_image_blob("sixel").ok()
+        }
+    }
+}
+
+#[cfg(not(feature = "magick"))]
+mod imp {
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use image::{imageops, AnimationDecoder, DynamicImage, ImageFormat, ImageOutputFormat, RgbaImage};
+    use image::codecs::gif::GifDecoder;
+    use resvg::{tiny_skia, usvg::{self, TreeParsing}};
+
+    use crate::error::{Error, Result};
+
+    /// Density of SVG user units
+    const SVG_DENSITY: f32 = 96.0;
+
+    #[derive(Clone)]
+    pub struct Image {
+        pixels: RgbaImage,
+        vector: bool,
+    }
+
+    /// ImageMagick is not linked in static builds
+    pub fn version() -> String {
+        "none".to_string()
+    }
+
+    pub fn delegates() -> Option<String> {
+        None
+    }
+
+    /// Rasterize a SVG file at the given density
+    fn read_svg(path: &Path, density: usize) -> Result<RgbaImage> {
+        let invalid = || Error::InvalidImage(path.to_str().unwrap().to_string());
+
+        let data = std::fs::read(path).map_err(Error::Io)?;
+        let tree = usvg::Tree::from_data(&data, &usvg::Options::default()).map_err(|_| invalid())?;
+        let tree = resvg::Tree::from_usvg(&tree);
+
+        let scale = density as f32 / SVG_DENSITY;
+        let size = tree.size.to_int_size().scale_by(scale).ok_or_else(invalid)?;
+        let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height()).ok_or_else(invalid)?;
+        tree.render(tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+        // the pixmap stores premultiplied colors
+        let pixels = pixmap.pixels().iter()
+            .flat_map(|x| {
+                let x = x.demultiply();
+                [x.red(), x.green(), x.blue(), x.alpha()]
+            })
+            .collect();
+
+        RgbaImage::from_raw(size.width(), size.height(), pixels).ok_or_else(invalid)
+    }
+
+    impl Image {
+        /// Read a file with the format named like the ImageMagick decoder, vector graphics are
+        /// rasterized with the given density
+        pub fn read(format: &str, path: &Path, density: usize) -> Result<Image> {
+            if format == "SVG" {
+                return Ok(Image { pixels: read_svg(path, density)?, vector: true });
+            }
+
+            let format = match format {
+                "PNG" => ImageFormat::Png,
+                "JPEG" => ImageFormat::Jpeg,
+                "GIF" => ImageFormat::Gif,
+                "BMP" => ImageFormat::Bmp,
+                "TIFF" => ImageFormat::Tiff,
+                "WEBP" => ImageFormat::WebP,
+                _ => return Err(Error::MissingFeature("magick")),
+            };
+
+            let data = std::fs::read(path).map_err(Error::Io)?;
+            let pixels = image::load_from_memory_with_format(&data, format)
+                .map_err(|_| Error::InvalidImage(path.to_str().unwrap().to_string()))?
+                .into_rgba8();
+
+            Ok(Image { pixels, vector: false })
+        }
+
+        /// Read all frames of an animated GIF with the time each frame is shown
+        pub fn read_frames(path: &Path) -> Result<Vec<(Image, Duration)>> {
+            let invalid = |_| Error::InvalidImage(path.to_str().unwrap().to_string());
+
+            let data = std::fs::read(path).map_err(Error::Io)?;
+            let frames = GifDecoder::new(Cursor::new(data)).map_err(invalid)?
+                .into_frames()
+                .collect_frames()
+                .map_err(invalid)?;
+
+            Ok(frames.into_iter()
+                .map(|frame| {
+                    let (numer, denom) = frame.delay().numer_denom_ms();
+                    let delay = super::frame_delay((numer / denom.max(1)) as usize / 10);
+
+                    (Image { pixels: frame.into_buffer(), vector: false }, delay)
+                })
+                .collect())
+        }
+
+        /// Image from RGBA pixels, row by row
+        pub fn from_rgba(width: usize, height: usize, pixels: &[u8]) -> Result<Image> {
+            let pixels = RgbaImage::from_raw(width as u32, height as u32, pixels.to_vec())
+                .ok_or_else(|| Error::InvalidImage("pixels".to_string()))?;
+
+            Ok(Image { pixels, vector: false })
+        }
+
+        pub fn width(&self) -> usize {
+            self.pixels.width() as usize
+        }
+
+        pub fn height(&self) -> usize {
+            self.pixels.height() as usize
+        }
+
+        /// Whether the image was rasterized from vector graphics
+        pub fn is_vector(&self) -> bool {
+            self.vector
+        }
+
+        /// Scale the image to the given height, keeping the aspect ratio
+        pub fn fit(&mut self, height: usize) {
+            let width = self.width() * height / self.height().max(1);
+            self.pixels = imageops::resize(&self.pixels, width.max(1) as u32, height.max(1) as u32, imageops::FilterType::Lanczos3);
+        }
+
+        /// Keep the region of `width` and `height` pixels starting at `x` and `y`
+        pub fn crop(&mut self, x: usize, y: usize, width: usize, height: usize) -> Result<()> {
+            self.pixels = imageops::crop_imm(&self.pixels, x as u32, y as u32, width as u32, height as u32).to_image();
+
+            Ok(())
+        }
+
+        /// There is no text rendering without ImageMagick, the overlay is left out
+        pub fn stamp(&mut self, _text: &str) {}
+
+        /// Pixels as RGBA bytes, row by row
+        pub fn rgba(&self) -> Option<Vec<u8>> {
+            Some(self.pixels.as_raw().clone())
+        }
+
+        pub fn png(&self) -> Vec<u8> {
+            let mut buf = Vec::new();
+            DynamicImage::ImageRgba8(self.pixels.clone())
+                .write_to(&mut Cursor::new(&mut buf), ImageOutputFormat::Png)
+                .unwrap();
+
+            buf
+        }
+
+        /// Pixels can always be exported, so the internal encoder is used
+        pub fn sixel(&self) -> Option<Vec<u8>> {
+            None
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Renderers of all buffers
+//!
+//! Every exported call starts with the buffer number followed by a newline, the rest is passed on
+//! to the renderer of that buffer. Renderers are created on first use and configured with the
+//! last configuration.
+//!
+//! The configuration of vim is merged with the configuration files, which are checked for
+//! changes at most once per second and reloaded without restarting vim.
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+use miniserde::{json, Serialize};
+
+use crate::backend;
+use crate::cache;
+use crate::config::{self, Config};
+use crate::content::ContentType;
+use crate::error::Result;
+use crate::quality::QualityStats;
+use crate::raster;
+use crate::render::Render;
+
+pub type BufferId = usize;
+
+/// Interval in which the configuration files are checked for changes
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the library was built, for bug reports
+#[derive(Debug, Serialize)]
+struct BuildInfo {
+    version: &'static str,
+    git_hash: &'static str,
+    features: Vec<&'static str>,
+    backends: Vec<&'static str>,
+    content_types: Vec<&'static str>,
+    imagemagick: String,
+}
+
+/// Cached artifacts and the quality images of the buffer are drawn with
+#[derive(Debug, Serialize)]
+struct Stats {
+    artifacts: Vec<cache::CacheGroup>,
+    /// Quality loop of the buffer, `null` if it isn't previewed
+    quality: Option<QualityStats>,
+    /// Why the last draw of the buffer couldn't write to the cache
+    store_error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    renders: HashMap<BufferId, Render>,
+    /// Configuration passed by vim
+    vim_config: Option<String>,
+    /// Configuration of vim merged with the configuration files, `None` if nothing is configured
+    config: Option<Config>,
+    /// Configuration files with their modification time when they were read
+    watched: Vec<(PathBuf, Option<SystemTime>)>,
+    last_check: Option<Instant>,
+    /// Whether the terminal lost focus, e.g. after switching tmux windows
+    unfocused: bool,
+    /// Number of active suspends
+    suspended: usize,
+    /// Whether a prompt or the command-line window takes the screen, images would be drawn over it
+    ui_busy: bool,
+    /// Buffers not shown in any window of the current tab
+    hidden: HashSet<BufferId>,
+    /// Ueberzugpp process shared by all buffers
+    layer: backend::Layer,
+}
+
+/// Cargo features the library was compiled with
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "magick") {
+        features.push("magick");
+    }
+    if cfg!(feature = "static") {
+        features.push("static");
+    }
+    if cfg!(feature = "snap") {
+        features.push("snap");
+    }
+    if cfg!(feature = "cli") {
+        features.push("cli");
+    }
+
+    features
+}
+
+/// Split the buffer number from the input, without a number buffer zero is addressed
+fn split_buffer(input: &str) -> (BufferId, &str) {
+    let (id, rest) = input.split_once('\n').unwrap_or((input, ""));
+
+    match id.trim().parse() {
+        Ok(id) => (id, rest),
+        Err(_) => (0, input),
+    }
+}
+
+impl Registry {
+    /// Renderer of the buffer in the input and the remaining input
+    pub fn render<'a>(&mut self, input: &'a str) -> Result<(&mut Render, &'a str)> {
+        let (id, input) = split_buffer(input);
+        self.check_config_files()?;
+
+        if !self.renders.contains_key(&id) {
+            let mut render = Render::new();
+            render.share_layer(self.layer.clone());
+            if let Some(config) = &self.config {
+                render.set_config(config.clone())?;
+            }
+            render.set_visible(self.is_visible(id))?;
+
+            self.renders.insert(id, render);
+        }
+
+        Ok((self.renders.get_mut(&id).unwrap(), input))
+    }
+
+    /// Configuration is shared by all buffers
+    pub fn update_config(&mut self, input: &str) -> Result<bool> {
+        let (_, config) = split_buffer(input);
+        Config::parse(config)?;
+        self.vim_config = Some(config.to_string());
+        self.reload_config("")?;
+
+        // make sure that the configuration is checked by at least one renderer
+        self.render(input)?;
+
+        Ok(true)
+    }
+
+    /// Read the configuration files again and apply the merged configuration to all renderers
+    pub fn reload_config(&mut self, _: &str) -> Result<bool> {
+        // remember the files before parsing, so that a broken file is only reported once
+        let files = config::files();
+        self.watched = files.iter().map(|x| (x.clone(), config::modified(x))).collect();
+        self.last_check = Some(Instant::now());
+
+        let vim_config = self.vim_config.as_deref().map(Config::parse).transpose()?;
+        self.config = config::merge_files(vim_config, &files)?;
+
+        if let Some(config) = &self.config {
+            for render in self.renders.values_mut() {
+                render.set_config(config.clone())?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Reload the configuration if a file was changed, created or removed
+    fn check_config_files(&mut self) -> Result<()> {
+        if self.last_check.map_or(false, |x| x.elapsed() < CONFIG_CHECK_INTERVAL) {
+            return Ok(());
+        }
+        self.last_check = Some(Instant::now());
+
+        let files = config::files();
+        let changed = files.len() != self.watched.len()
+            || files.iter().zip(&self.watched).any(|(path, (watched, time))| path != watched || config::modified(path) != *time);
+
+        if changed {
+            self.reload_config("")?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether vim is in the foreground, not suspended and not showing a prompt
+    fn is_active(&self) -> bool {
+        !self.unfocused && self.suspended == 0 && !self.ui_busy
+    }
+
+    fn is_visible(&self, id: BufferId) -> bool {
+        self.is_active() && !self.hidden.contains(&id)
+    }
+
+    fn update_visible(&mut self) -> Result<()> {
+        let active = self.is_active();
+        for (id, render) in self.renders.iter_mut() {
+            render.set_visible(active && !self.hidden.contains(id))?;
+        }
+
+        Ok(())
+    }
+
+    /// Set whether vim is in the foreground, nothing is drawn without focus
+    pub fn set_focus(&mut self, input: &str) -> Result<bool> {
+        let (_, focus) = split_buffer(input);
+        self.unfocused = focus.trim() == "0";
+        self.update_visible()?;
+
+        Ok(self.is_active())
+    }
+
+    /// Set whether the UI is `busy` with the command-line window, a more or a hit-enter prompt
+    ///
+    /// Nothing is drawn until the state is `idle` again, then all images are drawn anew.
+    pub fn set_ui_state(&mut self, input: &str) -> Result<bool> {
+        let (_, state) = split_buffer(input);
+        self.ui_busy = state.trim() == "busy";
+        self.update_visible()?;
+
+        Ok(self.is_active())
+    }
+
+    /// Suppress drawing until the matching `resume`
+    pub fn suspend(&mut self, _: &str) -> Result<bool> {
+        self.suspended += 1;
+        self.update_visible()?;
+
+        Ok(self.is_active())
+    }
+
+    pub fn resume(&mut self, _: &str) -> Result<bool> {
+        self.suspended = self.suspended.saturating_sub(1);
+        self.update_visible()?;
+
+        Ok(self.is_active())
+    }
+
+    /// Remove the images of a buffer from the screen, e.g. when switching to another tab
+    ///
+    /// Nothing is drawn for the buffer until `buffer_shown`.
+    pub fn buffer_hidden(&mut self, input: &str) -> Result<bool> {
+        let (id, _) = split_buffer(input);
+        self.hidden.insert(id);
+
+        if let Some(render) = self.renders.get_mut(&id) {
+            render.clear_all("")?;
+        }
+        self.update_visible()?;
+
+        Ok(false)
+    }
+
+    /// Draw a hidden buffer again, all of its nodes are placed anew on the next draw
+    pub fn buffer_shown(&mut self, input: &str) -> Result<bool> {
+        let (id, _) = split_buffer(input);
+        self.hidden.remove(&id);
+        self.update_visible()?;
+
+        Ok(self.is_visible(id))
+    }
+
+    /// Version, git revision, enabled features and the linked ImageMagick version
+    pub fn build_info(&mut self, _: &str) -> Result<String> {
+        let info = BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: env!("GIT_HASH"),
+            features: features(),
+            backends: backend::NAMES.to_vec(),
+            content_types: ContentType::compiled(),
+            imagemagick: raster::version(),
+        };
+
+        Ok(json::to_string(&info))
+    }
+
+    /// Artifacts in the cache, grouped by document and content type, and the quality level and
+    /// measured throughput of the terminal for the buffer
+    pub fn cache_stats(&mut self, input: &str) -> Result<String> {
+        let (id, _) = split_buffer(input);
+        let stats = Stats {
+            artifacts: cache::stats()?,
+            quality: self.renders.get(&id).map(|x| x.quality_stats()),
+            store_error: self.renders.get(&id).and_then(|x| x.store_error()),
+        };
+
+        Ok(json::to_string(&stats))
+    }
+
+    /// Remove artifacts from the cache, selected by a JSON filter
+    pub fn purge_cache(&mut self, input: &str) -> Result<String> {
+        let (_, filter) = split_buffer(input);
+
+        cache::purge(filter)
+    }
+
+    /// Forget the renderer of a buffer, for example after the buffer was deleted
+    pub fn remove_buffer(&mut self, input: &str) -> Result<()> {
+        let (id, _) = split_buffer(input);
+        self.hidden.remove(&id);
+        if let Some(mut render) = self.renders.remove(&id) {
+            render.clear_all("")?;
+        }
+
+        Ok(())
+    }
+}
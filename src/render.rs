@@ -1,29 +1,80 @@
 use std::io::{Write, Stdout};
 use std::collections::BTreeMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::os::unix::io::FromRawFd;
 use std::mem;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use miniserde::{json, Serialize, Deserialize};
 
-use crate::error::Result;
-use crate::utils;
+use crate::error::{Error, Result};
+use crate::utils::{self, LatexEngine};
 use crate::node_view::NodeView;
-use crate::content::{Content, Node, NodeDim};
+use crate::content::{Align, Content, ContentType, Crop, Node, NodeDim, Encoding, Syntax};
+use crate::manifest::{Manifest, Origin};
+use crate::config::Config;
+use crate::filter::Filters;
+use crate::backend;
+use crate::process;
+use crate::history::{self, HISTORY_LEN};
+use crate::mirror;
+use crate::raster::{self, CompareMode};
+use crate::terminal::{self, Capabilities, Theme};
+use crate::cache::{self, Purged};
+use crate::quality::{Adaptive, QualityStats, Written};
 
 pub const ART_PATH: &str = "/tmp/nvim_arts/";
+/// Bytes written to the terminal in a single draw before yielding back to vim
+const DRAW_BUDGET: usize = 512 * 1024;
+/// Time in milliseconds the first draw of a document waits for visible nodes
+const INITIAL_BUDGET: u64 = 300;
+/// Interval in which visible nodes are polled during the first draw
+const INITIAL_POLL: Duration = Duration::from_millis(10);
+/// Factor a node is zoomed by per step, and the smallest and largest zoom
+const ZOOM_STEP: f32 = 1.25;
+const ZOOM_RANGE: (f32, f32) = (0.25, 8.0);
+/// Lines and columns a panned image moves per mouse scroll
+const PAN_STEP: isize = 3;
 
 pub type CodeId = String;
 pub type Folds = Vec<(usize, isize)>;
 
-#[derive(Debug, Deserialize)]
+/// Window showing the buffer, with its visible lines, size and position on the screen
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Window {
+    pub file_range: (u64, u64),
+    pub viewport: (u64, u64),
+    pub winpos: (usize, usize),
+}
+
+/// Colors of the `Normal` highlight of the colorscheme as `#rrggbb`, missing for terminal colors
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Colors {
+    pub foreground: Option<String>,
+    pub background: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Metadata {
     pub file_range: (u64, u64),
     pub viewport: (u64, u64),
     pub cursor: u64,
     pub winpos: (usize, usize),
     pub char_height: usize,
+    pub char_width: usize,
+    /// Offset of the tmux pane in the terminal, if running inside tmux
+    pub tmux_pane: Option<(usize, usize)>,
+    /// All windows showing the buffer, if missing only the current window is drawn
+    pub windows: Option<Vec<Window>>,
+    /// Path of the file in the buffer
+    pub file: Option<String>,
+    /// Filetype of the buffer, e.g. `org.graphics`, which selects the markup language
+    pub filetype: Option<String>,
+    /// Colors of the colorscheme, equations are written with them
+    pub colors: Option<Colors>,
 }
 
 impl Metadata {
@@ -34,8 +85,35 @@ impl Metadata {
             cursor: 1,
             winpos: (1, 1),
             char_height: 0,
+            char_width: 0,
+            tmux_pane: None,
+            windows: None,
+            file: None,
+            filetype: None,
+            colors: None,
         }
     }
+
+    /// Metadata as seen from each window showing the buffer
+    pub fn per_window(&self) -> Vec<Metadata> {
+        match &self.windows {
+            Some(windows) if !windows.is_empty() => windows.iter()
+                .map(|window| Metadata {
+                    file_range: window.file_range,
+                    viewport: window.viewport,
+                    winpos: window.winpos,
+                    windows: None,
+                    ..self.clone()
+                })
+                .collect(),
+            _ => vec![self.clone()],
+        }
+    }
+
+    /// Screen positions of all windows showing the buffer
+    pub fn positions(&self) -> Vec<(usize, usize)> {
+        self.per_window().iter().map(|x| x.winpos).collect()
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -76,6 +154,266 @@ impl FoldInner {
 pub struct RedrawState {
     should_redraw: bool,
     update_folding: Option<Vec<usize>>,
+    placeholders: Vec<Placeholder>,
+    changes: Changes,
+    overlaps: Vec<Overlap>,
+}
+
+/// Number of lines a node requires, known before rendering
+#[derive(Debug, Serialize)]
+pub struct Placeholder {
+    line: usize,
+    lines: usize,
+}
+
+/// Replacement of the lines `first` until `last` (exclusive) with `lines`
+#[derive(Debug, Deserialize)]
+pub struct LinesUpdate {
+    first: usize,
+    last: usize,
+    lines: Vec<String>,
+}
+
+/// New value of a fence parameter, either given or moved by a number of steps
+#[derive(Debug, Deserialize)]
+pub struct ParamUpdate {
+    line: usize,
+    name: String,
+    value: Option<f64>,
+    steps: Option<i64>,
+}
+
+/// Value a parameter was set to, clamped to its range, and the state after parsing again
+#[derive(Debug, Serialize)]
+pub struct ParamChange {
+    name: String,
+    value: f64,
+    state: RedrawState,
+}
+
+/// Version of the figure of a named fence to show, zero is the newest
+#[derive(Debug, Deserialize)]
+pub struct VersionRequest {
+    line: usize,
+    index: usize,
+}
+
+/// Two nodes to compare and how their figures are combined, `blend` or `side` by default
+#[derive(Debug, Deserialize)]
+pub struct CompareRequest {
+    line_a: usize,
+    line_b: usize,
+    mode: Option<String>,
+}
+
+/// Nodes to compare with the lines they were requested at, composed once both are generated
+struct Comparing {
+    id_a: CodeId,
+    id_b: CodeId,
+    line_a: usize,
+    line_b: usize,
+    mode: CompareMode,
+}
+
+/// Action on all nodes of the section containing `line`, one of `render`, `disable`, `enable`,
+/// `export` or `purge`
+#[derive(Debug, Deserialize)]
+pub struct SectionRequest {
+    line: usize,
+    action: String,
+}
+
+/// Lines of a section and the nodes an action applied to
+#[derive(Debug, Serialize)]
+pub struct SectionResult {
+    first: usize,
+    last: usize,
+    nodes: usize,
+    /// Manifest of the nodes of the section, for `export`
+    manifest: Option<DocumentManifest>,
+    /// Files removed from the cache, for `purge`
+    purged: Option<Purged>,
+}
+
+/// Distance in columns and lines the image of the node at `line` is panned by
+#[derive(Debug, Deserialize)]
+pub struct PanRequest {
+    line: usize,
+    dx: isize,
+    dy: isize,
+}
+
+/// Node identified by its id and first line
+#[derive(Debug, Serialize)]
+pub struct NodeRef {
+    pub id: CodeId,
+    pub line: usize,
+}
+
+/// Nodes touched by a content update
+///
+/// Nodes which only moved are `changed`, nodes with new content have a new id and appear in
+/// `appeared`, while their previous version is listed in `disappeared` with its old line.
+#[derive(Debug, Default, Serialize)]
+pub struct Changes {
+    pub changed: Vec<NodeRef>,
+    pub appeared: Vec<NodeRef>,
+    pub disappeared: Vec<NodeRef>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.appeared.is_empty() && self.disappeared.is_empty()
+    }
+}
+
+/// Node reaching into the following node, it is clamped to end at the next node
+#[derive(Debug, Serialize)]
+pub struct Overlap {
+    pub line: usize,
+    pub end: usize,
+    pub next: usize,
+}
+
+/// Node which took longer than the threshold to generate or encode
+#[derive(Debug, Serialize)]
+pub struct SlowNode {
+    id: CodeId,
+    line: usize,
+    kind: &'static str,
+    generate_ms: Option<u64>,
+    encode_ms: Option<u64>,
+}
+
+/// Node waiting for a free renderer slot, nodes of other buffers have no line
+#[derive(Debug, Serialize)]
+pub struct QueuedNode {
+    id: CodeId,
+    line: Option<usize>,
+    kind: String,
+    priority: usize,
+    waiting_ms: u64,
+}
+
+/// Screen region covered by the image of a node, rows and columns start at one
+#[derive(Debug, Clone, Serialize)]
+pub struct Painted {
+    row: usize,
+    rows: usize,
+    column: usize,
+    columns: usize,
+    id: CodeId,
+    /// First line of the node in the buffer
+    line: usize,
+    kind: &'static str,
+    /// Path of the displayed file for images of files
+    file: Option<String>,
+    /// Whether only a part of the image is visible, because it crosses the window border
+    cropped: bool,
+}
+
+/// Action taken in response to a mouse event on an image
+#[derive(Debug, Serialize)]
+pub struct MouseAction {
+    /// One of `none`, `zoom`, `unzoom`, `open`, `pan` or `pan_image`
+    action: &'static str,
+    /// First and last line of the node under the mouse
+    line: Option<usize>,
+    last: Option<usize>,
+    /// Image to open in an external viewer
+    path: Option<String>,
+}
+
+/// Time until the next frame of an animation is due, zero if nothing is animated
+#[derive(Debug, Serialize)]
+pub struct AnimationStep {
+    delay: u64,
+}
+
+/// Result of searching missing binaries again
+#[derive(Debug, Serialize)]
+pub struct BinaryRecheck {
+    found: Vec<String>,
+    missing: Vec<String>,
+    /// Number of nodes generated again
+    retried: usize,
+}
+
+/// Progress of a draw, nodes left out are drawn by `continue_draw`
+#[derive(Debug, Serialize)]
+pub struct DrawProgress {
+    /// Nodes still generated or encoded in the background
+    pending: usize,
+    /// Nodes not drawn because the write budget was used up
+    remaining: usize,
+    /// Suggested delay in milliseconds before the next call
+    delay: u64,
+    /// Nodes in view which are not shown, since the draw started
+    skipped: Vec<Skipped>,
+}
+
+/// Node in view which is not shown
+#[derive(Debug, Clone, Serialize)]
+pub struct Skipped {
+    id: CodeId,
+    line: usize,
+    kind: &'static str,
+    /// One of `occluded`, `too_large`, `error` or `unsupported`
+    reason: &'static str,
+    /// Error message for the reason `error`
+    error: Option<String>,
+}
+
+/// State of a node and a text to show instead of the image if it can't be displayed or is written
+/// as Unicode text
+#[derive(Debug, Serialize)]
+pub struct NodeStatus {
+    id: CodeId,
+    line: usize,
+    kind: &'static str,
+    /// One of `pending`, `ready`, `failed` or `disabled`
+    state: &'static str,
+    fallback: Option<String>,
+    /// Lines the image needs to fit into the window at its natural size, a `height=` for fences,
+    /// known once it was rendered
+    suggested_height: Option<usize>,
+}
+
+/// Nodes of a document with their artifacts, for tools reusing the rendered figures
+#[derive(Debug, Serialize)]
+pub struct DocumentManifest {
+    document: Option<String>,
+    /// Version and git revision of the plugin, which renders some content in process
+    version: String,
+    nodes: Vec<ManifestNode>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestNode {
+    line: usize,
+    kind: &'static str,
+    /// Hash of the rendered content
+    id: CodeId,
+    /// Generated SVG or the file of an image link, if it exists
+    path: Option<String>,
+    /// One of `pending`, `ready` or `failed`
+    state: &'static str,
+    renderers: Vec<Renderer>,
+}
+
+/// Binary rendering a node and the version it reports
+#[derive(Debug, Serialize)]
+pub struct Renderer {
+    binary: String,
+    version: Option<String>,
+}
+
+/// Mismatch between the reserved lines of a node and the lines required by its image
+#[derive(Debug, Serialize)]
+pub struct Mismatch {
+    line: usize,
+    reserved: usize,
+    required: usize,
 }
 
 pub struct Render {
@@ -84,6 +422,54 @@ pub struct Render {
     strcts: BTreeMap<usize, FoldInner>,
     metadata: Metadata,
     content: Content,
+    encoding: Encoding,
+    /// Ueberzugpp process shared with the other buffers
+    layer: backend::Layer,
+    manifest: Manifest,
+    pinned: Option<CodeId>,
+    config: Config,
+    capabilities: Option<Capabilities>,
+    visible: bool,
+    /// Lines of the buffer, patched by `update_lines`
+    lines: Vec<String>,
+    /// Window index and line of the node where an interrupted draw continues
+    resume: Option<(usize, usize)>,
+    /// Regions covered by images since the last full draw
+    painted: Vec<Painted>,
+    /// Node drawn over the whole window after it was clicked
+    zoomed: Option<CodeId>,
+    /// Nodes not shown since the last full draw
+    skipped: Vec<Skipped>,
+    /// Whether the first frame of the document was drawn
+    opened: bool,
+    /// Previous figure of a named fence shown in the upper right corner
+    version: Option<Node>,
+    /// Artifact last copied to the history per fence name
+    recorded: BTreeMap<String, String>,
+    /// Composite of two figures shown in the upper right corner
+    comparison: Option<Node>,
+    comparing: Option<Comparing>,
+    /// Quality of images stepped with the throughput of the terminal
+    quality: Adaptive,
+    /// Writes of the nodes in the current draw
+    written: Written,
+    /// Why the configured mirror couldn't be opened, drawing goes on without it
+    mirror_error: Option<String>,
+    /// Why the manifest or the history couldn't be written by the last draw, drawing goes on
+    /// without them
+    store_error: Option<String>,
+}
+
+/// Outcome of generating a node outside of vim
+pub struct Generated {
+    pub line: usize,
+    pub kind: &'static str,
+    pub id: CodeId,
+    /// Generated artifact, files of the document have none
+    pub path: Option<PathBuf>,
+    /// Image of nodes drawn in memory, which have no artifact
+    pub png: Option<Vec<u8>>,
+    pub error: Option<String>,
 }
 
 impl Render {
@@ -92,31 +478,251 @@ impl Render {
             std::fs::create_dir(ART_PATH).unwrap();
         }
 
+        // has to run before the manifest is loaded, which is keyed by artifact ids
+        let _ = utils::migrate_cache();
+
         Render {
             stdout: std::io::stdout(),
             blocks: BTreeMap::new(),
             strcts: BTreeMap::new(),
             metadata: Metadata::new(),
             content: Content::new(),
+            encoding: Encoding {
+                backend: Arc::new(backend::Sixel::default()),
+                overlay: false,
+                inline: false,
+            },
+            layer: backend::Layer::default(),
+            manifest: Manifest::load(),
+            pinned: None,
+            config: Default::default(),
+            capabilities: None,
+            visible: true,
+            lines: Vec::new(),
+            resume: None,
+            painted: Vec::new(),
+            zoomed: None,
+            skipped: Vec::new(),
+            opened: false,
+            version: None,
+            recorded: BTreeMap::new(),
+            comparison: None,
+            comparing: None,
+            quality: Adaptive::default(),
+            written: Written::default(),
+            mirror_error: None,
+            store_error: None,
+        }
+    }
+
+    pub fn draw(&mut self, _: &str) -> Result<String> {
+        self.resume = None;
+        self.skipped.clear();
+        // regions of nodes which were removed by an update are gone as well
+        let blocks = &self.blocks;
+        self.painted.retain(|x| blocks.contains_key(&x.id));
+
+        // the first frame of a document waits a moment for visible nodes, instead of being blank
+        // until the background threads finished
+        if !self.opened && !self.blocks.is_empty() {
+            self.opened = true;
+            self.generate_visible();
+
+            self.encoding.inline = true;
+            let res = self.draw_from();
+            self.encoding.inline = false;
+
+            return res;
+        }
+
+        self.draw_from()
+    }
+
+    /// Generate the nodes visible in any window, waiting at most for the initial budget
+    ///
+    /// Nodes not finished in time continue in the background like on any other draw.
+    fn generate_visible(&self) {
+        let budget = Duration::from_millis(self.config.initial_budget.unwrap_or(INITIAL_BUDGET));
+        if budget.is_zero() {
+            return;
+        }
+        let deadline = Instant::now() + budget;
+
+        let windows = self.metadata.per_window();
+        let visible = self.blocks.values()
+            .filter(|node| node.text.is_none() && !node.disabled)
+            .filter(|node| windows.iter().any(|metadata|
+                node.range.1 as u64 >= metadata.file_range.0 && node.range.0 as u64 <= metadata.file_range.1))
+            .collect::<Vec<_>>();
+
+        for node in &visible {
+            node.start();
+        }
+        while Instant::now() < deadline && visible.iter().any(|node| !node.is_generated()) {
+            thread::sleep(INITIAL_POLL);
+        }
+    }
+
+    /// Remember sizes of finished nodes for the next session and where artifacts belong to
+    ///
+    /// New figures of named fences are copied to their history. A failed copy doesn't keep the
+    /// other figures or the manifest from being stored, its error is returned afterwards.
+    fn store_manifest(&mut self) -> Result<()> {
+        let document = self.metadata.file.clone().unwrap_or_default();
+        let keep = self.config.history.unwrap_or(HISTORY_LEN);
+        let mut error = None;
+        for node in self.blocks.values() {
+            if let Some(size) = node.natural_size() {
+                self.manifest.insert(&node.id, size);
+
+                if let Some(artifact) = node.artifact() {
+                    self.manifest.insert_origin(&artifact, Origin { kind: node.kind().to_string(), document: document.clone() });
+
+                    // a failed copy is tried again when the figure changed, not with every draw
+                    let path = node.image_path().filter(|x| x.exists());
+                    if let (Some(name), Some(path)) = (&node.name, path) {
+                        if self.recorded.get(name) != Some(&artifact) {
+                            self.recorded.insert(name.clone(), artifact);
+                            if let Err(err) = history::record(&document, name, &path, keep) {
+                                error.get_or_insert(err);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let stored = self.manifest.store();
+        error.map_or(stored, Err)
+    }
+
+    /// Open a document outside of vim, the configuration is applied before it is parsed
+    ///
+    /// Documents ending with `.org` are parsed as org-mode.
+    pub fn open_document(&mut self, file: &str, content: &str, config: Config) -> Result<()> {
+        self.metadata.file = Some(file.to_string());
+        self.metadata.filetype = Path::new(file).extension()
+            .map(|x| x.to_string_lossy().to_lowercase());
+        self.update_syntax();
+        self.set_config(config)?;
+        self.update_content(content)?;
+
+        Ok(())
+    }
+
+    /// Generate the images of all nodes and wait until they are finished
+    ///
+    /// Each node is generated on its own thread, how many run at once is limited by the
+    /// concurrency of its content type like while drawing. The nodes are ordered by line.
+    pub fn generate_all(&mut self) -> Result<Vec<Generated>> {
+        thread::scope(|scope| {
+            for node in self.blocks.values() {
+                scope.spawn(move || node.generate());
+            }
+        });
+        self.store_manifest()?;
+
+        let mut generated = self.blocks.values()
+            .map(|node| Generated {
+                line: node.range.0,
+                kind: node.kind(),
+                id: node.id.clone(),
+                path: node.artifact().and(node.image_path()),
+                png: if node.image_path().is_none() { node.png() } else { None },
+                error: node.error(),
+            })
+            .collect::<Vec<_>>();
+        generated.sort_by_key(|x| x.line);
+
+        Ok(generated)
+    }
+
+    /// Continue a draw which was interrupted because the write budget was used up
+    pub fn continue_draw(&mut self, _: &str) -> Result<String> {
+        if self.resume.is_none() {
+            return Ok(json::to_string(&DrawProgress { pending: 0, remaining: 0, delay: 0, skipped: self.skipped.clone() }));
+        }
+
+        self.draw_from()
+    }
+
+    /// Draw all windows, starting at the node where the last draw stopped
+    fn draw_from(&mut self) -> Result<String> {
+        // nothing is written while another client is in the foreground
+        if !self.visible {
+            return Ok(json::to_string(&DrawProgress { pending: 0, remaining: 0, delay: 0, skipped: Vec::new() }));
+        }
+
+        let (start_window, start_line) = self.resume.take().unwrap_or((0, 0));
+        let mut budget = self.config.draw_budget.unwrap_or(DRAW_BUDGET);
+        self.written = Written::default();
+
+        // node views are shared between windows, so each window draws all of its nodes
+        let (mut pending, mut remaining) = (0, 0);
+        for (idx, metadata) in self.metadata.per_window().iter().enumerate().skip(start_window) {
+            let from = if idx == start_window { start_line } else { 0 };
+            let (window_pending, stop) = self.draw_nodes(metadata, from, &mut budget)?;
+            pending += window_pending;
+
+            if let Some((line, left)) = stop {
+                self.resume = Some((idx, line));
+                remaining = left;
+                break;
+            }
+        }
+
+        if self.resume.is_none() && self.draw_pinned()? {
+            pending += 1;
+        }
+
+        if self.resume.is_none() && self.draw_version()? {
+            pending += 1;
+        }
+
+        if self.resume.is_none() && self.draw_comparison()? {
+            pending += 1;
         }
+
+        if self.resume.is_none() && self.draw_zoomed()? {
+            pending += 1;
+        }
+
+        // a full or read-only cache folder doesn't keep the images from being drawn
+        self.store_error = self.store_manifest().err().map(|x| x.to_string());
+        self.adapt_quality();
+
+        // continue right away with deferred nodes, but poll background work less often
+        let delay = if remaining == 0 && pending > 0 { 50 } else { 0 };
+
+        Ok(json::to_string(&DrawProgress { pending, remaining, delay, skipped: self.skipped.clone() }))
     }
 
-    pub fn draw(&mut self, _: &str) -> Result<usize> {
-        let mut pending = false;
+    /// Draw the nodes in the viewport of a window, beginning at line `from`
+    ///
+    /// Returns the number of nodes still pending and, if the write budget was used up, the line
+    /// of the next node together with the number of nodes left out.
+    fn draw_nodes(&mut self, metadata: &Metadata, from: usize, budget: &mut usize) -> Result<(usize, Option<(usize, usize)>)> {
+        let mut pending = 0;
+        let mut stop = None;
+
+        let unsupported = self.unsupported();
+        // the zoomed node covers the window it is drawn in
+        let occluded = self.zoomed.clone().filter(|_| metadata.winpos == self.metadata.winpos);
 
         // mutable iterator of items, skipping things outside the viewport
         let mut items = self.strcts.iter_mut()
             .map(|(a, item)| {
-                if !item.is_in_view(&self.metadata, &self.blocks) {
-                    if let FoldInner::Node((_, ref mut view)) = item {
+                if !item.is_in_view(metadata, &self.blocks) {
+                    if let FoldInner::Node((id, ref mut view)) = item {
                         *view = NodeView::Hidden;
+                        self.painted.retain(|x| x.id != *id);
                     }
                 }
 
                 (a, item)
             })
             .filter(|(_, item)| {
-                item.is_in_view(&self.metadata, &self.blocks)
+                item.is_in_view(metadata, &self.blocks)
             })
             .collect::<Vec<_>>();
 
@@ -124,11 +730,11 @@ impl Render {
         let mut iter = items.iter_mut();
         let mut item = match iter.next() {
             Some(x) => x,
-            None => return Ok(0)
+            None => return Ok((0, None))
         };
 
         // initialize last line and top offset, so that first iteration gives offset to first item
-        let mut last_line = self.metadata.file_range.0 as usize;
+        let mut last_line = metadata.file_range.0 as usize;
         let mut top_offset: isize = 0;
     
         // perform fold skipping if folded in
@@ -142,7 +748,54 @@ impl Render {
                     top_offset += node.range.0 as isize - last_line as isize;
                     last_line = node.range.0;
 
-                    pending |= Render::draw_node(&self.metadata, &self.stdout, node, node_view, top_offset)?;
+                    if *item.0 < from {
+                        // already drawn before the last draw was interrupted
+                    } else if *budget == 0 {
+                        let line = *item.0;
+                        let left = iter.by_ref().filter(|(_, x)| matches!(x, FoldInner::Node(_))).count();
+                        stop = Some((line, left + 1));
+                        break 'outer;
+                    } else {
+                        let too_large = Render::image_columns(metadata, node)
+                            .map_or(false, |x| x + node.column.unwrap_or(0) > metadata.viewport.1 as usize);
+
+                        let reason = if !NodeView::new(node, metadata, top_offset).is_visible() {
+                            None
+                        } else if unsupported {
+                            Some("unsupported")
+                        } else if occluded.as_ref().map_or(false, |id| *id != node.id) {
+                            Some("occluded")
+                        } else if too_large {
+                            // would spill into neighbouring windows
+                            Some("too_large")
+                        } else {
+                            None
+                        };
+
+                        let mut written = Written::default();
+                        let res = match reason {
+                            Some(_) => Ok(false),
+                            // written as text by vim or disabled with its section
+                            None if node.text.is_some() || node.disabled => Ok(false),
+                            None => Render::draw_node(metadata, &self.stdout, node, node_view, top_offset, &self.encoding, &mut written, &mut self.painted),
+                        };
+                        *budget = budget.saturating_sub(written.bytes);
+                        self.written.add(written.bytes, written.time);
+
+                        // a failing node doesn't keep the others from being drawn
+                        let (reason, error) = match res {
+                            Ok(true) => { pending += 1; (reason, None) },
+                            Ok(false) => (reason, None),
+                            Err(err) => (Some("error"), Some(err.to_string())),
+                        };
+
+                        if let Some(reason) = reason {
+                            self.painted.retain(|x| x.id != node.id);
+                            if !self.skipped.iter().any(|x| x.id == node.id) {
+                                self.skipped.push(Skipped { id: node.id.clone(), line: node.range.0, kind: node.kind(), reason, error });
+                            }
+                        }
+                    }
                 },
                 FoldInner::Fold(ref fold) => {
                     // offset has a header of single line
@@ -179,156 +832,1522 @@ impl Render {
 
         //dbg!(&pending);
 
-        Ok(if pending { 1 } else { 0 })
+        Ok((pending, stop))
     }
-    pub fn draw_node(metadata: &Metadata, stdout: &Stdout, node: &mut Node, view: &mut NodeView, top_offset: isize) -> Result<bool> {
-        // calculate new view and height of node
-        let new_view = NodeView::new(node,  metadata, top_offset);
-        let char_height = metadata.char_height;
-        let theight = node.range.1 - node.range.0;
 
-        let (pos, crop) = match (&view, &new_view) {
-            (NodeView::UpperBorder(_, _) | NodeView::LowerBorder(_, _) | NodeView::Hidden, NodeView::Visible(pos, _)) =>
-                (*pos, None),
-            (NodeView::Hidden, NodeView::LowerBorder(pos, height)) =>
-                (*pos, Some((height * char_height, 0))),
-            (NodeView::LowerBorder(_, height_old), NodeView::LowerBorder(pos, height)) if height_old < height =>
-                (*pos, Some((height * char_height, 0))),
-            (NodeView::Hidden, NodeView::UpperBorder(y, height)) => 
-                (0, Some((height * char_height, y * char_height))),
-            (NodeView::UpperBorder(y_old, _), NodeView::UpperBorder(y, height)) if y < y_old =>
-                (0, Some((height * char_height, y * char_height))),
-            _ => return Ok(false),
-        };
+    /// Draw the pinned node in the upper right corner, independent of the scroll position
+    fn draw_pinned(&mut self) -> Result<bool> {
+        match self.pinned.as_ref().and_then(|id| self.blocks.get_mut(id)) {
+            Some(node) => Render::draw_in_corner(&self.stdout, &self.metadata, &self.encoding, node),
+            None => Ok(false),
+        }
+    }
 
-        let dim = NodeDim {
-            height: theight * char_height,
-            crop
-        };
+    /// Draw the shown version of a named fence in the upper right corner
+    fn draw_version(&mut self) -> Result<bool> {
+        match &mut self.version {
+            Some(node) => Render::draw_in_corner(&self.stdout, &self.metadata, &self.encoding, node),
+            None => Ok(false),
+        }
+    }
 
-        if let Some(buf) = node.get_sixel(dim) {
-            // bail out if an error happened during conversion
-            let mut buf = buf?;
-
-            //dbg!(&metadata.winpos.0, &metadata.winpos.1);
-            let mut wbuf = format!("\x1b[s\x1b[{};{}H", pos + metadata.winpos.0, metadata.winpos.1).into_bytes();
-            //for _ in 0..(node.range.1-node.range.0 - 1) {
-            //    wbuf.extend_from_slice(b"\x1b[B\x1b[K");
-            //}
-
-            //wbuf.append(&mut format!("\x1b[{};{}H", pos + metadata.winpos.0, metadata.winpos.1).into_bytes());
-            //dbg!(&buf.len());
-            wbuf.append(&mut buf);
-            //wbuf.append(&mut format!("\x1b[{};{}H", metadata.viewport.0, metadata.winpos.1).into_bytes());
-            //wbuf.append(&mut format!("\x1b[?80h\x1bP100;1q\"1;1;2000;50\"1;1;2000;50\x1b[u\x1b\\").into_bytes());
-            //wbuf.extend_from_slice(b"\x1b[u");
-            wbuf.extend_from_slice(b"\x1b[u");
-
-            {
-                let outer_lock = stdout.lock();
-                let mut stdout = unsafe { File::from_raw_fd(1) };
-                let mut idx = 0;
-                while idx < wbuf.len() {
-                    match stdout.write(&wbuf[idx..]) {
-                        Ok(n) => idx += n,
-                        Err(_) => {/*eprintln!("{}", err);*/},
-                    }
-                }
-                std::mem::forget(stdout);
-                drop(outer_lock);
+    /// Draw the composite of two compared figures in the upper right corner
+    ///
+    /// The composite is made by the first draw after both figures are generated, a failure is
+    /// reported as skipped first node.
+    fn draw_comparison(&mut self) -> Result<bool> {
+        if let Some(comparing) = self.comparing.take() {
+            match self.compose(&comparing) {
+                Ok(Some(node)) => self.comparison = Some(node),
+                Ok(None) => {
+                    self.comparing = Some(comparing);
+                    return Ok(true);
+                },
+                Err(err) => self.skipped.push(Skipped {
+                    id: comparing.id_a, line: comparing.line_a, kind: "compare", reason: "error", error: Some(err.to_string()),
+                }),
             }
+        }
 
-            Ok(false)
-        } else {
-            Ok(new_view.is_visible())
+        match &mut self.comparison {
+            Some(node) => Render::draw_in_corner(&self.stdout, &self.metadata, &self.encoding, node),
+            None => Ok(false),
         }
     }
 
-    pub fn clear_all(&mut self, _: &str) -> Result<()> {
-        for fold in self.strcts.values_mut() {
-            if let FoldInner::Node(ref mut node) = fold {
-                node.1 = NodeView::Hidden;
-            }
+    /// Draw a node in the upper right corner with the height of its lines, returns whether it is
+    /// pending
+    fn draw_in_corner(stdout: &Stdout, metadata: &Metadata, encoding: &Encoding, node: &mut Node) -> Result<bool> {
+        let Metadata { char_height, char_width, viewport, winpos, .. } = *metadata;
+        let height = (node.range.1 - node.range.0) * char_height;
+
+        // the column is derived from the image width at the drawn height
+        let columns = match node.natural_size() {
+            Some((w, h)) if h > 0 => (w * height / h + char_width - 1) / char_width.max(1),
+            _ => return Ok(true),
+        };
+        let column = (viewport.1 as usize + 1).saturating_sub(columns).max(1);
+
+        let dim = NodeDim { height, crop: None };
+        Render::write_image_at(stdout, metadata, node, dim, encoding, (winpos.0, column), &mut Written::default())
+    }
+
+    /// Draw the zoomed node over the window, fitted to its height
+    fn draw_zoomed(&mut self) -> Result<bool> {
+        let node = match self.zoomed.as_ref().and_then(|id| self.blocks.get_mut(id)) {
+            Some(node) => node,
+            None => return Ok(false),
+        };
+
+        let Metadata { char_height, char_width, viewport, winpos, .. } = self.metadata;
+        let rows = viewport.0 as usize;
+
+        let columns = match node.natural_size() {
+            Some((w, h)) if h > 0 => ((w * rows * char_height / h + char_width - 1) / char_width.max(1)).clamp(1, viewport.1 as usize),
+            _ => return Ok(true),
+        };
+
+        let dim = NodeDim { height: rows * char_height, crop: None };
+        if Render::write_image_at(&self.stdout, &self.metadata, node, dim, &self.encoding, winpos, &mut Written::default())? {
+            return Ok(true);
         }
 
+        Render::paint(&mut self.painted, Painted {
+            row: winpos.0,
+            rows,
+            column: winpos.1,
+            columns,
+            id: node.id.clone(),
+            line: node.range.0,
+            kind: node.kind(),
+            file: node.file().map(|x| x.to_string()),
+            cropped: false,
+        });
+
+        Ok(false)
+    }
+
+    /// Pin the node at the given line to the upper right corner, returns the first line of it
+    pub fn pin_node(&mut self, line: &str) -> Result<usize> {
+        let line = line.trim().parse::<usize>().map_err(|_| Error::NodeNotFound(0))?;
+        let node = self.node_at(line)?;
+        let (id, start) = (node.id.clone(), node.range.0);
+        self.pinned = Some(id);
+        self.version = None;
+        self.comparison = None;
+        self.comparing = None;
+
+        Ok(start)
+    }
+
+    pub fn unpin_node(&mut self, _: &str) -> Result<()> {
+        self.pinned = None;
+
         Ok(())
     }
 
-    pub fn update_metadata(&mut self, metadata: &str) -> Result<()> {
-        let mut metadata: Metadata = json::from_str(metadata).unwrap();
-        metadata.char_height = utils::char_pixel_height();
+    /// Enlarge the image of the node at the given line by a step, returns the new zoom
+    pub fn zoom_in(&mut self, line: &str) -> Result<f32> {
+        self.zoom_node(line, ZOOM_STEP)
+    }
 
-        let rerender = metadata.viewport != self.metadata.viewport;
-        if rerender {
-            self.clear_all("")?;
+    /// Shrink the image of the node at the given line by a step, returns the new zoom
+    pub fn zoom_out(&mut self, line: &str) -> Result<f32> {
+        self.zoom_node(line, 1.0 / ZOOM_STEP)
+    }
+
+    /// Multiply the zoom of a node, blobs encoded at the previous size are dropped and all images
+    /// drawn again, the enlarged image is cut to the lines of the node
+    fn zoom_node(&mut self, line: &str, factor: f32) -> Result<f32> {
+        let line = line.trim().parse::<usize>().map_err(|_| Error::NodeNotFound(0))?;
+        let node = self.node_at(line)?;
+
+        // steps back and forth return to exactly one
+        let zoom = (node.zoom * factor).clamp(ZOOM_RANGE.0, ZOOM_RANGE.1);
+        node.zoom = if (zoom - 1.0).abs() < 1e-3 { 1.0 } else { zoom };
+        node.clear_cache();
+        let zoom = node.zoom;
+
+        // a shrunk image may end before the pan offset
+        match node.pan {
+            Some(_) => {
+                let id = node.id.clone();
+                self.pan_by(&id, 0, 0)?;
+            },
+            None => self.clear_all("")?,
         }
 
-        self.metadata = metadata;
+        Ok(zoom)
+    }
 
-        Ok(())
+    /// Move the visible part of the image of the node at the given line
+    ///
+    /// The input is `{"line": 12, "dx": 4, "dy": -2}` with the distance in columns and lines. Once
+    /// panned, an image is no longer shrunk to the width of the window and only the part covering
+    /// the lines of the node is drawn, letting large or zoomed diagrams be inspected in place.
+    /// Returns the first line of the node.
+    pub fn pan_node(&mut self, input: &str) -> Result<usize> {
+        let PanRequest { line, dx, dy } = json::from_str(input).map_err(|_| Error::InvalidUpdate)?;
+        let id = self.node_at(line)?.id.clone();
+        self.pan_by(&id, dx, dy)?;
+
+        Ok(line)
     }
 
-    pub fn update_content(&mut self, content: &str) -> Result<String> {
-        let old_blocks = mem::take(&mut self.blocks);
-        let (nodes, strcts, folds, any_changed) = self.content.process(content, old_blocks)?;
+    /// Draw the whole image of the node at the given line again
+    pub fn reset_pan(&mut self, line: &str) -> Result<usize> {
+        let line = line.trim().parse::<usize>().map_err(|_| Error::NodeNotFound(0))?;
+        let node = self.node_at(line)?;
+        node.pan = None;
+        node.clear_cache();
+        let first = node.range.0;
 
-        self.strcts = strcts;
-        self.blocks = nodes;
+        self.clear_all("")?;
 
-        let ret = RedrawState {
-            should_redraw: any_changed,
-            update_folding: Some(folds),
-        };
+        Ok(first)
+    }
+
+    /// Move the pan offset of a node by columns and lines, clamped to the image
+    fn pan_by(&mut self, id: &CodeId, dx: isize, dy: isize) -> Result<()> {
+        let metadata = self.metadata.clone();
+        let node = self.blocks.get_mut(id).ok_or(Error::NodeNotFound(0))?;
+        let (x, y) = node.pan.unwrap_or((0, 0));
+        node.pan = Some((x, y));
+
+        // the offset ends where the image ends at the right or bottom of its window
+        let offset = Render::image_offset(&metadata, node);
+        let width = Render::image_width(&metadata, node).unwrap_or(0);
+        let height = Render::image_height(&metadata, node);
+        let max_x = width.saturating_sub((metadata.viewport.1 as usize).saturating_sub(offset) * metadata.char_width);
+        let max_y = height.saturating_sub(Render::text_height(node) * metadata.char_height);
+
+        let x = (x as isize + dx * metadata.char_width as isize).clamp(0, max_x as isize) as usize;
+        let y = (y as isize + dy * metadata.char_height as isize).clamp(0, max_y as isize) as usize;
+        node.pan = Some((x, y));
+        node.clear_cache();
 
-        Ok(json::to_string(&ret))
+        self.clear_all("")
     }
 
-    pub fn set_folds(&mut self, folds: &str) -> Result<usize> {
-        let folds: Folds = json::from_str(folds).unwrap();
-        let mut folds = folds.into_iter();
+    /// Previous figures of the named fence at the given line, newest first
+    pub fn figure_history(&mut self, line: &str) -> Result<String> {
+        let line = line.trim().parse::<usize>().map_err(|_| Error::NodeNotFound(0))?;
+        let document = self.metadata.file.clone().unwrap_or_default();
+        let name = self.node_at(line)?.name.clone().ok_or(Error::NodeNotNamed(line))?;
 
-        let mut any_changed = false;
+        Ok(json::to_string(&history::versions(&document, &name)))
+    }
 
-        // loop through structs and update fold information
-        let mut end_fold: Option<usize> = None;
-        for (line, elm) in &mut self.strcts {
-            if let Some(tmp) = &end_fold {
-                if tmp < line {
-                    end_fold = None;
-                }
-            }
+    /// Show a previous figure of the named fence at the given line in the upper right corner
+    ///
+    /// The version is shown at the height of the fence until it is hidden or a node is pinned.
+    pub fn show_version(&mut self, input: &str) -> Result<String> {
+        let VersionRequest { line, index } = json::from_str(input).map_err(|_| Error::InvalidUpdate)?;
+        let document = self.metadata.file.clone().unwrap_or_default();
+        let node = self.node_at(line)?;
+        let name = node.name.clone().ok_or(Error::NodeNotNamed(line))?;
+        let range = node.range;
 
-            match elm {
-                FoldInner::Fold(ref mut fold) => {
-                    let (start, end) = folds.next().unwrap();
-                    assert!(*line == start);
+        let version = history::versions(&document, &name).into_iter()
+            .nth(index)
+            .ok_or(Error::VersionNotFound(name, index))?;
 
-                    let prev = fold.state.clone();
+        self.pinned = None;
+        self.comparison = None;
+        self.comparing = None;
+        self.version = Some(Node::new(utils::hash(&version.path), range, &version.path, ContentType::File));
 
-                    if end == -1 {
-                        fold.state = FoldState::Open;
-                    } else {
-                        fold.state = FoldState::Folded(end as usize);
+        Ok(json::to_string(&version))
+    }
 
-                        if prev == FoldState::Open {
-                            end_fold = Some(end as usize);
-                        }
-                    }
+    pub fn hide_version(&mut self, _: &str) -> Result<()> {
+        self.version = None;
 
-                    if prev != fold.state {
-                        any_changed = true;
-                    }
-                },
-                FoldInner::Node((_, ref mut view)) => {
-                    if let Some(tmp) = &end_fold {
-                        if line < tmp {
-                            *view = NodeView::Hidden;
-                        }
-                    }
+        Ok(())
+    }
+
+    /// Show the figures of the nodes at two lines blended or side by side in the upper right corner
+    ///
+    /// Both nodes are generated in the background and composed by a later draw, the composite
+    /// covers as many lines as the taller node and is shown until it is hidden, a node is pinned
+    /// or a version shown. Returns the first line of the first node.
+    pub fn compare_nodes(&mut self, input: &str) -> Result<usize> {
+        let CompareRequest { line_a, line_b, mode } = json::from_str(input).map_err(|_| Error::InvalidUpdate)?;
+        let mode = match mode.as_deref() {
+            Some(name) => CompareMode::parse(name).ok_or(Error::InvalidUpdate)?,
+            None => CompareMode::SideBySide,
+        };
+
+        let mut start = |line: usize| -> Result<(CodeId, usize)> {
+            let node = self.node_at(line)?;
+            node.start();
+
+            Ok((node.id.clone(), node.range.0))
+        };
+        let (id_a, first) = start(line_a)?;
+        let (id_b, _) = start(line_b)?;
+
+        self.pinned = None;
+        self.version = None;
+        self.comparison = None;
+        self.comparing = Some(Comparing { id_a, id_b, line_a, line_b, mode });
+
+        Ok(first)
+    }
+
+    /// Composite of two compared figures as node, `None` while one of them is generated
+    fn compose(&self, comparing: &Comparing) -> Result<Option<Node>> {
+        let Comparing { id_a, id_b, line_a, line_b, mode } = comparing;
+        let (node_a, node_b) = match (self.blocks.get(id_a), self.blocks.get(id_b)) {
+            (Some(a), Some(b)) => (a, b),
+            (None, _) => return Err(Error::NodeNotFound(*line_a)),
+            (_, None) => return Err(Error::NodeNotFound(*line_b)),
+        };
+        if !node_a.is_generated() || !node_b.is_generated() {
+            return Ok(None);
+        }
+
+        let mut image_a = node_a.image().ok_or(Error::NodeNotReady(*line_a))?;
+        let mut image_b = node_b.image().ok_or(Error::NodeNotReady(*line_b))?;
+
+        // both figures are compared at the same height
+        let height = image_a.height().max(image_b.height());
+        image_a.fit(height);
+        image_b.fit(height);
+
+        let path = PathBuf::from(ART_PATH)
+            .join(utils::hash(&format!("{}\n{}\n{:?}", id_a, id_b, mode)))
+            .with_extension("png");
+        if !path.exists() {
+            let image = raster::compose(&image_a, &image_b, *mode)?;
+            std::fs::write(&path, image.png()).map_err(Error::Io)?;
+        }
+
+        let (range_a, range_b) = (node_a.range, node_b.range);
+        let lines = (range_a.1 - range_a.0).max(range_b.1 - range_b.0).max(1);
+        let path = path.to_string_lossy().to_string();
+
+        Ok(Some(Node::new(utils::hash(&path), (range_a.0, range_a.0 + lines), &path, ContentType::File)))
+    }
+
+    pub fn hide_comparison(&mut self, _: &str) -> Result<()> {
+        self.comparison = None;
+        self.comparing = None;
+
+        Ok(())
+    }
+
+    /// Display the node at the given line in a tmux popup
+    pub fn popup_node(&mut self, line: &str) -> Result<usize> {
+        let line = line.trim().parse::<usize>().map_err(|_| Error::NodeNotFound(0))?;
+
+        // leave space for the popup border
+        let rows = self.metadata.viewport.0 as usize * 8 / 10;
+        let dim = NodeDim {
+            height: rows.saturating_sub(2) * self.metadata.char_height,
+            crop: None,
+        };
+
+        let encoding = self.encoding.clone();
+        let node = self.node_at(line)?;
+        let blob = node.encode_blocking(dim, &encoding)?;
+        utils::tmux_popup(&blob, &node.id, rows)?;
+
+        Ok(node.range.0)
+    }
+
+    /// Find the node covering the given line
+    fn node_at(&mut self, line: usize) -> Result<&mut Node> {
+        self.blocks.values_mut()
+            .find(|node| node.range.0 <= line && line <= node.range.1)
+            .ok_or(Error::NodeNotFound(line))
+    }
+    /// Draw a node if its view changed, returns whether it is pending
+    ///
+    /// The region covered by the image replaces the one of the node in `painted` once it is
+    /// drawn and is removed when the node is hidden.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_node(metadata: &Metadata, stdout: &Stdout, node: &mut Node, view: &mut NodeView, top_offset: isize, encoding: &Encoding, written: &mut Written, painted: &mut Vec<Painted>) -> Result<bool> {
+        // calculate new view and height of node
+        let new_view = NodeView::new(node,  metadata, top_offset);
+        let char_height = metadata.char_height;
+        let height = Render::image_height(metadata, node);
+
+        let (pos, rows) = match (&view, &new_view) {
+            (NodeView::UpperBorder(_, _) | NodeView::LowerBorder(_, _) | NodeView::Hidden, NodeView::Visible(pos, _)) =>
+                (*pos, None),
+            (NodeView::Hidden, NodeView::LowerBorder(pos, height)) =>
+                (*pos, Some((height * char_height, 0))),
+            (NodeView::LowerBorder(_, height_old), NodeView::LowerBorder(pos, height)) if height_old < height =>
+                (*pos, Some((height * char_height, 0))),
+            (NodeView::Hidden, NodeView::UpperBorder(y, height)) => 
+                (0, Some((height * char_height, y * char_height))),
+            (NodeView::UpperBorder(y_old, _), NodeView::UpperBorder(y, height)) if y < y_old =>
+                (0, Some((height * char_height, y * char_height))),
+            _ => return Ok(false),
+        };
+
+        let offset = Render::image_offset(metadata, node);
+
+        // panned images show the part of the size of their lines at the pan offset
+        let rows = match node.pan {
+            Some((x, y)) => {
+                let (rows, top) = rows.unwrap_or((Render::text_height(node) * char_height, 0));
+                let width = (metadata.viewport.1 as usize).saturating_sub(offset) * metadata.char_width;
+                Some(Crop { x, y: y + top, width, height: rows })
+            },
+            // zoomed images are cut to the lines of the node, instead of covering the text below
+            None => rows.map(|(rows, y)| Crop::rows(rows, y))
+                .or_else(|| Some(Crop::rows(Render::text_height(node) * char_height, 0)).filter(|x| x.height < height)),
+        };
+
+        // images limited by their bounds may end above the lines they cover
+        let crop = match rows {
+            Some(crop) if crop.y >= height => return Ok(false),
+            Some(crop) => Some(Crop { height: crop.height.min(height - crop.y), ..crop }),
+            None => None,
+        };
+        let dim = NodeDim { height, crop };
+
+        let column = metadata.winpos.1 + offset;
+        let res = Render::write_image_at(stdout, metadata, node, dim, encoding, (pos + metadata.winpos.0, column), written)?;
+
+        let rows = match new_view {
+            NodeView::Visible(_, height) | NodeView::LowerBorder(_, height) | NodeView::UpperBorder(_, height) => height,
+            NodeView::Hidden => 0,
+        };
+        if rows == 0 {
+            painted.retain(|x| x.id != node.id);
+        } else if !res {
+            let available = (metadata.viewport.1 as usize).saturating_sub(offset).max(1);
+            let columns = Render::image_columns(metadata, node)
+                .map_or(available, |x| x.clamp(1, available));
+
+            Render::paint(painted, Painted {
+                row: pos + metadata.winpos.0,
+                rows,
+                column,
+                columns,
+                id: node.id.clone(),
+                line: node.range.0,
+                kind: node.kind(),
+                file: node.file().map(|x| x.to_string()),
+                cropped: matches!(new_view, NodeView::UpperBorder(_, _) | NodeView::LowerBorder(_, _)),
+            });
+        }
+
+        Ok(res && new_view.is_visible())
+    }
+
+    /// Replace the region of a node, the region drawn last lies on top
+    fn paint(painted: &mut Vec<Painted>, region: Painted) {
+        painted.retain(|x| x.id != region.id);
+        painted.push(region);
+    }
+
+    /// Number of text lines the image of a node is fitted to
+    ///
+    /// Inline nodes are scaled to a single line of text, as well as nodes of a single line.
+    fn text_height(node: &Node) -> usize {
+        match node.column {
+            Some(_) => 1,
+            None => (node.range.1 - node.range.0).max(1),
+        }
+    }
+
+    /// Height in pixels the image of a node is drawn with
+    ///
+    /// The image fills the lines of the node enlarged by its zoom, unless its bounds or the width of
+    /// the window make it smaller.
+    fn image_height(metadata: &Metadata, node: &Node) -> usize {
+        let mut height = (Render::text_height(node) as f32 * node.zoom * metadata.char_height as f32) as usize;
+
+        // images don't reach beyond the right edge of the window, inline nodes start after the text,
+        // except panned images of which only a window sized part is drawn
+        let window_width = (metadata.viewport.1 as usize).saturating_sub(node.column.unwrap_or(0)) * metadata.char_width;
+        let window_width = if node.pan.is_some() { 0 } else { window_width };
+        let max_width = node.bounds.width.into_iter()
+            .chain(Some(window_width).filter(|x| *x > 0))
+            .min();
+        if let (Some(max_width), Some((width, natural))) = (max_width, node.natural_size()) {
+            if width > 0 {
+                height = height.min(natural * max_width / width);
+            }
+        }
+        if let Some(max_height) = node.bounds.height {
+            height = height.min(max_height);
+        }
+
+        height.max(1)
+    }
+
+    /// Column of the window the image of a node starts at
+    ///
+    /// Inline nodes start after their text, others are aligned in the window once the width of
+    /// their image is known.
+    fn image_offset(metadata: &Metadata, node: &Node) -> usize {
+        match (node.column, Render::image_columns(metadata, node)) {
+            (Some(column), _) => column,
+            (None, Some(columns)) => node.align.offset(columns, metadata.viewport.1 as usize),
+            (None, None) => 0,
+        }
+    }
+
+    /// Number of columns covered by the image of a node
+    ///
+    /// The image is fitted to the height of the node, the width follows from its aspect. Unknown
+    /// until the image was generated. Panned images cover at most the window.
+    fn image_columns(metadata: &Metadata, node: &Node) -> Option<usize> {
+        let available = (metadata.viewport.1 as usize).saturating_sub(node.column.unwrap_or(0)).max(1);
+
+        Render::image_width(metadata, node)
+            .map(|width| (width + metadata.char_width - 1) / metadata.char_width.max(1))
+            .map(|columns| if node.pan.is_some() { columns.min(available) } else { columns })
+    }
+
+    /// Width in pixels of the image of a node fitted to its height
+    fn image_width(metadata: &Metadata, node: &Node) -> Option<usize> {
+        node.natural_size()
+            .filter(|(_, height)| *height > 0)
+            .map(|(width, height)| width * Render::image_height(metadata, node) / height)
+    }
+
+    /// Write the image of a node at the given row and column, returns whether it is pending
+    ///
+    /// The number of bytes sent to the terminal and the time writing them took is added to
+    /// `written`.
+    fn write_image_at(stdout: &Stdout, metadata: &Metadata, node: &mut Node, dim: NodeDim, encoding: &Encoding, (row, column): (usize, usize), written: &mut Written) -> Result<bool> {
+        // move the cursor before the first chunk is written, the outer terminal counts from its
+        // own origin when the sequences are passed through tmux
+        let (top, left) = metadata.tmux_pane.unwrap_or((0, 0));
+        let mut head = Some(format!("\x1b[s\x1b[{};{}H", row + top, column + left).into_bytes());
+        let mut moved = false;
+        let res = node.draw_image(dim, encoding, &mut |chunk| {
+            if encoding.backend.place(chunk, (row, column)) {
+                return;
+            }
+
+            let start = Instant::now();
+            if let Some(head) = head.take() {
+                Render::write_term(stdout, metadata, &head);
+                moved = true;
+            }
+            Render::write_term(stdout, metadata, chunk);
+            written.add(chunk.len(), start.elapsed());
+        });
+
+        if let Some(res) = res {
+            // bail out if an error happened during conversion
+            res?;
+
+            if moved {
+                Render::write_term(stdout, metadata, b"\x1b[u");
+            }
+
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Write an encoded image at the given row and column
+    fn write_blob_at(stdout: &Stdout, metadata: &Metadata, encoding: &Encoding, blob: &[u8], (row, column): (usize, usize)) {
+        if encoding.backend.place(blob, (row, column)) {
+            return;
+        }
+
+        let (top, left) = metadata.tmux_pane.unwrap_or((0, 0));
+        Render::write_term(stdout, metadata, format!("\x1b[s\x1b[{};{}H", row + top, column + left).as_bytes());
+        Render::write_term(stdout, metadata, blob);
+        Render::write_term(stdout, metadata, b"\x1b[u");
+    }
+
+    /// Write a sequence to the terminal, wrapped in a passthrough when running inside tmux
+    ///
+    /// The sequence is mirrored to the second screen as is, if there is one.
+    fn write_term(stdout: &Stdout, metadata: &Metadata, buf: &[u8]) {
+        mirror::write(buf);
+        if metadata.tmux_pane.is_some() {
+            Render::write_raw(stdout, &utils::tmux_passthrough(buf));
+        } else {
+            Render::write_raw(stdout, buf);
+        }
+    }
+
+    /// Write a buffer directly to the terminal, retrying until everything is written
+    fn write_raw(stdout: &Stdout, buf: &[u8]) {
+        let outer_lock = stdout.lock();
+        let mut stdout = unsafe { File::from_raw_fd(1) };
+        let mut idx = 0;
+        while idx < buf.len() {
+            match stdout.write(&buf[idx..]) {
+                Ok(n) => idx += n,
+                Err(_) => {/*eprintln!("{}", err);*/},
+            }
+        }
+        std::mem::forget(stdout);
+        drop(outer_lock);
+    }
+
+    pub fn clear_all(&mut self, _: &str) -> Result<()> {
+        self.painted.clear();
+        for fold in self.strcts.values_mut() {
+            if let FoldInner::Node(ref mut node) = fold {
+                node.1 = NodeView::Hidden;
+            }
+        }
+
+        if let Some(seq) = self.encoding.backend.clear() {
+            Render::write_term(&self.stdout, &self.metadata, &seq);
+        }
+
+        Ok(())
+    }
+
+    /// Place images of the `ueberzug` backend with a layer shared by other buffers
+    pub fn share_layer(&mut self, layer: backend::Layer) {
+        self.layer = layer;
+    }
+
+    /// Enable or disable drawing, nodes are drawn again once visible
+    pub fn set_visible(&mut self, visible: bool) -> Result<()> {
+        if visible && !self.visible {
+            self.clear_all("")?;
+        }
+        self.visible = visible;
+
+        Ok(())
+    }
+
+    /// Toggle the debug overlay and invalidate all cached blobs
+    pub fn toggle_overlay(&mut self, _: &str) -> Result<bool> {
+        self.encoding.overlay = !self.encoding.overlay;
+        self.invalidate()?;
+
+        Ok(self.encoding.overlay)
+    }
+
+    /// Remove all cached blobs and hide all nodes, so that they are encoded again
+    fn invalidate(&mut self) -> Result<()> {
+        for node in self.blocks.values() {
+            node.clear_cache();
+        }
+
+        self.clear_all("")
+    }
+
+    /// Compare the reserved space of each node with the aspect of its rendered image
+    ///
+    /// The image is fitted into the window width and the number of lines necessary to display it
+    /// is compared with the lines reserved in the document. Nodes not rendered yet are skipped.
+    pub fn validate(&mut self, _: &str) -> Result<String> {
+        let mismatches = self.blocks.values()
+            .filter(|node| node.column.is_none())
+            .filter_map(|node| node.natural_size().map(|size| (node, size)))
+            .filter_map(|(node, size)| {
+                let required = self.required_lines(size)?;
+                let reserved = node.range.1 - node.range.0;
+
+                if required != reserved {
+                    Some(Mismatch { line: node.range.0, reserved, required })
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Ok(json::to_string(&mismatches))
+    }
+
+    /// List nodes whose generation or encoding exceeded the configured threshold
+    pub fn slow_nodes(&mut self, _: &str) -> Result<String> {
+        let threshold = self.config.slow_threshold.unwrap_or(500);
+
+        let mut slow = self.blocks.values()
+            .filter_map(|node| {
+                let (generate, encode) = node.timings();
+                let (generate_ms, encode_ms) = (generate.map(|x| x.as_millis() as u64), encode.map(|x| x.as_millis() as u64));
+
+                if generate_ms.max(encode_ms)? > threshold {
+                    Some(SlowNode { id: node.id.clone(), line: node.range.0, kind: node.kind(), generate_ms, encode_ms })
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        slow.sort_by_key(|x| x.line);
+
+        Ok(json::to_string(&slow))
+    }
+
+    /// Draw the next frames of animated GIFs, returns when to call again
+    ///
+    /// Only images drawn completely are animated, so animations stop once they leave the view.
+    /// The zoomed image covers the others and stops them as well.
+    pub fn animate(&mut self, _: &str) -> Result<String> {
+        if !self.visible || self.zoomed.is_some() || self.config.animate == Some(false) {
+            return Ok(json::to_string(&AnimationStep { delay: 0 }));
+        }
+
+        let mut next: Option<Duration> = None;
+        for region in self.painted.iter().filter(|x| !x.cropped) {
+            let node = match self.blocks.get(&region.id) {
+                Some(node) => node,
+                None => continue,
+            };
+
+            let dim = NodeDim { height: Render::image_height(&self.metadata, node), crop: None };
+            if let Some((blob, wait)) = node.next_frame(&dim, &self.encoding) {
+                if let Some(blob) = blob {
+                    Render::write_blob_at(&self.stdout, &self.metadata, &self.encoding, &blob, (region.row, region.column));
+                }
+
+                next = Some(next.map_or(wait, |x| x.min(wait)));
+            }
+        }
+
+        let delay = next.map_or(0, |x| (x.as_millis() as u64).max(1));
+        Ok(json::to_string(&AnimationStep { delay }))
+    }
+
+    /// Screen regions covered by images with the line and file they originate from
+    pub fn painted_regions(&mut self, _: &str) -> Result<String> {
+        Ok(json::to_string(&self.painted))
+    }
+
+    /// React to a mouse event `<row> <column> <kind>` on the screen
+    ///
+    /// A `click` zooms the image to the window height or restores it, a `double` click opens the
+    /// image in an external viewer and `scroll_up` or `scroll_down` scrolls the window over cropped
+    /// images or moves panned images within their lines. Images
+    /// drawn last lie on top and are hit first.
+    pub fn mouse_event(&mut self, event: &str) -> Result<String> {
+        let mut parts = event.split_whitespace();
+        let (row, column, kind) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(row), Some(column), Some(kind)) => (
+                row.parse::<usize>().map_err(|_| Error::InvalidEvent)?,
+                column.parse::<usize>().map_err(|_| Error::InvalidEvent)?,
+                kind,
+            ),
+            _ => return Err(Error::InvalidEvent),
+        };
+
+        let region = self.painted.iter().rev()
+            .find(|x| row >= x.row && row < x.row + x.rows && column >= x.column && column < x.column + x.columns)
+            .cloned();
+
+        let mut action = MouseAction { action: "none", line: None, last: None, path: None };
+        let region = match region {
+            Some(region) => region,
+            None => return Ok(json::to_string(&action)),
+        };
+
+        let node = match self.blocks.get(&region.id) {
+            Some(node) => node,
+            None => return Ok(json::to_string(&action)),
+        };
+        action.line = Some(node.range.0);
+        action.last = Some(node.range.1);
+
+        let is_zoomed = self.zoomed.as_ref() == Some(&region.id);
+        action.action = match kind {
+            "click" if is_zoomed => {
+                self.zoomed = None;
+                "unzoom"
+            },
+            "click" => {
+                self.zoomed = Some(region.id.clone());
+                "zoom"
+            },
+            "double" => {
+                // the first click of a double click zoomed the image already
+                if is_zoomed {
+                    self.zoomed = None;
+                }
+
+                action.path = node.image_path().map(|x| x.to_string_lossy().to_string());
+                if action.path.is_some() { "open" } else { "none" }
+            },
+            "scroll_up" | "scroll_down" if node.pan.is_some() => {
+                let dy = if kind == "scroll_up" { -PAN_STEP } else { PAN_STEP };
+                self.pan_by(&region.id, 0, dy)?;
+                "pan_image"
+            },
+            "scroll_up" | "scroll_down" if region.cropped => "pan",
+            "scroll_up" | "scroll_down" => "none",
+            _ => return Err(Error::InvalidEvent),
+        };
+
+        Ok(json::to_string(&action))
+    }
+
+    /// Search binaries again which were missing, nodes waiting for them are generated again
+    ///
+    /// Binaries may also have been found by another buffer. Returns the binaries found and those
+    /// still missing, the view is cleared if any node is retried so that the next draw picks
+    /// them up.
+    pub fn recheck_binaries(&mut self, _: &str) -> Result<String> {
+        let found = process::recheck_binaries();
+        let missing = process::missing_binaries();
+
+        let mut retried = 0;
+        for node in self.blocks.values() {
+            if node.retry_missing(&missing) {
+                retried += 1;
+            }
+        }
+
+        if retried > 0 {
+            self.clear_all("")?;
+        }
+
+        Ok(json::to_string(&BinaryRecheck { found, missing, retried }))
+    }
+
+    /// Nodes waiting to be generated, in the order they will be started per content type
+    pub fn render_queue(&mut self, _: &str) -> Result<String> {
+        let queue = process::queue().into_iter()
+            .map(|job| QueuedNode {
+                line: self.blocks.get(&job.id).map(|node| node.range.0),
+                id: job.id,
+                kind: job.key,
+                priority: job.priority,
+                waiting_ms: job.waiting.as_millis() as u64,
+            })
+            .collect::<Vec<_>>();
+
+        Ok(json::to_string(&queue))
+    }
+
+    /// Move the node at the given line to the front of the render queue
+    ///
+    /// Returns `false` if the node is not waiting, because it is already rendered or was never
+    /// requested.
+    pub fn prioritize(&mut self, line: &str) -> Result<bool> {
+        let line = line.trim().parse::<usize>().map_err(|_| Error::NodeNotFound(0))?;
+        let node = self.node_at(line)?;
+
+        Ok(process::prioritize(&node.id))
+    }
+
+    /// State of all nodes, with a fallback text for nodes which can't be displayed
+    pub fn node_status(&mut self, _: &str) -> Result<String> {
+        let unsupported = self.unsupported();
+
+        let mut status = self.blocks.values()
+            .map(|node| {
+                let error = node.error();
+                let state = if node.disabled {
+                    "disabled"
+                } else if error.is_some() {
+                    "failed"
+                } else if node.is_ready() || node.text.is_some() {
+                    "ready"
+                } else {
+                    "pending"
+                };
+
+                // simple math written as text is never rendered
+                let fallback = match error {
+                    _ if node.text.is_some() => node.text.clone(),
+                    Some(err) => Some(node.fallback(&format!("render failed: {}", err))),
+                    None if unsupported => Some(node.fallback("no graphics protocol")),
+                    None => None,
+                };
+
+                // inline nodes and text don't reserve lines
+                let suggested_height = node.natural_size()
+                    .filter(|_| node.column.is_none() && node.text.is_none())
+                    .and_then(|size| self.required_lines(size));
+
+                NodeStatus { id: node.id.clone(), line: node.range.0, kind: node.kind(), state, fallback, suggested_height }
+            })
+            .collect::<Vec<_>>();
+        status.sort_by_key(|x| x.line);
+
+        Ok(json::to_string(&status))
+    }
+
+    /// Manifest of all nodes of the document with their content hash, artifact and renderers
+    ///
+    /// Versions are asked from each binary in the background when the document is parsed, they
+    /// are `null` until the binary answered.
+    pub fn document_manifest(&mut self, _: &str) -> Result<String> {
+        Ok(json::to_string(&self.manifest_of(0, usize::MAX)))
+    }
+
+    /// Manifest of the nodes starting between the given lines
+    fn manifest_of(&self, first: usize, last: usize) -> DocumentManifest {
+        let mut nodes = self.blocks.values()
+            .filter(|node| first <= node.range.0 && node.range.0 <= last)
+            .map(|node| {
+                let state = if node.error().is_some() {
+                    "failed"
+                } else if node.is_ready() {
+                    "ready"
+                } else {
+                    "pending"
+                };
+                let path = node.image_path()
+                    .filter(|x| x.exists())
+                    .map(|x| x.to_string_lossy().to_string());
+                let renderers = node.renderers().into_iter()
+                    .map(|binary| Renderer { version: process::binary_version(&binary), binary })
+                    .collect();
+
+                ManifestNode { line: node.range.0, kind: node.kind(), id: node.id.clone(), path, state, renderers }
+            })
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|x| x.line);
+
+        DocumentManifest {
+            document: self.metadata.file.clone(),
+            version: format!("{} ({})", env!("CARGO_PKG_VERSION"), env!("GIT_HASH")),
+            nodes,
+        }
+    }
+
+    /// Act on all nodes of the section containing a line
+    ///
+    /// The input is `{"line": 12, "action": "render"}`. Sections reach from a heading to the line
+    /// before the next heading, lines before the first heading form a section as well. Actions
+    /// are
+    ///  - `render` renders the nodes in the background, also those out of view
+    ///  - `disable` neither renders nor draws them until `enable`
+    ///  - `export` returns the manifest of the nodes, like `document_manifest`
+    ///  - `purge` removes their artifacts from the cache and renders them again
+    pub fn section_action(&mut self, input: &str) -> Result<String> {
+        let SectionRequest { line, action } = json::from_str(input).map_err(|_| Error::InvalidUpdate)?;
+        let (first, last) = self.section_at(line);
+        let ids = self.blocks.values()
+            .filter(|node| first <= node.range.0 && node.range.0 <= last)
+            .map(|node| node.id.clone())
+            .collect::<Vec<_>>();
+
+        let mut result = SectionResult { first, last: last.min(self.lines.len()), nodes: ids.len(), manifest: None, purged: None };
+        match action.as_str() {
+            "render" => {
+                for node in ids.iter().filter_map(|id| self.blocks.get(id)) {
+                    if !node.disabled && node.text.is_none() {
+                        node.start();
+                    }
                 }
+            },
+            "disable" | "enable" => {
+                for id in &ids {
+                    if let Some(node) = self.blocks.get_mut(id) {
+                        node.disabled = action == "disable";
+                    }
+                }
+                self.clear_all("")?;
+            },
+            "export" => result.manifest = Some(self.manifest_of(first, last)),
+            "purge" => {
+                let artifacts = ids.iter()
+                    .filter_map(|id| self.blocks.get(id))
+                    .filter_map(|node| node.artifact())
+                    .collect::<Vec<_>>();
+                result.purged = Some(cache::purge_ids(&artifacts)?);
+
+                // nodes created anew by parsing are rendered again
+                for id in &ids {
+                    self.blocks.remove(id);
+                }
+                self.clear_all("")?;
+                let content = self.lines.join("\n");
+                self.update_content(&content)?;
+            },
+            _ => return Err(Error::InvalidUpdate),
+        }
+
+        Ok(json::to_string(&result))
+    }
+
+    /// First and last line of the section containing a line, bounded by headings
+    fn section_at(&self, line: usize) -> (usize, usize) {
+        let headings = self.strcts.values()
+            .filter_map(|item| match item {
+                FoldInner::Fold(fold) => Some(fold.line),
+                FoldInner::Node(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        let first = headings.iter().copied().filter(|x| *x <= line).max().unwrap_or(0);
+        let last = headings.iter().copied().filter(|x| *x > line).min().map_or(usize::MAX, |x| x - 1);
+
+        (first, last)
+    }
+
+    /// Number of lines necessary to display an image of given size fitted into the window width
+    fn required_lines(&self, (width, height): (usize, usize)) -> Option<usize> {
+        let Metadata { char_height, char_width, viewport, .. } = self.metadata;
+        let window_width = viewport.1 as usize * char_width.max(1);
+
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        let height = if width > window_width { height * window_width / width } else { height };
+        Some(((height + char_height - 1) / char_height.max(1)).max(1))
+    }
+
+    /// Apply a configuration, caches are only invalidated if settings they depend on changed
+    pub fn set_config(&mut self, config: Config) -> Result<bool> {
+        // settings which may be invalid are checked first, so that an invalid configuration
+        // changes nothing
+        let align = match config.align.as_deref() {
+            Some(name) => Align::parse(name).ok_or(Error::InvalidConfig)?,
+            None => Align::Left,
+        };
+        let engine = match config.latex_engine.as_deref() {
+            Some(name) => Some(LatexEngine::parse(name).ok_or(Error::InvalidConfig)?),
+            None => None,
+        };
+        let filters = match config.filters != self.config.filters {
+            true => Some(Filters::new(&config.filters.clone().unwrap_or_default())?),
+            false => None,
+        };
+        let name = match config.backend.as_deref() {
+            None | Some("auto") => self.detect_capabilities().best_backend(),
+            Some(name) => name,
+        };
+        let backend = match name != self.encoding.backend.name() {
+            true => Some(backend::from_name(name, &self.layer)?),
+            false => None,
+        };
+
+        config.apply();
+        self.mirror_error = mirror::set_mirror(config.mirror.as_deref()).err().map(|x| x.to_string());
+        let aliases_changed = config.aliases != self.config.aliases || config.handlers != self.config.handlers
+            || filters.is_some();
+        if let Some(filters) = filters {
+            self.content.set_filters(filters);
+        }
+        self.config = config;
+        self.content.set_aliases(self.config.aliases.clone().unwrap_or_default());
+        self.content.set_handlers(self.config.handlers.clone().unwrap_or_default());
+        let align_changed = self.content.set_align(align);
+        let unicode_changed = self.content.set_unicode_math(self.config.unicode_math.unwrap_or(false));
+        let preamble = self.config.preamble.iter().flatten().cloned().collect::<Vec<_>>();
+        let preamble_changed = self.content.set_preamble(preamble.join("\n")) | self.content.set_engine(engine);
+        let document_changed = self.update_document();
+
+        let theme = match self.config.theme {
+            Some(false) => None,
+            _ => Some(self.detect_capabilities().theme.clone()).filter(|x| !x.is_empty()),
+        };
+        let theme_changed = self.content.set_theme(theme) | self.update_colors();
+
+        // fence names and document options are resolved while parsing, so the text is parsed again
+        if aliases_changed || document_changed || theme_changed || align_changed || unicode_changed || preamble_changed {
+            if !self.lines.is_empty() {
+                let content = self.lines.join("\n");
+                self.update_content(&content)?;
             }
         }
+        // images drawn at their previous position or replaced by text are removed
+        if align_changed || unicode_changed {
+            self.clear_all("")?;
+        }
+
+        if let Some(backend) = backend {
+            self.encoding.backend = backend;
+            self.quality = Adaptive::default();
+            self.invalidate()?;
+        }
+
+        Ok(true)
+    }
+
+    /// Select the markup language of the filetype, returns whether it changed
+    fn update_syntax(&mut self) -> bool {
+        let syntax = Syntax::from_filetype(self.metadata.filetype.as_deref().unwrap_or_default());
+
+        self.content.set_syntax(syntax)
+    }
+
+    /// Resolve image links and search figures of latex fences next to the file of the buffer, the
+    /// latter also in the configured folders, and allow `-shell-escape` if the file is trusted
+    ///
+    /// Returns whether anything changed.
+    fn update_document(&mut self) -> bool {
+        let file = self.metadata.file.as_deref().map(Path::new);
+        let dir = file
+            .and_then(|x| x.parent())
+            .filter(|x| !x.as_os_str().is_empty());
+
+        let mut paths = dir.iter()
+            .map(|x| x.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        for asset_dir in self.config.asset_dirs.iter().flatten() {
+            let path = match dir {
+                Some(dir) => dir.join(asset_dir),
+                None => Path::new(asset_dir).to_path_buf(),
+            };
+            paths.push(path.to_string_lossy().to_string());
+        }
+
+        // entries are either the document itself or one of its parent folders
+        let trusted = file.map_or(false, |file| {
+            self.config.shell_escape.iter().flatten().any(|x| file.starts_with(x))
+        });
+
+        // the vault is configured relative to the document, or is the closest folder Obsidian set up
+        let vault = match (&self.config.vault, dir) {
+            (Some(vault), Some(dir)) => Some(dir.join(vault)),
+            (Some(vault), None) => Some(PathBuf::from(vault)),
+            (None, dir) => dir
+                .and_then(|dir| dir.ancestors().find(|x| x.join(".obsidian").is_dir()))
+                .map(|x| x.to_path_buf()),
+        };
+        let vault_changed = self.content.set_vault(vault);
+
+        self.content.set_document(dir.map(|x| x.to_path_buf()), paths, trusted) | vault_changed
+    }
+
+    /// Probe the terminal once and return its capabilities
+    fn detect_capabilities(&mut self) -> &Capabilities {
+        self.capabilities.get_or_insert_with(terminal::detect)
+    }
+
+    /// Whether no image can be shown, without a graphics protocol and unless a backend is forced
+    fn unsupported(&mut self) -> bool {
+        matches!(self.config.backend.as_deref(), None | Some("auto")) && !self.detect_capabilities().any()
+    }
+
+    /// Pass the colors equations are written with to the content, returns whether they changed
+    ///
+    /// The colors of the colorscheme take precedence over those reported by the terminal, with
+    /// `theme` disabled equations stay black.
+    fn update_colors(&mut self) -> bool {
+        let colors = match (self.config.theme, self.metadata.colors.clone()) {
+            (Some(false), _) => None,
+            (_, Some(Colors { foreground, background })) if foreground.is_some() || background.is_some() =>
+                Some(Theme { foreground, background, accent: None }),
+            _ => Some(self.detect_capabilities().theme.clone()).filter(|x| !x.is_empty()),
+        };
+
+        self.content.set_colors(colors)
+    }
+
+    /// Step the quality of the backend with the throughput of the last draws
+    ///
+    /// Cached blobs are dropped, so that images are encoded at the new quality when they are
+    /// drawn next, those on the screen stay.
+    fn adapt_quality(&mut self) {
+        let levels = self.encoding.backend.qualities();
+        let level = match self.config.adaptive_quality {
+            Some(false) => {
+                self.quality = Adaptive::default();
+                Some(0)
+            },
+            _ => self.quality.record(self.written, levels, Instant::now()),
+        };
+
+        if level.map_or(false, |x| self.encoding.backend.set_quality(x)) {
+            for node in self.blocks.values() {
+                node.clear_cache();
+            }
+        }
+    }
+
+    /// Quality level of the backend and the measured throughput of the terminal
+    pub fn quality_stats(&self) -> QualityStats {
+        self.quality.stats(self.encoding.backend.name(), self.encoding.backend.qualities())
+    }
+
+    /// Why the last draw couldn't write the manifest or the history
+    pub fn store_error(&self) -> Option<String> {
+        self.store_error.clone()
+    }
+
+    /// Detected capabilities of the terminal and the selected backend
+    pub fn capabilities(&mut self, _: &str) -> Result<String> {
+        let caps = self.detect_capabilities().clone();
+
+        Ok(format!("{{ \"detected\": {}, \"backend\": \"{}\", \"supported\": {}, \"mirror_error\": {} }}",
+            json::to_string(&caps), self.encoding.backend.name(), caps.any(), json::to_string(&self.mirror_error)))
+    }
+
+    pub fn update_metadata(&mut self, metadata: &str) -> Result<()> {
+        let mut metadata: Metadata = json::from_str(metadata).unwrap();
+        metadata.char_height = utils::char_pixel_height();
+        metadata.char_width = utils::char_pixel_width();
+
+        // the pane can only move together with a resize, so tmux is only asked then
+        metadata.tmux_pane = if metadata.viewport != self.metadata.viewport {
+            utils::tmux_pane_offset()
+        } else {
+            self.metadata.tmux_pane
+        };
+
+        // a window moved, e.g. because a split was opened above, all placements are outdated
+        let moved = metadata.positions() != self.metadata.positions();
+
+        // images of some protocols are not overwritten by text and have to be removed on scroll
+        let persistent = self.encoding.backend.persistent();
+        let rerender = metadata.viewport != self.metadata.viewport
+            || moved
+            || (persistent && metadata.file_range != self.metadata.file_range)
+            || (persistent && metadata.windows != self.metadata.windows);
+        if rerender {
+            self.clear_all("")?;
+        }
+
+        let file_changed = metadata.file != self.metadata.file;
+        self.metadata = metadata;
+        let syntax_changed = self.update_syntax();
+
+        // plots depend on the size of the window and the font
+        let Metadata { char_width, char_height, viewport, .. } = self.metadata;
+        let size_changed = self.content.set_cell_size(char_width, char_height, viewport.1 as usize);
+        let document_changed = file_changed && self.update_document();
+        let colors_changed = self.update_colors();
+
+        if (document_changed || size_changed || syntax_changed || colors_changed) && !self.lines.is_empty() {
+            let content = self.lines.join("\n");
+            self.update_content(&content)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn update_content(&mut self, content: &str) -> Result<String> {
+        self.lines = content.split('\n').map(|x| x.to_string()).collect();
+
+        self.process(content).map(|state| json::to_string(&state))
+    }
+
+    /// Parse the whole text again, nodes with unchanged content are kept
+    fn process(&mut self, content: &str) -> Result<RedrawState> {
+        let old_blocks = mem::take(&mut self.blocks);
+        let (nodes, strcts, folds, changes, overlaps) = self.content.process(content, old_blocks)?;
+
+        self.strcts = strcts;
+        self.blocks = nodes;
+
+        // ask the renderers for their versions early, so the manifest doesn't wait for them
+        for binary in self.blocks.values().flat_map(|node| node.renderers()) {
+            process::binary_version(&binary);
+        }
+
+        Ok(self.redraw_state(folds, changes, overlaps))
+    }
+
+    /// Set a parameter of the fence at the given line and render it again with the new value
+    ///
+    /// The value is either given or moved by a number of steps, and clamped to the declared range.
+    pub fn set_param(&mut self, update: &str) -> Result<String> {
+        let ParamUpdate { line, name, value, steps } = json::from_str(update).map_err(|_| Error::InvalidUpdate)?;
+
+        let node = self.node_at(line)?;
+        let (source, param) = node.params.as_ref()
+            .and_then(|x| Some((x.source.clone(), x.params.iter().find(|param| param.name == name)?.clone())))
+            .ok_or_else(|| Error::UnknownParam(name.clone(), line))?;
+
+        let value = match (value, steps) {
+            (Some(value), _) => value,
+            (None, Some(steps)) => param.value + steps as f64 * param.step,
+            (None, None) => return Err(Error::InvalidUpdate),
+        };
+        let value = param.clamp(value);
+        self.content.set_param(&source, &name, value);
+
+        let state = self.process(&self.lines.join("\n"))?;
+
+        Ok(json::to_string(&ParamChange { name, value, state }))
+    }
+
+    /// Whether a block starts at the line with the index, that is after a blank line
+    ///
+    /// Indented lines after a blank line continue a list item or an indented code block.
+    fn is_block_start(lines: &[String], idx: usize) -> bool {
+        let blank = |idx: usize| lines.get(idx).is_none_or(|x| x.trim().is_empty());
+        let indented = lines.get(idx).is_some_and(|x| x.starts_with([' ', '\t']));
+
+        idx > 0 && blank(idx - 1) && !blank(idx) && !indented
+    }
+
+    /// Replace lines `first` until `last` (exclusive, starting at one) with new lines
+    ///
+    /// Only the region of the change, extended to the nodes it touches and the blocks around
+    /// them, is parsed again. Nodes below are shifted. If the change adds or removes a fence
+    /// delimiter, happens inside a fence which isn't a node, or the text contains references, the
+    /// whole text is parsed.
+    pub fn update_lines(&mut self, update: &str) -> Result<String> {
+        let LinesUpdate { first, last, lines } = json::from_str(update).map_err(|_| Error::InvalidUpdate)?;
+        if first == 0 || first > last || last > self.lines.len() + 1 {
+            return Err(Error::InvalidUpdate);
+        }
+
+        let delta = lines.len() as isize - (last - first) as isize;
+        let is_fence = |x: &&String| self.content.is_fence(x);
+        // delimiters open or close fences below the change as well
+        let touches_fence = lines.iter().any(|x| self.content.is_fence(x))
+            || self.lines[first - 1..last - 1].iter().any(|x| self.content.is_fence(x));
+        self.lines.splice(first - 1..last - 1, lines);
+
+        // extend the region to all nodes touched by the change and the blocks containing them, in
+        // old line numbers, until it doesn't grow anymore
+        let (mut start, mut end) = (first, last);
+        loop {
+            let previous = (start, end);
+            for node in self.blocks.values() {
+                if node.range.0 <= last.max(end - 1) && node.range.1 >= start {
+                    start = start.min(node.range.0);
+                    end = end.max(node.range.1 + 1);
+                }
+            }
+
+            while start > 1 && !Render::is_block_start(&self.lines, start - 1) {
+                start -= 1;
+            }
+            let mut new_end = (end as isize + delta) as usize;
+            while new_end <= self.lines.len() && !Render::is_block_start(&self.lines, new_end - 1) {
+                new_end += 1;
+            }
+            end = (new_end as isize - delta) as usize;
+
+            if (start, end) == previous {
+                break;
+            }
+        }
+        let new_end = (end as isize + delta) as usize;
+        let region = &self.lines[start - 1..(new_end - 1).min(self.lines.len())];
+
+        // lines of a fence which isn't a node would be parsed as text
+        let in_fence = self.content.has_closing_fence()
+            && self.lines[..start - 1].iter().filter(is_fence).count() % 2 != 0;
+        // inline math is numbered by occurrence in the whole text, so it is parsed in whole as well
+        let has_math = |x: &String| x.contains('$') || x.contains("\\(");
+        let had_math = self.blocks.values()
+            .any(|node| node.column.is_some() && node.range.0 >= start && node.range.0 < end);
+        // fences may refer to fences and images to link definitions outside of the region
+        let has_references = self.lines.iter()
+            .any(|x| x.contains("{{") || self.content.is_link_definition(x));
+        if touches_fence || in_fence || region.iter().filter(is_fence).count() % 2 != 0 || region.iter().any(has_math) || had_math || has_references {
+            let content = self.lines.join("\n");
+            return self.update_content(&content);
+        }
+
+        // remove nodes and folds of the region and shift everything below
+        let shift = |line: usize, by: isize| (line as isize + by) as usize;
+        let (mut old_blocks, mut moved) = (BTreeMap::new(), Vec::new());
+        for (id, mut node) in mem::take(&mut self.blocks) {
+            if node.range.0 >= end {
+                node.range = (shift(node.range.0, delta), shift(node.range.1, delta));
+                if delta != 0 {
+                    moved.push(NodeRef { id: id.clone(), line: node.range.0 });
+                }
+                self.blocks.insert(id, node);
+            } else if node.range.0 < start {
+                self.blocks.insert(id, node);
+            } else {
+                old_blocks.insert(id, node);
+            }
+        }
+
+        let strcts = mem::take(&mut self.strcts);
+        for (line, mut item) in strcts {
+            if line >= end {
+                if let FoldInner::Fold(ref mut fold) = item {
+                    fold.line = shift(fold.line, delta);
+                }
+                self.strcts.insert(shift(line, delta), item);
+            } else if line < start {
+                self.strcts.insert(line, item);
+            }
+        }
+
+        // parse the region with a leading blank line, which ends any block before the region, so
+        // region line numbers are offset by the region start minus two
+        let offset = start as isize - 2;
+        for node in old_blocks.values_mut() {
+            node.range = (shift(node.range.0, -offset), shift(node.range.1, -offset));
+        }
+
+        // the line break before the next block belongs to the blank lines images cover
+        let mut content = format!("\n{}", region.join("\n"));
+        if new_end <= self.lines.len() {
+            content.push('\n');
+        }
+        let (nodes, strcts, _, mut changes, mut overlaps) = self.content.process(&content, old_blocks)?;
+
+        for (id, mut node) in nodes {
+            node.range = (shift(node.range.0, offset), shift(node.range.1, offset));
+            self.blocks.insert(id, node);
+        }
+        for (line, mut item) in strcts {
+            if let FoldInner::Fold(ref mut fold) = item {
+                fold.line = shift(fold.line, offset);
+            }
+            self.strcts.insert(shift(line, offset), item);
+        }
+        for node in changes.changed.iter_mut().chain(&mut changes.appeared).chain(&mut changes.disappeared) {
+            node.line = shift(node.line, offset);
+        }
+        for overlap in &mut overlaps {
+            overlap.line = shift(overlap.line, offset);
+            overlap.end = shift(overlap.end, offset);
+            overlap.next = shift(overlap.next, offset);
+        }
+        changes.changed.extend(moved);
+
+        let folds = self.strcts.iter()
+            .filter(|(_, item)| matches!(item, FoldInner::Fold(_)))
+            .map(|(line, _)| *line)
+            .collect();
+
+        Ok(json::to_string(&self.redraw_state(folds, changes, overlaps)))
+    }
+
+    /// Collect placeholders and serialize the response of a content update
+    fn redraw_state(&self, folds: Vec<usize>, changes: Changes, overlaps: Vec<Overlap>) -> RedrawState {
+        let mut placeholders = Vec::new();
+        for node in self.blocks.values() {
+            if let Some(size) = self.manifest.get(&node.id) {
+                node.set_natural_size(size);
+            }
+
+            // inline nodes don't reserve lines
+            if node.column.is_some() {
+                continue;
+            }
+
+            if let Some(lines) = node.natural_size().and_then(|size| self.required_lines(size)) {
+                placeholders.push(Placeholder { line: node.range.0, lines });
+            }
+        }
+
+        RedrawState {
+            should_redraw: !changes.is_empty(),
+            update_folding: Some(folds),
+            placeholders,
+            changes,
+            overlaps,
+        }
+    }
+
+    /// Set the state of all folds, given as list of start line and end line or `-1` if open
+    ///
+    /// Lines without a known fold are ignored, returns `1` if any fold changed.
+    pub fn set_folds(&mut self, folds: &str) -> Result<usize> {
+        let folds: Folds = json::from_str(folds).map_err(|_| Error::InvalidFolds)?;
+
+        let mut any_changed = false;
+        for (start, end) in folds {
+            let state = if end < 0 { FoldState::Open } else { FoldState::Folded(end as usize) };
+            any_changed |= self.set_fold_state(start, state);
+        }
+
+        Ok(self.folds_changed(any_changed))
+    }
+
+    /// Open the fold starting at the given line
+    pub fn fold_opened(&mut self, line: &str) -> Result<usize> {
+        let line = line.trim().parse().map_err(|_| Error::InvalidFolds)?;
+        let changed = self.set_fold_state(line, FoldState::Open);
+
+        Ok(self.folds_changed(changed))
+    }
+
+    /// Close the fold starting at the first line up to the second, e.g. `"12 20"`
+    pub fn fold_closed(&mut self, lines: &str) -> Result<usize> {
+        let lines = lines.split_whitespace()
+            .map(|x| x.parse::<usize>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| Error::InvalidFolds)?;
+
+        let (line, end) = match lines[..] {
+            [line, end] if line <= end => (line, end),
+            _ => return Err(Error::InvalidFolds),
+        };
+        let changed = self.set_fold_state(line, FoldState::Folded(end));
+
+        Ok(self.folds_changed(changed))
+    }
+
+    /// Update the fold at the given line, returns whether its state changed
+    fn set_fold_state(&mut self, line: usize, state: FoldState) -> bool {
+        match self.strcts.get_mut(&line) {
+            Some(FoldInner::Fold(fold)) if fold.state != state => {
+                fold.state = state;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Hide nodes inside of closed folds after a change, so that they are drawn again once opened
+    fn folds_changed(&mut self, changed: bool) -> usize {
+        if !changed {
+            return 0;
+        }
+
+        let mut end_fold: Option<usize> = None;
+        for (line, elm) in &mut self.strcts {
+            match elm {
+                FoldInner::Fold(Fold { state: FoldState::Folded(end), .. }) =>
+                    end_fold = Some(end_fold.map_or(*end, |x| x.max(*end))),
+                FoldInner::Node((id, view)) if end_fold.map_or(false, |end| *line < end) => {
+                    *view = NodeView::Hidden;
+                    self.painted.retain(|x| x.id != *id);
+                },
+                _ => {},
+            }
+        }
+
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Ranges = Vec<(usize, usize)>;
+
+    /// Ranges of the nodes after replacing lines, and after parsing the new text in whole
+    fn ranges_after(text: &str, first: usize, last: usize, lines: &[&str]) -> (Ranges, Ranges) {
+        let ranges = |render: &Render| {
+            let mut ranges = render.blocks.values().map(|x| x.range).collect::<Vec<_>>();
+            ranges.sort();
+            ranges
+        };
+
+        let mut render = Render::new();
+        render.update_content(text).unwrap();
+        let lines = json::to_string(&lines.iter().map(|x| x.to_string()).collect::<Vec<_>>());
+        render.update_lines(&format!("{{\"first\":{},\"last\":{},\"lines\":{}}}", first, last, lines)).unwrap();
+
+        let mut whole = Render::new();
+        whole.update_content(&render.lines.join("\n")).unwrap();
+
+        (ranges(&render), ranges(&whole))
+    }
+
+    #[test]
+    fn region_parse_matches_whole_parse() {
+        // an image written inside a code block which isn't a node
+        let text = "# a\n\n```\nx = 1\n\ny = 2\n```\n\n![](b.png)\n";
+        let (region, whole) = ranges_after(text, 6, 7, &["![](c.png)"]);
+        assert_eq!(region, whole);
+
+        // removing the opening delimiter turns the code into text
+        let (region, whole) = ranges_after(text, 3, 4, &[]);
+        assert_eq!(region, whole);
+
+        // an image in the continuation of a list item after a blank line
+        let text = "- item\n\n  text\n\n![](b.png)\n";
+        let (region, whole) = ranges_after(text, 3, 4, &["  ![](c.png)"]);
+        assert_eq!(region, whole);
+    }
+
+    #[test]
+    fn blocks_start_after_blank_lines() {
+        let lines = ["a", "", "b", "", "  c", "d"].map(String::from);
+        let starts = (0..lines.len()).filter(|x| Render::is_block_start(&lines, *x)).collect::<Vec<_>>();
 
-        Ok(if any_changed { 1 } else { 0 })
+        assert_eq!(starts, vec![2]);
     }
 }
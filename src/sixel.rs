@@ -0,0 +1,242 @@
+//! Fast SIXEL encoder
+//!
+//! The ImageMagick encoder performs a full color quantization for every blob, which dominates the
+//! draw latency for photos. This encoder maps pixels onto a fixed palette instead (a color cube
+//! plus a ramp of grays), where the nearest color is computed per pixel without any search.
+//! Bands of six rows are then encoded in parallel and concatenated.
+//!
+//! Coarser palettes need fewer passes per band and give smaller blobs, they are selected when
+//! the terminal can't keep up with the images.
+use std::thread;
+
+/// Palette index marking a transparent pixel, palettes have fewer colors than this
+const TRANSPARENT: u8 = u8::MAX;
+
+/// Color cube with a number of steps per channel and the number of gray levels appended after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    cube: usize,
+    grays: usize,
+}
+
+/// Palettes from the finest, a 6x6x6 cube with 39 grays, to the coarsest
+pub const PALETTES: &[Palette] = &[
+    Palette { cube: 6, grays: 39 },
+    Palette { cube: 5, grays: 24 },
+    Palette { cube: 4, grays: 16 },
+    Palette { cube: 3, grays: 8 },
+];
+
+// no color of a palette may take the index of transparent pixels
+const _: () = {
+    let mut i = 0;
+    while i < PALETTES.len() {
+        assert!(PALETTES[i].count() <= TRANSPARENT as usize);
+        i += 1;
+    }
+};
+
+impl Palette {
+    /// Number of colors in the palette
+    pub const fn count(&self) -> usize {
+        self.cube * self.cube * self.cube + self.grays
+    }
+
+    /// Generate the palette as RGB triplets
+    fn colors(&self) -> Vec<(u8, u8, u8)> {
+        let step = |x: usize| (x * 255 / (self.cube - 1)) as u8;
+
+        let mut palette = Vec::with_capacity(self.count());
+        for r in 0..self.cube {
+            for g in 0..self.cube {
+                for b in 0..self.cube {
+                    palette.push((step(r), step(g), step(b)));
+                }
+            }
+        }
+
+        for i in 0..self.grays {
+            let val = (i * 255 / (self.grays - 1)) as u8;
+            palette.push((val, val, val));
+        }
+
+        palette
+    }
+}
+
+/// Map a chunk of RGBA pixels onto palette indices
+///
+/// Every pixel is compared against its nearest cube color and its nearest gray and the one with
+/// smaller error is taken.
+fn quantize_chunk(pixels: &[u8], out: &mut [u8], palette: Palette) {
+    let (steps, grays) = (palette.cube as i32 - 1, palette.grays as i32 - 1);
+    let cube = palette.cube as i32;
+
+    for (px, idx) in pixels.chunks_exact(4).zip(out.iter_mut()) {
+        let (r, g, b, a) = (px[0] as i32, px[1] as i32, px[2] as i32, px[3] as i32);
+
+        // nearest color in the cube
+        let (cr, cg, cb) = ((r * steps + 127) / 255, (g * steps + 127) / 255, (b * steps + 127) / 255);
+        let (dr, dg, db) = (r - cr * 255 / steps, g - cg * 255 / steps, b - cb * 255 / steps);
+        let cube_err = dr * dr + dg * dg + db * db;
+        let cube_idx = cr * cube * cube + cg * cube + cb;
+
+        // nearest gray level
+        let luma = (r + g + b) / 3;
+        let gray = (luma * grays + 127) / 255;
+        let gray_val = gray * 255 / grays;
+        let (gr, gg, gb) = (r - gray_val, g - gray_val, b - gray_val);
+        let gray_err = gr * gr + gg * gg + gb * gb;
+        let gray_idx = cube * cube * cube + gray;
+
+        let color = if gray_err < cube_err { gray_idx } else { cube_idx };
+        *idx = if a < 128 { TRANSPARENT } else { color as u8 };
+    }
+}
+
+/// Push a run of sixel characters, compressed with the repeat introducer
+fn push_run(buf: &mut Vec<u8>, chr: u8, count: usize) {
+    match count {
+        0 => {},
+        1..=3 => buf.extend(std::iter::repeat(chr).take(count)),
+        _ => {
+            buf.extend_from_slice(format!("!{}", count).as_bytes());
+            buf.push(chr);
+        }
+    }
+}
+
+/// Encode a single band of up to six rows
+fn encode_band(indices: &[u8], width: usize, rows: usize, ncolors: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    // find colors used in this band
+    let mut used = vec![false; ncolors];
+    for idx in indices.iter().filter(|x| **x != TRANSPARENT) {
+        used[*idx as usize] = true;
+    }
+
+    let mut first = true;
+    for color in (0..ncolors).filter(|x| used[*x]) {
+        if !first {
+            // return to the start of the band and overlay the next color
+            buf.push(b'$');
+        }
+        first = false;
+
+        buf.extend_from_slice(format!("#{}", color).as_bytes());
+
+        let (mut last, mut count) = (0u8, 0);
+        for x in 0..width {
+            let mut bits = 0u8;
+            for y in 0..rows {
+                if indices[y * width + x] as usize == color {
+                    bits |= 1 << y;
+                }
+            }
+
+            let chr = 63 + bits;
+            if chr == last {
+                count += 1;
+            } else {
+                push_run(&mut buf, last, count);
+                last = chr;
+                count = 1;
+            }
+        }
+
+        // trailing empty columns can be skipped
+        if last != 63 {
+            push_run(&mut buf, last, count);
+        }
+    }
+
+    buf.push(b'-');
+    buf
+}
+
+/// Incremental SIXEL encoder
+///
+/// The encoder yields the header first and then batches of bands, one band per thread, so that
+/// the beginning of an image can be written to the terminal while the rest is still encoded.
+pub struct Encoder {
+    indices: Vec<u8>,
+    palette: Palette,
+    width: usize,
+    height: usize,
+    threads: usize,
+    next_band: Option<usize>,
+}
+
+impl Encoder {
+    /// Create a new encoder and map RGBA pixels with given width and height onto the palette
+    pub fn new(pixels: &[u8], width: usize, height: usize, palette: Palette) -> Encoder {
+        let threads = thread::available_parallelism().map_or(1, |x| x.get());
+
+        // map all pixels to palette indices in parallel chunks of rows
+        let mut indices = vec![0u8; width * height];
+        let rows_per_thread = ((height + threads - 1) / threads).max(1);
+        if width > 0 {
+            thread::scope(|s| {
+                for (pixels, out) in pixels.chunks(rows_per_thread * width * 4).zip(indices.chunks_mut(rows_per_thread * width)) {
+                    s.spawn(move || quantize_chunk(pixels, out, palette));
+                }
+            });
+        }
+
+        Encoder { indices, palette, width, height, threads, next_band: None }
+    }
+
+    fn header(&self) -> Vec<u8> {
+        // P2 = 1 keeps transparent pixels at the background color
+        let mut buf = format!("\x1bP0;1;0q\"1;1;{};{}", self.width, self.height).into_bytes();
+        for (i, (r, g, b)) in self.palette.colors().iter().enumerate() {
+            buf.extend_from_slice(format!("#{};2;{};{};{}", i,
+                *r as usize * 100 / 255, *g as usize * 100 / 255, *b as usize * 100 / 255).as_bytes());
+        }
+
+        buf
+    }
+}
+
+impl Iterator for Encoder {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let band = match self.next_band {
+            None => {
+                self.next_band = Some(0);
+                return Some(self.header());
+            },
+            Some(band) => band,
+        };
+
+        let nbands = (self.height + 5) / 6;
+        if band > nbands {
+            return None;
+        } else if band == nbands || self.width == 0 {
+            self.next_band = Some(nbands + 1);
+            return Some(b"\x1b\\".to_vec());
+        }
+
+        // encode the next batch of bands in parallel
+        let (width, ncolors) = (self.width, self.palette.count());
+        let end = (band + self.threads).min(nbands);
+        let bands = self.indices[band * width * 6..(end * width * 6).min(self.indices.len())]
+            .chunks(width * 6)
+            .collect::<Vec<_>>();
+
+        let encoded = thread::scope(|s| {
+            let handles = bands.iter()
+                .map(|band| s.spawn(move || encode_band(band, width, band.len() / width, ncolors)))
+                .collect::<Vec<_>>();
+
+            handles.into_iter()
+                .flat_map(|x| x.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        self.next_band = Some(end);
+        Some(encoded)
+    }
+}
@@ -0,0 +1,79 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+use crate::error::{Error, Result};
+use crate::render::ART_PATH;
+use crate::utils;
+
+const THEME: &str = "base16-ocean.dark";
+const FONT_SIZE: usize = 16;
+const LINE_HEIGHT: usize = 20;
+const PADDING: usize = 12;
+
+fn color(c: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Highlight code with the syntax of `lang` and write it as SVG image
+///
+/// The SVG contains a single text element per line, the rasterization is then done by
+/// ImageMagick like for all other content.
+pub fn generate_svg(content: &str, lang: &str) -> Result<PathBuf> {
+    let path = Path::new(ART_PATH).join(utils::hash(&format!("{}\n{}", lang, content))).with_extension("svg");
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let syntax_set = SyntaxSet::load_defaults_newlines();
+    let theme_set = ThemeSet::load_defaults();
+    let theme = &theme_set.themes[THEME];
+    let syntax = syntax_set.find_syntax_by_token(lang)
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    let mut lines = Vec::new();
+    let mut max_width = 0;
+    for (nr, line) in LinesWithEndings::from(content).enumerate() {
+        let ranges = highlighter.highlight_line(line, &syntax_set)
+            .map_err(|err| Error::InvalidImage(err.to_string()))?;
+
+        let mut buf = format!("<text x=\"{}\" y=\"{}\">", PADDING, PADDING + (nr + 1) * LINE_HEIGHT);
+        for (style, text) in ranges {
+            buf.push_str(&format!("<tspan fill=\"{}\">{}</tspan>", color(style.foreground), escape(text.trim_end_matches('\n'))));
+        }
+        buf.push_str("</text>\n");
+
+        max_width = max_width.max(line.trim_end().chars().count());
+        lines.push(buf);
+    }
+
+    let background = theme.settings.background.map(color).unwrap_or_else(|| "#000000".to_string());
+    let width = max_width * FONT_SIZE * 6 / 10 + 2 * PADDING;
+    let height = lines.len() * LINE_HEIGHT + 2 * PADDING;
+
+    let mut file = File::create(&path).map_err(Error::Io)?;
+    write!(file,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n<rect width=\"100%\" height=\"100%\" fill=\"{}\"/>\n<g font-family=\"monospace\" font-size=\"{}\" xml:space=\"preserve\">\n",
+        width, height, background, FONT_SIZE
+    ).map_err(Error::Io)?;
+
+    for line in lines {
+        file.write_all(line.as_bytes()).map_err(Error::Io)?;
+    }
+
+    file.write_all(b"</g>\n</svg>\n").map_err(Error::Io)?;
+
+    Ok(path)
+}
@@ -0,0 +1,203 @@
+//! Detection of the graphics protocols supported by the terminal
+//!
+//! Terminals are first recognized by environment variables set by kitty, iTerm2, wezterm and
+//! others. Then the terminal is queried directly: a kitty graphics query, XTSMGRAPHICS for the
+//! number of sixel color registers, the foreground, background and blue palette color (OSC 10, 11
+//! and 4) and finally primary device attributes (DA1), where parameter `4` announces sixel. Every
+//! terminal answers DA1, so its response ends the probe.
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use miniserde::Serialize;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios::{self, SetArg};
+
+/// Time the terminal has to answer all queries
+const PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Capabilities {
+    pub sixel: bool,
+    pub kitty: bool,
+    pub iterm: bool,
+    /// The `ueberzugpp` binary is available
+    pub ueberzug: bool,
+    pub tmux: bool,
+    /// Whether the terminal answered the queries at all
+    pub responded: bool,
+    pub theme: Theme,
+}
+
+/// Colors of the terminal as `#rrggbb`, missing if the terminal didn't report them
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Theme {
+    pub foreground: Option<String>,
+    pub background: Option<String>,
+    /// Blue of the palette, used to highlight
+    pub accent: Option<String>,
+}
+
+impl Theme {
+    /// Whether the terminal reported any color
+    pub fn is_empty(&self) -> bool {
+        self.foreground.is_none() && self.background.is_none() && self.accent.is_none()
+    }
+
+    /// Colors joined to a string, part of the hash of artifacts drawn with them
+    pub fn key(&self) -> String {
+        [&self.foreground, &self.background, &self.accent].iter()
+            .map(|x| x.as_deref().unwrap_or("-"))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+impl Capabilities {
+    /// Name of the best supported backend
+    pub fn best_backend(&self) -> &'static str {
+        if self.kitty {
+            "kitty"
+        } else if self.iterm {
+            "iterm"
+        } else if self.sixel {
+            "sixel"
+        } else if self.ueberzug {
+            "ueberzug"
+        } else {
+            "sixel"
+        }
+    }
+
+    /// Whether any way of displaying images was found
+    pub fn any(&self) -> bool {
+        self.kitty || self.iterm || self.sixel || self.ueberzug
+    }
+}
+
+/// Detect capabilities from the environment and by querying the terminal
+///
+/// The terminal is only probed once, later calls return the same result.
+pub fn detect() -> Capabilities {
+    static DETECTED: OnceLock<Capabilities> = OnceLock::new();
+
+    DETECTED.get_or_init(probe).clone()
+}
+
+fn probe() -> Capabilities {
+    let var = |key: &str| std::env::var(key).unwrap_or_default();
+    let (term, program) = (var("TERM"), var("TERM_PROGRAM"));
+
+    let mut caps = Capabilities {
+        kitty: std::env::var_os("KITTY_WINDOW_ID").is_some()
+            || term.contains("kitty")
+            || term.contains("ghostty")
+            || program == "ghostty",
+        iterm: program == "iTerm.app" || program == "WezTerm",
+        ueberzug: which::which("ueberzugpp").is_ok(),
+        tmux: std::env::var_os("TMUX").is_some(),
+        ..Default::default()
+    };
+
+    if let Some(response) = query() {
+        caps.responded = true;
+        caps.kitty |= response.contains("_Gi=31;OK");
+        caps.sixel |= has_sixel_registers(&response) || has_sixel_attribute(&response);
+        caps.theme = Theme {
+            foreground: osc_color(&response, "\x1b]10;"),
+            background: osc_color(&response, "\x1b]11;"),
+            accent: osc_color(&response, "\x1b]4;4;"),
+        };
+    }
+
+    caps
+}
+
+/// Send all queries and collect the responses until the DA1 answer arrived
+fn query() -> Option<String> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty").ok()?;
+    let fd = tty.as_raw_fd();
+
+    // responses are not line buffered and must not be echoed
+    let orig = termios::tcgetattr(fd).ok()?;
+    let mut raw = orig.clone();
+    termios::cfmakeraw(&mut raw);
+    termios::tcsetattr(fd, SetArg::TCSANOW, &raw).ok()?;
+
+    let _ = tty.write_all(b"\x1b_Gi=31,s=1,v=1,a=q,t=d,f=24;AAAA\x1b\\\x1b[?1;1;0S\x1b]10;?\x1b\\\x1b]11;?\x1b\\\x1b]4;4;?\x1b\\\x1b[c");
+    let _ = tty.flush();
+
+    let start = Instant::now();
+    let mut response = Vec::new();
+    while let Some(remaining) = PROBE_TIMEOUT.checked_sub(start.elapsed()) {
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        match poll(&mut fds, remaining.as_millis() as i32) {
+            Ok(n) if n > 0 => {},
+            _ => break,
+        }
+
+        let mut buf = [0u8; 256];
+        match tty.read(&mut buf) {
+            Ok(n) if n > 0 => response.extend_from_slice(&buf[..n]),
+            _ => break,
+        }
+
+        if da1_response(&response).is_some() {
+            break;
+        }
+    }
+
+    let _ = termios::tcsetattr(fd, SetArg::TCSANOW, &orig);
+
+    if response.is_empty() {
+        None
+    } else {
+        Some(String::from_utf8_lossy(&response).to_string())
+    }
+}
+
+/// Find the parameters of the DA1 response `ESC [ ? Ps ; ... c`
+fn da1_response(response: &[u8]) -> Option<&[u8]> {
+    let start = response.windows(3).position(|x| x == b"\x1b[?")? + 3;
+    let rest = &response[start..];
+    let end = rest.iter().position(|x| !(x.is_ascii_digit() || *x == b';'))?;
+
+    // the XTSMGRAPHICS response starts the same way but ends with `S`
+    match rest[end] {
+        b'c' => Some(&rest[..end]),
+        _ => da1_response(&rest[end..]),
+    }
+}
+
+fn has_sixel_attribute(response: &str) -> bool {
+    da1_response(response.as_bytes())
+        .map(|params| params.split(|x| *x == b';').any(|x| x == b"4"))
+        .unwrap_or(false)
+}
+
+/// Color of an OSC response `prefix rgb:rrrr/gggg/bbbb`, with one to four hex digits per channel
+fn osc_color(response: &str, prefix: &str) -> Option<String> {
+    let start = response.find(prefix)? + prefix.len();
+    let rest = response[start..].strip_prefix("rgb:")?;
+    let end = rest.find(|x: char| !(x.is_ascii_hexdigit() || x == '/'))?;
+
+    let channels = rest[..end].split('/')
+        .map(|x| {
+            let max = 16u32.checked_pow(x.len() as u32)? - 1;
+            let value = u32::from_str_radix(x, 16).ok()?;
+            Some(value * 255 / max.max(1))
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    match channels[..] {
+        [r, g, b] => Some(format!("#{:02x}{:02x}{:02x}", r, g, b)),
+        _ => None,
+    }
+}
+
+/// XTSMGRAPHICS answers with status `0` and the number of color registers if sixel is supported
+fn has_sixel_registers(response: &str) -> bool {
+    response.contains("\x1b[?1;0;")
+}
@@ -0,0 +1,198 @@
+//! Unicode rendering of simple math
+//!
+//! Expressions made of letters, Greek symbols, operators and single level sub- and superscripts,
+//! like `\alpha^2 + \beta_1 \leq \infty`, are written with Unicode characters instead of being
+//! compiled by latex. Anything else, for example fractions, roots, matrices or unknown commands,
+//! has no conversion and is rendered as image.
+use std::iter::Peekable;
+use std::str::Chars;
+
+use unicode_width::UnicodeWidthStr;
+
+/// Commands written as a single character or string
+const SYMBOLS: &[(&str, &str)] = &[
+    ("alpha", "α"), ("beta", "β"), ("gamma", "γ"), ("delta", "δ"), ("epsilon", "ϵ"),
+    ("varepsilon", "ε"), ("zeta", "ζ"), ("eta", "η"), ("theta", "θ"), ("vartheta", "ϑ"),
+    ("iota", "ι"), ("kappa", "κ"), ("lambda", "λ"), ("mu", "μ"), ("nu", "ν"), ("xi", "ξ"),
+    ("pi", "π"), ("rho", "ρ"), ("sigma", "σ"), ("tau", "τ"), ("upsilon", "υ"), ("phi", "ϕ"),
+    ("varphi", "φ"), ("chi", "χ"), ("psi", "ψ"), ("omega", "ω"),
+    ("Gamma", "Γ"), ("Delta", "Δ"), ("Theta", "Θ"), ("Lambda", "Λ"), ("Xi", "Ξ"), ("Pi", "Π"),
+    ("Sigma", "Σ"), ("Upsilon", "Υ"), ("Phi", "Φ"), ("Psi", "Ψ"), ("Omega", "Ω"),
+    ("cdot", "⋅"), ("times", "×"), ("div", "÷"), ("pm", "±"), ("mp", "∓"), ("circ", "∘"),
+    ("ast", "∗"), ("star", "⋆"), ("oplus", "⊕"), ("otimes", "⊗"),
+    ("leq", "≤"), ("le", "≤"), ("geq", "≥"), ("ge", "≥"), ("neq", "≠"), ("ne", "≠"),
+    ("approx", "≈"), ("equiv", "≡"), ("sim", "∼"), ("simeq", "≃"), ("propto", "∝"),
+    ("ll", "≪"), ("gg", "≫"),
+    ("to", "→"), ("rightarrow", "→"), ("leftarrow", "←"), ("leftrightarrow", "↔"),
+    ("Rightarrow", "⇒"), ("Leftarrow", "⇐"), ("Leftrightarrow", "⇔"), ("implies", "⇒"),
+    ("iff", "⇔"), ("mapsto", "↦"),
+    ("in", "∈"), ("notin", "∉"), ("ni", "∋"), ("subset", "⊂"), ("subseteq", "⊆"),
+    ("supset", "⊃"), ("supseteq", "⊇"), ("cup", "∪"), ("cap", "∩"), ("setminus", "∖"),
+    ("emptyset", "∅"), ("forall", "∀"), ("exists", "∃"), ("neg", "¬"), ("land", "∧"),
+    ("lor", "∨"), ("wedge", "∧"), ("vee", "∨"),
+    ("infty", "∞"), ("partial", "∂"), ("nabla", "∇"), ("hbar", "ℏ"), ("ell", "ℓ"),
+    ("Re", "ℜ"), ("Im", "ℑ"), ("aleph", "ℵ"), ("prime", "′"), ("degree", "°"),
+    ("sum", "∑"), ("prod", "∏"), ("int", "∫"), ("iint", "∬"), ("oint", "∮"),
+    ("ldots", "…"), ("cdots", "⋯"), ("dots", "…"),
+    ("langle", "⟨"), ("rangle", "⟩"), ("lfloor", "⌊"), ("rfloor", "⌋"), ("lceil", "⌈"),
+    ("rceil", "⌉"), ("vert", "|"), ("Vert", "‖"),
+    ("sin", "sin"), ("cos", "cos"), ("tan", "tan"), ("log", "log"), ("ln", "ln"),
+    ("exp", "exp"), ("min", "min"), ("max", "max"), ("lim", "lim"), ("det", "det"),
+    ("quad", "\u{2003}"), ("qquad", "\u{2003}\u{2003}"),
+    // spacing and sizing commands without a visible character
+    ("textstyle", ""), ("displaystyle", ""), ("left", ""), ("right", ""),
+    (",", "\u{2009}"), (";", " "), (":", " "), ("!", ""), (" ", " "),
+    ("{", "{"), ("}", "}"), ("|", "‖"), ("%", "%"), ("#", "#"), ("&", "&"), ("_", "_"),
+];
+
+/// Characters with a superscript and subscript form
+const SUPERSCRIPTS: &[(char, char)] = &[
+    ('0', '⁰'), ('1', '¹'), ('2', '²'), ('3', '³'), ('4', '⁴'), ('5', '⁵'), ('6', '⁶'),
+    ('7', '⁷'), ('8', '⁸'), ('9', '⁹'), ('+', '⁺'), ('-', '⁻'), ('=', '⁼'), ('(', '⁽'),
+    (')', '⁾'), ('n', 'ⁿ'), ('i', 'ⁱ'), ('′', '′'), ('*', '*'), ('∗', '*'),
+];
+const SUBSCRIPTS: &[(char, char)] = &[
+    ('0', '₀'), ('1', '₁'), ('2', '₂'), ('3', '₃'), ('4', '₄'), ('5', '₅'), ('6', '₆'),
+    ('7', '₇'), ('8', '₈'), ('9', '₉'), ('+', '₊'), ('-', '₋'), ('=', '₌'), ('(', '₍'),
+    (')', '₎'), ('a', 'ₐ'), ('e', 'ₑ'), ('h', 'ₕ'), ('i', 'ᵢ'), ('j', 'ⱼ'), ('k', 'ₖ'),
+    ('l', 'ₗ'), ('m', 'ₘ'), ('n', 'ₙ'), ('o', 'ₒ'), ('p', 'ₚ'), ('r', 'ᵣ'), ('s', 'ₛ'),
+    ('t', 'ₜ'), ('u', 'ᵤ'), ('v', 'ᵥ'), ('x', 'ₓ'),
+];
+/// Letters of `\mathbb`
+const DOUBLE_STRUCK: &[(char, char)] = &[
+    ('C', 'ℂ'), ('H', 'ℍ'), ('N', 'ℕ'), ('P', 'ℙ'), ('Q', 'ℚ'), ('R', 'ℝ'), ('Z', 'ℤ'),
+];
+/// Relations are surrounded by spaces, as are binary operators following an operand
+const RELATIONS: &str = "=<>≤≥≠≈≡∼≃∝≪≫→←↔⇒⇐⇔↦∈∉∋⊂⊆⊃⊇";
+const OPERATORS: &str = "+-±∓×÷⋅∘⊕⊗∪∩";
+
+/// Number of terminal columns a text takes, CJK characters and emoji take two columns
+pub fn display_width(text: &str) -> usize {
+    text.width()
+}
+
+/// Unicode text of a simple math expression, `None` if it needs latex
+pub fn convert(content: &str) -> Option<String> {
+    let mut out = String::new();
+    convert_into(&mut content.chars().peekable(), &mut out)?;
+
+    let text = out.split(' ').filter(|x| !x.is_empty()).collect::<Vec<_>>().join(" ");
+    Some(text).filter(|x| !x.is_empty())
+}
+
+fn convert_into(chars: &mut Peekable<Chars>, out: &mut String) -> Option<()> {
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let name = command(chars)?;
+                if name == "mathbb" {
+                    for c in argument(chars)?.chars().filter(|x| !x.is_whitespace()) {
+                        out.push(lookup(DOUBLE_STRUCK, c)?);
+                    }
+                    continue;
+                }
+
+                let symbol = SYMBOLS.iter().find(|(x, _)| *x == name)?.1;
+                push(out, symbol);
+            },
+            '^' | '_' => {
+                let table = if c == '^' { SUPERSCRIPTS } else { SUBSCRIPTS };
+
+                // scripts are converted first, so that `^\prime` or `_{i+1}` work
+                let mut script = String::new();
+                convert_into(&mut argument(chars)?.chars().peekable(), &mut script)?;
+                for c in script.chars().filter(|x| !x.is_whitespace()) {
+                    out.push(lookup(table, c)?);
+                }
+            },
+            '\'' => out.push('′'),
+            '~' => out.push(' '),
+            '{' | '}' => {},
+            // alignment, line breaks and comments belong to larger environments
+            '&' | '#' | '%' | '$' => return None,
+            c if c.is_whitespace() => {},
+            c if c.is_ascii_alphanumeric() || "+-*/=<>()[],.;:!|".contains(c) => push(out, &c.to_string()),
+            _ => return None,
+        }
+    }
+
+    Some(())
+}
+
+/// Name of a command after its backslash, either letters or a single other character
+fn command(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(|x| x.is_ascii_alphabetic()) {
+        name.push(c);
+    }
+    if name.is_empty() {
+        name.push(chars.next()?);
+    }
+
+    Some(name)
+}
+
+/// Argument of a command or script, a group in braces, a command or a single character
+fn argument(chars: &mut Peekable<Chars>) -> Option<String> {
+    while chars.next_if(|x| x.is_whitespace()).is_some() {}
+
+    match chars.next()? {
+        '{' => {
+            let mut depth = 1;
+            let mut arg = String::new();
+            for c in chars.by_ref() {
+                depth += match c { '{' => 1, '}' => -1, _ => 0 };
+                if depth == 0 {
+                    return Some(arg);
+                }
+                arg.push(c);
+            }
+
+            None
+        },
+        '\\' => command(chars).map(|x| format!("\\{}", x)),
+        c => Some(c.to_string()),
+    }
+}
+
+fn lookup(table: &[(char, char)], c: char) -> Option<char> {
+    table.iter().find(|(x, _)| *x == c).map(|x| x.1)
+}
+
+/// Append a converted symbol, relations and operators are set apart by spaces and functions like
+/// `sin` are followed by one
+fn push(out: &mut String, symbol: &str) {
+    // a sign at the start or after an opening bracket is unary
+    let unary = out.trim_end().ends_with(|x: char| "([{,".contains(x) || RELATIONS.contains(x) || OPERATORS.contains(x))
+        || out.trim_end().is_empty();
+    let binary = OPERATORS.contains(symbol) && !unary;
+
+    if symbol.chars().count() == 1 && (RELATIONS.contains(symbol) || binary) {
+        out.push(' ');
+        out.push_str(symbol);
+        out.push(' ');
+    } else if symbol.len() > 1 && symbol.chars().all(|x| x.is_ascii_alphabetic()) {
+        out.push_str(symbol);
+        out.push(' ');
+    } else {
+        out.push_str(symbol);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wide_characters_take_two_columns() {
+        assert_eq!(display_width("plot"), 4);
+        assert_eq!(display_width("图表"), 4);
+        assert_eq!(display_width("グラフ"), 6);
+        assert_eq!(display_width("한글"), 4);
+        assert_eq!(display_width("🎉"), 2);
+        assert_eq!(display_width("a🎉b"), 4);
+        // combining marks take no column of their own
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("αβγ"), 3);
+    }
+}
@@ -1,25 +1,164 @@
+use std::ffi::OsStr;
 use std::io::Read;
 use std::{str, usize, io::Write};
 use std::path::{Path, PathBuf};
 use std::fs::File;
-use std::process::{Command, Stdio};
-use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
 use nix::{ioctl_read_bad, pty::Winsize};
+use regex::{Captures, Regex};
 
 use crate::error::{Error, Result};
 use crate::render::ART_PATH;
+use crate::process::Process;
+use crate::terminal::Theme;
 
+/// PlantUML server used instead of a local `plantuml` binary, set from the configuration
+static PLANTUML_SERVER: Mutex<Option<String>> = Mutex::new(None);
+
+/// Version of the content hash, artifacts of other versions are removed by `migrate_cache`
+const HASH_VERSION: &str = "xxh3-128";
+
+/// Hash content to an artifact id of 24 hex characters
 pub fn hash(input: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(input.as_bytes());
-    let result = hasher.finalize();
-    let mut x = format!("{:x}", &result);
+    hash_bytes(input.as_bytes())
+}
+
+pub fn hash_bytes(input: &[u8]) -> String {
+    let mut x = format!("{:032x}", xxhash_rust::xxh3::xxh3_128(input));
     x.truncate(24);
     x
 }
 
-/// Get pixel height of a character
-pub fn char_pixel_height() -> usize {
+/// Remove artifacts named by a previous hash function
+///
+/// The hash version is stored in the artifact folder. If it differs (or is missing, as with the
+/// SHA-256 ids of earlier versions) all artifacts are stale, because no id will be requested
+/// again, and the folder is emptied before the version is written.
+pub fn migrate_cache() -> Result<()> {
+    let marker = Path::new(ART_PATH).join("hash_version");
+    if std::fs::read_to_string(&marker).map_or(false, |x| x.trim() == HASH_VERSION) {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(ART_PATH).map_err(Error::Io)? {
+        let path = entry.map_err(Error::Io)?.path();
+        if path.is_file() {
+            std::fs::remove_file(&path).map_err(Error::Io)?;
+        }
+    }
+
+    std::fs::write(&marker, HASH_VERSION).map_err(Error::Io)
+}
+
+/// Encode bytes with the standard base64 alphabet and padding
+pub fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let buf = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let val = (buf[0] as usize) << 16 | (buf[1] as usize) << 8 | buf[2] as usize;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(val >> (18 - 6 * i)) & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// Split a media fragment like `#t=12` off a file name, returns the file name and the time
+///
+/// Only the start of a range `#t=10,20` is used, times may be given in seconds or as
+/// `[hh:]mm:ss`. Anything else after `#` is part of the file name.
+pub fn split_media_fragment(name: &str) -> (&str, Option<&str>) {
+    let (file, time) = match name.rsplit_once("#t=") {
+        Some(x) => x,
+        None => return (name, None),
+    };
+
+    let time = time.split(',').next().unwrap_or("");
+    let time = time.strip_prefix("npt:").unwrap_or(time);
+    if !time.is_empty() && time.chars().all(|x| x.is_ascii_digit() || x == ':' || x == '.') {
+        (file, Some(time))
+    } else {
+        (name, None)
+    }
+}
+
+/// Find a file by its name in a folder and its subfolders, breadth first up to the given depth
+///
+/// Hidden folders like `.git` or `.obsidian` are skipped.
+pub fn find_file(root: &Path, name: &str, depth: usize) -> Option<PathBuf> {
+    let mut folders = vec![root.to_path_buf()];
+    for _ in 0..=depth {
+        let mut next = Vec::new();
+        for folder in folders {
+            let entries = match std::fs::read_dir(&folder) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+
+                if path.is_dir() {
+                    next.push(path);
+                } else if entry.file_name().to_str() == Some(name) {
+                    return Some(path);
+                }
+            }
+        }
+
+        next.sort();
+        folders = next;
+    }
+
+    None
+}
+
+/// Decode `%XX` escapes of a link target, invalid escapes are kept as they are
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .filter(|x| x.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|x| str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(val)) => {
+                out.push(val);
+                i += 3;
+            },
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Quote an argument for the shell with single quotes
+pub fn shell_quote(input: &str) -> String {
+    format!("'{}'", input.replace('\'', "'\\''"))
+}
+
+/// Query the terminal size in characters and pixels
+fn terminal_size() -> Winsize {
     ioctl_read_bad! { tiocgwinsz, 21523, Winsize }
 
     let mut size = Winsize {
@@ -31,6 +170,13 @@ pub fn char_pixel_height() -> usize {
 
     unsafe {tiocgwinsz(0, &mut size).unwrap() };
 
+    size
+}
+
+/// Get pixel height of a character
+pub fn char_pixel_height() -> usize {
+    let size = terminal_size();
+
     if size.ws_ypixel > 2 {
         size.ws_ypixel as usize / size.ws_row as usize
     } else {
@@ -38,23 +184,192 @@ pub fn char_pixel_height() -> usize {
     }
 }
 
-/// Generate SVG file from latex file with given zoom
-pub fn generate_svg_from_latex(path: &Path, zoom: f32) -> Result<PathBuf> {
+/// Get pixel width of a character
+pub fn char_pixel_width() -> usize {
+    let size = terminal_size();
+
+    if size.ws_xpixel > 2 {
+        size.ws_xpixel as usize / size.ws_col as usize
+    } else {
+        14
+    }
+}
+
+/// Format of a file referenced in the document
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    /// Image with the name of the ImageMagick decoder
+    Image(&'static str),
+    Latex,
+    Gnuplot,
+    /// Video of which a poster frame is shown
+    Video,
+}
+
+/// Detect the format of a file from its first bytes
+///
+/// Binary formats are recognized by their magic bytes, so the extension doesn't matter. Text
+/// files are either SVG, a latex document or a gnuplot script, the latter two are distinguished
+/// by content and by extension.
+pub fn probe_file(path: &Path) -> Result<FileFormat> {
+    let mut head = Vec::with_capacity(512);
+    File::open(path)
+        .and_then(|f| f.take(512).read_to_end(&mut head))
+        .map_err(Error::Io)?;
+
+    let magic: &[(&[u8], &'static str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "PNG"),
+        (b"\xff\xd8\xff", "JPEG"),
+        (b"GIF87a", "GIF"),
+        (b"GIF89a", "GIF"),
+        (b"BM", "BMP"),
+        (b"II*\0", "TIFF"),
+        (b"MM\0*", "TIFF"),
+        (b"%PDF-", "PDF"),
+        (b"%!PS", "PS"),
+        (b"\0\0\0\x0cjP  ", "JP2"),
+    ];
+
+    if let Some((_, format)) = magic.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Ok(FileFormat::Image(format));
+    }
+
+    // WebP and AVIF have the magic bytes after a size field
+    if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+        return Ok(FileFormat::Image("WEBP"));
+    }
+    if head.get(4..12).map_or(false, |x| x == b"ftypavif" || x == b"ftypavis") {
+        return Ok(FileFormat::Image("AVIF"));
+    }
+
+    // MP4 and QuickTime share the box layout of AVIF, Matroska and WebM start with an EBML header
+    if head.get(4..8) == Some(b"ftyp") || head.starts_with(b"\x1a\x45\xdf\xa3")
+        || (head.starts_with(b"RIFF") && head.get(8..12) == Some(b"AVI ")) {
+        return Ok(FileFormat::Video);
+    }
+
+    let text = match str::from_utf8(&head) {
+        Ok(text) => text,
+        // the head may end within a multibyte character
+        Err(err) if err.error_len().is_none() => str::from_utf8(&head[..err.valid_up_to()]).unwrap(),
+        Err(_) => return Err(Error::UnsupportedFile(path.to_path_buf(), "binary".to_string())),
+    };
+
+    let extension = path.extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_lowercase())
+        .unwrap_or_default();
+
+    if text.contains("<svg") {
+        Ok(FileFormat::Image("SVG"))
+    } else if extension == "tex" || text.contains("\\documentclass") {
+        Ok(FileFormat::Latex)
+    } else if matches!(extension.as_str(), "plt" | "gp" | "gnuplot") {
+        Ok(FileFormat::Gnuplot)
+    } else {
+        Err(Error::UnsupportedFile(path.to_path_buf(), "text".to_string()))
+    }
+}
+
+/// Toolchain necessary for a latex document, detected from the packages it uses
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LatexEngine {
+    /// `latex` and `dvisvgm`
+    Latex,
+    /// PSTricks draws with raw PostScript, so the DVI is converted with `dvips` to EPS first
+    PsTricks,
+    /// `xelatex` for `fontspec` and `unicode-math`, the resulting PDF is converted by `dvisvgm`
+    XeLatex,
+    /// `lualatex`, also for the MetaPost code of `luamplib`, the PDF is converted like for xelatex
+    LuaLatex,
+}
+
+impl LatexEngine {
+    /// Engine of a document, selected by a `% !TEX program = xelatex` comment, the `latex_engine`
+    /// setting or the packages
+    pub fn detect(source: &str, setting: Option<LatexEngine>) -> LatexEngine {
+        let program = source.lines()
+            .filter_map(|x| x.trim_start().strip_prefix('%'))
+            .filter_map(|x| x.trim_start().strip_prefix("!TEX program"))
+            .filter_map(|x| x.trim_start().strip_prefix('='))
+            .find_map(|x| LatexEngine::parse(x.trim()));
+
+        if let Some(engine) = program.or(setting) {
+            engine
+        } else if source.contains("luamplib") || source.contains("\\begin{mplibcode}") {
+            LatexEngine::LuaLatex
+        } else if source.contains("{pstricks") || source.contains("{pst-") {
+            LatexEngine::PsTricks
+        } else if source.contains("{fontspec}") || source.contains("{unicode-math}") {
+            LatexEngine::XeLatex
+        } else {
+            LatexEngine::Latex
+        }
+    }
+
+    /// Engine of the `latex_engine` setting, one of `latex`, `xelatex` or `lualatex`
+    pub fn parse(name: &str) -> Option<LatexEngine> {
+        match name {
+            "latex" => Some(LatexEngine::Latex),
+            "xelatex" => Some(LatexEngine::XeLatex),
+            "lualatex" => Some(LatexEngine::LuaLatex),
+            _ => None,
+        }
+    }
+
+    fn binary(&self) -> &'static str {
+        match self {
+            LatexEngine::Latex | LatexEngine::PsTricks => "latex",
+            LatexEngine::XeLatex => "xelatex",
+            LatexEngine::LuaLatex => "lualatex",
+        }
+    }
+
+    /// Extension of the file written by the latex binary
+    fn output(&self) -> &'static str {
+        match self {
+            LatexEngine::Latex | LatexEngine::PsTricks => "dvi",
+            LatexEngine::XeLatex | LatexEngine::LuaLatex => "pdf",
+        }
+    }
+}
+
+/// SVG file converted from a latex file with the given zoom
+///
+/// Other zooms than one are suffixed with the zoom in percent, e.g. `<hash>-150.svg`, so that the
+/// compiled document is shared between them.
+pub fn latex_svg_path(path: &Path, zoom: f32) -> PathBuf {
+    let percent = (zoom * 100.0).round() as usize;
+    match (percent, path.file_stem().and_then(|x| x.to_str())) {
+        (100, _) | (_, None) => path.with_extension("svg"),
+        (percent, Some(stem)) => path.with_file_name(format!("{}-{}.svg", stem, percent)),
+    }
+}
+
+/// Generate SVG file from latex file with given zoom, returns the path of the SVG file
+///
+/// With `shell_escape` latex may run arbitrary commands, which packages like `minted` rely on.
+/// Without an `engine` it is detected from the document.
+pub fn generate_svg_from_latex(path: &Path, zoom: f32, shell_escape: bool, engine: Option<LatexEngine>) -> Result<PathBuf> {
     let dest_path = path.parent().unwrap();
     let file: &Path = path.file_name().unwrap().as_ref();
 
-    // use latex to generate a dvi
-    let dvi_path = path.with_extension("dvi");
+    let source = std::fs::read_to_string(path.with_extension("tex")).unwrap_or_default();
+    let engine = LatexEngine::detect(&source, engine);
+
+    // use latex to generate a dvi, or a pdf in case of lualatex
+    let dvi_path = path.with_extension(engine.output());
     if !dvi_path.exists() {
-        let latex_path = which::which("latex")
-            .map_err(Error::BinaryNotFound)?;
+        let mut cmd = Process::new(engine.binary())?
+            .current_dir(&dest_path);
+        if shell_escape {
+            cmd = cmd.arg("-shell-escape");
+        }
 
-        let cmd = Command::new(latex_path)
-            .current_dir(&dest_path)
+        let cmd = cmd
             //.arg("--jobname").arg(&dvi_path)
             .arg(&file.with_extension("tex"))
-            .output()
-            .expect("Could not spawn latex");
+            .run()?;
 
         if !cmd.status.success() {
             let buf = String::from_utf8_lossy(&cmd.stdout);
@@ -96,44 +411,124 @@ pub fn generate_svg_from_latex(path: &Path, zoom: f32) -> Result<PathBuf> {
         }
     }
 
+    // PostScript specials are only understood by dvips, dvisvgm converts the resulting EPS
+    let mut input_path = dvi_path.clone();
+    let svg_path = latex_svg_path(path, zoom);
+    if engine == LatexEngine::PsTricks && !svg_path.exists() && dvi_path.exists() {
+        input_path = path.with_extension("eps");
+
+        let cmd = Process::new("dvips")?
+            .current_dir(&dest_path)
+            .arg("-q")
+            .arg("-E")
+            .arg("-o")
+            .arg(&input_path)
+            .arg(&dvi_path)
+            .run()?;
+
+        if !cmd.status.success() {
+            let buf = String::from_utf8_lossy(&cmd.stderr);
+            return Err(Error::InvalidDiagram("dvips", buf.trim().to_string()));
+        }
+    }
+
     // convert the dvi to a svg file with the woff font format
-    let svg_path = path.with_extension("svg");
     if !svg_path.exists() && dvi_path.exists() {
-        let dvisvgm_path = which::which("dvisvgm")
-            .map_err(Error::BinaryNotFound)?;
+        let mut cmd = Process::new("dvisvgm")?;
+        match engine {
+            LatexEngine::PsTricks => cmd = cmd.arg("--eps"),
+            LatexEngine::XeLatex | LatexEngine::LuaLatex => cmd = cmd.arg("--pdf"),
+            LatexEngine::Latex => {},
+        }
 
-        let cmd = Command::new(dvisvgm_path)
+        let cmd = cmd
             .current_dir(&dest_path)
             .arg("-b")
             .arg("1")
             //.arg("--font-format=woff")
             .arg("--no-fonts")
             .arg(&format!("--zoom={}", zoom))
-            .arg(&dvi_path)
-            .output()
-            .expect("Couldn't run svisvgm properly!");
+            .arg(&format!("--output={}", svg_path.display()))
+            .arg(&input_path)
+            .run()?;
 
         let buf = String::from_utf8_lossy(&cmd.stderr);
         if !cmd.status.success() || buf.contains("error:") {
-            return Err(Error::InvalidDvisvgm(buf.to_string()));
+            // dvisvgm reads PDFs only with a recent Ghostscript or mutool, poppler is the fallback
+            let converted = engine.output() == "pdf" && pdf_to_svg(&input_path, &svg_path, zoom).is_ok();
+            if !converted {
+                return Err(Error::InvalidDvisvgm(buf.to_string()));
+            }
         }
     }
 
-    Ok(path.to_path_buf())
+    Ok(svg_path)
+}
+
+/// Convert the first page of a PDF with `pdftocairo`, scaling the size of the SVG by the zoom
+fn pdf_to_svg(pdf_path: &Path, svg_path: &Path, zoom: f32) -> Result<()> {
+    let cmd = Process::new("pdftocairo")?
+        .arg("-svg")
+        .arg("-f")
+        .arg("1")
+        .arg("-l")
+        .arg("1")
+        .arg(pdf_path)
+        .arg(svg_path)
+        .run()?;
+
+    if !cmd.status.success() {
+        let buf = String::from_utf8_lossy(&cmd.stderr);
+        return Err(Error::InvalidDiagram("pdftocairo", buf.trim().to_string()));
+    }
+
+    // the viewBox keeps the coordinates, so only the width and height of the root are scaled
+    let svg = std::fs::read_to_string(svg_path).map_err(Error::Io)?;
+    let scaled = Regex::new(r#"^((?s:.*?)<svg[^>]*?\swidth=")([\d.]+)([a-z]*"[^>]*?\sheight=")([\d.]+)"#)
+        .unwrap()
+        .replace(&svg, |caps: &Captures| {
+            let scale = |x: &str| x.parse::<f32>().unwrap_or(0.0) * zoom;
+            format!("{}{}{}{}", &caps[1], scale(&caps[2]), &caps[3], scale(&caps[4]))
+        });
+    std::fs::write(svg_path, scaled.as_bytes()).map_err(Error::Io)?;
+
+    Ok(())
+}
+
+/// SVG file of an equation written with the given colors, preamble and engine
+pub fn equation_path(content: &str, colors: Option<&Theme>, preamble: &str, engine: Option<LatexEngine>) -> PathBuf {
+    let id = match (colors, preamble, engine) {
+        (None, "", None) => hash(content),
+        (colors, preamble, None) => hash(&format!("{}\n{}\n{}", colors.map(|x| x.key()).unwrap_or_default(), preamble, content)),
+        (colors, preamble, Some(engine)) => hash(&format!("{}\n{}\n{:?}\n{}", colors.map(|x| x.key()).unwrap_or_default(), preamble, engine, content)),
+    };
+
+    Path::new(ART_PATH).join(id).with_extension("svg")
 }
 
 /// Parse an equation with the given zoom
+///
+/// With the colors of the colorscheme the equation is written in its foreground, or in black or
+/// white, whichever is readable on its background. The page stays transparent. The `preamble`
+/// may load packages and define macros used by the equation.
 pub fn parse_equation(
     content: &str,
     zoom: f32,
+    colors: Option<&Theme>,
+    preamble: &str,
+    engine: Option<LatexEngine>,
 ) -> Result<PathBuf> {
-    let path = Path::new(ART_PATH).join(hash(content)).with_extension("svg");
+    let path = equation_path(content, colors, preamble, engine);
 
     // create a new tex file containing the equation
     if !path.with_extension("tex").exists() {
         let mut file = File::create(path.with_extension("tex")).map_err(Error::Io)?;
 
-        file.write_all("\\documentclass[20pt, preview]{standalone}\n\\usepackage{amsmath}\\usepackage{amsfonts}\n\\begin{document}\n$$\n".as_bytes())
+        let (package, color) = match colors.and_then(text_color) {
+            Some(color) => ("\\usepackage{xcolor}\n".to_string(), format!("\\color[HTML]{{{}}}\n", color)),
+            None => (String::new(), String::new()),
+        };
+        file.write_all(format!("\\documentclass[20pt, preview]{{standalone}}\n\\usepackage{{amsmath}}\\usepackage{{amsfonts}}\n{}{}\\begin{{document}}\n{}$$\n", package, preamble_lines(preamble), color).as_bytes())
             .map_err(Error::Io)?;
 
         file.write_all(content.as_bytes())
@@ -143,7 +538,188 @@ pub fn parse_equation(
             .map_err(Error::Io)?;
     }
 
-    generate_svg_from_latex(&path, zoom)
+    generate_svg_from_latex(&path, zoom, false, engine)
+}
+
+/// Preamble on lines of its own, empty if there is none
+fn preamble_lines(preamble: &str) -> String {
+    match preamble.trim() {
+        "" => String::new(),
+        preamble => format!("{}\n", preamble),
+    }
+}
+
+/// Color of text as `RRGGBB` for `\color[HTML]`, the foreground or the contrast to the background
+fn text_color(colors: &Theme) -> Option<String> {
+    let rgb = |color: &str| {
+        let hex = color.strip_prefix('#').unwrap_or(color);
+        let value = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6)?;
+
+        Some(((value >> 16) & 0xff, (value >> 8) & 0xff, value & 0xff))
+    };
+
+    if let Some((r, g, b)) = colors.foreground.as_deref().and_then(rgb) {
+        return Some(format!("{:02X}{:02X}{:02X}", r, g, b));
+    }
+
+    // relative luminance of the background decides between white and black text
+    let (r, g, b) = colors.background.as_deref().and_then(rgb)?;
+    let luminance = 299 * r + 587 * g + 114 * b;
+    Some(if luminance < 128_000 { "FFFFFF" } else { "000000" }.to_string())
+}
+
+/// Cells of a row of a pipe table, `\|` is a literal pipe
+fn pipe_table_cells(row: &str) -> Vec<String> {
+    let row = row.trim();
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = row.strip_suffix('|').unwrap_or(row);
+
+    row.replace("\\|", "\u{0}")
+        .split('|')
+        .map(|x| x.trim().replace('\u{0}', "|"))
+        .collect()
+}
+
+/// Escape special characters of latex in a cell, except inside of `$..$` math
+fn escape_table_cell(cell: &str) -> String {
+    let mut math = false;
+    let mut escaped = String::with_capacity(cell.len());
+    for c in cell.chars() {
+        match c {
+            '$' => math = !math,
+            '&' | '%' | '#' | '_' if !math => escaped.push('\\'),
+            _ => {},
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Convert a markdown pipe table to a `tabular` with booktabs rules
+///
+/// Returns `None` if the content has no delimiter row in the second line, e.g. because it is a
+/// `tabular` already. The characters `&`, `%`, `#` and `_` are escaped outside of math, latex
+/// commands can be used in cells.
+pub fn pipe_table_to_latex(content: &str) -> Option<String> {
+    let rows = content.lines()
+        .filter(|x| !x.trim().is_empty())
+        .collect::<Vec<_>>();
+
+    let is_delimiter = |x: &str| x.contains('-') && x.chars().all(|c| matches!(c, '|' | '-' | ':' | ' '));
+    if rows.len() < 2 || !rows[1].contains('|') || !is_delimiter(rows[1]) {
+        return None;
+    }
+
+    let columns = pipe_table_cells(rows[1]).into_iter()
+        .map(|x| match (x.starts_with(':'), x.ends_with(':')) {
+            (true, true) => 'c',
+            (false, true) => 'r',
+            _ => 'l',
+        })
+        .collect::<String>();
+
+    let row_to_latex = |row: &str| {
+        let mut cells = pipe_table_cells(row).into_iter()
+            .map(|x| escape_table_cell(&x))
+            .collect::<Vec<_>>();
+        cells.resize(columns.len(), String::new());
+
+        format!("{} \\\\\n", cells.join(" & "))
+    };
+
+    let mut table = format!("\\begin{{tabular}}{{{}}}\n\\toprule\n", columns);
+    table.push_str(&row_to_latex(rows[0]));
+    table.push_str("\\midrule\n");
+    for row in &rows[2..] {
+        table.push_str(&row_to_latex(row));
+    }
+    table.push_str("\\bottomrule\n\\end{tabular}");
+
+    Some(table)
+}
+
+/// Parse a table and convert it to a SVG file
+///
+/// The content is placed into a standalone document with packages for typesetting tables, like
+/// `booktabs` and `multirow`, loaded. Markdown pipe tables are converted to a `tabular` first.
+pub fn parse_table(
+    content: &str,
+    zoom: f32,
+) -> Result<PathBuf> {
+    let path = Path::new(ART_PATH).join(hash(content)).with_extension("svg");
+
+    // create a new tex file containing the table
+    if !path.with_extension("tex").exists() {
+        let mut file = File::create(path.with_extension("tex")).map_err(Error::Io)?;
+
+        file.write_all("\\documentclass[preview]{standalone}\n\\usepackage{booktabs}\\usepackage{multirow}\\usepackage{array}\\usepackage{amsmath}\\usepackage{amsfonts}\n\\begin{document}\n".as_bytes())
+            .map_err(Error::Io)?;
+
+        let table = pipe_table_to_latex(content);
+        file.write_all(table.as_deref().unwrap_or(content).as_bytes())
+            .map_err(Error::Io)?;
+
+        file.write_all("\n\\end{document}".as_bytes())
+            .map_err(Error::Io)?;
+    }
+
+    generate_svg_from_latex(&path, zoom, false, None)
+}
+
+/// Content of a fence passed to a binary rendering it
+enum SvgInput<'a> {
+    /// Piped to stdin
+    Stdin(&'a str),
+    /// Written to the path unless it exists, for binaries which only read files
+    File(&'a str, &'a Path),
+}
+
+/// Where a binary rendering a fence leaves its SVG
+enum SvgOutput<'a> {
+    /// Written by the binary to the path
+    File(&'a Path),
+    /// Printed to stdout and written to the path afterwards
+    Stdout(&'a Path),
+    /// Written to a file named by the binary, which the caller looks up
+    Named,
+}
+
+/// Run a binary rendering a fence to SVG in the folder of the artifacts
+///
+/// Fails with the message of the binary if it failed or didn't write the SVG, some print their
+/// errors to stdout instead of stderr. A partially written SVG is removed.
+fn run_svg_tool(name: &'static str, args: &[&OsStr], input: SvgInput, output: SvgOutput) -> Result<()> {
+    let mut cmd = args.iter()
+        .fold(Process::new(name)?, |cmd, arg| cmd.arg(arg))
+        .current_dir(ART_PATH);
+    match input {
+        SvgInput::Stdin(content) => cmd = cmd.stdin(content),
+        SvgInput::File(content, path) => if !path.exists() {
+            std::fs::write(path, content).map_err(Error::Io)?;
+        },
+    }
+    let cmd = cmd.run()?;
+
+    let written = match output {
+        SvgOutput::File(path) => path.exists(),
+        SvgOutput::Stdout(_) | SvgOutput::Named => true,
+    };
+    if !cmd.status.success() || !written {
+        if let SvgOutput::File(path) = output {
+            let _ = std::fs::remove_file(path);
+        }
+
+        let (stderr, stdout) = (String::from_utf8_lossy(&cmd.stderr), String::from_utf8_lossy(&cmd.stdout));
+        let buf = if stderr.trim().is_empty() { stdout } else { stderr };
+        return Err(Error::InvalidDiagram(name, buf.trim().to_string()));
+    }
+
+    if let SvgOutput::Stdout(path) = output {
+        std::fs::write(path, &cmd.stdout).map_err(Error::Io)?;
+    }
+
+    Ok(())
 }
 
 /// Generate latex file from gnuplot
@@ -153,30 +729,102 @@ pub fn parse_equation(
 pub fn generate_latex_from_gnuplot(content: &str) -> Result<PathBuf> {
     let path = Path::new(ART_PATH).join(hash(content)).with_extension("tex");
 
-    let gnuplot_path = which::which("gnuplot")
-        .map_err(Error::BinaryNotFound)?;
+    let mut script = format!("set output '{}'\n", path.file_name().unwrap().to_str().unwrap());
+    script.push_str("set terminal epslatex color standalone\n");
+    script.push_str(content);
 
-    let cmd = Command::new(gnuplot_path)
-        .stdin(Stdio::piped())
+    Process::new("gnuplot")?
         .current_dir(ART_PATH)
         .arg("-p")
-        .spawn()
+        .stdin(script)
+        .run()?;
+
+    Ok(path)
+}
+
+/// Plot a gnuplot script with the SVG terminal at the given size in pixels and write it to `path`
+///
+/// The plot is drawn at the size it is displayed at, so that labels keep their font size instead
+/// of being scaled together with the plot.
+///
+/// Colors of the terminal are set before the script, which may override them.
+pub fn generate_svg_from_gnuplot(content: &str, (width, height): (usize, usize), theme: Option<&Theme>, path: &Path) -> Result<PathBuf> {
+    let mut script = format!("set terminal svg size {},{} dynamic enhanced", width, height);
+    if let Some(background) = theme.and_then(|x| x.background.as_ref()) {
+        script.push_str(&format!(" background '{}'", background));
+    }
+    script.push('\n');
+    script.push_str(&format!("set output '{}'\n", path.file_name().unwrap().to_str().unwrap()));
+
+    if let Some(foreground) = theme.and_then(|x| x.foreground.as_ref()) {
+        script.push_str(&format!("set border linecolor rgb '{}'\n", foreground));
+        for element in ["tics", "key", "title", "xlabel", "ylabel"] {
+            script.push_str(&format!("set {} textcolor rgb '{}'\n", element, foreground));
+        }
+    }
+    if let Some(accent) = theme.and_then(|x| x.accent.as_ref()) {
+        script.push_str(&format!("set linetype 1 linecolor rgb '{}'\n", accent));
+    }
+    script.push_str(content);
+
+    run_svg_tool("gnuplot", &[], SvgInput::Stdin(&script), SvgOutput::File(path))?;
+
+    Ok(path.to_path_buf())
+}
+
+/// Plot styles of CSV fences and their gnuplot equivalent
+pub const CSV_PLOT_STYLES: &[(&str, &str)] = &[("line", "lines"), ("scatter", "points"), ("linepoints", "linespoints"), ("bar", "boxes")];
+
+/// Gnuplot script plotting CSV data, the first column is used as x axis for all others
+///
+/// Fields may be separated by commas, semicolons or tabs. A first row which doesn't start with a
+/// number names the columns.
+pub fn gnuplot_from_csv(content: &str, style: &str) -> String {
+    let rows = content.lines()
+        .filter(|x| !x.trim().is_empty())
+        .collect::<Vec<_>>();
+
+    let first = rows.first().copied().unwrap_or("");
+    let separator = [',', ';', '\t'].into_iter()
+        .max_by_key(|x| first.matches(*x).count())
         .unwrap();
-    //.expect("Could not spawn gnuplot");
+    let columns = first.split(separator).count();
+    let header = first.split(separator).next()
+        .map_or(false, |x| x.trim().trim_matches('"').parse::<f64>().is_err());
 
-    let mut stdin = cmd.stdin.unwrap();
+    let style = CSV_PLOT_STYLES.iter()
+        .find(|(name, _)| *name == style)
+        .map_or("lines", |(_, style)| style);
 
-    stdin
-        .write_all(format!("set output '{}'\n", path.file_name().unwrap().to_str().unwrap()).as_bytes())
-        .map_err(Error::Io)?;
-    stdin
-        .write_all("set terminal epslatex color standalone\n".as_bytes())
-        .map_err(Error::Io)?;
-    stdin
-        .write_all(content.as_bytes())
-        .map_err(Error::Io)?;
+    let mut script = format!("set datafile separator '{}'\n", separator);
+    if header {
+        script.push_str("set key autotitle columnhead\n");
+    } else {
+        script.push_str("unset key\n");
+    }
+    if style == "boxes" {
+        script.push_str("set style fill solid 0.5\nset boxwidth 0.8 relative\n");
+    }
 
-    Ok(path)
+    script.push_str("$data << EOD\n");
+    for (i, row) in rows.iter().enumerate() {
+        // column names end up in the key, which is typeset by latex
+        if i == 0 && header {
+            script.push_str(&row.replace('_', "\\_").replace('%', "\\%").replace('&', "\\&").replace('#', "\\#"));
+        } else {
+            script.push_str(row);
+        }
+        script.push('\n');
+    }
+    script.push_str("EOD\n");
+
+    if columns < 2 {
+        script.push_str(&format!("plot $data using 0:1 with {}\n", style));
+    } else {
+        script.push_str(&format!("plot for [i=2:{}] $data using 1:i with {}\n", columns, style));
+    }
+
+    script
 }
 
 pub fn generate_latex_from_gnuplot_file(path: &Path) -> Result<PathBuf> {
@@ -186,28 +834,319 @@ pub fn generate_latex_from_gnuplot_file(path: &Path) -> Result<PathBuf> {
     f.read_to_string(&mut content).unwrap();
 
     let path = generate_latex_from_gnuplot(&content)?;
-    generate_svg_from_latex(&path, 1.0)
+    generate_svg_from_latex(&path, 1.0, false, None)
+}
+
+/// Lay out a graphviz graph with `dot` and write it as SVG file
+///
+/// Colors of the terminal are passed as default attributes, attributes of the graph take
+/// precedence.
+pub fn generate_svg_from_dot(content: &str, theme: Option<&Theme>, path: &Path) -> Result<PathBuf> {
+    let mut attrs = Vec::new();
+    let (foreground, background, accent) = theme
+        .map_or((None, None, None), |x| (x.foreground.as_ref(), x.background.as_ref(), x.accent.as_ref()));
+    if let Some(background) = background {
+        attrs.push(format!("-Gbgcolor={}", background));
+    }
+    if let Some(foreground) = foreground {
+        for attr in ["-Gfontcolor", "-Ncolor", "-Nfontcolor", "-Efontcolor"] {
+            attrs.push(format!("{}={}", attr, foreground));
+        }
+    }
+    if let Some(color) = accent.or(foreground) {
+        attrs.push(format!("-Ecolor={}", color));
+    }
+
+    let mut args = vec!["-Tsvg".as_ref()];
+    args.extend(attrs.iter().map(OsStr::new));
+    args.extend(["-o".as_ref(), path.as_os_str()]);
+    run_svg_tool("dot", &args, SvgInput::Stdin(content), SvgOutput::File(path))?;
+
+    Ok(path.to_path_buf())
+}
+
+/// Render a mermaid diagram with `mmdc` of mermaid-cli and write it as SVG file
+pub fn generate_svg_from_mermaid(content: &str) -> Result<PathBuf> {
+    let path = Path::new(ART_PATH).join(hash(content)).with_extension("svg");
+
+    // mmdc only reads diagrams from files
+    let input = path.with_extension("mmd");
+    let args = ["--quiet".as_ref(), "--backgroundColor".as_ref(), "transparent".as_ref(),
+        "--input".as_ref(), input.as_os_str(), "--output".as_ref(), path.as_os_str()];
+    run_svg_tool("mmdc", &args, SvgInput::File(content, &input), SvgOutput::File(&path))?;
+
+    Ok(path)
+}
+
+/// Render a Vega-Lite specification with `vl-convert` and write it as SVG file
+pub fn generate_svg_from_vega_lite(content: &str) -> Result<PathBuf> {
+    let path = Path::new(ART_PATH).join(hash(content)).with_extension("svg");
+
+    let input = path.with_extension("vl.json");
+    let args = ["vl2svg".as_ref(), "--input".as_ref(), input.as_os_str(), "--output".as_ref(), path.as_os_str()];
+    run_svg_tool("vl-convert", &args, SvgInput::File(content, &input), SvgOutput::File(&path))?;
+
+    Ok(path)
+}
+
+/// Render a fence with a user configured command and write it as SVG file to `path`
+///
+/// The command is split at whitespace, `{input}` is replaced by a file holding the content and
+/// `{output}` by the SVG file. Without `{output}` the SVG is read from stdout.
+pub fn generate_svg_from_command(content: &str, name: &str, command: &str, path: &Path) -> Result<PathBuf> {
+    let input = path.with_extension("input");
+    if !input.exists() {
+        std::fs::write(&input, content).map_err(Error::Io)?;
+    }
+
+    let mut args = command.split_whitespace()
+        .map(|x| x.replace("{input}", &input.to_string_lossy()).replace("{output}", &path.to_string_lossy()));
+    let binary = args.next().ok_or_else(|| Error::InvalidCustom(name.to_string(), "empty command".to_string()))?;
+
+    let cmd = args.fold(Process::new(&binary)?, |cmd, arg| cmd.arg(arg))
+        .run()?;
+
+    if cmd.status.success() && !command.contains("{output}") {
+        std::fs::write(path, &cmd.stdout).map_err(Error::Io)?;
+    }
+
+    if !cmd.status.success() || !path.exists() {
+        let buf = String::from_utf8_lossy(&cmd.stderr);
+        return Err(Error::InvalidCustom(name.to_string(), buf.trim().to_string()));
+    }
+
+    Ok(path.to_path_buf())
+}
+
+/// Extract the frame of a video at the given time and write it as PNG file
+///
+/// The frame is extracted with `ffmpeg`, or `ffmpegthumbnailer` if ffmpeg is missing. Frames
+/// are cached per file, time and modification time of the file.
+pub fn generate_video_poster(path: &Path, time: Option<&str>) -> Result<PathBuf> {
+    let modified = std::fs::metadata(path)
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |x| x.as_secs());
+    let time = time.unwrap_or("0");
+
+    let poster = Path::new(ART_PATH).join(hash(&format!("{}\n{}\n{}", path.display(), time, modified))).with_extension("png");
+    if poster.exists() {
+        return Ok(poster);
+    }
+
+    let (binary, cmd) = match Process::new("ffmpeg") {
+        Ok(cmd) => ("ffmpeg", cmd
+            .arg("-ss").arg(time)
+            .arg("-i").arg(path)
+            .arg("-frames:v").arg("1")
+            .arg("-y").arg(&poster)
+            .run()?),
+        // ffmpegthumbnailer reads plain numbers as percentage
+        Err(_) => ("ffmpegthumbnailer", Process::new("ffmpegthumbnailer")?
+            .arg("-i").arg(path)
+            .arg("-o").arg(&poster)
+            .arg("-t").arg(clock_time(time))
+            .arg("-s").arg("0")
+            .arg("-c").arg("png")
+            .run()?),
+    };
+
+    if !cmd.status.success() || !poster.exists() {
+        let buf = String::from_utf8_lossy(&cmd.stderr);
+        return Err(Error::InvalidDiagram(binary, buf.trim().to_string()));
+    }
+
+    Ok(poster)
+}
+
+/// Time in seconds as `hh:mm:ss`, times with colons are kept
+fn clock_time(time: &str) -> String {
+    if time.contains(':') {
+        return time.to_string();
+    }
+
+    let seconds = time.parse::<f64>().unwrap_or(0.0) as u64;
+    format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Render a pikchr diagram with the `pikchr` binary and write it as SVG file
+pub fn generate_svg_from_pikchr(content: &str) -> Result<PathBuf> {
+    let path = Path::new(ART_PATH).join(hash(content)).with_extension("svg");
+
+    let input = path.with_extension("pikchr");
+    let args = ["--svg-only".as_ref(), input.as_os_str()];
+    run_svg_tool("pikchr", &args, SvgInput::File(content, &input), SvgOutput::Stdout(&path))?;
+
+    Ok(path)
+}
+
+/// Engrave a LilyPond score with `lilypond` and write it as SVG file
+///
+/// The score is cropped to its content, older versions without `-dcrop` and scores spanning
+/// multiple pages fall back to the first page.
+pub fn generate_svg_from_lilypond(content: &str) -> Result<PathBuf> {
+    let path = Path::new(ART_PATH).join(hash(content)).with_extension("svg");
+
+    let input = path.with_extension("ly");
+    let base = path.with_extension("");
+    let args = ["--silent".as_ref(), "-dbackend=svg".as_ref(), "-dno-point-and-click".as_ref(), "-dcrop".as_ref(),
+        "-o".as_ref(), base.as_os_str(), input.as_os_str()];
+    run_svg_tool("lilypond", &args, SvgInput::File(content, &input), SvgOutput::Named)?;
+
+    let first_page = PathBuf::from(format!("{}-1.svg", base.display()));
+    let output = [base.with_extension("cropped.svg"), path.clone(), first_page]
+        .into_iter()
+        .find(|x| x.exists())
+        .ok_or_else(|| Error::InvalidDiagram("lilypond", "no SVG written".to_string()))?;
+
+    if output != path {
+        std::fs::rename(&output, &path).map_err(Error::Io)?;
+    }
+
+    Ok(path)
+}
+
+/// Encode text as QR code and return the width and RGBA pixels of the square image
+///
+/// Each module is drawn as block of pixels, surrounded by the quiet zone of four modules.
+pub fn qr_code_pixels(content: &str) -> Result<(usize, Vec<u8>)> {
+    const MODULE: usize = 8;
+    const QUIET_ZONE: usize = 4;
+
+    let code = qrcode::QrCode::new(content.trim_end_matches('\n'))
+        .map_err(|err| Error::InvalidQrCode(err.to_string()))?;
+    let colors = code.to_colors();
+    let modules = code.width();
+
+    let width = (modules + 2 * QUIET_ZONE) * MODULE;
+    let mut pixels = Vec::with_capacity(width * width * 4);
+    for y in 0..width {
+        for x in 0..width {
+            let (mx, my) = ((x / MODULE).wrapping_sub(QUIET_ZONE), (y / MODULE).wrapping_sub(QUIET_ZONE));
+            let dark = mx < modules && my < modules && colors[my * modules + mx] == qrcode::Color::Dark;
+
+            let value = if dark { 0 } else { 255 };
+            pixels.extend_from_slice(&[value, value, value, 255]);
+        }
+    }
+
+    Ok((width, pixels))
+}
+
+pub fn set_plantuml_server(url: Option<String>) {
+    *PLANTUML_SERVER.lock().unwrap() = url;
 }
 
-/// Parse a latex content and convert it to a SVG file
+/// Render a PlantUML diagram to a SVG file
+///
+/// The diagram is posted to the configured server with `curl` or otherwise piped through a local
+/// `plantuml` binary.
+pub fn generate_svg_from_plantuml(content: &str) -> Result<PathBuf> {
+    let path = Path::new(ART_PATH).join(hash(content)).with_extension("svg");
+    let server = PLANTUML_SERVER.lock().unwrap().clone();
+
+    let (binary, cmd) = match server {
+        Some(url) => ("curl", Process::new("curl")?
+            .arg("--silent")
+            .arg("--show-error")
+            .arg("--fail")
+            .arg("--data-binary")
+            .arg("@-")
+            .arg(format!("{}/svg", url.trim_end_matches('/')))
+            .stdin(content)
+            .run()?),
+        None => ("plantuml", Process::new("plantuml")?
+            .arg("-tsvg")
+            .arg("-pipe")
+            .stdin(content)
+            .run()?),
+    };
+
+    if !cmd.status.success() || !String::from_utf8_lossy(&cmd.stdout).contains("<svg") {
+        let buf = String::from_utf8_lossy(&cmd.stderr);
+        return Err(Error::InvalidDiagram(binary, buf.trim().to_string()));
+    }
+
+    std::fs::write(&path, &cmd.stdout).map_err(Error::Io)?;
+
+    Ok(path)
+}
+
+/// Standalone beamer document of a single frame
+///
+/// The content is either a complete `frame` environment or the body of one. The background
+/// canvas is filled, such that the bounding box covers the whole slide.
+pub fn beamer_document(content: &str, theme: &str, aspect: &str) -> String {
+    let frame = if content.contains("\\begin{frame}") {
+        content.to_string()
+    } else {
+        format!("\\begin{{frame}}\n{}\n\\end{{frame}}", content)
+    };
+
+    format!(
+        "\\documentclass[aspectratio={}]{{beamer}}\n\\usetheme{{{}}}\n\\setbeamercolor{{background canvas}}{{bg=white}}\n\\setbeamertemplate{{navigation symbols}}{{}}\n\\begin{{document}}\n{}\n\\end{{document}}",
+        aspect, theme, frame
+    )
+}
+
+/// SVG file of a latex document, figures are searched in `graphics_paths`
+pub fn latex_path(content: &str, graphics_paths: &[String], preamble: &str, engine: Option<LatexEngine>) -> PathBuf {
+    let id = match (graphics_paths.is_empty(), preamble, engine) {
+        (true, "", None) => hash(content),
+        (_, "", None) => hash(&format!("{}\n{}", graphics_paths.join("\n"), content)),
+        (_, _, None) => hash(&format!("{}\n{}\n{}", graphics_paths.join("\n"), preamble, content)),
+        (_, _, Some(engine)) => hash(&format!("{}\n{}\n{:?}\n{}", graphics_paths.join("\n"), preamble, engine, content)),
+    };
+
+    Path::new(ART_PATH).join(id).with_extension("svg")
+}
+
+/// Compile a latex document, figures of `\includegraphics` are searched in `graphics_paths`
+///
+/// The document is compiled in the cache folder, so the paths are added to the `\graphicspath`
+/// of the document if it loads `graphicx`. The `preamble` is added before `\begin{document}`.
 pub fn parse_latex(
     content: &str,
+    graphics_paths: &[String],
+    preamble: &str,
+    shell_escape: bool,
+    zoom: f32,
+    engine: Option<LatexEngine>,
 ) -> Result<PathBuf> {
-    let path = Path::new(ART_PATH).join(hash(content)).with_extension("svg");
+    let path = latex_path(content, graphics_paths, preamble, engine);
 
     // create a new tex file containing the equation
     if !path.with_extension("tex").exists() {
          let mut file = File::create(&path.with_extension("tex")).map_err(Error::Io)?;
 
+         let content = match content.split_once("\\begin{document}") {
+             Some((head, body)) if !graphics_paths.is_empty() || !preamble.is_empty() => {
+                 let paths = if graphics_paths.is_empty() {
+                     String::new()
+                 } else {
+                     let paths = graphics_paths.iter()
+                         .map(|x| format!("{{{}/}}", x.trim_end_matches('/')))
+                         .collect::<String>();
+
+                     format!("\\makeatletter\\@ifpackageloaded{{graphicx}}{{\\graphicspath{{{}}}}}{{}}\\makeatother", paths)
+                 };
+
+                 format!("{}{}\\begin{{document}}{}{}", head, preamble_lines(preamble), paths, body)
+             },
+             _ => content.to_string(),
+         };
+
          file.write_all(content.as_bytes())
             .map_err(Error::Io)?;
     }
 
-    if !path.exists() {
-        generate_svg_from_latex(&path, 1.0)?;
+    let svg_path = latex_svg_path(&path, zoom);
+    if !svg_path.exists() {
+        generate_svg_from_latex(&path, zoom, shell_escape, engine)?;
     }
 
-    Ok(path)
+    Ok(svg_path)
 }
 
 pub fn parse_latex_from_file(
@@ -218,5 +1157,65 @@ pub fn parse_latex_from_file(
         .map_err(Error::Io)?;
     f.read_to_string(&mut content).unwrap();
 
-    parse_latex(&content)
+    // figures are relative to the file
+    let dir = path.parent()
+        .and_then(|x| x.canonicalize().ok())
+        .map(|x| x.to_string_lossy().to_string());
+
+    parse_latex(&content, &Vec::from_iter(dir), "", false, 1.0, None)
+}
+
+/// Offset of the current tmux pane as rows and columns, or `None` outside of tmux
+pub fn tmux_pane_offset() -> Option<(usize, usize)> {
+    std::env::var_os("TMUX")?;
+
+    let out = Process::new("tmux").ok()?
+        .arg("display-message")
+        .arg("-p")
+        .arg("#{pane_top} #{pane_left}")
+        .immediate()
+        .run().ok()?;
+
+    let out = String::from_utf8_lossy(&out.stdout);
+    let (top, left) = out.trim().split_once(' ')?;
+
+    Some((top.parse().ok()?, left.parse().ok()?))
+}
+
+/// Wrap a sequence in a tmux passthrough, so that it reaches the outer terminal
+///
+/// Escape characters inside the passthrough have to be doubled.
+pub fn tmux_passthrough(buf: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len() + 16);
+    out.extend_from_slice(b"\x1bPtmux;");
+    for byte in buf {
+        if *byte == 0x1b {
+            out.push(0x1b);
+        }
+        out.push(*byte);
+    }
+    out.extend_from_slice(b"\x1b\\");
+
+    out
+}
+
+/// Show a SIXEL blob in a tmux popup window with given number of rows
+///
+/// The blob is written next to the other artifacts and printed by a shell inside the popup, which
+/// closes after any key press.
+pub fn tmux_popup(blob: &[u8], id: &str, rows: usize) -> Result<()> {
+    if std::env::var_os("TMUX").is_none() {
+        return Err(Error::NoTmux);
+    }
+
+    let path = Path::new(ART_PATH).join(id).with_extension("six");
+    std::fs::write(&path, blob).map_err(Error::Io)?;
+
+    Process::new("tmux")?
+        .arg("display-popup")
+        .arg("-E")
+        .arg("-w").arg("80%")
+        .arg("-h").arg(rows.to_string())
+        .arg(format!("cat {}; read _", shell_quote(&path.to_string_lossy())))
+        .spawn_detached()
 }